@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use v4l2capture_rs::parse;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(s) = std::str::from_utf8(data) {
+        if let Ok(code) = parse::fourcc(s) {
+            assert!(code.iter().all(|b| b.is_ascii_graphic() || *b == b' '));
+        }
+    }
+});
@@ -0,0 +1,18 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use v4l2capture_rs::parse;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(s) = std::str::from_utf8(data) {
+        _ = parse::number::<u32>("width", s);
+        _ = parse::number::<u64>("bytes", s);
+        _ = parse::number::<usize>("frames", s);
+        _ = parse::number::<i32>("fd", s);
+        _ = parse::nonzero::<u32>("framerate", s);
+        _ = parse::octal("mode", s);
+        if let Ok((w, h)) = parse::resolution(s) {
+            assert!(w > 0 && h > 0);
+        }
+    }
+});
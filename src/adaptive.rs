@@ -0,0 +1,203 @@
+// --adaptive-rate: a writer that keeps falling behind, with frames dropped
+// from the full --decouple queue second after second, has the camera asked
+// for a lower frame rate rather than losing frames at random. Once the queue
+// kept up for a while the rate goes back up a step, until it is the one
+// negotiated at the start. Drivers that refuse a new rate while streaming,
+// as uvcvideo does, get it with the stream restarted.
+
+use std::io;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use v4l::frameinterval::FrameIntervalEnum;
+use v4l::video::capture::Parameters;
+use v4l::video::Capture;
+use v4l::{Device, Format, Fraction};
+
+use crate::decouple::Dropped;
+use crate::device;
+
+// How often the drops are looked at
+const WINDOW: Duration = Duration::from_secs(1);
+// Windows with drops in a row before the rate goes down
+const BEHIND_WINDOWS: u32 = 2;
+// Windows without drops in a row before it goes up again
+const CLEAR_WINDOWS: u32 = 10;
+
+fn fps(interval: &Fraction) -> f64 {
+    interval.denominator as f64 / interval.numerator.max(1) as f64
+}
+
+// The rates to step through, the negotiated one first and then slower ones:
+// those the driver lists, else the rate halved down to 1 fps
+fn steps(dev: &Device, fmt: &Format, start: Fraction) -> Vec<Fraction> {
+    let nominal = fps(&start);
+    let mut discrete: Vec<Fraction> = dev
+        .enum_frameintervals(fmt.fourcc, fmt.width, fmt.height)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|interval| match interval.interval {
+            FrameIntervalEnum::Discrete(f) => Some(f),
+            FrameIntervalEnum::Stepwise(_) => None,
+        })
+        .filter(|f| fps(f) < nominal)
+        .collect();
+    discrete.sort_by(|a, b| fps(b).total_cmp(&fps(a)));
+    discrete.dedup_by(|a, b| fps(a) == fps(b));
+    let mut steps = vec![start];
+    if !discrete.is_empty() {
+        steps.extend(discrete);
+        return steps;
+    }
+    let mut denominator = start.denominator;
+    while denominator / 2 >= start.numerator.max(1) {
+        denominator /= 2;
+        steps.push(Fraction {
+            numerator: start.numerator,
+            denominator,
+        });
+    }
+    steps
+}
+
+pub enum Change {
+    // The driver took the rate while streaming
+    Applied(Parameters),
+    // The stream is to be restarted with the rate, see restarted()
+    Restart(Fraction),
+}
+
+pub struct AdaptiveRate {
+    dev: Device,
+    dropped: Arc<Dropped>,
+    steps: Vec<Fraction>,
+    // Index into steps of the rate in use
+    step: usize,
+    seen: usize,
+    behind: u32,
+    clear: u32,
+    next_check: Instant,
+    // The step waiting for the stream restart, and why
+    pending: Option<(usize, String)>,
+    // Set after the driver failed to change the rate, not tried again
+    disabled: bool,
+}
+
+impl AdaptiveRate {
+    pub fn new(
+        path: &str,
+        fmt: &Format,
+        params: &Parameters,
+        dropped: Arc<Dropped>,
+    ) -> io::Result<AdaptiveRate> {
+        let dev = device::open(path)?;
+        let steps = steps(&dev, fmt, params.interval);
+        let seen = dropped.frames.load(Ordering::Relaxed);
+        Ok(AdaptiveRate {
+            dev,
+            dropped,
+            steps,
+            step: 0,
+            seen,
+            behind: 0,
+            clear: 0,
+            next_check: Instant::now() + WINDOW,
+            pending: None,
+            disabled: false,
+        })
+    }
+
+    // Back at the start after the stream was negotiated again
+    pub fn reset(&mut self, fmt: &Format, params: &Parameters) {
+        self.steps = steps(&self.dev, fmt, params.interval);
+        self.step = 0;
+        self.behind = 0;
+        self.clear = 0;
+        self.seen = self.dropped.frames.load(Ordering::Relaxed);
+        self.next_check = Instant::now() + WINDOW;
+        self.pending = None;
+    }
+
+    fn give_up(&mut self, why: &str) {
+        eprintln!("warning: --adaptive-rate: {why}, keeping the frame rate from now on");
+        self.disabled = true;
+        self.pending = None;
+    }
+
+    fn changed(&mut self, step: usize, why: &str, params: &Parameters) {
+        eprintln!(
+            "Frame rate {} to {:.2} fps, {why}",
+            if step > self.step {
+                "lowered"
+            } else {
+                "raised"
+            },
+            fps(&params.interval)
+        );
+        self.step = step;
+    }
+
+    // A change of the rate if one is due
+    pub fn check(&mut self) -> Option<Change> {
+        let now = Instant::now();
+        if self.disabled || self.pending.is_some() || now < self.next_check {
+            return None;
+        }
+        self.next_check = now + WINDOW;
+        let dropped = self.dropped.frames.load(Ordering::Relaxed);
+        let new_drops = dropped - self.seen;
+        self.seen = dropped;
+        if new_drops > 0 {
+            (self.behind, self.clear) = (self.behind + 1, 0);
+        } else {
+            (self.behind, self.clear) = (0, self.clear + 1);
+        }
+        let step = if self.behind >= BEHIND_WINDOWS && self.step + 1 < self.steps.len() {
+            self.step + 1
+        } else if self.clear >= CLEAR_WINDOWS && self.step > 0 {
+            self.step - 1
+        } else {
+            return None;
+        };
+        (self.behind, self.clear) = (0, 0);
+        let why = match step > self.step {
+            true => format!("{new_drops} frames dropped by the writer in the last second"),
+            false => format!(
+                "the writer kept up for {} s",
+                CLEAR_WINDOWS as u64 * WINDOW.as_secs()
+            ),
+        };
+        let rate = self.steps[step];
+        match self.dev.set_params(&Parameters::new(rate)) {
+            Ok(params) => {
+                self.changed(step, &why, &params);
+                Some(Change::Applied(params))
+            }
+            Err(e) if e.raw_os_error() == Some(libc::EBUSY) => {
+                self.pending = Some((step, format!("{why}, stream restarted")));
+                Some(Change::Restart(rate))
+            }
+            Err(e) => {
+                self.give_up(&format!("failed to set the frame rate: {e}"));
+                None
+            }
+        }
+    }
+
+    // The stream came back with `params` after a Change::Restart. False
+    // if the driver did not take the rate.
+    pub fn restarted(&mut self, params: &Parameters) -> bool {
+        let Some((step, why)) = self.pending.take() else {
+            return false;
+        };
+        let wanted = fps(&self.steps[step]);
+        if (fps(&params.interval) - wanted).abs() > 0.01 {
+            self.give_up(&format!("the driver did not take {wanted:.2} fps"));
+            return false;
+        }
+        self.changed(step, &why, params);
+        self.seen = self.dropped.frames.load(Ordering::Relaxed);
+        self.next_check = Instant::now() + WINDOW;
+        true
+    }
+}
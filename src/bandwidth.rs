@@ -0,0 +1,101 @@
+// --usb-bandwidth: the byte rate the negotiated mode needs, set against
+// what the USB bus of the camera carries isochronously and, at the end,
+// against what came through. Cameras sharing a bus get less than their mode
+// asks for and deliver fewer frames, which is what this points out.
+
+use std::time::Duration;
+use v4l::Format;
+use v4l2capture_rs::Stats;
+
+use crate::discover;
+use crate::pixfmt;
+
+// Under this share of the nominal frame rate the camera is underdelivering
+const UNDERDELIVERY: f64 = 0.9;
+
+// The most the bus moves for one isochronous endpoint, in bytes per second:
+// 1023 bytes a frame at full speed, 3 x 1024 a microframe at high speed,
+// 48 KiB a service interval at SuperSpeed
+fn isochronous_limit(mbps: f64) -> Option<f64> {
+    match mbps {
+        s if s >= 10000.0 => Some(2.0 * 48.0 * 1024.0 * 8000.0),
+        s if s >= 5000.0 => Some(48.0 * 1024.0 * 8000.0),
+        s if s >= 480.0 => Some(3.0 * 1024.0 * 8000.0),
+        s if s >= 12.0 => Some(1023.0 * 1000.0),
+        // Low speed has no isochronous transfers
+        _ => None,
+    }
+}
+
+fn mb(bytes_per_sec: f64) -> String {
+    format!("{:.1} MB/s", bytes_per_sec / 1e6)
+}
+
+pub struct Bandwidth {
+    fps: f64,
+    // Bytes a second at the nominal rate, an upper bound for compressed
+    // formats whose frames are smaller than the buffer
+    nominal: f64,
+    compressed: bool,
+}
+
+impl Bandwidth {
+    // Says what the mode needs and the bus allows
+    pub fn new(source: &str, fmt: &Format, interval: Duration) -> Bandwidth {
+        let fps = 1.0 / interval.as_secs_f64();
+        let compressed = pixfmt::is_compressed(&fmt.fourcc.repr);
+        let bw = Bandwidth {
+            fps,
+            nominal: fmt.size as f64 * fps,
+            compressed,
+        };
+        eprintln!(
+            "USB bandwidth: {}x{} {} at {fps:.2} fps needs {}{}",
+            fmt.width,
+            fmt.height,
+            fmt.fourcc,
+            if compressed { "up to " } else { "" },
+            mb(bw.nominal)
+        );
+        match discover::usb_speed(source) {
+            Some(speed) => match isochronous_limit(speed) {
+                Some(limit) => {
+                    eprintln!(
+                        "  {speed} Mbit/s bus, {} isochronous at most, {:.0}% of it",
+                        mb(limit),
+                        100.0 * bw.nominal / limit
+                    );
+                    if bw.nominal > limit && !compressed {
+                        eprintln!("  warning: more than the bus can carry, expect lost frames");
+                    }
+                }
+                None => eprintln!("  {speed} Mbit/s bus, too slow for a camera"),
+            },
+            None => eprintln!("  {source} is not a USB device, not comparing with a bus"),
+        }
+        bw
+    }
+
+    // What came through, from the first frame to the last
+    pub fn report(&self, stats: &Stats) {
+        let elapsed = stats.arrival.total.as_secs_f64();
+        if elapsed <= 0.0 {
+            return;
+        }
+        let fps = stats.arrival.count as f64 / elapsed;
+        eprintln!(
+            "USB bandwidth: observed {fps:.2} fps, {} of nominal {:.2} fps, {}{}",
+            mb(stats.bytes_captured as f64 / elapsed),
+            self.fps,
+            if self.compressed { "up to " } else { "" },
+            mb(self.nominal)
+        );
+        if fps < UNDERDELIVERY * self.fps {
+            eprintln!(
+                "  warning: the camera delivers {:.0}% of its nominal frame rate, the bus may \
+                 be shared with other cameras or too slow for the mode",
+                100.0 * fps / self.fps
+            );
+        }
+    }
+}
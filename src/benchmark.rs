@@ -0,0 +1,207 @@
+// --benchmark-formats: capture a moment in every pixel format, frame size
+// and frame rate a device lists, and tabulate what it really delivers there
+
+use std::io;
+use std::time::{Duration, Instant};
+use v4l::buffer::Type;
+use v4l::fraction::Fraction;
+use v4l::frameinterval::FrameIntervalEnum;
+use v4l::framesize::FrameSizeEnum;
+use v4l::video::Capture;
+use v4l::{Device, FourCC};
+use v4l2capture_rs::stream::MmapStream;
+
+use crate::discover::{fps, json_str};
+
+// Capture time per combination
+const CAPTURE_TIME: Duration = Duration::from_secs(2);
+const BUFFERS: u32 = 4;
+
+struct Combination {
+    fourcc: FourCC,
+    width: u32,
+    height: u32,
+    interval: Fraction,
+}
+
+struct Measurement {
+    frames: usize,
+    fps: f64,
+    avg_size: u64,
+    // Frames missing from the sequence numbers
+    drops: u32,
+}
+
+// Every discrete mode, stepwise ones at the largest size and highest rate
+fn combinations(dev: &Device) -> io::Result<Vec<Combination>> {
+    let mut all = Vec::new();
+    for desc in dev.enum_formats()? {
+        for size in dev.enum_framesizes(desc.fourcc).unwrap_or_default() {
+            let (width, height) = match size.size {
+                FrameSizeEnum::Discrete(d) => (d.width, d.height),
+                FrameSizeEnum::Stepwise(s) => (s.max_width, s.max_height),
+            };
+            for interval in dev
+                .enum_frameintervals(desc.fourcc, width, height)
+                .unwrap_or_default()
+            {
+                let interval = match interval.interval {
+                    FrameIntervalEnum::Discrete(f) => f,
+                    FrameIntervalEnum::Stepwise(s) => s.min,
+                };
+                all.push(Combination {
+                    fourcc: desc.fourcc,
+                    width,
+                    height,
+                    interval,
+                });
+            }
+        }
+    }
+    Ok(all)
+}
+
+// Set the combination up exactly, or say why not
+fn negotiate(dev: &Device, c: &Combination) -> Result<(), String> {
+    let mut fmt = dev.format().map_err(|e| format!("read format: {e}"))?;
+    (fmt.fourcc, fmt.width, fmt.height) = (c.fourcc, c.width, c.height);
+    let got = dev
+        .set_format(&fmt)
+        .map_err(|e| format!("set format: {e}"))?;
+    if (got.fourcc, got.width, got.height) != (c.fourcc, c.width, c.height) {
+        return Err(format!(
+            "driver chose {}x{} {}",
+            got.width, got.height, got.fourcc
+        ));
+    }
+    let mut params = dev.params().map_err(|e| format!("read params: {e}"))?;
+    params.interval = c.interval;
+    dev.set_params(&params)
+        .map_err(|e| format!("set frame rate: {e}"))?;
+    Ok(())
+}
+
+fn measure(dev: &Device) -> io::Result<Measurement> {
+    let mut stream = MmapStream::allocate(dev, Type::VideoCapture, BUFFERS)?;
+    stream.set_poll_timeout(Some(Duration::from_secs(1)));
+    let mut frames = 0;
+    let mut bytes = 0u64;
+    let mut drops = 0;
+    let mut first: Option<Instant> = None;
+    let mut elapsed = Duration::ZERO;
+    let mut last = None;
+    let end = Instant::now() + CAPTURE_TIME;
+    while Instant::now() < end {
+        let meta = match stream.next_frame() {
+            Ok((_, meta)) => *meta,
+            // No frame for a second, nothing more to measure
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => break,
+            Err(e) => return Err(e),
+        };
+        // The first frame only starts the clock, the stream warms up before it
+        match first {
+            None => first = Some(Instant::now()),
+            Some(start) => {
+                elapsed = start.elapsed();
+                frames += 1;
+                bytes += meta.bytesused as u64;
+                if let Some(prev) = last {
+                    drops += meta.sequence.wrapping_sub(prev).saturating_sub(1);
+                }
+            }
+        }
+        last = Some(meta.sequence);
+    }
+    Ok(Measurement {
+        frames,
+        fps: match elapsed.as_secs_f64() {
+            secs if secs > 0.0 => frames as f64 / secs,
+            _ => 0.0,
+        },
+        avg_size: bytes.checked_div(frames as u64).unwrap_or(0),
+        drops,
+    })
+}
+
+fn result_json(c: &Combination, result: &Result<Measurement, String>) -> String {
+    let mut fields = vec![
+        format!("\"fourcc\":{}", json_str(&c.fourcc.to_string())),
+        format!("\"width\":{}", c.width),
+        format!("\"height\":{}", c.height),
+        format!("\"fps\":{}", fps(&c.interval)),
+    ];
+    match result {
+        Ok(m) => fields.push(format!(
+            "\"frames\":{},\"achieved_fps\":{:.3},\"avg_size\":{},\"drops\":{}",
+            m.frames, m.fps, m.avg_size, m.drops
+        )),
+        Err(e) => fields.push(format!("\"skipped\":{}", json_str(e))),
+    }
+    format!("{{{}}}", fields.join(","))
+}
+
+pub fn run(path: &str, json: bool) -> Result<(), String> {
+    let dev = Device::with_path(path).map_err(|e| format!("{path}: {e}"))?;
+    let all = combinations(&dev).map_err(|e| format!("{path}: failed to list formats: {e}"))?;
+    // Put back for other clients afterwards
+    let saved = (dev.format().ok(), dev.params().ok());
+    let mut results = Vec::new();
+    for (i, c) in all.iter().enumerate() {
+        eprintln!(
+            "[{}/{}] {} {}x{} at {} fps",
+            i + 1,
+            all.len(),
+            c.fourcc,
+            c.width,
+            c.height,
+            fps(&c.interval)
+        );
+        let result =
+            negotiate(&dev, c).and_then(|_| measure(&dev).map_err(|e| format!("capture: {e}")));
+        if let Err(e) = &result {
+            eprintln!("  skipped: {e}");
+        }
+        results.push(result);
+    }
+    if let Some(fmt) = saved.0 {
+        _ = dev.set_format(&fmt);
+    }
+    if let Some(params) = saved.1 {
+        _ = dev.set_params(&params);
+    }
+    if json {
+        let rows: Vec<String> = all
+            .iter()
+            .zip(&results)
+            .map(|(c, r)| result_json(c, r))
+            .collect();
+        println!(
+            "{{\"device\":{},\"results\":[{}]}}",
+            json_str(path),
+            rows.join(",")
+        );
+        return Ok(());
+    }
+    println!("FORMAT        SIZE      FPS  ACHIEVED   AVG SIZE  DROPS");
+    for (c, result) in all.iter().zip(&results) {
+        let size = format!("{}x{}", c.width, c.height);
+        match result {
+            Ok(m) => println!(
+                "{:<6} {:>11} {:>8} {:>9.2} {:>10} {:>6}",
+                c.fourcc,
+                size,
+                fps(&c.interval),
+                m.fps,
+                m.avg_size,
+                m.drops
+            ),
+            Err(e) => println!(
+                "{:<6} {:>11} {:>8}  skipped: {e}",
+                c.fourcc,
+                size,
+                fps(&c.interval)
+            ),
+        }
+    }
+    Ok(())
+}
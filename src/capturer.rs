@@ -0,0 +1,290 @@
+use std::io;
+use std::ops::Deref;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use v4l::buffer::{Metadata, Type};
+use v4l::video::capture::Parameters;
+use v4l::video::Capture;
+use v4l::{Device, Format, FourCC};
+
+use crate::stats::Stats;
+use crate::stream::{self, MmapStream};
+
+// Library entry point: a configured capture device with its buffers
+pub struct Capturer {
+    dev: Device,
+    stream: MmapStream,
+    stats: Stats,
+}
+
+impl Capturer {
+    // Take an already configured device and allocate `buf_count` buffers
+    pub fn new(dev: Device, buf_count: u32) -> io::Result<Capturer> {
+        Capturer::with_type(dev, Type::VideoCapture, buf_count)
+    }
+
+    // As new(), for other queues than video capture, e.g. Type::MetaCapture
+    pub fn with_type(dev: Device, buf_type: Type, buf_count: u32) -> io::Result<Capturer> {
+        let stream = MmapStream::allocate(&dev, buf_type, buf_count)?;
+        Ok(Capturer {
+            dev,
+            stream,
+            stats: Stats::default(),
+        })
+    }
+
+    // Open a device and negotiate the given geometry, format and frame rate.
+    // The driver may adjust the values; check format() for what is in use.
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        width: u32,
+        height: u32,
+        fourcc: FourCC,
+        framerate: u32,
+    ) -> io::Result<Capturer> {
+        let dev = Device::with_path(path)?;
+        // Not left to a child the caller starts
+        stream::set_cloexec(dev.handle().fd(), true)?;
+        let mut fmt = dev.format()?;
+        fmt.width = width;
+        fmt.height = height;
+        fmt.fourcc = fourcc;
+        dev.set_format(&fmt)?;
+        let mut params = dev.params()?;
+        params.interval = v4l::Fraction::new(1, framerate);
+        dev.set_params(&params)?;
+        Capturer::new(dev, 4)
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.dev
+    }
+
+    pub fn format(&self) -> io::Result<Format> {
+        self.dev.format()
+    }
+
+    // Switch to another format and frame rate on the open device, so no other
+    // client can take it in between: stream off, free the buffers, apply the
+    // settings and map as many buffers as before. Streaming starts again with
+    // the next frame. Returns what the driver made of the settings. If the
+    // driver rejects them the previous format and parameters are restored
+    // before the error is returned, and capturing goes on as before. The
+    // rejection is returned even where the buffers cannot be mapped again
+    // either; a failed mapping alone is returned as that.
+    pub fn reconfigure(
+        &mut self,
+        format: &Format,
+        params: &Parameters,
+    ) -> io::Result<(Format, Parameters)> {
+        let buf_count = self.stream.count() as u32;
+        let old_format = self.dev.format()?;
+        let old_params = self.dev.params()?;
+        // S_FMT fails with EBUSY while buffers are allocated
+        self.stream.release();
+        let applied = self
+            .dev
+            .set_format(format)
+            .and_then(|fmt| Ok((fmt, self.dev.set_params(params)?)));
+        if applied.is_err() {
+            _ = self.dev.set_format(&old_format);
+            _ = self.dev.set_params(&old_params);
+        }
+        let reallocated = self.stream.reallocate(buf_count);
+        self.stats.restart();
+        match (applied, reallocated) {
+            (Err(e), _) | (Ok(_), Err(e)) => Err(e),
+            (Ok(applied), Ok(())) => Ok(applied),
+        }
+    }
+
+    pub fn stream(&mut self) -> &mut MmapStream {
+        &mut self.stream
+    }
+
+    // Counters of the frames captured so far
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    // For callers to account for what they write, skip or drop
+    pub fn stats_mut(&mut self) -> &mut Stats {
+        &mut self.stats
+    }
+
+    // Block until the next frame is available. The frame is valid until the
+    // next call and holds the `bytesused` bytes of the buffer, unlike the
+    // whole buffers of MmapStream.
+    pub fn next_frame(&mut self) -> io::Result<(&[u8], &Metadata)> {
+        let (buf, meta) = self.stream.next_frame()?;
+        self.stats.record_frame(meta);
+        Ok((used(buf, meta), meta))
+    }
+
+    // Wait for the next frame and keep its buffer out of the queue until the
+    // returned guard is dropped, so that the frame can be used in place
+    // without a copy. While it is held the driver has a buffer less to fill;
+    // holding it for longer than the other buffers last makes the driver drop
+    // frames, and with a single buffer the stream stalls.
+    pub fn hold_frame(&mut self) -> io::Result<HeldFrame<'_>> {
+        let index = self.stream.next_held()?;
+        self.stats.record_frame(self.stream.metadata(index));
+        Ok(HeldFrame {
+            capturer: self,
+            index,
+        })
+    }
+
+    // Manual queueing, for callers that need a fixed queue and dequeue order
+    // (e.g. tests of buffer recycling) instead of next_frame(), which must not
+    // be mixed with these. Queue the buffers to start with here; streaming
+    // starts with the first dequeue_buffer().
+    pub fn queue_buffer(&mut self, index: usize) -> io::Result<()> {
+        self.check_index(index)?;
+        self.stream.queue(index)
+    }
+
+    // Wait for the next filled buffer and return its index with the frame.
+    // The buffer stays ours until it is given back with requeue().
+    pub fn dequeue_buffer(&mut self) -> io::Result<(usize, &[u8], &Metadata)> {
+        if !self.stream.is_streaming() {
+            self.stream.stream_on()?;
+        }
+        let index = self.stream.dequeue_wait(libc::POLLIN)?;
+        let meta = self.stream.metadata(index);
+        self.stats.record_frame(meta);
+        Ok((index, used(self.stream.buffer(index), meta), meta))
+    }
+
+    // Give a dequeued buffer back to the driver
+    pub fn requeue(&mut self, index: usize) -> io::Result<()> {
+        self.queue_buffer(index)
+    }
+
+    fn check_index(&self, index: usize) -> io::Result<()> {
+        if index >= self.stream.count() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("no buffer {index}, there are {}", self.stream.count()),
+            ));
+        }
+        Ok(())
+    }
+
+    // Capture `n` frames and return copies of them. Each frame is copied out
+    // before the next dequeue lets the driver reuse its buffer.
+    pub fn capture_to_vec(&mut self, n: usize) -> io::Result<Vec<Vec<u8>>> {
+        let mut frames = Vec::with_capacity(n);
+        while frames.len() < n {
+            match self.stream.next_frame() {
+                Ok((buf, meta)) => {
+                    self.stats.record_frame(meta);
+                    frames.push(used(buf, meta).to_vec())
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(frames)
+    }
+}
+
+// A frame in its driver buffer, from Capturer::hold_frame(). The buffer is
+// queued again on drop, or with release() to see the error.
+pub struct HeldFrame<'a> {
+    capturer: &'a mut Capturer,
+    index: usize,
+}
+
+impl HeldFrame<'_> {
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        self.capturer.stream.metadata(self.index)
+    }
+
+    pub fn release(self) -> io::Result<()> {
+        let mut frame = std::mem::ManuallyDrop::new(self);
+        let index = frame.index;
+        frame.capturer.stream.give_back(index)
+    }
+}
+
+impl Deref for HeldFrame<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        let stream = &self.capturer.stream;
+        used(stream.buffer(self.index), stream.metadata(self.index))
+    }
+}
+
+impl Drop for HeldFrame<'_> {
+    fn drop(&mut self) {
+        _ = self.capturer.stream.give_back(self.index);
+    }
+}
+
+// The part of a driver buffer holding the frame
+fn used<'a>(buf: &'a [u8], meta: &Metadata) -> &'a [u8] {
+    &buf[..(meta.bytesused as usize).min(buf.len())]
+}
+
+impl AsRawFd for Capturer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.dev.handle().fd()
+    }
+}
+
+#[cfg(feature = "async")]
+pub use self::nonblocking::AsyncCapturer;
+
+#[cfg(feature = "async")]
+mod nonblocking {
+    use std::io::{self, ErrorKind};
+    use tokio::io::unix::AsyncFd;
+    use v4l::buffer::Metadata;
+
+    use super::{used, Capturer};
+
+    // Capturer driven by the tokio reactor instead of a blocking poll.
+    // Must be created from within a tokio runtime with I/O enabled.
+    pub struct AsyncCapturer {
+        inner: AsyncFd<Capturer>,
+    }
+
+    impl AsyncCapturer {
+        pub fn new(capturer: Capturer) -> io::Result<AsyncCapturer> {
+            Ok(AsyncCapturer {
+                inner: AsyncFd::new(capturer)?,
+            })
+        }
+
+        pub fn get_mut(&mut self) -> &mut Capturer {
+            self.inner.get_mut()
+        }
+
+        pub fn into_inner(self) -> Capturer {
+            self.inner.into_inner()
+        }
+
+        // Wait until the device has a filled buffer and dequeue it
+        pub async fn next_frame(&mut self) -> io::Result<(&[u8], &Metadata)> {
+            let index = loop {
+                let mut guard = self.inner.readable_mut().await?;
+                match guard.get_inner_mut().stream.try_next_frame() {
+                    Ok(index) => break index,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => guard.clear_ready(),
+                    Err(e) => return Err(e),
+                }
+            };
+            let capturer = self.inner.get_mut();
+            capturer.stats.record_frame(capturer.stream.metadata(index));
+            let stream = &self.inner.get_ref().stream;
+            let meta = stream.metadata(index);
+            Ok((used(stream.buffer(index), meta), meta))
+        }
+    }
+}
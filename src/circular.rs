@@ -0,0 +1,379 @@
+// --circular-file: frames written into a preallocated file used as a ring,
+// the oldest frames overwritten when it is full, for a rolling window on
+// disk without segment files coming and going. An existing ring of the same
+// size is carried on. --circular-dump reads the frames out oldest first.
+//
+// The file is a header and the data area after it. A frame is a record of
+// its length (u32 LE), sequence number (u32 LE) and timestamp in
+// microseconds (u64 LE), then the frame. Records that would cross the end of
+// the data area go to its start instead, the header keeping where the lap
+// before ended. The header is rewritten to forget overwritten records before
+// they are overwritten, and to take a record in after it was written, so a
+// crash loses the frame being written at most.
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+use v4l::buffer::Metadata;
+use v4l::video::capture::Parameters;
+use v4l::{Format, FourCC};
+
+use crate::container::ContainerWriter;
+
+const MAGIC: &[u8; 8] = b"V4LRING1";
+pub const HEADER_LEN: u64 = 64;
+pub const RECORD_LEN: u64 = 16;
+
+#[derive(Default)]
+struct Header {
+    // Size of the data area
+    size: u64,
+    // Offsets in the data area: the oldest record, where the next goes
+    head: u64,
+    tail: u64,
+    // End of the lap before, while records of it are left
+    wrap: u64,
+    frames: u64,
+    fourcc: [u8; 4],
+    width: u32,
+    height: u32,
+}
+
+impl Header {
+    fn read(file: &File) -> io::Result<Option<Header>> {
+        let mut buf = [0u8; HEADER_LEN as usize];
+        match file.read_exact_at(&mut buf, 0) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        if &buf[..8] != MAGIC {
+            return Ok(None);
+        }
+        let u64_at = |at: usize| u64::from_le_bytes(buf[at..at + 8].try_into().unwrap());
+        let u32_at = |at: usize| u32::from_le_bytes(buf[at..at + 4].try_into().unwrap());
+        Ok(Some(Header {
+            size: u64_at(8),
+            head: u64_at(16),
+            tail: u64_at(24),
+            wrap: u64_at(32),
+            frames: u64_at(40),
+            fourcc: buf[48..52].try_into().unwrap(),
+            width: u32_at(52),
+            height: u32_at(56),
+        }))
+    }
+
+    fn write(&self, file: &File) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(HEADER_LEN as usize);
+        buf.extend_from_slice(MAGIC);
+        for field in [self.size, self.head, self.tail, self.wrap, self.frames] {
+            buf.extend_from_slice(&field.to_le_bytes());
+        }
+        buf.extend_from_slice(&self.fourcc);
+        buf.extend_from_slice(&self.width.to_le_bytes());
+        buf.extend_from_slice(&self.height.to_le_bytes());
+        buf.resize(HEADER_LEN as usize, 0);
+        file.write_all_at(&buf, 0)
+    }
+}
+
+struct Record {
+    offset: u64,
+    // With the record header
+    len: u64,
+    sequence: u32,
+}
+
+// The records in the ring, oldest first
+fn records(file: &File, header: &Header) -> io::Result<VecDeque<Record>> {
+    let corrupt = |what: &str| io::Error::new(ErrorKind::InvalidData, format!("ring {what}"));
+    let mut all = VecDeque::new();
+    let mut pos = header.head;
+    let mut wrapped = header.head < header.tail;
+    for _ in 0..header.frames {
+        if !wrapped && pos == header.wrap {
+            pos = 0;
+            wrapped = true;
+        }
+        if pos + RECORD_LEN > header.size {
+            return Err(corrupt("record past the end"));
+        }
+        let mut buf = [0u8; RECORD_LEN as usize];
+        file.read_exact_at(&mut buf, HEADER_LEN + pos)?;
+        let len = RECORD_LEN + u32::from_le_bytes(buf[..4].try_into().unwrap()) as u64;
+        if pos + len > header.size {
+            return Err(corrupt("frame past the end"));
+        }
+        all.push_back(Record {
+            offset: pos,
+            len,
+            sequence: u32::from_le_bytes(buf[4..8].try_into().unwrap()),
+        });
+        pos += len;
+    }
+    // Past the last record of the lap before at the end of it
+    if !wrapped && pos == header.wrap {
+        pos = 0;
+    }
+    if header.frames > 0 && pos != header.tail {
+        return Err(corrupt("records do not end at the write position"));
+    }
+    Ok(all)
+}
+
+pub struct Circular {
+    file: File,
+    header: Header,
+    records: VecDeque<Record>,
+}
+
+impl Circular {
+    // `size` of the whole file
+    pub fn open(path: &str, size: u64) -> io::Result<Circular> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let size = size - HEADER_LEN;
+        if let Some(header) = Header::read(&file)?.filter(|h| h.size == size) {
+            match records(&file, &header) {
+                Ok(records) => {
+                    eprintln!("{path}: carrying on a ring of {} frames", records.len());
+                    return Ok(Circular {
+                        file,
+                        header,
+                        records,
+                    });
+                }
+                Err(e) => eprintln!("warning: {path}: {e}, starting it over"),
+            }
+        }
+        // All of it up front, so that a full disk shows at the start
+        let err = unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, (HEADER_LEN + size) as _) };
+        if err != 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() != Some(libc::EOPNOTSUPP) {
+                return Err(e);
+            }
+        }
+        file.set_len(HEADER_LEN + size)?;
+        let header = Header {
+            size,
+            ..Header::default()
+        };
+        header.write(&file)?;
+        Ok(Circular {
+            file,
+            header,
+            records: VecDeque::new(),
+        })
+    }
+}
+
+impl ContainerWriter for Circular {
+    fn begin(&mut self, format: &Format, _params: &Parameters) -> io::Result<()> {
+        let h = &mut self.header;
+        (h.fourcc, h.width, h.height) = (format.fourcc.repr, format.width, format.height);
+        h.write(&self.file)
+    }
+
+    fn write_frame(&mut self, buf: &[u8], meta: &Metadata, _mapped: bool) -> io::Result<()> {
+        let len = RECORD_LEN + buf.len() as u64;
+        if len > self.header.size {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("frame of {} bytes larger than the ring", buf.len()),
+            ));
+        }
+        let h = &mut self.header;
+        let mut pos = h.tail;
+        let mut forgot = false;
+        if pos + len > h.size {
+            // What is left of the lap before is the oldest, and in the way
+            while self.records.front().is_some_and(|r| r.offset >= pos) {
+                self.records.pop_front();
+            }
+            h.wrap = pos;
+            pos = 0;
+            forgot = true;
+        }
+        while self
+            .records
+            .front()
+            .is_some_and(|r| r.offset >= pos && r.offset < pos + len)
+        {
+            self.records.pop_front();
+            forgot = true;
+        }
+        if forgot {
+            h.head = self.records.front().map_or(pos, |r| r.offset);
+            h.tail = pos;
+            h.frames = self.records.len() as u64;
+            h.write(&self.file)?;
+        }
+        let mut record = Vec::with_capacity(RECORD_LEN as usize);
+        record.extend_from_slice(&(buf.len() as u32).to_le_bytes());
+        record.extend_from_slice(&meta.sequence.to_le_bytes());
+        let micros = Duration::from(meta.timestamp).as_micros() as u64;
+        record.extend_from_slice(&micros.to_le_bytes());
+        self.file.write_all_at(&record, HEADER_LEN + pos)?;
+        self.file.write_all_at(buf, HEADER_LEN + pos + RECORD_LEN)?;
+        self.records.push_back(Record {
+            offset: pos,
+            len,
+            sequence: meta.sequence,
+        });
+        let h = &mut self.header;
+        if self.records.len() == 1 {
+            h.head = pos;
+        }
+        h.tail = pos + len;
+        h.frames = self.records.len() as u64;
+        h.write(&self.file)
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+// --circular-dump: the frames of a ring one after the other, oldest first
+pub fn dump(path: &str, out: &mut impl Write) -> io::Result<()> {
+    let file = File::open(path)?;
+    let header = Header::read(&file)?
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "not a --circular-file ring"))?;
+    let records = records(&file, &header)?;
+    let mut buf = Vec::new();
+    for r in &records {
+        buf.resize((r.len - RECORD_LEN) as usize, 0);
+        file.read_exact_at(&mut buf, HEADER_LEN + r.offset + RECORD_LEN)?;
+        out.write_all(&buf)?;
+    }
+    out.flush()?;
+    match (records.front(), records.back()) {
+        (Some(first), Some(last)) => eprintln!(
+            "{}x{} {}, {} frames, seq {} to {}",
+            header.width,
+            header.height,
+            FourCC::new(&header.fourcc),
+            records.len(),
+            first.sequence,
+            last.sequence
+        ),
+        _ => eprintln!("The ring is empty"),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TempRing(String);
+
+    impl TempRing {
+        fn new(name: &str) -> TempRing {
+            let path = std::env::temp_dir().join(format!("ring-{}-{name}", std::process::id()));
+            TempRing(path.to_string_lossy().into_owned())
+        }
+    }
+
+    impl Drop for TempRing {
+        fn drop(&mut self) {
+            _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn frame(sequence: u32) -> Vec<u8> {
+        vec![sequence as u8; 40]
+    }
+
+    fn write(ring: &mut Circular, sequence: u32) {
+        let meta = Metadata {
+            sequence,
+            ..Metadata::default()
+        };
+        ring.write_frame(&frame(sequence), &meta, false).unwrap();
+    }
+
+    fn sequences(path: &str) -> Vec<u32> {
+        let file = File::open(path).unwrap();
+        let header = Header::read(&file).unwrap().unwrap();
+        records(&file, &header)
+            .unwrap()
+            .iter()
+            .map(|r| r.sequence)
+            .collect()
+    }
+
+    fn dumped(path: &str) -> Vec<u8> {
+        let mut out = Vec::new();
+        dump(path, &mut out).unwrap();
+        out
+    }
+
+    // Room for three records of 56 bytes and a bit, so every lap wraps early
+    const SIZE: u64 = HEADER_LEN + 200;
+
+    #[test]
+    fn wraps_around() {
+        let tmp = TempRing::new("wrap");
+        let mut ring = Circular::open(&tmp.0, SIZE).unwrap();
+        for sequence in 0..10 {
+            write(&mut ring, sequence);
+            let seqs = sequences(&tmp.0);
+            assert_eq!(seqs.last(), Some(&sequence));
+            assert!(seqs.windows(2).all(|w| w[1] == w[0] + 1));
+        }
+        assert_eq!(sequences(&tmp.0), [7, 8, 9]);
+        let expected: Vec<u8> = (7..10).flat_map(frame).collect();
+        assert_eq!(dumped(&tmp.0), expected);
+    }
+
+    #[test]
+    fn carries_on_after_reopen() {
+        let tmp = TempRing::new("reopen");
+        let mut ring = Circular::open(&tmp.0, SIZE).unwrap();
+        for sequence in 0..5 {
+            write(&mut ring, sequence);
+        }
+        drop(ring);
+        let mut ring = Circular::open(&tmp.0, SIZE).unwrap();
+        assert_eq!(ring.records.len(), 3);
+        write(&mut ring, 5);
+        assert_eq!(sequences(&tmp.0), [3, 4, 5]);
+        // Another size starts it over
+        drop(ring);
+        let ring = Circular::open(&tmp.0, SIZE + 100).unwrap();
+        assert!(ring.records.is_empty());
+        drop(ring);
+        assert!(dumped(&tmp.0).is_empty());
+    }
+
+    #[test]
+    fn starts_over_a_corrupt_ring() {
+        let tmp = TempRing::new("corrupt");
+        let mut ring = Circular::open(&tmp.0, SIZE).unwrap();
+        write(&mut ring, 0);
+        ring.header.frames = 2;
+        ring.header.write(&ring.file).unwrap();
+        drop(ring);
+        assert!(Circular::open(&tmp.0, SIZE).unwrap().records.is_empty());
+    }
+
+    #[test]
+    fn rejects_frames_larger_than_the_ring() {
+        let tmp = TempRing::new("large");
+        let mut ring = Circular::open(&tmp.0, SIZE).unwrap();
+        let err = ring
+            .write_frame(&[0; 200], &Metadata::default(), false)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+}
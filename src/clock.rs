@@ -0,0 +1,74 @@
+use std::fs;
+use std::time::Duration;
+use v4l::buffer::Flags;
+
+// Clock used for userspace arrival timestamps.
+//
+// Driver timestamps are usually CLOCK_MONOTONIC, but some drivers copy
+// timestamps from elsewhere (TIMESTAMP_COPY) or do not say (TIMESTAMP_UNKNOWN),
+// so the per-frame report shows what the buffer flags claim.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Clock {
+    Monotonic,
+    Realtime,
+}
+
+impl Clock {
+    pub fn parse(s: &str) -> Option<Clock> {
+        match s {
+            "monotonic" => Some(Clock::Monotonic),
+            "realtime" => Some(Clock::Realtime),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Clock::Monotonic => "monotonic",
+            Clock::Realtime => "realtime",
+        }
+    }
+
+    pub fn now(self) -> Duration {
+        let id = match self {
+            Clock::Monotonic => libc::CLOCK_MONOTONIC,
+            Clock::Realtime => libc::CLOCK_REALTIME,
+        };
+        let mut ts: libc::timespec = unsafe { std::mem::zeroed() };
+        unsafe { libc::clock_gettime(id, &mut ts) };
+        Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+    }
+}
+
+// Clock the driver says it used for the buffer timestamp
+pub fn driver_clock(flags: Flags) -> &'static str {
+    let ts = flags & Flags::TIMESTAMP_MASK;
+    if ts == Flags::TIMESTAMP_MONOTONIC {
+        "monotonic"
+    } else if ts == Flags::TIMESTAMP_COPY {
+        "copy"
+    } else {
+        "unknown"
+    }
+}
+
+// V4L2 has no ioctl for choosing the timestamp clock. The uvcvideo driver
+// is the only common one with a knob, a module parameter shared by all
+// UVC devices, so try that and report if it is not possible.
+pub fn request_driver_clock(driver: &str, clock: Clock) {
+    const UVC_CLOCK_PARAM: &str = "/sys/module/uvcvideo/parameters/clock";
+    if driver != "uvcvideo" {
+        eprintln!(
+            "Driver {} has no timestamp clock setting, using its default",
+            driver
+        );
+        return;
+    }
+    let value = match clock {
+        Clock::Monotonic => "CLOCK_MONOTONIC",
+        Clock::Realtime => "CLOCK_REALTIME",
+    };
+    if let Err(e) = fs::write(UVC_CLOCK_PARAM, value) {
+        eprintln!("Failed to set {} to {}: {}", UVC_CLOCK_PARAM, value, e);
+    }
+}
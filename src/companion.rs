@@ -0,0 +1,107 @@
+use std::fs::File;
+use std::io::{self, ErrorKind, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use v4l::buffer::Type;
+use v4l::video::Capture;
+use v4l2capture_rs::Capturer;
+
+use crate::device;
+use crate::syncstart::SyncStart;
+
+// How often the capture thread looks at the stop flag when no frames come
+const STOP_CHECK: Duration = Duration::from_millis(200);
+
+// A second node of the same sensor, e.g. the encoder output next to the raw
+// node, recorded in its own thread with the format it is configured for.
+// Metadata nodes (--meta-capture) are recorded the same way, every buffer as
+// a record of its sequence number and length (u32 little endian each) and
+// the data, to be matched with the frames.
+pub struct Companion {
+    device: String,
+    thread: JoinHandle<(Capturer, io::Result<()>)>,
+}
+
+impl Companion {
+    // Open the node and the output here, so that mistakes show up before the
+    // main capture starts
+    pub fn spawn(
+        device: &str,
+        output: &str,
+        buffers: u32,
+        stop: Arc<AtomicBool>,
+        meta: bool,
+        sync: Option<Arc<SyncStart>>,
+        sequence_offset: u32,
+    ) -> io::Result<Companion> {
+        let dev = device::open(device)?;
+        let mut capturer = if meta {
+            let (fourcc, size) = device::meta_format(&dev)?;
+            eprintln!("Metadata {device} to {output}: {fourcc}, {size} byte buffers");
+            Capturer::with_type(dev, Type::MetaCapture, buffers)?
+        } else {
+            let fmt = dev.format()?;
+            eprintln!("Companion {device} to {output}:\n{fmt}");
+            Capturer::new(dev, buffers)?
+        };
+        capturer.stream().set_poll_timeout(Some(STOP_CHECK));
+        let mut out = File::create(output)?;
+        let name = device.to_string();
+        let thread = thread::spawn(move || {
+            if let Some(sync) = sync {
+                if let Err(e) = sync.start(&name, Some(capturer.stream())) {
+                    return (capturer, Err(e));
+                }
+            }
+            let meta = meta.then_some(sequence_offset);
+            let result = run(&mut capturer, &mut out, &stop, meta);
+            (capturer, result)
+        });
+        Ok(Companion {
+            device: device.to_string(),
+            thread,
+        })
+    }
+
+    // Wait for the thread to see the stop flag and report what it captured
+    pub fn finish(self) {
+        match self.thread.join() {
+            Ok((capturer, result)) => {
+                if let Err(e) = result {
+                    eprintln!("Companion {}: {e}", self.device);
+                }
+                eprintln!("Companion {}:", self.device);
+                capturer.stats().report();
+            }
+            Err(_) => eprintln!("Companion {}: capture thread panicked", self.device),
+        }
+    }
+}
+
+// `meta` is the --sequence-offset for metadata records, None for frames
+fn run(
+    capturer: &mut Capturer,
+    out: &mut File,
+    stop: &AtomicBool,
+    meta: Option<u32>,
+) -> io::Result<()> {
+    while !stop.load(Ordering::SeqCst) {
+        let (buf, sequence) = match capturer.next_frame() {
+            Ok((buf, m)) => (buf, m.sequence),
+            Err(e) if matches!(e.kind(), ErrorKind::Interrupted | ErrorKind::TimedOut) => continue,
+            Err(e) => return Err(e),
+        };
+        if let Some(offset) = meta {
+            let mut header = [0; 8];
+            header[..4].copy_from_slice(&sequence.wrapping_add(offset).to_le_bytes());
+            header[4..].copy_from_slice(&(buf.len() as u32).to_le_bytes());
+            out.write_all(&header)?;
+        }
+        out.write_all(buf)?;
+        let len = buf.len();
+        capturer.stats_mut().record_written(len);
+    }
+    capturer.stream().stop()
+}
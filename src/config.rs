@@ -0,0 +1,203 @@
+// Settings file in a small subset of TOML: `key = value` lines with string,
+// integer and boolean values, `[table]` headers and `#` comments.
+
+use v4l2capture_rs::parse::ParseError;
+
+pub enum Value {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+}
+
+// One setting, and the table it is in
+pub struct Entry {
+    pub line: usize,
+    pub table: Option<String>,
+    pub key: String,
+    pub value: Value,
+}
+
+pub fn load(path: &str) -> Result<Vec<Entry>, ParseError> {
+    let text = std::fs::read_to_string(path).map_err(|e| ParseError::new("config", path, e))?;
+    parse(&text)
+        .map_err(|(line, reason)| ParseError::new("config", path, format!("line {line}: {reason}")))
+}
+
+fn parse(text: &str) -> Result<Vec<Entry>, (usize, String)> {
+    let mut entries = Vec::new();
+    let mut table = None;
+    for (i, raw) in text.lines().enumerate() {
+        let line = i + 1;
+        let s = strip_comment(raw).trim();
+        if s.is_empty() {
+            continue;
+        }
+        if let Some(name) = s.strip_prefix('[') {
+            let name = name
+                .strip_suffix(']')
+                .map(str::trim)
+                .filter(|name| is_bare_key(name))
+                .ok_or((line, format!("bad table header {s:?}")))?;
+            table = Some(name.to_string());
+            continue;
+        }
+        let (key, value) = s
+            .split_once('=')
+            .ok_or((line, "expected key = value".to_string()))?;
+        let key = key.trim();
+        if !is_bare_key(key) {
+            return Err((line, format!("bad key {key:?}")));
+        }
+        let value = parse_value(value.trim()).map_err(|reason| (line, reason))?;
+        entries.push(Entry {
+            line,
+            table: table.clone(),
+            key: key.to_string(),
+            value,
+        });
+    }
+    Ok(entries)
+}
+
+fn is_bare_key(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+}
+
+// Drop a trailing comment, minding `#` inside strings
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), _) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '#') => return &line[..i],
+            _ => {}
+        }
+        escaped = false;
+    }
+    line
+}
+
+fn parse_value(s: &str) -> Result<Value, String> {
+    match s {
+        "true" => return Ok(Value::Bool(true)),
+        "false" => return Ok(Value::Bool(false)),
+        _ => {}
+    }
+    if let Some(literal) = s.strip_prefix('\'') {
+        return literal
+            .strip_suffix('\'')
+            .filter(|inner| !inner.contains('\''))
+            .map(|inner| Value::Str(inner.to_string()))
+            .ok_or_else(|| format!("unterminated string {s}"));
+    }
+    if let Some(basic) = s.strip_prefix('"') {
+        return unescape(basic).map(Value::Str);
+    }
+    let digits = s.replace('_', "");
+    digits
+        .parse()
+        .map(Value::Int)
+        .map_err(|_| format!("unsupported value {s}"))
+}
+
+// Body of a basic string after the opening quote
+fn unescape(s: &str) -> Result<String, String> {
+    let mut out = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if chars.as_str().is_empty() => return Ok(out),
+            '"' => return Err("text after string".to_string()),
+            '\\' => match chars.next() {
+                Some('"') => out.push('"'),
+                Some('\\') => out.push('\\'),
+                Some('n') => out.push('\n'),
+                Some('t') => out.push('\t'),
+                other => return Err(format!("unsupported escape \\{}", other.unwrap_or(' '))),
+            },
+            c => out.push(c),
+        }
+    }
+    Err("unterminated string".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn one(text: &str) -> Entry {
+        let mut entries = parse(text).unwrap();
+        assert_eq!(entries.len(), 1);
+        entries.remove(0)
+    }
+
+    fn string(text: &str) -> String {
+        match one(text).value {
+            Value::Str(s) => s,
+            _ => panic!("not a string"),
+        }
+    }
+
+    #[test]
+    fn values() {
+        assert!(matches!(one("buffers = 1_000").value, Value::Int(1000)));
+        assert!(matches!(one("verbose = true").value, Value::Bool(true)));
+        assert!(matches!(one("w = -3").value, Value::Int(-3)));
+        assert!(parse("w = 1.5").is_err());
+    }
+
+    #[test]
+    fn comments() {
+        assert!(parse("# only a comment\n\n   # indented\n")
+            .unwrap()
+            .is_empty());
+        assert_eq!(string("s = \"a#b\" # comment"), "a#b");
+        assert_eq!(string("s = 'a#b' # comment"), "a#b");
+        assert_eq!(string("s = \"a\\\"#b\""), "a\"#b");
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(string("s = \"tab\\there\\n\""), "tab\there\n");
+        assert_eq!(string("s = 'C:\\raw'"), "C:\\raw");
+        assert!(parse("s = \"open").is_err());
+        assert!(parse("s = 'open").is_err());
+        assert!(parse("s = \"a\" b").is_err());
+        assert!(parse("s = \"\\x\"").is_err());
+    }
+
+    #[test]
+    fn tables() {
+        let entries = parse("width = 640\n[controls]\nbrightness = 10").unwrap();
+        assert_eq!(entries[0].table, None);
+        assert_eq!(entries[1].table.as_deref(), Some("controls"));
+        assert_eq!(
+            (entries[1].key.as_str(), entries[1].line),
+            ("brightness", 3)
+        );
+    }
+
+    #[test]
+    fn bad_lines() {
+        for text in [
+            "[controls",
+            "[]",
+            "[a.b]",
+            "[a b]",
+            "width",
+            "a b = 1",
+            "= 1",
+        ] {
+            assert!(parse(text).is_err(), "{text:?} accepted");
+        }
+        assert_eq!(parse("x = 1\n[bad").err().map(|(line, _)| line), Some(2));
+    }
+}
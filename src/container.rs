@@ -0,0 +1,50 @@
+use std::io;
+use std::os::unix::io::RawFd;
+use v4l::buffer::Metadata;
+use v4l::video::capture::Parameters;
+use v4l::Format;
+
+// File format the captured frames are written in. Formats with headers or
+// an index to patch up (AVI, MKV) do so in `finalize`, which runs on every
+// exit from the capture loop, including Ctrl-C, SIGTERM and a closed pipe.
+// Writers are Send for --decouple, which runs them in a thread of their own.
+pub trait ContainerWriter: Send {
+    fn begin(&mut self, format: &Format, params: &Parameters) -> io::Result<()>;
+    // `mapped` as for Output::write_buffer
+    fn write_frame(&mut self, buf: &[u8], meta: &Metadata, mapped: bool) -> io::Result<()>;
+    fn finalize(&mut self) -> io::Result<()>;
+    // Go on in a new file, e.g. for a new frame geometry; begin() follows
+    fn split(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+    // Last call after finalize(), `clean` unless the capture failed
+    fn complete(&mut self, _clean: bool) -> io::Result<()> {
+        Ok(())
+    }
+    // The one file all frames went to, for --verify-output
+    fn written_path(&self) -> Option<&str> {
+        None
+    }
+    // The pipe the frames are written to, for --exit-on-consumer-close
+    fn pipe_fd(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+// --null-output: frames go through all processing and into the counters,
+// then nowhere, without even a write to /dev/null
+pub struct Discard;
+
+impl ContainerWriter for Discard {
+    fn begin(&mut self, _format: &Format, _params: &Parameters) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_frame(&mut self, _buf: &[u8], _meta: &Metadata, _mapped: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,145 @@
+// --control-socket: line based commands from any number of clients over a
+// unix socket, each answered by a line starting with "ok" or "error". The
+// commands are parsed here and carried out by the capture loop between
+// frames, which is where the replies come from.
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use v4l2capture_rs::stats::Stats;
+
+pub enum Command {
+    // Write frames again after stop or --start-paused
+    Start,
+    // Throw frames away, the stream running on
+    Stop,
+    Snapshot,
+    SetCtrl(String, String),
+    // Restart the stream with the format the device has now
+    Reconfigure,
+    Stats,
+    Quit,
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command, String> {
+        let (word, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let rest = rest.trim();
+        let command = match word {
+            "start" => Command::Start,
+            "stop" => Command::Stop,
+            "snapshot" => Command::Snapshot,
+            "set-ctrl" => {
+                let (name, value) = rest.split_once('=').ok_or("usage: set-ctrl NAME=VALUE")?;
+                return Ok(Command::SetCtrl(
+                    name.trim().to_string(),
+                    value.trim().to_string(),
+                ));
+            }
+            "reconfigure" => Command::Reconfigure,
+            "stats" => Command::Stats,
+            "quit" => Command::Quit,
+            _ => return Err(format!("unknown command {word:?}")),
+        };
+        match rest {
+            "" => Ok(command),
+            _ => Err(format!("{word} takes no arguments")),
+        }
+    }
+}
+
+// Where the answer to a command goes. Dropping it unanswered tells the
+// client that the capture ended first.
+pub struct Reply(Sender<String>);
+
+impl Reply {
+    pub fn ok(self, msg: &str) {
+        _ = self.0.send(format!("ok {msg}").trim_end().to_string());
+    }
+
+    pub fn error(self, msg: &str) {
+        _ = self.0.send(format!("error {msg}"));
+    }
+
+    pub fn result(self, result: Result<String, String>) {
+        match result {
+            Ok(msg) => self.ok(&msg),
+            Err(e) => self.error(&e),
+        }
+    }
+}
+
+pub fn stats_line(stats: &Stats) -> String {
+    format!(
+        "captured={} written={} dropped={} bytes={}",
+        stats.frames_captured, stats.frames_written, stats.frames_dropped, stats.bytes_written
+    )
+}
+
+pub struct ControlSocket {
+    path: String,
+    commands: Receiver<(Command, Reply)>,
+}
+
+fn serve(client: UnixStream, commands: Sender<(Command, Reply)>) -> io::Result<()> {
+    let mut out = client.try_clone()?;
+    for line in BufReader::new(client).lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let answer = match Command::parse(line) {
+            Ok(command) => {
+                let (reply, answer) = mpsc::channel();
+                if commands.send((command, Reply(reply))).is_err() {
+                    writeln!(out, "error the capture has ended")?;
+                    return Ok(());
+                }
+                answer
+                    .recv()
+                    .unwrap_or_else(|_| "error the capture has ended".to_string())
+            }
+            Err(e) => format!("error {e}"),
+        };
+        writeln!(out, "{answer}")?;
+    }
+    Ok(())
+}
+
+impl ControlSocket {
+    pub fn bind(path: &str) -> io::Result<ControlSocket> {
+        // A socket left behind by an earlier run that did not clean up
+        let stale = fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket());
+        if stale && UnixStream::connect(path).is_err() {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        let (sender, commands) = mpsc::channel();
+        thread::spawn(move || {
+            for client in listener.incoming() {
+                let Ok(client) = client else { continue };
+                let sender = sender.clone();
+                thread::spawn(move || serve(client, sender));
+            }
+        });
+        Ok(ControlSocket {
+            path: path.to_string(),
+            commands,
+        })
+    }
+
+    // The next command waiting, if any
+    pub fn take(&self) -> Option<(Command, Reply)> {
+        self.commands.try_recv().ok()
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        _ = fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,391 @@
+// --list-controls: the controls of a device with their ranges and values
+
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::raw::c_void;
+use std::os::unix::io::RawFd;
+use std::sync::Arc;
+use v4l::control::{Description, Flags, MenuItem, Type, Value};
+use v4l::device::Handle;
+use v4l::v4l2;
+use v4l::v4l_sys::*;
+use v4l::Device;
+
+use crate::discover::json_str;
+
+fn type_name(typ: Type) -> &'static str {
+    match typ {
+        Type::Integer => "integer",
+        Type::Boolean => "boolean",
+        Type::Menu => "menu",
+        Type::Button => "button",
+        Type::Integer64 => "integer64",
+        Type::CtrlClass => "ctrl_class",
+        Type::String => "string",
+        Type::Bitmask => "bitmask",
+        Type::IntegerMenu => "integer_menu",
+        Type::U8 => "u8",
+        Type::U16 => "u16",
+        Type::U32 => "u32",
+        Type::Area => "area",
+    }
+}
+
+const FLAG_NAMES: &[(Flags, &str)] = &[
+    (Flags::DISABLED, "disabled"),
+    (Flags::GRABBED, "grabbed"),
+    (Flags::READ_ONLY, "read_only"),
+    (Flags::UPDATE, "update"),
+    (Flags::INACTIVE, "inactive"),
+    (Flags::SLIDER, "slider"),
+    (Flags::WRITE_ONLY, "write_only"),
+    (Flags::VOLATILE, "volatile"),
+    (Flags::EXECUTE_ON_WRITE, "execute_on_write"),
+];
+
+fn flag_names(flags: Flags) -> Vec<&'static str> {
+    FLAG_NAMES
+        .iter()
+        .filter(|(flag, _)| flags.contains(*flag))
+        .map(|&(_, name)| name)
+        .collect()
+}
+
+// Current value as JSON, null for controls without a readable scalar value
+fn value_json(dev: &Device, desc: &Description) -> String {
+    let readable = !matches!(desc.typ, Type::Button | Type::CtrlClass)
+        && !desc.flags.intersects(Flags::WRITE_ONLY | Flags::DISABLED);
+    if !readable {
+        return "null".to_string();
+    }
+    match dev.control(desc.id).map(|c| c.value) {
+        Ok(Value::Integer(v)) => v.to_string(),
+        Ok(Value::Boolean(v)) => v.to_string(),
+        Ok(Value::String(v)) => json_str(&v),
+        _ => "null".to_string(),
+    }
+}
+
+fn menu_json(desc: &Description) -> String {
+    let items: Vec<String> = desc
+        .items
+        .iter()
+        .flatten()
+        .map(|(index, item)| match item {
+            MenuItem::Name(name) => format!("{{\"index\":{index},\"name\":{}}}", json_str(name)),
+            MenuItem::Value(value) => format!("{{\"index\":{index},\"value\":{value}}}"),
+        })
+        .collect();
+    items.join(",")
+}
+
+fn control_json(dev: &Device, desc: &Description) -> String {
+    let flags: Vec<String> = flag_names(desc.flags).iter().map(|f| json_str(f)).collect();
+    let mut json = format!(
+        "{{\"id\":{},\"name\":{},\"type\":{},\"min\":{},\"max\":{},\"step\":{},\"default\":{},\"value\":{},\"flags\":[{}]",
+        desc.id,
+        json_str(&desc.name),
+        json_str(type_name(desc.typ)),
+        desc.minimum,
+        desc.maximum,
+        desc.step,
+        desc.default,
+        value_json(dev, desc),
+        flags.join(",")
+    );
+    if matches!(desc.typ, Type::Menu | Type::IntegerMenu) {
+        json += &format!(",\"menu\":[{}]", menu_json(desc));
+    }
+    json.push('}');
+    json
+}
+
+pub fn list_controls(path: &str, json: bool) -> Result<(), String> {
+    let dev = Device::with_path(path).map_err(|e| format!("{path}: {e}"))?;
+    let controls = dev
+        .query_controls()
+        .map_err(|e| format!("{path}: failed to query controls: {e}"))?;
+    if json {
+        println!(
+            "{{\"device\":{},\"controls\":{}}}",
+            json_str(path),
+            controls_json(&dev, &controls)
+        );
+        return Ok(());
+    }
+    print_controls(&dev, &controls);
+    Ok(())
+}
+
+// The controls as a JSON list
+pub fn controls_json(dev: &Device, controls: &[Description]) -> String {
+    let list: Vec<String> = controls.iter().map(|c| control_json(dev, c)).collect();
+    format!("[{}]", list.join(","))
+}
+
+pub fn print_controls(dev: &Device, controls: &[Description]) {
+    for desc in controls {
+        if matches!(desc.typ, Type::CtrlClass) {
+            println!("{}", desc.name);
+            continue;
+        }
+        let flags = flag_names(desc.flags);
+        println!(
+            "  {} (0x{:08x}, {}): min {} max {} step {} default {} value {}{}",
+            desc.name,
+            desc.id,
+            type_name(desc.typ),
+            desc.minimum,
+            desc.maximum,
+            desc.step,
+            desc.default,
+            value_json(dev, desc),
+            if flags.is_empty() {
+                String::new()
+            } else {
+                format!(" [{}]", flags.join(", "))
+            }
+        );
+        for (index, item) in desc.items.iter().flatten() {
+            println!("      {index}: {item}");
+        }
+    }
+}
+
+// Control names as v4l2-ctl spells them: "White Balance, Auto" is
+// white_balance_auto
+pub fn short_name(name: &str) -> String {
+    let mut out = String::new();
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            out.push(c.to_ascii_lowercase());
+        } else if !out.is_empty() && !out.ends_with('_') {
+            out.push('_');
+        }
+    }
+    out.trim_end_matches('_').to_string()
+}
+
+// A control by its short name or its id, in decimal or 0x hex
+fn find<'a>(controls: &'a [Description], name: &str) -> Option<&'a Description> {
+    let id = match name.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => name.parse().ok(),
+    };
+    controls.iter().find(|desc| {
+        !matches!(desc.typ, Type::CtrlClass)
+            && (Some(desc.id) == id || short_name(&desc.name) == short_name(name))
+    })
+}
+
+// Value of a --set-ctrl as the ioctl takes it
+enum Setting {
+    Int(i32),
+    Int64(i64),
+    Str(CString),
+}
+
+fn parse_value(desc: &Description, value: &str) -> Result<Setting, String> {
+    let bad = |reason: &str| format!("bad value {value:?} for {}: {reason}", desc.name);
+    let int = || value.parse::<i64>().map_err(|e| bad(&e.to_string()));
+    let in_range = |v: i64| {
+        if v < desc.minimum || v > desc.maximum {
+            return Err(bad(&format!("not in {}..{}", desc.minimum, desc.maximum)));
+        }
+        i32::try_from(v).map_err(|e| bad(&e.to_string()))
+    };
+    Ok(match desc.typ {
+        Type::Boolean => Setting::Int(match value {
+            "true" | "1" => 1,
+            "false" | "0" => 0,
+            _ => return Err(bad("not a boolean")),
+        }),
+        Type::Integer | Type::Bitmask => Setting::Int(in_range(int()?)?),
+        Type::Integer64 => Setting::Int64(int()?),
+        Type::Menu | Type::IntegerMenu => {
+            // A menu entry by index or by (short) name
+            let by_name = desc.items.iter().flatten().find(|(_, item)| match item {
+                MenuItem::Name(name) => short_name(name) == short_name(value),
+                MenuItem::Value(_) => false,
+            });
+            match by_name {
+                Some(&(index, _)) => Setting::Int(index as i32),
+                None => Setting::Int(in_range(int()?)?),
+            }
+        }
+        Type::Button => Setting::Int(0),
+        Type::String => Setting::Str(CString::new(value).map_err(|e| bad(&e.to_string()))?),
+        _ => return Err(bad("unsupported control type")),
+    })
+}
+
+fn ext_ctrls(
+    fd: RawFd,
+    request: v4l2::vidioc::_IOC_TYPE,
+    ctrls: &mut [v4l2_ext_control],
+    request_fd: Option<RawFd>,
+) -> (io::Result<()>, usize) {
+    let mut ext = v4l2_ext_controls {
+        count: ctrls.len() as u32,
+        controls: ctrls.as_mut_ptr(),
+        ..unsafe { mem::zeroed() }
+    };
+    ext.__bindgen_anon_1.which = match request_fd {
+        Some(request_fd) => {
+            ext.request_fd = request_fd;
+            V4L2_CTRL_WHICH_REQUEST_VAL
+        }
+        None => V4L2_CTRL_WHICH_CUR_VAL,
+    };
+    let result = unsafe { v4l2::ioctl(fd, request, &mut ext as *mut _ as *mut c_void) };
+    (result, ext.error_idx as usize)
+}
+
+// NAME=VALUE settings checked against the controls of a device, ready to
+// be applied in one VIDIOC_S_EXT_CTRLS
+pub struct Settings {
+    names: Vec<String>,
+    ctrls: Vec<v4l2_ext_control>,
+    // The string values the controls point to
+    _strings: Vec<CString>,
+}
+
+// The pointers only lead into `_strings`
+unsafe impl Send for Settings {}
+
+pub fn resolve(
+    controls: &[Description],
+    settings: &[(String, String)],
+) -> Result<Settings, String> {
+    let mut names = Vec::with_capacity(settings.len());
+    let mut ctrls = Vec::with_capacity(settings.len());
+    let mut strings = Vec::new();
+    for (name, value) in settings {
+        let desc = find(controls, name).ok_or_else(|| format!("no control {name:?}"))?;
+        if desc.flags.intersects(Flags::READ_ONLY | Flags::DISABLED) {
+            return Err(format!("{} is read-only", desc.name));
+        }
+        let mut ctrl = v4l2_ext_control {
+            id: desc.id,
+            ..unsafe { mem::zeroed() }
+        };
+        match parse_value(desc, value)? {
+            Setting::Int(v) => ctrl.__bindgen_anon_1.value = v,
+            Setting::Int64(v) => ctrl.__bindgen_anon_1.value64 = v,
+            Setting::Str(s) => {
+                ctrl.__bindgen_anon_1.string = s.as_ptr() as *mut _;
+                ctrl.size = s.as_bytes_with_nul().len() as u32;
+                strings.push(s);
+            }
+        }
+        names.push(format!("{name}={value}"));
+        ctrls.push(ctrl);
+    }
+    Ok(Settings {
+        names,
+        ctrls,
+        _strings: strings,
+    })
+}
+
+impl Settings {
+    // Set the controls now, or when the media request `request_fd` is
+    // processed
+    pub fn apply(&mut self, fd: RawFd, request_fd: Option<RawFd>) -> Result<(), String> {
+        let count = self.ctrls.len();
+        let (result, mut index) = ext_ctrls(
+            fd,
+            v4l2::vidioc::VIDIOC_S_EXT_CTRLS,
+            &mut self.ctrls,
+            request_fd,
+        );
+        let Err(e) = result else {
+            return Ok(());
+        };
+        // An index of count: the batch failed validation before anything was
+        // changed, and trying it tells the culprit
+        if index >= count {
+            index = ext_ctrls(
+                fd,
+                v4l2::vidioc::VIDIOC_TRY_EXT_CTRLS,
+                &mut self.ctrls,
+                request_fd,
+            )
+            .1;
+        }
+        match self.names.get(index) {
+            Some(name) => Err(format!(
+                "failed to set controls, none changed: {e} (at {name})"
+            )),
+            None => Err(format!("failed to set controls: {e}")),
+        }
+    }
+}
+
+// Apply all --set-ctrl NAME=VALUE settings in one VIDIOC_S_EXT_CTRLS, so
+// that either all of them take effect or none
+pub fn set_controls(dev: &Device, settings: &[(String, String)]) -> Result<(), String> {
+    let controls = dev
+        .query_controls()
+        .map_err(|e| format!("failed to query controls: {e}"))?;
+    resolve(&controls, settings)?.apply(dev.handle().fd(), None)
+}
+
+// V4L2_CID_MPEG_VIDEO_FORCE_KEY_FRAME, missing from the bindings: codec class
+// base (0x00990900) + 229
+const CID_FORCE_KEY_FRAME: u32 = 0x0099_09e5;
+
+// --keyframe-interval: presses the force-keyframe button of an encoder every
+// so many frames. The handle matters for converters, whose controls belong
+// to the file handle streaming through them.
+pub struct KeyframeForcer {
+    handle: Arc<Handle>,
+    interval: u64,
+    frames: u64,
+}
+
+impl KeyframeForcer {
+    pub fn new(handle: Arc<Handle>, interval: u64) -> Result<KeyframeForcer, String> {
+        let mut query = v4l2_queryctrl {
+            id: CID_FORCE_KEY_FRAME,
+            ..unsafe { mem::zeroed() }
+        };
+        let result = unsafe {
+            v4l2::ioctl(
+                handle.fd(),
+                v4l2::vidioc::VIDIOC_QUERYCTRL,
+                &mut query as *mut _ as *mut c_void,
+            )
+        };
+        if result.is_err() || query.flags & V4L2_CTRL_FLAG_DISABLED != 0 {
+            return Err("the encoder has no force key frame control".to_string());
+        }
+        Ok(KeyframeForcer {
+            handle,
+            interval,
+            frames: 0,
+        })
+    }
+
+    // Count a frame about to be encoded, forcing a keyframe on every
+    // interval-th one, the first included
+    pub fn frame(&mut self) -> io::Result<()> {
+        let due = self.frames.is_multiple_of(self.interval);
+        self.frames += 1;
+        if !due {
+            return Ok(());
+        }
+        let mut ctrl = v4l2_control {
+            id: CID_FORCE_KEY_FRAME,
+            value: 0,
+        };
+        unsafe {
+            v4l2::ioctl(
+                self.handle.fd(),
+                v4l2::vidioc::VIDIOC_S_CTRL,
+                &mut ctrl as *mut _ as *mut c_void,
+            )
+        }
+    }
+}
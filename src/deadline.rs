@@ -0,0 +1,129 @@
+// --duration: stop the capture when the time is up, even with the main
+// thread stuck waiting for a frame from a hung camera. --max-open-time stops
+// it the same way, and has a HoldLimit as the last resort.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use v4l::device::Handle;
+use v4l::v4l2;
+use v4l2capture_rs::stream::MmapStream;
+
+pub struct Deadline {
+    // Dropped when the capture is over, which stops the timer
+    done: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+    // The stream to switch off, None once the capture has stopped it itself
+    handle: Arc<Mutex<Option<Arc<Handle>>>>,
+    expired: Arc<AtomicBool>,
+}
+
+// VIDIOC_STREAMOFF wakes up a poll() on the queue, the dequeue then fails
+fn stream_off(handle: &Handle) {
+    let mut typ = v4l::buffer::Type::VideoCapture as u32;
+    let result = unsafe {
+        v4l2::ioctl(
+            handle.fd(),
+            v4l2::vidioc::VIDIOC_STREAMOFF,
+            &mut typ as *mut _ as *mut std::os::raw::c_void,
+        )
+    };
+    if let Err(e) = result {
+        eprintln!("Deadline: VIDIOC_STREAMOFF failed: {e}");
+    }
+}
+
+impl Deadline {
+    pub fn start(limit: Duration, running: Arc<AtomicBool>) -> Deadline {
+        let (done, wait) = mpsc::channel::<()>();
+        let handle: Arc<Mutex<Option<Arc<Handle>>>> = Arc::default();
+        let expired = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let handle = handle.clone();
+            let expired = expired.clone();
+            std::thread::spawn(move || {
+                if wait.recv_timeout(limit) != Err(RecvTimeoutError::Timeout) {
+                    return;
+                }
+                // Under the lock, so that the capture cannot be stopping the
+                // stream at the same time
+                let handle = handle.lock().unwrap_or_else(|e| e.into_inner());
+                expired.store(true, Ordering::SeqCst);
+                running.store(false, Ordering::SeqCst);
+                if let Some(handle) = handle.as_ref() {
+                    stream_off(handle);
+                }
+            })
+        };
+        Deadline {
+            done: Some(done),
+            thread: Some(thread),
+            handle,
+            expired,
+        }
+    }
+
+    // The stream frames come from now; None for a file
+    pub fn set_stream(&self, stream: Option<&MmapStream>) {
+        let mut handle = self.handle.lock().unwrap_or_else(|e| e.into_inner());
+        *handle = stream.map(MmapStream::handle);
+    }
+
+    pub fn expired(&self) -> bool {
+        self.expired.load(Ordering::SeqCst)
+    }
+
+    // The capture loop is over: stop the timer before the stream goes away
+    pub fn finish(&mut self) {
+        self.set_stream(None);
+        drop(self.done.take());
+        if let Some(thread) = self.thread.take() {
+            _ = thread.join();
+        }
+    }
+}
+
+// What a stuck teardown gets after the HoldLimit cleared the running flag,
+// before the process exits
+const EXIT_GRACE: Duration = Duration::from_secs(2);
+
+// --max-open-time: clear the running flag if the device is still held when
+// the time is up, and exit the process, which closes it, if it is still held
+// EXIT_GRACE later. Dropped once the device is closed.
+pub struct HoldLimit {
+    _done: Sender<()>,
+    expired: Arc<AtomicBool>,
+}
+
+impl HoldLimit {
+    pub fn start(limit: Duration, running: Arc<AtomicBool>) -> HoldLimit {
+        let (done, wait) = mpsc::channel::<()>();
+        let expired = Arc::new(AtomicBool::new(false));
+        {
+            let expired = expired.clone();
+            std::thread::spawn(move || {
+                if wait.recv_timeout(limit) != Err(RecvTimeoutError::Timeout) {
+                    return;
+                }
+                eprintln!("--max-open-time: device still open after {limit:?}, stopping");
+                expired.store(true, Ordering::SeqCst);
+                running.store(false, Ordering::SeqCst);
+                if wait.recv_timeout(EXIT_GRACE) == Err(RecvTimeoutError::Timeout) {
+                    eprintln!("--max-open-time: device still open {EXIT_GRACE:?} later, exiting");
+                    std::process::exit(1);
+                }
+            });
+        }
+        HoldLimit {
+            _done: done,
+            expired,
+        }
+    }
+
+    // The device was still held when the time was up
+    pub fn expired(&self) -> bool {
+        self.expired.load(Ordering::SeqCst)
+    }
+}
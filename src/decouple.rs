@@ -0,0 +1,263 @@
+// --decouple: the writer runs in a thread of its own behind a queue of frame
+// copies, so that a slow sink cannot hold up the dequeue loop until the
+// driver runs out of buffers. When the queue is full the oldest frame in it
+// is dropped.
+
+use std::collections::VecDeque;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use v4l::buffer::Metadata;
+use v4l::video::capture::Parameters;
+use v4l::Format;
+
+use crate::container::ContainerWriter;
+
+// How often the frames dropped are reported, as one line for all of them
+const DROP_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+enum Job {
+    Frame(Vec<u8>, Metadata),
+    Begin(Format, Parameters),
+    Finalize,
+    Split,
+    Complete(bool),
+}
+
+#[derive(Default)]
+struct Queue {
+    jobs: VecDeque<Job>,
+    // Outcome of the last job other than a frame, taken by the one waiting
+    reply: Option<io::Result<()>>,
+    // The first write error, returned by the next write_frame()
+    error: Option<io::Error>,
+    // What written_path() of the writer said after the last job
+    path: Option<String>,
+    closed: bool,
+    // The writer thread is gone, by a panic too
+    finished: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    queue: Mutex<Queue>,
+    // A job was queued, or the queue closed
+    queued: Condvar,
+    // A reply is there
+    replied: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// Marks the queue finished when the writer thread ends, however it does
+struct Finished<'a>(&'a Shared);
+
+impl Drop for Finished<'_> {
+    fn drop(&mut self) {
+        self.0.lock().finished = true;
+        self.0.replied.notify_one();
+    }
+}
+
+fn writer_gone() -> io::Error {
+    io::Error::other("--decouple: the writer thread ended")
+}
+
+// Frames dropped from a full queue, already counted as written by the capture
+#[derive(Default)]
+pub struct Dropped {
+    pub frames: AtomicUsize,
+    pub bytes: AtomicU64,
+}
+
+pub struct Decoupled {
+    shared: Arc<Shared>,
+    depth: usize,
+    dropped: Arc<Dropped>,
+    pipe_fd: Option<RawFd>,
+    path: Option<String>,
+    thread: Option<JoinHandle<()>>,
+    // Dropped frames reported so far, the last one dropped and when the next
+    // report is due
+    reported: usize,
+    last_dropped: u32,
+    next_report: Instant,
+}
+
+fn run(mut writer: Box<dyn ContainerWriter>, shared: &Shared) {
+    let _finished = Finished(shared);
+    loop {
+        let job = {
+            let mut queue = shared.lock();
+            loop {
+                if let Some(job) = queue.jobs.pop_front() {
+                    break job;
+                }
+                if queue.closed {
+                    return;
+                }
+                queue = shared.queued.wait(queue).unwrap_or_else(|e| e.into_inner());
+            }
+        };
+        let result = match job {
+            Job::Frame(buf, meta) => {
+                // After a failed write the rest is thrown away, the capture
+                // stops on the error
+                if shared.lock().error.is_some() {
+                    continue;
+                }
+                if let Err(e) = writer.write_frame(&buf, &meta, false) {
+                    shared.lock().error = Some(e);
+                }
+                continue;
+            }
+            Job::Begin(format, params) => writer.begin(&format, &params),
+            Job::Finalize => writer.finalize(),
+            Job::Split => writer.split(),
+            Job::Complete(clean) => writer.complete(clean),
+        };
+        let mut queue = shared.lock();
+        queue.reply = Some(result);
+        queue.path = writer.written_path().map(str::to_string);
+        shared.replied.notify_one();
+    }
+}
+
+impl Decoupled {
+    // Up to `depth` frames wait for the writer
+    pub fn new(writer: Box<dyn ContainerWriter>, depth: usize) -> Decoupled {
+        let shared = Arc::new(Shared::default());
+        let pipe_fd = writer.pipe_fd();
+        let path = writer.written_path().map(str::to_string);
+        let thread = {
+            let shared = shared.clone();
+            thread::spawn(move || run(writer, &shared))
+        };
+        Decoupled {
+            shared,
+            depth: depth.max(1),
+            dropped: Arc::default(),
+            pipe_fd,
+            path,
+            thread: Some(thread),
+            reported: 0,
+            last_dropped: 0,
+            next_report: Instant::now(),
+        }
+    }
+
+    pub fn dropped(&self) -> Arc<Dropped> {
+        self.dropped.clone()
+    }
+
+    // Queue behind the frames still waiting and wait for the writer to do it
+    fn call(&mut self, job: Job) -> io::Result<()> {
+        let mut queue = self.shared.lock();
+        queue.jobs.push_back(job);
+        self.shared.queued.notify_one();
+        let result = loop {
+            if let Some(result) = queue.reply.take() {
+                break result;
+            }
+            if queue.finished {
+                return Err(queue.error.take().unwrap_or_else(writer_gone));
+            }
+            queue = self
+                .shared
+                .replied
+                .wait(queue)
+                .unwrap_or_else(|e| e.into_inner());
+        };
+        self.path = queue.path.clone();
+        // A frame that failed before is as much an error of this call
+        match queue.error.take() {
+            Some(e) => Err(e),
+            None => result,
+        }
+    }
+
+    // The frames dropped since the last report, at most every
+    // DROP_REPORT_INTERVAL unless `now`
+    fn report_drops(&mut self, now: bool) {
+        let dropped = self.dropped.frames.load(Ordering::Relaxed);
+        if dropped == self.reported || !now && Instant::now() < self.next_report {
+            return;
+        }
+        eprintln!(
+            "warning: writer behind, {} frames dropped, the last seq {}",
+            dropped - self.reported,
+            self.last_dropped
+        );
+        self.reported = dropped;
+        self.next_report = Instant::now() + DROP_REPORT_INTERVAL;
+    }
+}
+
+impl ContainerWriter for Decoupled {
+    fn begin(&mut self, format: &Format, params: &Parameters) -> io::Result<()> {
+        self.call(Job::Begin(*format, *params))
+    }
+
+    fn write_frame(&mut self, buf: &[u8], meta: &Metadata, _mapped: bool) -> io::Result<()> {
+        let mut queue = self.shared.lock();
+        if let Some(e) = queue.error.take() {
+            return Err(e);
+        }
+        if queue.finished {
+            return Err(writer_gone());
+        }
+        // Only frames wait in the queue, the other jobs are waited for
+        if queue.jobs.len() >= self.depth {
+            if let Some(Job::Frame(old, meta)) = queue.jobs.pop_front() {
+                self.last_dropped = meta.sequence;
+                self.dropped.frames.fetch_add(1, Ordering::Relaxed);
+                self.dropped
+                    .bytes
+                    .fetch_add(old.len() as u64, Ordering::Relaxed);
+            }
+        }
+        queue.jobs.push_back(Job::Frame(buf.to_vec(), *meta));
+        self.shared.queued.notify_one();
+        drop(queue);
+        self.report_drops(false);
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        self.call(Job::Finalize)
+    }
+
+    fn split(&mut self) -> io::Result<()> {
+        self.call(Job::Split)
+    }
+
+    fn complete(&mut self, clean: bool) -> io::Result<()> {
+        self.report_drops(true);
+        self.call(Job::Complete(clean))
+    }
+
+    fn written_path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+
+    fn pipe_fd(&self) -> Option<RawFd> {
+        self.pipe_fd
+    }
+}
+
+impl Drop for Decoupled {
+    fn drop(&mut self) {
+        self.shared.lock().closed = true;
+        self.shared.queued.notify_one();
+        if let Some(thread) = self.thread.take() {
+            _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use crate::crc;
+
+// Detects frames identical to the previous one, as produced by stalling cameras.
+// Only `sample_size` bytes spread evenly over the frame are hashed.
+pub struct Dedup {
+    sample_size: usize,
+    last: Option<(usize, u32)>,
+}
+
+impl Dedup {
+    pub fn new(sample_size: usize) -> Dedup {
+        Dedup {
+            sample_size,
+            last: None,
+        }
+    }
+
+    fn hash(&self, buf: &[u8]) -> u32 {
+        if self.sample_size == 0 || buf.len() <= self.sample_size {
+            return crc::crc32(buf);
+        }
+        // Hash 64 evenly spaced chunks so that a change anywhere in the
+        // frame is likely to be seen
+        let chunks = 64.min(self.sample_size);
+        let chunk_len = self.sample_size / chunks;
+        let stride = buf.len() / chunks;
+        let mut c = 0;
+        for i in 0..chunks {
+            let start = i * stride;
+            c = crc::update(c, &buf[start..start + chunk_len]);
+        }
+        c
+    }
+
+    // Returns true if `buf` looks like a repeat of the previous frame
+    pub fn is_duplicate(&mut self, buf: &[u8]) -> bool {
+        let key = (buf.len(), self.hash(buf));
+        let dup = self.last == Some(key);
+        self.last = Some(key);
+        dup
+    }
+}
@@ -0,0 +1,506 @@
+use std::fmt;
+use std::io;
+use std::mem;
+use std::os::raw::c_void;
+use std::os::unix::io::RawFd;
+use v4l::capability::Flags;
+use v4l::v4l2;
+use v4l::v4l_sys::*;
+use v4l::{Device, Format, Fraction};
+
+// Access priority of our file handle. Only the handle with the highest
+// priority may change the device configuration.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    Background,
+    Interactive,
+    Record,
+}
+
+impl Priority {
+    pub fn parse(s: &str) -> Option<Priority> {
+        match s {
+            "background" => Some(Priority::Background),
+            "interactive" => Some(Priority::Interactive),
+            "record" => Some(Priority::Record),
+            _ => None,
+        }
+    }
+
+    fn raw(self) -> v4l2_priority {
+        match self {
+            Priority::Background => v4l2_priority_V4L2_PRIORITY_BACKGROUND,
+            Priority::Interactive => v4l2_priority_V4L2_PRIORITY_INTERACTIVE,
+            Priority::Record => v4l2_priority_V4L2_PRIORITY_RECORD,
+        }
+    }
+}
+
+pub use v4l2capture_rs::stream::set_cloexec;
+
+// A device opened close-on-exec, which the v4l crate does not ask for, so
+// that children such as gst-launch-1.0 do not hold it open
+pub fn open(path: &str) -> io::Result<Device> {
+    let dev = Device::with_path(path)?;
+    set_cloexec(dev.handle().fd(), true)?;
+    Ok(dev)
+}
+
+// The priority belongs to the file handle and is dropped when it is closed
+pub fn set_priority(dev: &Device, prio: Priority) -> io::Result<()> {
+    let mut raw = prio.raw();
+    unsafe {
+        v4l2::ioctl(
+            dev.handle().fd(),
+            v4l2::vidioc::VIDIOC_S_PRIORITY,
+            &mut raw as *mut _ as *mut c_void,
+        )
+    }
+}
+
+// Data format and buffer size of a metadata capture node
+pub fn meta_format(dev: &Device) -> io::Result<(v4l::FourCC, u32)> {
+    let mut fmt = v4l2_format {
+        type_: v4l::buffer::Type::MetaCapture as u32,
+        ..unsafe { mem::zeroed() }
+    };
+    unsafe {
+        v4l2::ioctl(
+            dev.handle().fd(),
+            v4l2::vidioc::VIDIOC_G_FMT,
+            &mut fmt as *mut _ as *mut c_void,
+        )?;
+        let meta = fmt.fmt.meta;
+        Ok((v4l::FourCC::from(meta.dataformat), meta.buffersize))
+    }
+}
+
+// Format the capture queue is set to now, through the stream's handle
+pub fn capture_format(fd: RawFd) -> io::Result<Format> {
+    let mut fmt = v4l2_format {
+        type_: v4l::buffer::Type::VideoCapture as u32,
+        ..unsafe { mem::zeroed() }
+    };
+    unsafe {
+        v4l2::ioctl(
+            fd,
+            v4l2::vidioc::VIDIOC_G_FMT,
+            &mut fmt as *mut _ as *mut c_void,
+        )?;
+        Ok(Format::from(fmt.fmt.pix))
+    }
+}
+
+// What differs between the negotiated format and the current one
+pub fn format_drift(negotiated: &Format, current: &Format) -> Vec<String> {
+    let mut drift = Vec::new();
+    if (current.width, current.height) != (negotiated.width, negotiated.height) {
+        drift.push(format!(
+            "size {}x{} instead of {}x{}",
+            current.width, current.height, negotiated.width, negotiated.height
+        ));
+    }
+    if current.fourcc != negotiated.fourcc {
+        drift.push(format!(
+            "{} instead of {}",
+            current.fourcc, negotiated.fourcc
+        ));
+    }
+    if current.stride != negotiated.stride {
+        drift.push(format!(
+            "bytesperline {} instead of {}",
+            current.stride, negotiated.stride
+        ));
+    }
+    if current.size != negotiated.size {
+        drift.push(format!(
+            "sizeimage {} instead of {}",
+            current.size, negotiated.size
+        ));
+    }
+    drift
+}
+
+// The ioctls go to the kernel directly, the v4l crate is built without
+// libv4l. Only a libv4l preloaded into the process, e.g. v4l2convert.so for
+// applications that cannot convert formats themselves, puts its format
+// emulation in between. The preloaded library if so.
+pub fn libv4l_preload() -> Option<String> {
+    let preload = std::env::var("LD_PRELOAD").ok()?;
+    preload
+        .split([' ', ':'])
+        .find(|lib| {
+            lib.contains("libv4l") || lib.contains("v4l2convert") || lib.contains("v4l1compat")
+        })
+        .map(str::to_string)
+}
+
+// Have the driver write its state to the kernel log
+pub fn log_status(fd: RawFd) -> io::Result<()> {
+    unsafe { v4l2::ioctl(fd, v4l2::vidioc::VIDIOC_LOG_STATUS, std::ptr::null_mut()) }
+}
+
+pub const EDID_BLOCK_SIZE: usize = 128;
+
+fn edid_ioctl(
+    dev: &Device,
+    request: v4l2::vidioc::_IOC_TYPE,
+    edid: &mut v4l2_edid,
+) -> io::Result<()> {
+    unsafe { v4l2::ioctl(dev.handle().fd(), request, edid as *mut _ as *mut c_void) }
+}
+
+// Read the EDID the receiver currently advertises
+pub fn get_edid(dev: &Device) -> io::Result<Vec<u8>> {
+    // With zero blocks requested the driver reports how many it has
+    let mut edid = v4l2_edid {
+        pad: 0,
+        start_block: 0,
+        blocks: 0,
+        reserved: [0; 5],
+        edid: std::ptr::null_mut(),
+    };
+    edid_ioctl(dev, v4l2::vidioc::VIDIOC_G_EDID, &mut edid)?;
+    let mut data = vec![0u8; edid.blocks as usize * EDID_BLOCK_SIZE];
+    if !data.is_empty() {
+        edid.edid = data.as_mut_ptr();
+        edid_ioctl(dev, v4l2::vidioc::VIDIOC_G_EDID, &mut edid)?;
+        data.truncate(edid.blocks as usize * EDID_BLOCK_SIZE);
+    }
+    Ok(data)
+}
+
+// Replace the EDID advertised to the HDMI source. An empty blob removes it.
+pub fn set_edid(dev: &Device, data: &[u8]) -> io::Result<()> {
+    if !data.len().is_multiple_of(EDID_BLOCK_SIZE) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "EDID is {} bytes, not a multiple of {}",
+                data.len(),
+                EDID_BLOCK_SIZE
+            ),
+        ));
+    }
+    let mut data = data.to_vec();
+    let mut edid = v4l2_edid {
+        pad: 0,
+        start_block: 0,
+        blocks: (data.len() / EDID_BLOCK_SIZE) as u32,
+        reserved: [0; 5],
+        edid: data.as_mut_ptr(),
+    };
+    edid_ioctl(dev, v4l2::vidioc::VIDIOC_S_EDID, &mut edid)
+}
+
+// ioctl request number, as computed by the _IOC macro of the kernel headers
+const fn ioc(dir: u32, nr: u32, size: usize) -> v4l2::vidioc::_IOC_TYPE {
+    ((dir << 30) | ((size as u32) << 16) | ((b'V' as u32) << 8) | nr) as v4l2::vidioc::_IOC_TYPE
+}
+
+const IOC_WRITE: u32 = 1;
+const IOC_READ: u32 = 2;
+
+// Missing from v4l::v4l2::vidioc
+pub const VIDIOC_S_DV_TIMINGS: v4l2::vidioc::_IOC_TYPE =
+    ioc(IOC_READ | IOC_WRITE, 87, mem::size_of::<v4l2_dv_timings>());
+pub const VIDIOC_QUERY_DV_TIMINGS: v4l2::vidioc::_IOC_TYPE =
+    ioc(IOC_READ, 99, mem::size_of::<v4l2_dv_timings>());
+pub const VIDIOC_DQEVENT: v4l2::vidioc::_IOC_TYPE = ioc(IOC_READ, 89, mem::size_of::<v4l2_event>());
+pub const VIDIOC_SUBSCRIBE_EVENT: v4l2::vidioc::_IOC_TYPE =
+    ioc(IOC_WRITE, 90, mem::size_of::<v4l2_event_subscription>());
+
+// Digital video timings detected on an HDMI/DVI input
+pub struct DvTimings {
+    raw: v4l2_dv_timings,
+}
+
+impl DvTimings {
+    fn bt(&self) -> v4l2_bt_timings {
+        unsafe { self.raw.__bindgen_anon_1.bt }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.bt().width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.bt().height
+    }
+
+    // Field (interlaced) or frame rate derived from the pixel clock
+    pub fn refresh_rate(&self) -> f64 {
+        let bt = self.bt();
+        let htotal = bt.width + bt.hfrontporch + bt.hsync + bt.hbackporch;
+        let mut vtotal = bt.height + bt.vfrontporch + bt.vsync + bt.vbackporch;
+        if bt.interlaced != 0 {
+            vtotal += bt.il_vfrontporch + bt.il_vsync + bt.il_vbackporch;
+        }
+        let pixelclock = bt.pixelclock;
+        if htotal == 0 || vtotal == 0 {
+            return 0.0;
+        }
+        pixelclock as f64 / (htotal as f64 * vtotal as f64)
+    }
+}
+
+impl fmt::Display for DvTimings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bt = self.bt();
+        let pixelclock = bt.pixelclock;
+        write!(
+            f,
+            "{}x{}{} @ {:.2} Hz (pixel clock {} Hz)",
+            self.width(),
+            self.height(),
+            if bt.interlaced != 0 { "i" } else { "p" },
+            self.refresh_rate(),
+            pixelclock
+        )
+    }
+}
+
+// Describe the DV timing errors a user can do something about
+pub fn dv_timings_error(e: &io::Error) -> String {
+    match e.raw_os_error() {
+        Some(libc::ENOLINK) => "no signal on the input".to_string(),
+        Some(libc::ENOLCK) => "signal is unstable, cannot lock".to_string(),
+        Some(libc::ERANGE) => "signal timings are out of range".to_string(),
+        Some(libc::ENOTTY) => "device does not support DV timings".to_string(),
+        _ => e.to_string(),
+    }
+}
+
+pub fn query_dv_timings(dev: &Device) -> io::Result<DvTimings> {
+    let mut raw: v4l2_dv_timings = unsafe { mem::zeroed() };
+    unsafe {
+        v4l2::ioctl(
+            dev.handle().fd(),
+            VIDIOC_QUERY_DV_TIMINGS,
+            &mut raw as *mut _ as *mut c_void,
+        )?;
+    }
+    Ok(DvTimings { raw })
+}
+
+pub fn set_dv_timings(dev: &Device, timings: &DvTimings) -> io::Result<()> {
+    let mut raw = timings.raw;
+    unsafe {
+        v4l2::ioctl(
+            dev.handle().fd(),
+            VIDIOC_S_DV_TIMINGS,
+            &mut raw as *mut _ as *mut c_void,
+        )
+    }
+}
+
+// A video input of the device, from VIDIOC_ENUMINPUT
+pub struct Input {
+    pub index: u32,
+    pub name: String,
+    pub kind: &'static str,
+    // Signal problems the driver sees right now, e.g. "no signal"
+    pub status: Vec<&'static str>,
+}
+
+// Until the driver answers EINVAL, the end of the list
+fn enumerate<T: IndexedEntry>(request: v4l2::vidioc::_IOC_TYPE, fd: RawFd) -> io::Result<Vec<T>> {
+    let mut list = Vec::new();
+    for index in 0.. {
+        let mut raw: T = unsafe { mem::zeroed() };
+        raw.set_index(index);
+        match unsafe { v4l2::ioctl(fd, request, &mut raw as *mut _ as *mut c_void) } {
+            Ok(()) => list.push(raw),
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(list)
+}
+
+// The ENUM ioctls take the entry to fill in by its index
+trait IndexedEntry {
+    fn set_index(&mut self, index: u32);
+}
+
+impl IndexedEntry for v4l2_input {
+    fn set_index(&mut self, index: u32) {
+        self.index = index;
+    }
+}
+
+impl IndexedEntry for v4l2_standard {
+    fn set_index(&mut self, index: u32) {
+        self.index = index;
+    }
+}
+
+fn c_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+pub fn inputs(dev: &Device) -> io::Result<Vec<Input>> {
+    let raw: Vec<v4l2_input> = enumerate(v4l2::vidioc::VIDIOC_ENUMINPUT, dev.handle().fd())?;
+    Ok(raw
+        .iter()
+        .map(|input| Input {
+            index: input.index,
+            name: c_string(&input.name),
+            kind: match input.type_ {
+                1 => "tuner",
+                2 => "camera",
+                3 => "touch",
+                _ => "unknown",
+            },
+            status: [
+                (V4L2_IN_ST_NO_POWER, "no power"),
+                (V4L2_IN_ST_NO_SIGNAL, "no signal"),
+                (V4L2_IN_ST_NO_COLOR, "no color"),
+            ]
+            .iter()
+            .filter(|(bit, _)| input.status & bit != 0)
+            .map(|&(_, name)| name)
+            .collect(),
+        })
+        .collect())
+}
+
+pub fn current_input(dev: &Device) -> io::Result<u32> {
+    let mut index: std::os::raw::c_int = 0;
+    unsafe {
+        v4l2::ioctl(
+            dev.handle().fd(),
+            v4l2::vidioc::VIDIOC_G_INPUT,
+            &mut index as *mut _ as *mut c_void,
+        )?;
+    }
+    Ok(index as u32)
+}
+
+// An analog TV standard of the current input, from VIDIOC_ENUMSTD
+pub struct Standard {
+    pub id: u64,
+    pub name: String,
+    pub frame_period: Fraction,
+    pub lines: u32,
+}
+
+pub fn standards(dev: &Device) -> io::Result<Vec<Standard>> {
+    let raw: Vec<v4l2_standard> = enumerate(v4l2::vidioc::VIDIOC_ENUMSTD, dev.handle().fd())?;
+    Ok(raw
+        .iter()
+        .map(|std| Standard {
+            id: std.id,
+            name: c_string(&std.name),
+            frame_period: Fraction::new(std.frameperiod.numerator, std.frameperiod.denominator),
+            lines: std.framelines,
+        })
+        .collect())
+}
+
+// Names accepted by --require-caps
+const CAPABILITIES: &[(&str, Flags)] = &[
+    ("capture", Flags::VIDEO_CAPTURE),
+    ("capture-mplane", Flags::VIDEO_CAPTURE_MPLANE),
+    ("output", Flags::VIDEO_OUTPUT),
+    ("output-mplane", Flags::VIDEO_OUTPUT_MPLANE),
+    ("m2m", Flags::VIDEO_M2M),
+    ("m2m-mplane", Flags::VIDEO_M2M_MPLANE),
+    ("overlay", Flags::VIDEO_OVERLAY),
+    ("meta-capture", Flags::META_CAPTURE),
+    ("tuner", Flags::TUNER),
+    ("audio", Flags::AUDIO),
+    ("readwrite", Flags::READ_WRITE),
+    ("streaming", Flags::STREAMING),
+];
+
+pub fn capability(name: &str) -> Option<Flags> {
+    CAPABILITIES
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|&(_, flag)| flag)
+}
+
+pub fn capability_names(flags: Flags) -> Vec<&'static str> {
+    CAPABILITIES
+        .iter()
+        .filter(|(_, flag)| flags.contains(*flag))
+        .map(|&(name, _)| name)
+        .collect()
+}
+
+// Fail unless the node has every capability in `required`, naming the missing
+// ones and what the node offers instead
+pub fn require_caps(dev: &Device, required: Flags) -> Result<(), String> {
+    let caps = dev.query_caps().map_err(|e| format!("query_caps: {e}"))?;
+    let missing = required - caps.capabilities;
+    if missing.is_empty() {
+        return Ok(());
+    }
+    let present = capability_names(caps.capabilities);
+    Err(format!(
+        "missing capabilities: {} (device has: {})",
+        capability_names(missing).join(","),
+        if present.is_empty() {
+            "none".to_string()
+        } else {
+            present.join(",")
+        }
+    ))
+}
+
+// What a failing VIDIOC_STREAMON/STREAMOFF usually means
+pub fn stream_error_hint(e: &io::Error) -> &'static str {
+    match e.raw_os_error() {
+        Some(libc::EBUSY) => "another process is using the device",
+        Some(libc::EINVAL) => "format or buffer type not supported by the device",
+        Some(libc::ENOSPC) => "not enough USB bandwidth, try a lower resolution or frame rate",
+        Some(libc::ENOLINK) => "no signal on the input",
+        Some(libc::EPIPE) => "media pipeline is not configured",
+        Some(libc::ENODEV) => "device was disconnected",
+        Some(libc::ENOMEM) => "out of memory for the capture buffers",
+        _ => "unexpected error",
+    }
+}
+
+// Ask for V4L2_EVENT_SOURCE_CHANGE, which HDMI receivers send when the input
+// signal changes. Pending events make the fd poll with POLLPRI.
+pub fn subscribe_source_change(fd: RawFd) -> io::Result<()> {
+    let mut sub: v4l2_event_subscription = unsafe { mem::zeroed() };
+    sub.type_ = V4L2_EVENT_SOURCE_CHANGE;
+    unsafe {
+        v4l2::ioctl(
+            fd,
+            VIDIOC_SUBSCRIBE_EVENT,
+            &mut sub as *mut _ as *mut c_void,
+        )
+    }
+}
+
+// Dequeue all pending events
+pub fn take_events(fd: RawFd) -> io::Result<Vec<v4l2_event>> {
+    let mut taken = Vec::new();
+    loop {
+        let mut ev: v4l2_event = unsafe { mem::zeroed() };
+        match unsafe { v4l2::ioctl(fd, VIDIOC_DQEVENT, &mut ev as *mut _ as *mut c_void) } {
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => return Ok(taken),
+            result => result?,
+        }
+        let pending = ev.pending;
+        taken.push(ev);
+        if pending == 0 {
+            return Ok(taken);
+        }
+    }
+}
+
+// Whether the event is a change of the input resolution
+pub fn is_resolution_change(ev: &v4l2_event) -> bool {
+    if ev.type_ != V4L2_EVENT_SOURCE_CHANGE {
+        return false;
+    }
+    let data = unsafe { ev.u.data };
+    let changes = u32::from_ne_bytes([data[0], data[1], data[2], data[3]]);
+    changes & V4L2_EVENT_SRC_CH_RESOLUTION != 0
+}
@@ -0,0 +1,697 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use v4l::capability::Flags;
+use v4l::frameinterval::FrameIntervalEnum;
+use v4l::framesize::FrameSizeEnum;
+use v4l::video::Capture;
+use v4l::{Device, Fraction};
+
+use crate::controls;
+use crate::device;
+
+// How to recognize the wanted camera among the video nodes
+pub enum Selector {
+    Name(String),
+    Serial(String),
+}
+
+struct Candidate {
+    path: String,
+    card: String,
+    bus: String,
+    serial: Option<String>,
+}
+
+// Sysfs directory of the USB device behind a video node. The node's
+// `device` link points to the USB interface, the device is one level up.
+fn usb_device(node: &str) -> Option<PathBuf> {
+    let name = Path::new(node).file_name()?;
+    let dev = Path::new("/sys/class/video4linux")
+        .join(name)
+        .join("device");
+    let iface = fs::canonicalize(dev).ok()?;
+    Some(iface.parent()?.to_path_buf())
+}
+
+fn usb_attr(dir: &Path, attr: &str) -> Option<String> {
+    let value = fs::read_to_string(dir.join(attr)).ok()?;
+    Some(value.trim().to_string())
+}
+
+fn usb_serial(node: &str) -> Option<String> {
+    usb_attr(&usb_device(node)?, "serial")
+}
+
+// Link speed of the USB device in Mbit/s, 480 for high speed
+pub fn usb_speed(node: &str) -> Option<f64> {
+    usb_attr(&usb_device(node)?, "speed")?.parse().ok()
+}
+
+// Video nodes under /dev in numeric order
+fn video_nodes() -> Vec<String> {
+    let mut nodes: Vec<(u32, String)> = fs::read_dir("/dev")
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            let n = name.strip_prefix("video")?.parse().ok()?;
+            Some((n, format!("/dev/{}", name)))
+        })
+        .collect();
+    nodes.sort();
+    nodes.into_iter().map(|(_, path)| path).collect()
+}
+
+// Capture nodes under /dev in numeric order
+fn candidates() -> Vec<Candidate> {
+    video_nodes()
+        .into_iter()
+        .filter_map(|path| {
+            let caps = Device::with_path(&path).ok()?.query_caps().ok()?;
+            // Skip metadata and output nodes of the same card
+            if !caps.capabilities.contains(Flags::VIDEO_CAPTURE) {
+                return None;
+            }
+            Some(Candidate {
+                serial: usb_serial(&path),
+                path,
+                card: caps.card,
+                bus: caps.bus,
+            })
+        })
+        .collect()
+}
+
+// Find the node of the selected camera. Without `first_match`, more than
+// one matching camera is an error since the choice would be arbitrary.
+pub fn find_device(selector: &Selector, first_match: bool) -> Result<String, String> {
+    let matches: Vec<Candidate> = candidates()
+        .into_iter()
+        .filter(|c| match selector {
+            Selector::Name(name) => c.card == *name,
+            Selector::Serial(serial) => c.serial.as_deref() == Some(serial) || c.bus == *serial,
+        })
+        .collect();
+    let what = match selector {
+        Selector::Name(name) => format!("name \"{}\"", name),
+        Selector::Serial(serial) => format!("serial \"{}\"", serial),
+    };
+    match matches.len() {
+        0 => Err(format!("no capture device with {}", what)),
+        1 => Ok(matches[0].path.clone()),
+        _ if first_match => Ok(matches[0].path.clone()),
+        _ => {
+            let list: Vec<String> = matches
+                .iter()
+                .map(|c| {
+                    format!(
+                        "{} ({}, serial {})",
+                        c.path,
+                        c.bus,
+                        c.serial.as_deref().unwrap_or("?")
+                    )
+                })
+                .collect();
+            Err(format!(
+                "{} devices with {}: {} (use --first-match or --by-serial)",
+                matches.len(),
+                what,
+                list.join(", ")
+            ))
+        }
+    }
+}
+
+// First node of a driver, e.g. "vivid", taking frames (`output`) or giving them
+pub fn find_driver(driver: &str, output: bool) -> Option<String> {
+    let wanted = if output {
+        Flags::VIDEO_OUTPUT
+    } else {
+        Flags::VIDEO_CAPTURE
+    };
+    video_nodes().into_iter().find(|path| {
+        Device::with_path(path)
+            .and_then(|dev| dev.query_caps())
+            .is_ok_and(|caps| caps.driver == driver && caps.capabilities.contains(wanted))
+    })
+}
+
+// JSON string literal
+pub fn json_str(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// Frames per second of a frame interval, as a JSON number
+pub fn fps(interval: &Fraction) -> String {
+    if interval.numerator == 0 {
+        return "0".to_string();
+    }
+    let fps = interval.denominator as f64 / interval.numerator as f64;
+    format!("{}", (fps * 1000.0).round() / 1000.0)
+}
+
+fn intervals_json(dev: &Device, fourcc: v4l::FourCC, width: u32, height: u32) -> String {
+    let intervals = dev
+        .enum_frameintervals(fourcc, width, height)
+        .unwrap_or_default();
+    let mut discrete = Vec::new();
+    for interval in intervals {
+        match interval.interval {
+            FrameIntervalEnum::Discrete(fraction) => discrete.push(fps(&fraction)),
+            FrameIntervalEnum::Stepwise(step) => {
+                // The longest interval is the lowest rate
+                return format!(
+                    "\"fps_min\":{},\"fps_max\":{}",
+                    fps(&step.max),
+                    fps(&step.min)
+                );
+            }
+        }
+    }
+    format!("\"fps\":[{}]", discrete.join(","))
+}
+
+// Formats, frame sizes and frame rates of a capture node
+fn formats_json(dev: &Device) -> String {
+    let mut formats = Vec::new();
+    for desc in dev.enum_formats().unwrap_or_default() {
+        let mut sizes = Vec::new();
+        for size in dev.enum_framesizes(desc.fourcc).unwrap_or_default() {
+            match size.size {
+                FrameSizeEnum::Discrete(d) => sizes.push(format!(
+                    "{{\"width\":{},\"height\":{},{}}}",
+                    d.width,
+                    d.height,
+                    intervals_json(dev, desc.fourcc, d.width, d.height)
+                )),
+                // Too many sizes to list; the rates are those of the largest
+                FrameSizeEnum::Stepwise(s) => sizes.push(format!(
+                    "{{\"min_width\":{},\"max_width\":{},\"step_width\":{},\
+                     \"min_height\":{},\"max_height\":{},\"step_height\":{},{}}}",
+                    s.min_width,
+                    s.max_width,
+                    s.step_width,
+                    s.min_height,
+                    s.max_height,
+                    s.step_height,
+                    intervals_json(dev, desc.fourcc, s.max_width, s.max_height)
+                )),
+            }
+        }
+        formats.push(format!(
+            "{{\"fourcc\":{},\"description\":{},\"sizes\":[{}]}}",
+            json_str(&desc.fourcc.to_string()),
+            json_str(&desc.description),
+            sizes.join(",")
+        ));
+    }
+    formats.join(",")
+}
+
+// --list-devices: every capture node with what it can do, and the nodes that
+// were skipped with the reason, as JSON or one line per node
+pub fn list_devices(json: bool) {
+    let mut devices = Vec::new();
+    let mut skipped = Vec::new();
+    for path in video_nodes() {
+        let caps = Device::with_path(&path).and_then(|dev| Ok((dev.query_caps()?, dev)));
+        let (caps, dev) = match caps {
+            Ok(caps) => caps,
+            Err(e) => {
+                let reason = match e.kind() {
+                    io::ErrorKind::PermissionDenied => "permission denied".to_string(),
+                    _ => e.to_string(),
+                };
+                skipped.push((path, reason));
+                continue;
+            }
+        };
+        if !caps.capabilities.contains(Flags::VIDEO_CAPTURE) {
+            skipped.push((path, "not a capture device".to_string()));
+            continue;
+        }
+        if json {
+            devices.push(format!(
+                "{{\"path\":{},\"card\":{},\"driver\":{},\"bus\":{},\"formats\":[{}]}}",
+                json_str(&path),
+                json_str(&caps.card),
+                json_str(&caps.driver),
+                json_str(&caps.bus),
+                formats_json(&dev)
+            ));
+        } else {
+            let formats: Vec<String> = dev
+                .enum_formats()
+                .unwrap_or_default()
+                .iter()
+                .map(|desc| desc.fourcc.to_string())
+                .collect();
+            println!(
+                "{}: {} ({}, {}) {}",
+                path,
+                caps.card,
+                caps.driver,
+                caps.bus,
+                formats.join(" ")
+            );
+        }
+    }
+    if json {
+        let skipped: Vec<String> = skipped
+            .iter()
+            .map(|(path, reason)| {
+                format!(
+                    "{{\"path\":{},\"reason\":{}}}",
+                    json_str(path),
+                    json_str(reason)
+                )
+            })
+            .collect();
+        println!(
+            "{{\"devices\":[{}],\"skipped\":[{}]}}",
+            devices.join(","),
+            skipped.join(",")
+        );
+    } else {
+        for (path, reason) in &skipped {
+            println!("{path}: skipped, {reason}");
+        }
+    }
+}
+
+// Frame rate of the interval closest to `fps` the device offers for a mode
+fn nearest_fps(
+    dev: &Device,
+    fourcc: v4l::FourCC,
+    width: u32,
+    height: u32,
+    fps: f64,
+) -> Option<f64> {
+    let rate = |f: &Fraction| f.denominator as f64 / f.numerator.max(1) as f64;
+    let mut rates = Vec::new();
+    for interval in dev.enum_frameintervals(fourcc, width, height).ok()? {
+        match interval.interval {
+            FrameIntervalEnum::Discrete(f) => rates.push(rate(&f)),
+            FrameIntervalEnum::Stepwise(s) => {
+                rates.push(fps.clamp(rate(&s.max), rate(&s.min)));
+            }
+        }
+    }
+    rates
+        .into_iter()
+        .min_by(|a, b| (a - fps).abs().total_cmp(&(b - fps).abs()))
+}
+
+// The supported mode closest to the requested one, as "WxH FOURCC at N fps":
+// the nearest size in the requested pixel format if the device has it, in
+// any format otherwise
+pub fn nearest_mode(
+    dev: &Device,
+    fourcc: v4l::FourCC,
+    width: u32,
+    height: u32,
+    fps: u32,
+) -> Option<String> {
+    let formats = dev.enum_formats().ok()?;
+    let same: Vec<v4l::FourCC> = formats
+        .iter()
+        .map(|desc| desc.fourcc)
+        .filter(|&f| f == fourcc)
+        .collect();
+    let candidates = if same.is_empty() {
+        formats.iter().map(|desc| desc.fourcc).collect()
+    } else {
+        same
+    };
+    let distance = |w: u32, h: u32| w.abs_diff(width) as u64 + h.abs_diff(height) as u64;
+    let mut best: Option<(u64, v4l::FourCC, u32, u32)> = None;
+    for f in candidates {
+        for size in dev.enum_framesizes(f).unwrap_or_default() {
+            let (w, h) = match size.size {
+                FrameSizeEnum::Discrete(d) => (d.width, d.height),
+                FrameSizeEnum::Stepwise(s) => {
+                    let fit = |want: u32, min: u32, max: u32, step: u32| {
+                        let v = want.clamp(min, max);
+                        min + (v - min) / step.max(1) * step.max(1)
+                    };
+                    (
+                        fit(width, s.min_width, s.max_width, s.step_width),
+                        fit(height, s.min_height, s.max_height, s.step_height),
+                    )
+                }
+            };
+            let d = distance(w, h);
+            if best.is_none_or(|(bd, ..)| d < bd) {
+                best = Some((d, f, w, h));
+            }
+        }
+    }
+    let (_, f, w, h) = best?;
+    Some(match nearest_fps(dev, f, w, h, fps as f64) {
+        Some(rate) => format!("{w}x{h} {f} at {} fps", (rate * 1000.0).round() / 1000.0),
+        None => format!("{w}x{h} {f}"),
+    })
+}
+
+// Frame rates of one mode, in frames per second
+enum Rates {
+    Discrete(Vec<f64>),
+    // Continuous or stepwise, lowest and highest
+    Range(f64, f64),
+}
+
+impl Rates {
+    fn of(dev: &Device, fourcc: v4l::FourCC, width: u32, height: u32) -> Rates {
+        let rate = |f: &Fraction| f.denominator as f64 / f.numerator.max(1) as f64;
+        let mut discrete = Vec::new();
+        for interval in dev
+            .enum_frameintervals(fourcc, width, height)
+            .unwrap_or_default()
+        {
+            match interval.interval {
+                FrameIntervalEnum::Discrete(f) => discrete.push(rate(&f)),
+                FrameIntervalEnum::Stepwise(s) => return Rates::Range(rate(&s.max), rate(&s.min)),
+            }
+        }
+        discrete.sort_by(|a, b| b.total_cmp(a));
+        Rates::Discrete(discrete)
+    }
+
+    fn max(&self) -> Option<f64> {
+        match self {
+            Rates::Discrete(rates) => rates.first().copied(),
+            Rates::Range(_, max) => Some(*max),
+        }
+    }
+}
+
+fn round_fps(fps: f64) -> f64 {
+    (fps * 1000.0).round() / 1000.0
+}
+
+// One row of --format-table: a pixel format at a frame size
+struct Mode {
+    fourcc: v4l::FourCC,
+    // Discrete size, or the largest of a stepwise range with its steps
+    width: u32,
+    height: u32,
+    stepwise: Option<v4l::framesize::Stepwise>,
+    rates: Rates,
+}
+
+fn modes(dev: &Device) -> io::Result<Vec<Mode>> {
+    let mut modes = Vec::new();
+    for desc in dev.enum_formats()? {
+        let mut sizes = Vec::new();
+        for size in dev.enum_framesizes(desc.fourcc).unwrap_or_default() {
+            sizes.push(match size.size {
+                FrameSizeEnum::Discrete(d) => (d.width, d.height, None),
+                // The rates are those of the largest size
+                FrameSizeEnum::Stepwise(s) => (s.max_width, s.max_height, Some(s)),
+            });
+        }
+        // Largest first within a format
+        sizes.sort_by_key(|&(w, h, _)| std::cmp::Reverse(w as u64 * h as u64));
+        for (width, height, stepwise) in sizes {
+            modes.push(Mode {
+                fourcc: desc.fourcc,
+                width,
+                height,
+                stepwise,
+                rates: Rates::of(dev, desc.fourcc, width, height),
+            });
+        }
+    }
+    Ok(modes)
+}
+
+fn mode_json(mode: &Mode) -> String {
+    let mut fields = vec![
+        format!("\"fourcc\":{}", json_str(&mode.fourcc.to_string())),
+        format!("\"width\":{}", mode.width),
+        format!("\"height\":{}", mode.height),
+    ];
+    if let Some(s) = &mode.stepwise {
+        fields.push(format!(
+            "\"min_width\":{},\"step_width\":{},\"min_height\":{},\"step_height\":{}",
+            s.min_width, s.step_width, s.min_height, s.step_height
+        ));
+    }
+    match &mode.rates {
+        Rates::Discrete(rates) => {
+            let rates: Vec<String> = rates.iter().map(|&r| round_fps(r).to_string()).collect();
+            fields.push(format!("\"fps\":[{}]", rates.join(",")));
+        }
+        Rates::Range(min, max) => fields.push(format!(
+            "\"fps_min\":{},\"fps_max\":{}",
+            round_fps(*min),
+            round_fps(*max)
+        )),
+    }
+    let max = mode
+        .rates
+        .max()
+        .map_or("null".to_string(), |max| round_fps(max).to_string());
+    fields.push(format!("\"max_fps\":{max}"));
+    format!("{{{}}}", fields.join(","))
+}
+
+// --format-table: every pixel format and frame size of a device with the
+// frame rates it reaches there, one row each, the highest rate up front
+pub fn format_table(path: &str, json: bool) -> Result<(), String> {
+    let dev = Device::with_path(path).map_err(|e| format!("{path}: {e}"))?;
+    let modes = modes(&dev).map_err(|e| format!("{path}: failed to list formats: {e}"))?;
+    if json {
+        let modes: Vec<String> = modes.iter().map(mode_json).collect();
+        println!(
+            "{{\"device\":{},\"modes\":[{}]}}",
+            json_str(path),
+            modes.join(",")
+        );
+        return Ok(());
+    }
+    print_modes(&modes);
+    Ok(())
+}
+
+fn print_modes(modes: &[Mode]) {
+    println!("FORMAT        SIZE  MAX FPS  RATES");
+    for mode in modes {
+        let mut size = format!("{}x{}", mode.width, mode.height);
+        if mode.stepwise.is_some() {
+            size = format!("<={size}");
+        }
+        let max = mode
+            .rates
+            .max()
+            .map_or("-".to_string(), |max| round_fps(max).to_string());
+        let rates = match &mode.rates {
+            Rates::Discrete(rates) => {
+                let rates: Vec<String> = rates.iter().map(|&r| round_fps(r).to_string()).collect();
+                rates.join(" ")
+            }
+            Rates::Range(min, max) => format!("{}..{}", round_fps(*min), round_fps(*max)),
+        };
+        println!("{:<6} {:>11} {:>8}  {}", mode.fourcc, size, max, rates);
+    }
+}
+
+// USB attributes for --env-report; bcdDevice is the firmware revision
+const USB_ATTRS: [(&str, &str); 5] = [
+    ("idVendor", "vendor_id"),
+    ("idProduct", "product_id"),
+    ("bcdDevice", "firmware"),
+    ("manufacturer", "manufacturer"),
+    ("product", "product"),
+];
+
+// What a bug report needs to know about the system and the device, the USB
+// details only for USB devices
+fn env_fields(path: &str) -> Result<Vec<(&'static str, String)>, String> {
+    let uname = nix::sys::utsname::uname().map_err(|e| format!("uname: {e}"))?;
+    let kernel = format!(
+        "{} {} {}",
+        uname.sysname().to_string_lossy(),
+        uname.release().to_string_lossy(),
+        uname.machine().to_string_lossy()
+    );
+    let caps = Device::with_path(path)
+        .and_then(|dev| dev.query_caps())
+        .map_err(|e| format!("{path}: {e}"))?;
+    let (major, minor, patch) = caps.version;
+    let mut fields = vec![
+        (
+            "program",
+            format!("v4l2capture-rs {}", env!("CARGO_PKG_VERSION")),
+        ),
+        ("kernel", kernel),
+        ("device", path.to_string()),
+        ("driver", caps.driver),
+        ("driver_version", format!("{major}.{minor}.{patch}")),
+        ("card", caps.card),
+        ("bus", caps.bus),
+        (
+            "ioctls",
+            device::libv4l_preload().map_or("raw".to_string(), |lib| format!("through {lib}")),
+        ),
+    ];
+    if let Some(dir) = usb_device(path) {
+        for (attr, name) in USB_ATTRS {
+            if let Some(value) = usb_attr(&dir, attr) {
+                fields.push((name, value));
+            }
+        }
+    }
+    Ok(fields)
+}
+
+fn fields_json(fields: &[(&str, String)]) -> String {
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|(name, value)| format!("{}:{}", json_str(name), json_str(value)))
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+fn print_fields(fields: &[(&str, String)]) {
+    for (name, value) in fields {
+        println!("{:<15} {}", format!("{name}:"), value);
+    }
+}
+
+// --env-report
+pub fn env_report(path: &str, json: bool) -> Result<(), String> {
+    let fields = env_fields(path)?;
+    if json {
+        println!("{}", fields_json(&fields));
+    } else {
+        print_fields(&fields);
+    }
+    Ok(())
+}
+
+fn input_json(input: &device::Input, current: Option<u32>) -> String {
+    let status: Vec<String> = input.status.iter().map(|s| json_str(s)).collect();
+    format!(
+        "{{\"index\":{},\"name\":{},\"type\":{},\"current\":{},\"status\":[{}]}}",
+        input.index,
+        json_str(&input.name),
+        json_str(input.kind),
+        current == Some(input.index),
+        status.join(",")
+    )
+}
+
+fn standard_json(std: &device::Standard) -> String {
+    format!(
+        "{{\"id\":{},\"name\":{},\"fps\":{},\"lines\":{}}}",
+        std.id,
+        json_str(&std.name),
+        fps(&std.frame_period),
+        std.lines
+    )
+}
+
+// A --list-all section as JSON, its error as a string for the "errors" object
+fn section_json<T>(
+    name: &str,
+    section: &Result<T, String>,
+    json: impl Fn(&T) -> String,
+    fields: &mut Vec<String>,
+    errors: &mut Vec<String>,
+) {
+    match section {
+        Ok(value) => fields.push(format!("{}:{}", json_str(name), json(value))),
+        Err(e) => errors.push(format!("{}:{}", json_str(name), json_str(e))),
+    }
+}
+
+fn list_json<T>(items: &[T], json: impl Fn(&T) -> String) -> String {
+    let items: Vec<String> = items.iter().map(json).collect();
+    format!("[{}]", items.join(","))
+}
+
+// A --list-all section heading with its contents, or why it is missing
+fn print_section<T>(name: &str, section: &Result<T, String>, print: impl Fn(&T)) {
+    println!("{name}:");
+    match section {
+        Ok(value) => print(value),
+        Err(e) => println!("  not available: {e}"),
+    }
+}
+
+// --list-all: the environment report, formats, inputs, standards and
+// controls of a device in one go. What the device does not support is noted
+// and the rest still listed.
+pub fn list_all(path: &str, json: bool) -> Result<(), String> {
+    let dev = Device::with_path(path).map_err(|e| format!("{path}: {e}"))?;
+    let info = env_fields(path);
+    let modes = modes(&dev).map_err(|e| format!("failed to list formats: {e}"));
+    let current = device::current_input(&dev).ok();
+    let inputs = device::inputs(&dev).map_err(|e| format!("VIDIOC_ENUMINPUT: {e}"));
+    let standards = device::standards(&dev).map_err(|e| format!("VIDIOC_ENUMSTD: {e}"));
+    let controls = dev
+        .query_controls()
+        .map_err(|e| format!("failed to query controls: {e}"));
+    if json {
+        let mut fields = vec![format!("\"device\":{}", json_str(path))];
+        let mut errors = Vec::new();
+        let (f, e) = (&mut fields, &mut errors);
+        section_json("info", &info, |info| fields_json(info), f, e);
+        section_json("modes", &modes, |m| list_json(m, mode_json), f, e);
+        let input = |i: &device::Input| input_json(i, current);
+        section_json("inputs", &inputs, |i| list_json(i, input), f, e);
+        section_json(
+            "standards",
+            &standards,
+            |s| list_json(s, standard_json),
+            f,
+            e,
+        );
+        let controls_json = |c: &Vec<_>| controls::controls_json(&dev, c);
+        section_json("controls", &controls, controls_json, f, e);
+        fields.push(format!("\"errors\":{{{}}}", errors.join(",")));
+        println!("{{{}}}", fields.join(","));
+        return Ok(());
+    }
+    print_section("Device", &info, |info| print_fields(info));
+    print_section("Formats", &modes, |modes| print_modes(modes));
+    print_section("Inputs", &inputs, |inputs| {
+        for input in inputs {
+            let mut notes = input.status.clone();
+            if current == Some(input.index) {
+                notes.insert(0, "current");
+            }
+            let notes = match notes.is_empty() {
+                true => String::new(),
+                false => format!(" [{}]", notes.join(", ")),
+            };
+            println!("  {}: {} ({}){notes}", input.index, input.name, input.kind);
+        }
+    });
+    print_section("Standards", &standards, |standards| {
+        for std in standards {
+            println!(
+                "  0x{:016x} {}: {} fps, {} lines",
+                std.id,
+                std.name,
+                fps(&std.frame_period),
+                std.lines
+            );
+        }
+    });
+    print_section("Controls", &controls, |c| controls::print_controls(&dev, c));
+    Ok(())
+}
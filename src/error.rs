@@ -0,0 +1,24 @@
+// Errors of the library are std::io::Error throughout, those of a failed
+// call with its errno as raw_os_error(). Errnos that std leaves uncategorized
+// but V4L2 drivers use with a clear meaning get a kind from kind(), which
+// callers match on in place of e.kind().
+
+use std::io::{self, ErrorKind};
+
+// What V4L2 drivers mean by the errnos std has no kind for
+fn v4l2_kind(errno: i32) -> Option<ErrorKind> {
+    match errno {
+        // The device was unplugged, or the node has no device behind it
+        libc::ENODEV | libc::ENXIO => Some(ErrorKind::NotFound),
+        // No signal on the input
+        libc::ENOLINK => Some(ErrorKind::NotConnected),
+        _ => None,
+    }
+}
+
+// e.kind(), with the V4L2 meaning of the errnos std leaves uncategorized
+pub fn kind(e: &io::Error) -> ErrorKind {
+    e.raw_os_error()
+        .and_then(v4l2_kind)
+        .unwrap_or_else(|| e.kind())
+}
@@ -0,0 +1,43 @@
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::io::{FromRawFd, RawFd};
+
+use crate::clock::Clock;
+
+// Session lifecycle events as one `key=value` line each, written to a file
+// descriptor inherited from the parent. Does nothing unless enabled.
+pub struct Events {
+    out: Option<File>,
+}
+
+impl Events {
+    pub fn new(fd: Option<RawFd>) -> Events {
+        Events {
+            out: fd.map(|fd| unsafe { File::from_raw_fd(fd) }),
+        }
+    }
+
+    pub fn emit(&mut self, event: &str, fields: &[(&str, &dyn std::fmt::Display)]) {
+        let Some(out) = self.out.as_mut() else {
+            return;
+        };
+        let mut line = format!(
+            "time={:.6} event={}",
+            Clock::Realtime.now().as_secs_f64(),
+            event
+        );
+        for (key, value) in fields {
+            let value = value.to_string();
+            if value.is_empty() || value.contains(|c: char| c.is_whitespace() || c == '"') {
+                line += &format!(" {}={:?}", key, value);
+            } else {
+                line += &format!(" {}={}", key, value);
+            }
+        }
+        line.push('\n');
+        // A consumer that went away must not stop the capture
+        if out.write_all(line.as_bytes()).is_err() {
+            self.out = None;
+        }
+    }
+}
@@ -0,0 +1,189 @@
+use crate::pixfmt::Plane;
+use v4l::format::FieldOrder;
+
+// --deinterlace
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Deinterlace {
+    None,
+    // Keep one field and interpolate the rows of the other
+    Bob,
+    // Put buffers holding single fields together, as --assemble-fields
+    Weave,
+}
+
+impl Deinterlace {
+    pub fn parse(s: &str) -> Option<Deinterlace> {
+        match s {
+            "none" => Some(Deinterlace::None),
+            "bob" => Some(Deinterlace::Bob),
+            "weave" => Some(Deinterlace::Weave),
+            _ => None,
+        }
+    }
+}
+
+// Name of the field held by a buffer, from its metadata
+pub fn name(field: u32) -> String {
+    match FieldOrder::try_from(field) {
+        Ok(FieldOrder::Top) => "top".to_string(),
+        Ok(FieldOrder::Bottom) => "bottom".to_string(),
+        Ok(FieldOrder::Interlaced) => "interlaced".to_string(),
+        Ok(order) => order.to_string(),
+        Err(()) => format!("unknown ({field})"),
+    }
+}
+
+// Weaves buffers holding a single field (V4L2_FIELD_ALTERNATE) into frames.
+// The two fields of a frame may arrive in either order; they are matched by
+// sequence number, which drivers either share between both fields or count
+// per field.
+pub struct FieldAssembler {
+    // Layout of one field; the frame has twice the rows of every plane
+    planes: Vec<Plane>,
+    field_size: usize,
+    held: Vec<u8>,
+    // Parity (bottom?) and sequence of the field in `held`
+    held_field: Option<(bool, u32)>,
+    frame: Vec<u8>,
+    pub unpaired: usize,
+}
+
+impl FieldAssembler {
+    pub fn new(planes: Vec<Plane>) -> Self {
+        let field_size = planes.iter().map(|p| p.stride * p.rows).sum();
+        FieldAssembler {
+            planes,
+            field_size,
+            held: Vec::with_capacity(field_size),
+            held_field: None,
+            frame: vec![0; field_size * 2],
+            unpaired: 0,
+        }
+    }
+
+    // Take one field, returning the full frame once both fields are in
+    pub fn push(&mut self, buf: &[u8], bottom: bool, sequence: u32) -> Option<&[u8]> {
+        if buf.len() < self.field_size {
+            eprintln!(
+                "field seq {} has {} bytes, expected {}",
+                sequence,
+                buf.len(),
+                self.field_size
+            );
+            self.unpaired += 1;
+            return None;
+        }
+        let field = &buf[..self.field_size];
+        match self.held_field.take() {
+            Some((held_bottom, held_seq))
+                if held_bottom != bottom
+                    && (sequence.wrapping_sub(held_seq) <= 1
+                        || held_seq.wrapping_sub(sequence) <= 1) =>
+            {
+                let held = std::mem::take(&mut self.held);
+                if bottom {
+                    self.weave(&held, field);
+                } else {
+                    self.weave(field, &held);
+                }
+                self.held = held;
+                Some(&self.frame)
+            }
+            previous => {
+                // The partner of the held field never came
+                if previous.is_some() {
+                    self.unpaired += 1;
+                }
+                self.held.clear();
+                self.held.extend_from_slice(field);
+                self.held_field = Some((bottom, sequence));
+                None
+            }
+        }
+    }
+
+    // Top field lines go to the even rows of every plane, bottom to the odd
+    fn weave(&mut self, top: &[u8], bottom: &[u8]) {
+        let mut offset = 0;
+        for p in &self.planes {
+            let out = &mut self.frame[offset * 2..(offset + p.stride * p.rows) * 2];
+            for row in 0..p.rows {
+                let src = offset + row * p.stride;
+                let dst = row * 2 * p.stride;
+                out[dst..dst + p.stride].copy_from_slice(&top[src..src + p.stride]);
+                out[dst + p.stride..dst + 2 * p.stride]
+                    .copy_from_slice(&bottom[src..src + p.stride]);
+            }
+            offset += p.stride * p.rows;
+        }
+    }
+}
+
+// Bob deinterlacing of raw frames, plane by plane. Whole frames keep their
+// top field, whatever field order they are declared with, as sources sending
+// interlaced content labelled progressive are the reason to use it. Buffers
+// holding a single field become a frame of their own.
+pub struct Bob {
+    frame: Vec<Plane>,
+    // Layout of a buffer holding one field
+    field: Vec<Plane>,
+    out: Vec<u8>,
+}
+
+impl Bob {
+    pub fn new(frame: Vec<Plane>, field: Vec<Plane>) -> Self {
+        Bob {
+            frame,
+            field,
+            out: Vec::new(),
+        }
+    }
+
+    // `field` is Some(bottom?) for a buffer with a single field. None if the
+    // buffer is too short for the layout.
+    pub fn apply(&mut self, buf: &[u8], field: Option<bool>) -> Option<&[u8]> {
+        let (planes, parity) = match field {
+            Some(bottom) => (&self.field, bottom as usize),
+            None => (&self.frame, 0),
+        };
+        self.out.clear();
+        let mut offset = 0;
+        for p in planes {
+            let size = p.stride * p.rows;
+            let plane = buf.get(offset..offset + size)?;
+            offset += size;
+            // Rows of the kept field, and of the frame made from it
+            let (rows, out_rows) = match field {
+                Some(_) => (p.rows, p.rows * 2),
+                None => (p.rows.div_ceil(2), p.rows),
+            };
+            if rows == 0 {
+                continue;
+            }
+            let row = |k: usize| {
+                let k = k.min(rows - 1);
+                let r = if field.is_some() { k } else { 2 * k };
+                &plane[r * p.stride..(r + 1) * p.stride]
+            };
+            for r in 0..out_rows {
+                if r % 2 == parity {
+                    self.out.extend_from_slice(row(r / 2));
+                    continue;
+                }
+                // Field rows just below and above, the same one at the edges
+                let below = (r + 1 - parity) / 2;
+                let above = if r > parity {
+                    (r - 1 - parity) / 2
+                } else {
+                    below
+                };
+                let interpolated = row(above)
+                    .iter()
+                    .zip(row(below))
+                    .map(|(&a, &b)| (a as u16 + b as u16).div_ceil(2) as u8);
+                self.out.extend(interpolated);
+            }
+        }
+        Some(&self.out)
+    }
+}
@@ -0,0 +1,97 @@
+// Frames put in when the camera stalls, for --fill-on-stall, so that a sink
+// which expects a steady frame rate (e.g. a live encoder) never starves
+
+use std::time::Duration;
+use v4l::buffer::Metadata;
+use v4l::Format;
+
+use crate::pixfmt;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum FillMode {
+    // The last frame written again
+    Repeat,
+    // A black frame of the negotiated format
+    Black,
+}
+
+impl FillMode {
+    pub fn parse(s: &str) -> Option<FillMode> {
+        match s {
+            "repeat" => Some(FillMode::Repeat),
+            "black" => Some(FillMode::Black),
+            _ => None,
+        }
+    }
+}
+
+pub struct Filler {
+    mode: FillMode,
+    interval: Duration,
+    frame: Vec<u8>,
+    // Of the last real frame, with the timestamp moved on for every fill
+    meta: Option<Metadata>,
+}
+
+impl Filler {
+    pub fn new(mode: FillMode, interval: Duration) -> Filler {
+        Filler {
+            mode,
+            interval,
+            frame: Vec::new(),
+            meta: None,
+        }
+    }
+
+    // Make the black frame for a new format, with the rows `tight` if the
+    // padding is stripped from the captured frames. Formats it cannot be made
+    // for are filled by repeating instead.
+    pub fn set_format(&mut self, fmt: &Format, tight: bool) {
+        self.frame.clear();
+        self.meta = None;
+        if self.mode != FillMode::Black {
+            return;
+        }
+        let fourcc = &fmt.fourcc.repr;
+        let stride = if tight {
+            pixfmt::packed_stride(fourcc, fmt.width)
+        } else {
+            Some(fmt.stride)
+        };
+        let planes =
+            stride.and_then(|stride| pixfmt::planes(fourcc, fmt.width, fmt.height, stride));
+        match planes.and_then(|planes| pixfmt::black_frame(fourcc, &planes)) {
+            Some(frame) => self.frame = frame,
+            None => {
+                eprintln!(
+                    "--fill-on-stall: no black frame for {}, repeating instead",
+                    fmt.fourcc
+                );
+                self.mode = FillMode::Repeat;
+            }
+        }
+    }
+
+    // The frame rate changed
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    // Remember a frame as written
+    pub fn keep(&mut self, frame: &[u8], meta: &Metadata) {
+        if self.mode == FillMode::Repeat {
+            self.frame.clear();
+            self.frame.extend_from_slice(frame);
+        }
+        self.meta = Some(*meta);
+    }
+
+    // The frame to write for a stalled interval, once a real one came
+    pub fn next(&mut self) -> Option<(&[u8], Metadata)> {
+        let meta = self.meta.as_mut()?;
+        let t: Duration = meta.timestamp.into();
+        meta.timestamp = (t + self.interval).into();
+        meta.bytesused = self.frame.len() as u32;
+        Some((&self.frame, *meta))
+    }
+}
@@ -0,0 +1,51 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::time::Duration;
+
+// Per frame CSV for comparison with ffprobe: frame,<index>,<pts>,<size>,<keyframe>
+// with the pts in seconds and the keyframe flag as 0 or 1, as ffprobe prints them.
+// --jpeg-quality adds the estimated quality, empty where it is not known.
+pub struct FrameLog {
+    out: BufWriter<File>,
+    index: u64,
+    quality: bool,
+}
+
+impl FrameLog {
+    // Numbered from `first`, e.g. to go on from an earlier file
+    pub fn create(path: &str, first: u64, quality: bool) -> io::Result<FrameLog> {
+        Ok(FrameLog {
+            out: BufWriter::new(File::create(path)?),
+            index: first,
+            quality,
+        })
+    }
+
+    pub fn log(
+        &mut self,
+        pts: Duration,
+        size: usize,
+        keyframe: bool,
+        quality: Option<u32>,
+    ) -> io::Result<()> {
+        write!(
+            self.out,
+            "frame,{},{:.6},{},{}",
+            self.index,
+            pts.as_secs_f64(),
+            size,
+            keyframe as u8
+        )?;
+        match (self.quality, quality) {
+            (false, _) => writeln!(self.out)?,
+            (true, Some(quality)) => writeln!(self.out, ",{quality}")?,
+            (true, None) => writeln!(self.out, ",")?,
+        }
+        self.index += 1;
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
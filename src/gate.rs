@@ -0,0 +1,45 @@
+use std::fs;
+
+// --gate-file: frames are written only while a file says so, e.g. the value
+// of a GPIO exported to sysfs. It is read for every frame.
+pub struct Gate {
+    path: String,
+    open: Option<bool>,
+}
+
+// "1", "true", "on", "yes" or "high", in any case and with spaces around
+fn truthy(s: &str) -> bool {
+    let s = s.trim().to_ascii_lowercase();
+    matches!(s.as_str(), "1" | "true" | "on" | "yes" | "high")
+}
+
+impl Gate {
+    pub fn new(path: &str) -> Gate {
+        Gate {
+            path: path.to_string(),
+            open: None,
+        }
+    }
+
+    // Whether the gate is open now, and if that changed with this frame. A
+    // file that cannot be read keeps the gate closed.
+    pub fn poll(&mut self) -> (bool, bool) {
+        let open = match fs::read_to_string(&self.path) {
+            Ok(s) => truthy(&s),
+            Err(e) => {
+                if self.open != Some(false) {
+                    eprintln!("--gate-file {}: {e}", self.path);
+                }
+                false
+            }
+        };
+        let changed = self.open != Some(open);
+        self.open = Some(open);
+        (open, changed)
+    }
+
+    // Whether the gate is open now, leaving the change to poll
+    pub fn peek(&self) -> bool {
+        fs::read_to_string(&self.path).is_ok_and(|s| truthy(&s))
+    }
+}
@@ -0,0 +1,85 @@
+// --gst-pipeline: the frames become the source of a GStreamer pipeline run by
+// a gst-launch-1.0 child. They are piped into its fdsrc, followed by caps from
+// the negotiated format and a parser that splits the stream into frames
+// again. No appsrc: no GStreamer libraries need to be linked.
+
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use v4l::buffer::Metadata;
+use v4l::video::capture::Parameters;
+use v4l::Format;
+
+use crate::container::ContainerWriter;
+use crate::gstcaps::caps;
+use crate::pipe;
+
+pub struct Gst {
+    description: String,
+    child: Option<(Child, ChildStdin)>,
+}
+
+impl Gst {
+    pub fn new(description: &str) -> Gst {
+        Gst {
+            description: description.to_string(),
+            child: None,
+        }
+    }
+}
+
+// Started again for every format, as the caps go with it
+impl ContainerWriter for Gst {
+    fn begin(&mut self, format: &Format, params: &Parameters) -> io::Result<()> {
+        let (caps, parser) = caps(format, params).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("no GStreamer caps for {}", format.fourcc),
+            )
+        })?;
+        let launch = format!(
+            "fdsrc fd=0 do-timestamp=true ! {caps} ! {parser} ! {}",
+            self.description
+        );
+        eprintln!("GStreamer pipeline: {launch}");
+        let mut child = Command::new("gst-launch-1.0")
+            .args(["-q", "-e", &launch])
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|e| io::Error::new(e.kind(), format!("gst-launch-1.0: {e}")))?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        if let Err(e) = pipe::set_pipe_max_size(stdin.as_raw_fd()) {
+            eprintln!("set_pipe_max_size:{e} (ignored)");
+        }
+        self.child = Some((child, stdin));
+        Ok(())
+    }
+
+    fn write_frame(&mut self, buf: &[u8], _meta: &Metadata, mapped: bool) -> io::Result<()> {
+        let Some((_, stdin)) = &self.child else {
+            return Ok(());
+        };
+        if mapped {
+            pipe::vmsplice_single_buffer(buf, stdin.as_raw_fd())
+        } else {
+            pipe::write_all(buf, stdin.as_raw_fd())
+        }
+    }
+
+    fn pipe_fd(&self) -> Option<RawFd> {
+        self.child.as_ref().map(|(_, stdin)| stdin.as_raw_fd())
+    }
+
+    // End of stream for the pipeline, which is waited for
+    fn finalize(&mut self) -> io::Result<()> {
+        let Some((mut child, stdin)) = self.child.take() else {
+            return Ok(());
+        };
+        drop(stdin);
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(io::Error::other(format!("gst-launch-1.0 {status}")));
+        }
+        Ok(())
+    }
+}
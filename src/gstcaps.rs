@@ -0,0 +1,53 @@
+// GStreamer caps of a V4L2 format, for --gst-pipeline and --print-caps
+
+use v4l::video::capture::Parameters;
+use v4l::Format;
+
+// GStreamer name of a raw pixel format
+fn raw_format(fourcc: &[u8; 4]) -> Option<&'static str> {
+    Some(match fourcc {
+        b"YUYV" => "YUY2",
+        b"YVYU" => "YVYU",
+        b"UYVY" => "UYVY",
+        b"NV12" => "NV12",
+        b"NV21" => "NV21",
+        b"NV16" => "NV16",
+        b"NV61" => "NV61",
+        b"YU12" => "I420",
+        b"YV12" => "YV12",
+        b"422P" => "Y42B",
+        b"GREY" => "GRAY8",
+        b"Y16 " => "GRAY16_LE",
+        b"RGB3" => "RGB",
+        b"BGR3" => "BGR",
+        b"XR24" | b"BGR4" => "BGRx",
+        b"AR24" => "BGRA",
+        b"XB24" => "RGBx",
+        b"AB24" => "RGBA",
+        _ => return None,
+    })
+}
+
+// Caps of the negotiated format and the element that frames the byte stream
+pub fn caps(fmt: &Format, params: &Parameters) -> Option<(String, &'static str)> {
+    let geometry = format!(
+        "width={},height={},framerate={}/{}",
+        fmt.width, fmt.height, params.interval.denominator, params.interval.numerator
+    );
+    let fourcc = &fmt.fourcc.repr;
+    Some(match fourcc {
+        b"MJPG" | b"JPEG" => (format!("image/jpeg,{geometry}"), "jpegparse"),
+        b"H264" => (
+            format!("video/x-h264,stream-format=byte-stream,alignment=au,{geometry}"),
+            "h264parse",
+        ),
+        b"HEVC" | b"H265" => (
+            format!("video/x-h265,stream-format=byte-stream,alignment=au,{geometry}"),
+            "h265parse",
+        ),
+        _ => (
+            format!("video/x-raw,format={},{geometry}", raw_format(fourcc)?),
+            "rawvideoparse use-sink-caps=true",
+        ),
+    })
+}
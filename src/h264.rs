@@ -0,0 +1,158 @@
+// H.264 Annex B byte stream handling for --repeat-headers
+
+use std::time::{Duration, Instant};
+
+pub const NAL_IDR: u8 = 5;
+pub const NAL_SPS: u8 = 7;
+pub const NAL_PPS: u8 = 8;
+
+// One NAL unit: its type and byte range, start code included
+pub struct Nal {
+    pub kind: u8,
+    pub start: usize,
+    pub end: usize,
+}
+
+// Split a buffer at its 00 00 01 start codes. A zero byte before a start code
+// (the four byte form) belongs to the unit it starts.
+pub fn nal_units(buf: &[u8]) -> Vec<Nal> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= buf.len() {
+        if buf[i] == 0 && buf[i + 1] == 0 && buf[i + 2] == 1 {
+            let start = if i > 0 && buf[i - 1] == 0 { i - 1 } else { i };
+            starts.push((start, i + 3));
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+    let mut nals = Vec::with_capacity(starts.len());
+    for (n, &(start, header)) in starts.iter().enumerate() {
+        let end = starts.get(n + 1).map_or(buf.len(), |&(next, _)| next);
+        if let Some(&byte) = buf.get(header).filter(|_| header < end) {
+            nals.push(Nal {
+                kind: byte & 0x1f,
+                start,
+                end,
+            });
+        }
+    }
+    nals
+}
+
+pub fn is_keyframe(buf: &[u8]) -> bool {
+    nal_units(buf).iter().any(|nal| nal.kind == NAL_IDR)
+}
+
+// Puts the first SPS/PPS seen in front of keyframes again, at most once per
+// interval, so that consumers joining a running stream can start decoding
+pub struct HeaderRepeater {
+    interval: Duration,
+    sps: Option<Vec<u8>>,
+    pps: Option<Vec<u8>>,
+    // When the stream last carried the headers
+    last: Option<Instant>,
+}
+
+impl HeaderRepeater {
+    pub fn new(interval: Duration) -> Self {
+        HeaderRepeater {
+            interval,
+            sps: None,
+            pps: None,
+            last: None,
+        }
+    }
+
+    // The frame with the headers prepended, if they are due
+    pub fn process(&mut self, frame: &[u8]) -> Option<Vec<u8>> {
+        let nals = nal_units(frame);
+        let mut has_headers = false;
+        let mut keyframe = false;
+        for nal in &nals {
+            let data = &frame[nal.start..nal.end];
+            match nal.kind {
+                NAL_SPS => {
+                    has_headers = true;
+                    self.sps.get_or_insert_with(|| data.to_vec());
+                }
+                NAL_PPS => {
+                    self.pps.get_or_insert_with(|| data.to_vec());
+                }
+                NAL_IDR => keyframe = true,
+                _ => {}
+            }
+        }
+        let now = Instant::now();
+        if has_headers {
+            self.last = Some(now);
+            return None;
+        }
+        let due = self.last.is_none_or(|last| now - last >= self.interval);
+        match (&self.sps, &self.pps) {
+            (Some(sps), Some(pps)) if keyframe && due => {
+                self.last = Some(now);
+                let mut out = Vec::with_capacity(sps.len() + pps.len() + frame.len());
+                out.extend_from_slice(sps);
+                out.extend_from_slice(pps);
+                out.extend_from_slice(frame);
+                Some(out)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPS: &[u8] = &[0, 0, 0, 1, 0x67, 0x42, 0x00];
+    const PPS: &[u8] = &[0, 0, 0, 1, 0x68, 0xce];
+    const IDR: &[u8] = &[0, 0, 1, 0x65, 0x88, 0x84];
+    const SLICE: &[u8] = &[0, 0, 0, 1, 0x41, 0x9a];
+
+    fn stream(parts: &[&[u8]]) -> Vec<u8> {
+        parts.concat()
+    }
+
+    #[test]
+    fn splits_at_start_codes() {
+        let buf = stream(&[SPS, PPS, IDR]);
+        let nals = nal_units(&buf);
+        let kinds: Vec<u8> = nals.iter().map(|n| n.kind).collect();
+        assert_eq!(kinds, [NAL_SPS, NAL_PPS, NAL_IDR]);
+        // The zero byte of a four byte start code goes with its unit
+        assert_eq!((nals[0].start, nals[0].end), (0, SPS.len()));
+        assert_eq!(&buf[nals[2].start..nals[2].end], IDR);
+        // A start code at the very end carries no unit
+        assert_eq!(nal_units(&stream(&[SLICE, &[0, 0, 1]])).len(), 1);
+        assert!(nal_units(&[0x65, 0x88]).is_empty());
+    }
+
+    #[test]
+    fn finds_keyframes() {
+        assert!(is_keyframe(&stream(&[SPS, PPS, IDR])));
+        assert!(!is_keyframe(SLICE));
+    }
+
+    #[test]
+    fn repeats_headers_before_keyframes() {
+        let mut r = HeaderRepeater::new(Duration::ZERO);
+        // Frames with their own headers are left alone
+        assert!(r.process(&stream(&[SPS, PPS, IDR])).is_none());
+        assert!(r.process(SLICE).is_none());
+        assert_eq!(r.process(IDR).unwrap(), stream(&[SPS, PPS, IDR]));
+    }
+
+    #[test]
+    fn repeats_at_most_once_per_interval() {
+        let mut r = HeaderRepeater::new(Duration::from_secs(3600));
+        assert!(r.process(&stream(&[SPS, PPS, IDR])).is_none());
+        assert!(r.process(IDR).is_none());
+        // Nothing to repeat before headers were seen
+        let mut r = HeaderRepeater::new(Duration::ZERO);
+        assert!(r.process(IDR).is_none());
+    }
+}
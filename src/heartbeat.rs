@@ -0,0 +1,33 @@
+use std::fs;
+use std::io;
+use std::time::{Duration, Instant};
+
+const INTERVAL: Duration = Duration::from_secs(1);
+
+// Liveness file for watchdogs: rewritten with the frame count at most once a
+// second while frames keep arriving, so a stall shows up as a stale mtime
+pub struct Heartbeat {
+    path: String,
+    last: Option<Instant>,
+}
+
+impl Heartbeat {
+    pub fn new(path: &str) -> Heartbeat {
+        Heartbeat {
+            path: path.to_string(),
+            last: None,
+        }
+    }
+
+    pub fn beat(&mut self, frames: usize) -> io::Result<()> {
+        let now = Instant::now();
+        if self.last.is_some_and(|t| now.duration_since(t) < INTERVAL) {
+            return Ok(());
+        }
+        self.last = Some(now);
+        // Write a temporary file and rename it so readers never see it half written
+        let tmp = format!("{}.tmp", self.path);
+        fs::write(&tmp, format!("{}\n", frames))?;
+        fs::rename(&tmp, &self.path)
+    }
+}
@@ -0,0 +1,136 @@
+use v4l::buffer::Flags;
+
+// Distribution of frame sizes with bounded memory: fixed width buckets plus
+// running sums, so the median is only known to the bucket width
+pub struct SizeHistogram {
+    bucket_size: u64,
+    buckets: Vec<u64>,
+    count: u64,
+    sum: f64,
+    sum_sq: f64,
+    min: u64,
+    max: u64,
+}
+
+// Sizes beyond the last bucket are counted in it
+const MAX_BUCKETS: usize = 64;
+const BAR_WIDTH: u64 = 50;
+
+impl SizeHistogram {
+    pub fn new(bucket_size: u64) -> SizeHistogram {
+        SizeHistogram {
+            bucket_size: bucket_size.max(1),
+            buckets: Vec::new(),
+            count: 0,
+            sum: 0.0,
+            sum_sq: 0.0,
+            min: u64::MAX,
+            max: 0,
+        }
+    }
+
+    pub fn add(&mut self, size: u64) {
+        let i = ((size / self.bucket_size) as usize).min(MAX_BUCKETS - 1);
+        if self.buckets.len() <= i {
+            self.buckets.resize(i + 1, 0);
+        }
+        self.buckets[i] += 1;
+        self.count += 1;
+        self.sum += size as f64;
+        self.sum_sq += size as f64 * size as f64;
+        self.min = self.min.min(size);
+        self.max = self.max.max(size);
+    }
+
+    // Middle of the bucket holding the median
+    fn median(&self) -> u64 {
+        let mut seen = 0;
+        for (i, &n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen * 2 >= self.count {
+                let mid = i as u64 * self.bucket_size + self.bucket_size / 2;
+                return mid.clamp(self.min, self.max);
+            }
+        }
+        0
+    }
+
+    pub fn report(&self) {
+        if self.count == 0 {
+            return;
+        }
+        let mean = self.sum / self.count as f64;
+        let variance = (self.sum_sq / self.count as f64 - mean * mean).max(0.0);
+        eprintln!(
+            "Frame sizes: min {}, max {}, mean {:.0}, median ~{}, stddev {:.0} bytes",
+            self.min,
+            self.max,
+            mean,
+            self.median(),
+            variance.sqrt()
+        );
+        let peak = self.buckets.iter().copied().max().unwrap_or(0).max(1);
+        for (i, &n) in self.buckets.iter().enumerate() {
+            let low = i as u64 * self.bucket_size;
+            let range = if i == MAX_BUCKETS - 1 {
+                format!("{:>9}-", low)
+            } else {
+                format!("{:>9}-{:<9}", low, low + self.bucket_size - 1)
+            };
+            let bar = "#".repeat((n * BAR_WIDTH).div_ceil(peak) as usize);
+            eprintln!("{:<19} {:>7} {}", range, n, bar);
+        }
+    }
+}
+
+// Buffer flags that describe the frame rather than the state of the buffer
+const FRAME_FLAGS: [(Flags, &str); 6] = [
+    (Flags::KEYFRAME, "KEYFRAME"),
+    (Flags::PFRAME, "PFRAME"),
+    (Flags::BFRAME, "BFRAME"),
+    (Flags::ERROR, "ERROR"),
+    (Flags::TIMECODE, "TIMECODE"),
+    (Flags::LAST, "LAST"),
+];
+
+// --flag-histogram: frames that had each of FRAME_FLAGS set, e.g. for the
+// share of keyframes in a stream without parsing it
+#[derive(Default)]
+pub struct FlagHistogram {
+    counts: [u64; FRAME_FLAGS.len()],
+    // Frames with none of them
+    plain: u64,
+    count: u64,
+}
+
+impl FlagHistogram {
+    pub fn add(&mut self, flags: Flags) {
+        let mut any = false;
+        for (n, (flag, _)) in self.counts.iter_mut().zip(FRAME_FLAGS) {
+            if flags.contains(flag) {
+                *n += 1;
+                any = true;
+            }
+        }
+        if !any {
+            self.plain += 1;
+        }
+        self.count += 1;
+    }
+
+    pub fn report(&self) {
+        if self.count == 0 {
+            return;
+        }
+        eprintln!("Buffer flags of {} frames:", self.count);
+        let names = FRAME_FLAGS.iter().map(|(_, name)| *name).chain(["(none)"]);
+        let counts = self.counts.iter().copied().chain([self.plain]);
+        for (name, n) in names.zip(counts) {
+            let bar = "#".repeat((n * BAR_WIDTH).div_ceil(self.count) as usize);
+            eprintln!(
+                "{name:<9} {n:>9} {:>6.1}% {bar}",
+                100.0 * n as f64 / self.count as f64
+            );
+        }
+    }
+}
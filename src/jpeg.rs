@@ -0,0 +1,574 @@
+// Baseline JPEG decoder, just enough to turn MJPEG snapshots into RGB:
+// 8-bit sequential Huffman coding, any sampling factors, restart markers.
+// Progressive and arithmetic coded images are rejected.
+
+use std::f32::consts::PI;
+use std::io;
+
+use crate::mjpeg;
+
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    // Packed RGB, 3 bytes per pixel
+    pub rgb: Vec<u8>,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("JPEG: {}", msg))
+}
+
+// Natural order index of the n-th coefficient in zigzag order
+#[rustfmt::skip]
+const ZIGZAG: [usize; 64] = [
+     0,  1,  8, 16,  9,  2,  3, 10, 17, 24, 32, 25, 18, 11,  4,  5,
+    12, 19, 26, 33, 40, 48, 41, 34, 27, 20, 13,  6,  7, 14, 21, 28,
+    35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51,
+    58, 59, 52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+// Decoding tables of ITU T.81 F.2.2.3
+#[derive(Clone, Default)]
+struct Huffman {
+    mincode: [i32; 17],
+    maxcode: [i32; 18],
+    valptr: [i32; 17],
+    values: Vec<u8>,
+}
+
+impl Huffman {
+    fn new(counts: &[u8], values: &[u8]) -> Huffman {
+        let mut h = Huffman {
+            values: values.to_vec(),
+            ..Default::default()
+        };
+        let mut code = 0i32;
+        let mut k = 0i32;
+        for len in 1..=16 {
+            let n = counts[len - 1] as i32;
+            h.valptr[len] = k;
+            h.mincode[len] = code;
+            code += n;
+            k += n;
+            h.maxcode[len] = if n > 0 { code - 1 } else { -1 };
+            code <<= 1;
+        }
+        h.maxcode[17] = i32::MAX;
+        h
+    }
+}
+
+// Entropy coded data with byte stuffing removed. Stops at the next marker
+// and then reads zeros.
+struct Bits<'a> {
+    data: &'a [u8],
+    pos: usize,
+    acc: u32,
+    n: u32,
+}
+
+impl<'a> Bits<'a> {
+    fn fill(&mut self) {
+        while self.n <= 24 {
+            let mut byte = 0;
+            if let Some(&b) = self.data.get(self.pos) {
+                if b != 0xff {
+                    byte = b;
+                    self.pos += 1;
+                } else if self.data.get(self.pos + 1) == Some(&0) {
+                    byte = 0xff;
+                    self.pos += 2;
+                }
+            }
+            self.acc |= (byte as u32) << (24 - self.n);
+            self.n += 8;
+        }
+    }
+
+    fn bit(&mut self) -> i32 {
+        if self.n == 0 {
+            self.fill();
+        }
+        let b = self.acc >> 31;
+        self.acc <<= 1;
+        self.n -= 1;
+        b as i32
+    }
+
+    fn bits(&mut self, count: u8) -> i32 {
+        (0..count).fold(0, |v, _| (v << 1) | self.bit())
+    }
+
+    fn decode(&mut self, h: &Huffman) -> io::Result<u8> {
+        let mut code = self.bit();
+        let mut len = 1;
+        while code > h.maxcode[len] {
+            code = (code << 1) | self.bit();
+            len += 1;
+            if len > 16 {
+                return Err(invalid("bad Huffman code"));
+            }
+        }
+        let index = h.valptr[len] + code - h.mincode[len];
+        h.values
+            .get(index as usize)
+            .copied()
+            .ok_or_else(|| invalid("bad Huffman code"))
+    }
+
+    // Drop buffered bits and skip past the next RSTn marker
+    fn restart(&mut self) {
+        self.acc = 0;
+        self.n = 0;
+        while self.pos + 1 < self.data.len() {
+            if self.data[self.pos] == 0xff && (0xd0..=0xd7).contains(&self.data[self.pos + 1]) {
+                self.pos += 2;
+                return;
+            }
+            self.pos += 1;
+        }
+    }
+
+    // Offset of the marker ending the scan
+    fn end(&self) -> usize {
+        let mut pos = self.pos;
+        while pos + 1 < self.data.len() {
+            let next = self.data[pos + 1];
+            if self.data[pos] == 0xff && next != 0 && !(0xd0..=0xd7).contains(&next) {
+                return pos;
+            }
+            pos += 1;
+        }
+        self.data.len()
+    }
+}
+
+// Sign extension of a coefficient difference (F.2.2.1)
+fn extend(v: i32, size: u8) -> i32 {
+    if size == 0 {
+        0
+    } else if v < 1 << (size - 1) {
+        v - (1 << size) + 1
+    } else {
+        v
+    }
+}
+
+struct Component {
+    id: u8,
+    h: usize,
+    v: usize,
+    tq: usize,
+    // Samples of whole MCUs, `stride` wide
+    stride: usize,
+    samples: Vec<u8>,
+    pred: i32,
+    dc: usize,
+    ac: usize,
+}
+
+struct Decoder {
+    width: usize,
+    height: usize,
+    hmax: usize,
+    vmax: usize,
+    quant: [[u16; 64]; 4],
+    dc: [Huffman; 4],
+    ac: [Huffman; 4],
+    comps: Vec<Component>,
+    restart_interval: usize,
+    // cos((2x + 1) u pi / 16), scaled for the IDCT
+    idct: [[f32; 8]; 8],
+}
+
+fn be16(buf: &[u8], pos: usize) -> io::Result<usize> {
+    match buf.get(pos..pos + 2) {
+        Some(b) => Ok(u16::from_be_bytes([b[0], b[1]]) as usize),
+        None => Err(invalid("truncated")),
+    }
+}
+
+impl Decoder {
+    fn new() -> Decoder {
+        let mut idct = [[0f32; 8]; 8];
+        for (x, row) in idct.iter_mut().enumerate() {
+            for (u, c) in row.iter_mut().enumerate() {
+                let scale = if u == 0 { 1.0 / 2f32.sqrt() } else { 1.0 };
+                *c = scale * ((2 * x + 1) as f32 * u as f32 * PI / 16.0).cos() / 2.0;
+            }
+        }
+        Decoder {
+            width: 0,
+            height: 0,
+            hmax: 1,
+            vmax: 1,
+            quant: [[0; 64]; 4],
+            dc: Default::default(),
+            ac: Default::default(),
+            comps: Vec::new(),
+            restart_interval: 0,
+            idct,
+        }
+    }
+
+    fn read_dqt(&mut self, seg: &[u8]) -> io::Result<()> {
+        let mut pos = 0;
+        while pos < seg.len() {
+            let (pq, tq) = (seg[pos] >> 4, (seg[pos] & 3) as usize);
+            pos += 1;
+            for k in 0..64 {
+                self.quant[tq][k] = if pq == 0 {
+                    *seg.get(pos + k).ok_or_else(|| invalid("truncated DQT"))? as u16
+                } else {
+                    be16(seg, pos + 2 * k)? as u16
+                };
+            }
+            pos += if pq == 0 { 64 } else { 128 };
+        }
+        Ok(())
+    }
+
+    fn read_dht(&mut self, seg: &[u8]) -> io::Result<()> {
+        let mut pos = 0;
+        while pos + 17 <= seg.len() {
+            let (tc, th) = (seg[pos] >> 4, (seg[pos] & 3) as usize);
+            let counts = &seg[pos + 1..pos + 17];
+            let total: usize = counts.iter().map(|&n| n as usize).sum();
+            let values = seg
+                .get(pos + 17..pos + 17 + total)
+                .ok_or_else(|| invalid("truncated DHT"))?;
+            let table = Huffman::new(counts, values);
+            if tc == 0 {
+                self.dc[th] = table;
+            } else {
+                self.ac[th] = table;
+            }
+            pos += 17 + total;
+        }
+        Ok(())
+    }
+
+    fn read_sof(&mut self, seg: &[u8]) -> io::Result<()> {
+        if !self.comps.is_empty() {
+            return Err(invalid("more than one frame header"));
+        }
+        if seg.len() < 6 || seg[0] != 8 {
+            return Err(invalid("only 8-bit images are supported"));
+        }
+        self.height = be16(seg, 1)?;
+        self.width = be16(seg, 3)?;
+        let n = seg[5] as usize;
+        if self.width == 0 || self.height == 0 || !(n == 1 || n == 3) {
+            return Err(invalid("unsupported frame header"));
+        }
+        for i in 0..n {
+            let c = seg
+                .get(6 + 3 * i..9 + 3 * i)
+                .ok_or_else(|| invalid("truncated SOF"))?;
+            let (h, v) = ((c[1] >> 4) as usize, (c[1] & 15) as usize);
+            if !(1..=4).contains(&h) || !(1..=4).contains(&v) {
+                return Err(invalid("bad sampling factors"));
+            }
+            self.comps.push(Component {
+                id: c[0],
+                h,
+                v,
+                tq: (c[2] & 3) as usize,
+                stride: 0,
+                samples: Vec::new(),
+                pred: 0,
+                dc: 0,
+                ac: 0,
+            });
+        }
+        self.hmax = self.comps.iter().map(|c| c.h).max().unwrap_or(1);
+        self.vmax = self.comps.iter().map(|c| c.v).max().unwrap_or(1);
+        let mcux = self.width.div_ceil(8 * self.hmax);
+        let mcuy = self.height.div_ceil(8 * self.vmax);
+        for c in &mut self.comps {
+            c.stride = mcux * c.h * 8;
+            c.samples = vec![0; c.stride * mcuy * c.v * 8];
+        }
+        Ok(())
+    }
+
+    // Decode one 8x8 block of component `ci` into its sample plane
+    fn decode_block(&mut self, bits: &mut Bits, ci: usize, bx: usize, by: usize) -> io::Result<()> {
+        let mut coef = [0f32; 64];
+        let c = &self.comps[ci];
+        let q = &self.quant[c.tq];
+        // Differences of 8-bit samples take up to 11 bits, and so do the
+        // DC coefficients they add up to
+        let t = bits.decode(&self.dc[c.dc])?;
+        if t > 11 {
+            return Err(invalid("bad DC difference"));
+        }
+        let pred = c.pred + extend(bits.bits(t), t);
+        if !(-2048..=2047).contains(&pred) {
+            return Err(invalid("DC coefficient out of range"));
+        }
+        coef[0] = (pred * q[0] as i32) as f32;
+        let mut k = 1;
+        while k < 64 {
+            let rs = bits.decode(&self.ac[c.ac])?;
+            let (r, s) = ((rs >> 4) as usize, rs & 15);
+            if s == 0 {
+                if r != 15 {
+                    break;
+                }
+                k += 16;
+                continue;
+            }
+            k += r;
+            if k > 63 || s > 10 {
+                return Err(invalid("bad AC coefficient"));
+            }
+            coef[ZIGZAG[k]] = (extend(bits.bits(s), s) * q[k] as i32) as f32;
+            k += 1;
+        }
+        // Separable IDCT: rows, then columns
+        let mut tmp = [0f32; 64];
+        for y in 0..8 {
+            for x in 0..8 {
+                tmp[y * 8 + x] = (0..8).map(|u| self.idct[x][u] * coef[y * 8 + u]).sum();
+            }
+        }
+        let c = &mut self.comps[ci];
+        c.pred = pred;
+        for y in 0..8 {
+            for x in 0..8 {
+                let v: f32 = (0..8).map(|v| self.idct[y][v] * tmp[v * 8 + x]).sum();
+                let off = (by * 8 + y) * c.stride + bx * 8 + x;
+                c.samples[off] = (v + 128.0).round().clamp(0.0, 255.0) as u8;
+            }
+        }
+        Ok(())
+    }
+
+    // Decode the scan whose entropy coded data starts at data[0].
+    // Returns the length of the entropy coded data.
+    fn read_scan(&mut self, header: &[u8], data: &[u8]) -> io::Result<usize> {
+        let ns = *header.first().ok_or_else(|| invalid("truncated SOS"))? as usize;
+        let mut scan = Vec::new();
+        for i in 0..ns {
+            let s = header
+                .get(1 + 2 * i..3 + 2 * i)
+                .ok_or_else(|| invalid("truncated SOS"))?;
+            let ci = self
+                .comps
+                .iter()
+                .position(|c| c.id == s[0])
+                .ok_or_else(|| invalid("scan of unknown component"))?;
+            self.comps[ci].dc = (s[1] >> 4) as usize & 3;
+            self.comps[ci].ac = (s[1] & 3) as usize;
+            self.comps[ci].pred = 0;
+            scan.push(ci);
+        }
+        let mut bits = Bits {
+            data,
+            pos: 0,
+            acc: 0,
+            n: 0,
+        };
+        // A single component scan is not interleaved: its MCU is one block
+        // and only the blocks covering the image are coded
+        let (units_x, units_y) = if scan.len() == 1 {
+            let c = &self.comps[scan[0]];
+            (
+                (self.width * c.h).div_ceil(self.hmax * 8),
+                (self.height * c.v).div_ceil(self.vmax * 8),
+            )
+        } else {
+            (
+                self.width.div_ceil(8 * self.hmax),
+                self.height.div_ceil(8 * self.vmax),
+            )
+        };
+        let mut count = 0;
+        for my in 0..units_y {
+            for mx in 0..units_x {
+                if self.restart_interval > 0 && count > 0 && count % self.restart_interval == 0 {
+                    bits.restart();
+                    for &ci in &scan {
+                        self.comps[ci].pred = 0;
+                    }
+                }
+                count += 1;
+                if scan.len() == 1 {
+                    self.decode_block(&mut bits, scan[0], mx, my)?;
+                    continue;
+                }
+                for &ci in &scan {
+                    let (h, v) = (self.comps[ci].h, self.comps[ci].v);
+                    for y in 0..v {
+                        for x in 0..h {
+                            self.decode_block(&mut bits, ci, mx * h + x, my * v + y)?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(bits.end())
+    }
+
+    fn to_rgb(&self) -> Vec<u8> {
+        let mut rgb = Vec::with_capacity(self.width * self.height * 3);
+        let sample = |c: &Component, x: usize, y: usize| {
+            c.samples[(y * c.v / self.vmax) * c.stride + x * c.h / self.hmax] as f32
+        };
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if self.comps.len() == 1 {
+                    let l = sample(&self.comps[0], x, y) as u8;
+                    rgb.extend_from_slice(&[l, l, l]);
+                    continue;
+                }
+                let l = sample(&self.comps[0], x, y);
+                let cb = sample(&self.comps[1], x, y) - 128.0;
+                let cr = sample(&self.comps[2], x, y) - 128.0;
+                for v in [
+                    l + 1.402 * cr,
+                    l - 0.344136 * cb - 0.714136 * cr,
+                    l + 1.772 * cb,
+                ] {
+                    rgb.push(v.round().clamp(0.0, 255.0) as u8);
+                }
+            }
+        }
+        rgb
+    }
+}
+
+pub fn decode(buf: &[u8]) -> io::Result<Image> {
+    // MJPEG frames often rely on the standard Huffman tables
+    let fixed;
+    let buf = match mjpeg::segments(buf) {
+        Some(segs) if !mjpeg::has_dht(&segs) => {
+            fixed = mjpeg::insert_default_dht(buf, &segs);
+            &fixed[..]
+        }
+        Some(_) => buf,
+        None => return Err(invalid("not a JPEG image")),
+    };
+    let mut dec = Decoder::new();
+    let mut pos = 2;
+    loop {
+        while buf.get(pos) == Some(&0xff) && buf.get(pos + 1) == Some(&0xff) {
+            pos += 1;
+        }
+        if buf.get(pos) != Some(&0xff) {
+            break;
+        }
+        let marker = *buf.get(pos + 1).ok_or_else(|| invalid("truncated"))?;
+        if marker == mjpeg::EOI {
+            break;
+        }
+        let len = be16(buf, pos + 2)?;
+        let seg = buf
+            .get(pos + 4..pos + 2 + len)
+            .ok_or_else(|| invalid("truncated segment"))?;
+        match marker {
+            0xc0 | 0xc1 => dec.read_sof(seg)?,
+            m if mjpeg::is_sof(m) => return Err(invalid("only baseline images are supported")),
+            mjpeg::DHT => dec.read_dht(seg)?,
+            0xdb => dec.read_dqt(seg)?,
+            0xdd => dec.restart_interval = be16(seg, 0)?,
+            mjpeg::SOS => {
+                if dec.comps.is_empty() {
+                    return Err(invalid("scan before frame header"));
+                }
+                let start = pos + 2 + len;
+                pos = start + dec.read_scan(seg, &buf[start..])?;
+                continue;
+            }
+            _ => {}
+        }
+        pos += 2 + len;
+    }
+    if dec.comps.is_empty() {
+        return Err(invalid("no frame header"));
+    }
+    Ok(Image {
+        width: dec.width,
+        height: dec.height,
+        rgb: dec.to_rgb(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(out: &mut Vec<u8>, marker: u8, seg: &[u8]) {
+        out.extend_from_slice(&[0xff, marker]);
+        out.extend_from_slice(&(seg.len() as u16 + 2).to_be_bytes());
+        out.extend_from_slice(seg);
+    }
+
+    // Huffman table of a single 1-bit code, 0, for `value`
+    fn dht(class: u8, value: u8) -> Vec<u8> {
+        let mut seg = vec![class << 4, 1];
+        seg.extend_from_slice(&[0; 15]);
+        seg.push(value);
+        seg
+    }
+
+    // Entropy coded data of `bits`, padded with ones and byte stuffed
+    fn entropy(bits: &str) -> Vec<u8> {
+        let bits: Vec<u8> = bits.bytes().map(|b| b - b'0').collect();
+        let mut out = Vec::new();
+        for chunk in bits.chunks(8) {
+            let byte = (0..8).fold(0u8, |v, i| (v << 1) | chunk.get(i).copied().unwrap_or(1));
+            out.push(byte);
+            if byte == 0xff {
+                out.push(0);
+            }
+        }
+        out
+    }
+
+    // A greyscale image `blocks` 8x8 blocks wide whose DC differences are
+    // all coded as size `dc_size`
+    fn image(blocks: u8, dc_size: u8, bits: &str, sofs: usize) -> Vec<u8> {
+        let mut out = vec![0xff, mjpeg::SOI];
+        let mut dqt = vec![0];
+        dqt.extend_from_slice(&[1; 64]);
+        segment(&mut out, mjpeg::DQT, &dqt);
+        for _ in 0..sofs {
+            segment(&mut out, 0xc0, &[8, 0, 8, 0, 8 * blocks, 1, 1, 0x11, 0]);
+        }
+        segment(&mut out, mjpeg::DHT, &dht(0, dc_size));
+        segment(&mut out, mjpeg::DHT, &dht(1, 0));
+        segment(&mut out, mjpeg::SOS, &[1, 1, 0x00, 0, 63, 0]);
+        out.extend(entropy(bits));
+        out.extend_from_slice(&[0xff, mjpeg::EOI]);
+        out
+    }
+
+    #[test]
+    fn decodes_flat_grey() {
+        let img = decode(&image(1, 0, "00", 1)).unwrap();
+        assert_eq!((img.width, img.height), (8, 8));
+        assert!(img.rgb.iter().all(|&v| v == 128));
+    }
+
+    #[test]
+    fn rejects_oversized_dc_difference() {
+        assert!(decode(&image(1, 200, "00", 1)).is_err());
+        assert!(decode(&image(1, 12, "0000000000000", 1)).is_err());
+    }
+
+    #[test]
+    fn rejects_dc_coefficient_out_of_range() {
+        // +2047 in each of two blocks
+        let block = "0111111111110";
+        let bits = format!("{block}{block}");
+        assert!(decode(&image(2, 11, &bits, 1)).is_err());
+        assert!(decode(&image(1, 11, block, 1)).is_ok());
+    }
+
+    #[test]
+    fn rejects_second_frame_header() {
+        assert!(decode(&image(1, 0, "00", 2)).is_err());
+    }
+}
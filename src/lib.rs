@@ -0,0 +1,10 @@
+pub mod capturer;
+pub mod error;
+pub mod parse;
+pub mod stats;
+pub mod stream;
+
+#[cfg(feature = "async")]
+pub use capturer::AsyncCapturer;
+pub use capturer::{Capturer, HeldFrame};
+pub use stats::Stats;
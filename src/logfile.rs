@@ -0,0 +1,105 @@
+// --log-file: everything the tool prints on stderr also goes to a file,
+// which frame data never does (that goes to the output, stdout at most).
+// stderr becomes a pipe read by a forked child, which outlives every exit of
+// the main process and so gets its last words too.
+
+use nix::sys::signal::{signal, SigHandler, Signal};
+use nix::unistd::{close, dup2, fork, pipe, ForkResult};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::io::FromRawFd;
+
+const STDERR: i32 = 2;
+// Rotated logs kept next to the current one: PATH.1 (newest) to PATH.3
+const KEEP: usize = 3;
+
+struct LogFile {
+    path: String,
+    file: File,
+    written: u64,
+    rotate_size: Option<u64>,
+}
+
+impl LogFile {
+    fn open(path: &str, rotate_size: Option<u64>) -> io::Result<LogFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let written = file.metadata()?.len();
+        Ok(LogFile {
+            path: path.to_string(),
+            file,
+            written,
+            rotate_size,
+        })
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(data)?;
+        self.written += data.len() as u64;
+        if self.rotate_size.is_some_and(|max| self.written >= max) {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    // PATH.2 to PATH.3 and so on, PATH to PATH.1, then a new PATH
+    fn rotate(&mut self) -> io::Result<()> {
+        for n in (1..KEEP).rev() {
+            let from = format!("{}.{n}", self.path);
+            if fs::metadata(&from).is_ok() {
+                fs::rename(&from, format!("{}.{}", self.path, n + 1))?;
+            }
+        }
+        fs::rename(&self.path, format!("{}.1", self.path))?;
+        self.file = File::create(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+// Copy the pipe to the real stderr and the log until every writer is gone
+fn tee(mut input: File, mut stderr: File, mut log: LogFile) {
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = match input.read(&mut buf) {
+            Ok(0) => return,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => return,
+        };
+        _ = stderr.write_all(&buf[..n]);
+        if let Err(e) = log.write(&buf[..n]) {
+            _ = writeln!(stderr, "--log-file {}: {e}, no longer logging", log.path);
+            // Keep draining, the main process must not block on a full pipe
+            io::copy(&mut input, &mut stderr).ok();
+            return;
+        }
+    }
+}
+
+// Before any other thread is started
+pub fn start(path: &str, rotate_size: Option<u64>) -> io::Result<()> {
+    let log = LogFile::open(path, rotate_size)?;
+    let (read_end, write_end) = pipe()?;
+    match unsafe { fork() }? {
+        ForkResult::Child => {
+            // A Ctrl-C or kill for the process group is for the capture,
+            // which still has things to say on the way out
+            for sig in [Signal::SIGINT, Signal::SIGTERM, Signal::SIGHUP] {
+                unsafe { signal(sig, SigHandler::SigIgn) }.ok();
+            }
+            _ = close(write_end);
+            // Not holding the output open for a reader of stdout
+            _ = close(1);
+            let stderr = unsafe { File::from_raw_fd(STDERR) };
+            tee(unsafe { File::from_raw_fd(read_end) }, stderr, log);
+            unsafe { libc::_exit(0) };
+        }
+        ForkResult::Parent { .. } => {
+            drop(log);
+            _ = close(read_end);
+            dup2(write_end, STDERR)?;
+            _ = close(write_end);
+            Ok(())
+        }
+    }
+}
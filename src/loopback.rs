@@ -0,0 +1,161 @@
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::FileTypeExt;
+use std::time::Duration;
+use v4l::buffer::{Metadata, Type};
+use v4l::capability::Flags;
+use v4l::timestamp::Timestamp;
+use v4l::video::capture::Parameters;
+use v4l::video::{output, Output};
+use v4l::{Device, Format};
+use v4l2capture_rs::stream::MmapStream;
+
+use crate::clock::Clock;
+use crate::container::ContainerWriter;
+use crate::device;
+use crate::pts::PtsBase;
+
+const BUFFERS: u32 = 4;
+
+// Whether `path` is a V4L2 node taking frames, e.g. of v4l2loopback
+pub fn is_output_device(path: &str) -> bool {
+    let is_char_device = std::fs::metadata(path)
+        .map(|m| m.file_type().is_char_device())
+        .unwrap_or(false);
+    is_char_device
+        && Device::with_path(path)
+            .and_then(|dev| dev.query_caps())
+            .is_ok_and(|caps| caps.capabilities.contains(Flags::VIDEO_OUTPUT))
+}
+
+// Feeds the frames to the output queue of a video device, so that other
+// programs can open its capture side as a camera
+pub struct Loopback {
+    path: String,
+    dev: Device,
+    stream: Option<MmapStream>,
+    // Buffers not queued yet since the stream was allocated
+    fresh: usize,
+    started: bool,
+    // Timestamps of the queued buffers, the driver's own unless set
+    stamp: Option<PtsBase>,
+    interval: Duration,
+    // Monotonic time of the first frame and frames since, for Sequence
+    first: Option<Duration>,
+    frames: u32,
+}
+
+impl Loopback {
+    pub fn open(path: &str, stamp: Option<PtsBase>) -> io::Result<Loopback> {
+        Ok(Loopback {
+            path: path.to_string(),
+            dev: device::open(path)?,
+            stream: None,
+            fresh: 0,
+            started: false,
+            stamp,
+            interval: Duration::ZERO,
+            first: None,
+            frames: 0,
+        })
+    }
+
+    // Wallclock is the time of day the frame is queued, Sequence counts on the
+    // monotonic clock, as V4L2 timestamps do
+    fn timestamp(&mut self, base: PtsBase, meta: &Metadata) -> Timestamp {
+        match base {
+            PtsBase::Camera => meta.timestamp,
+            PtsBase::Wallclock => Timestamp::from(Clock::Realtime.now()),
+            PtsBase::Sequence => {
+                let first = *self.first.get_or_insert_with(|| Clock::Monotonic.now());
+                let t = first + self.interval * self.frames;
+                self.frames += 1;
+                Timestamp::from(t)
+            }
+        }
+    }
+}
+
+impl ContainerWriter for Loopback {
+    // The output side takes the format of the capture
+    fn begin(&mut self, format: &Format, params: &Parameters) -> io::Result<()> {
+        if let Some(mut stream) = self.stream.take() {
+            stream.release();
+        }
+        let fmt = Output::set_format(&self.dev, format)?;
+        if fmt.fourcc != format.fourcc || fmt.width != format.width || fmt.height != format.height {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "{} does not take {}x{} {}, offers {}x{} {}",
+                    self.path,
+                    format.width,
+                    format.height,
+                    format.fourcc,
+                    fmt.width,
+                    fmt.height,
+                    fmt.fourcc
+                ),
+            ));
+        }
+        // Only informs the readers, the frames come when they come
+        if let Err(e) = self
+            .dev
+            .set_params(&output::Parameters::new(params.interval))
+        {
+            eprintln!("{}: setting the frame rate failed: {e}", self.path);
+        }
+        let interval = params.interval;
+        if interval.numerator > 0 && interval.denominator > 0 {
+            self.interval = Duration::from_secs(interval.numerator as u64) / interval.denominator;
+        }
+        let stream = MmapStream::allocate(&self.dev, Type::VideoOutput, BUFFERS)?;
+        self.fresh = stream.count();
+        self.started = false;
+        self.stream = Some(stream);
+        eprintln!("Feeding {} with:\n{}", self.path, fmt);
+        Ok(())
+    }
+
+    fn write_frame(&mut self, buf: &[u8], meta: &Metadata, _mapped: bool) -> io::Result<()> {
+        let timestamp = self.stamp.map(|base| self.timestamp(base, meta));
+        let Some(stream) = self.stream.as_mut() else {
+            return Err(io::Error::other("loopback output not started"));
+        };
+        // Each buffer is used once before the first one is taken back
+        let index = if self.fresh > 0 {
+            self.fresh -= 1;
+            stream.count() - 1 - self.fresh
+        } else {
+            stream.dequeue_wait(libc::POLLOUT)?
+        };
+        let out = stream.buffer_mut(index);
+        if buf.len() > out.len() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "frame of {} bytes does not fit the {} byte buffer of {}",
+                    buf.len(),
+                    out.len(),
+                    self.path
+                ),
+            ));
+        }
+        out[..buf.len()].copy_from_slice(buf);
+        match timestamp {
+            Some(t) => stream.queue_stamped(index, buf.len() as u32, t)?,
+            None => stream.queue_filled(index, buf.len() as u32)?,
+        }
+        if !self.started {
+            stream.stream_on()?;
+            self.started = true;
+        }
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        if let Some(stream) = self.stream.as_mut() {
+            stream.release();
+        }
+        Ok(())
+    }
+}
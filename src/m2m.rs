@@ -0,0 +1,132 @@
+use std::io::{self, ErrorKind};
+use std::sync::Arc;
+use v4l::buffer::Type;
+use v4l::control::Value;
+use v4l::device::Handle;
+use v4l::video::{Capture, Output};
+use v4l::{Device, Format, FourCC};
+use v4l2capture_rs::stream::MmapStream;
+
+use crate::device;
+
+// V4L2_CID_MIN_BUFFERS_FOR_CAPTURE and _OUTPUT, missing from the bindings:
+// user class base (0x00980900) + 39 and + 40
+const CID_MIN_BUFFERS_FOR_CAPTURE: u32 = 0x0098_0927;
+const CID_MIN_BUFFERS_FOR_OUTPUT: u32 = 0x0098_0928;
+
+// Memory-to-memory converter (hardware scaler/CSC): frames are copied into
+// its output queue and the converted frames read back from its capture
+// queue, one at a time. Only single-planar devices are supported.
+pub struct M2m {
+    output: MmapStream,
+    capture: MmapStream,
+    started: bool,
+    // Output buffers never queued yet, used before waiting for one back
+    fresh: Vec<usize>,
+    format: Format,
+}
+
+// Buffers the driver needs on a queue at least, if it says
+fn min_buffers(dev: &Device, id: u32) -> Option<u32> {
+    match dev.control(id).ok()?.value {
+        Value::Integer(min) => u32::try_from(min).ok(),
+        _ => None,
+    }
+}
+
+// Check `count` against what the driver asks for, then allocate
+fn allocate(dev: &Device, path: &str, buf_type: Type, count: u32) -> io::Result<MmapStream> {
+    let (option, cid) = match buf_type {
+        Type::VideoOutput => ("--m2m-out-buffers", CID_MIN_BUFFERS_FOR_OUTPUT),
+        _ => ("--m2m-cap-buffers", CID_MIN_BUFFERS_FOR_CAPTURE),
+    };
+    if let Some(min) = min_buffers(dev, cid).filter(|&min| count < min) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("{option} {count}: {path} needs at least {min}"),
+        ));
+    }
+    let stream = MmapStream::allocate(dev, buf_type, count)?;
+    // REQBUFS raises the count to the minimum of the driver
+    if stream.count() != count as usize {
+        eprintln!("{option} {count}: {path} allocated {}", stream.count());
+    }
+    Ok(stream)
+}
+
+impl M2m {
+    // With `buffers` on the output and the capture queue
+    pub fn open(
+        path: &str,
+        input: &Format,
+        fourcc: FourCC,
+        buffers: (u32, u32),
+    ) -> io::Result<M2m> {
+        let dev = device::open(path)?;
+        let mut out_fmt = Output::format(&dev)?;
+        out_fmt.width = input.width;
+        out_fmt.height = input.height;
+        out_fmt.fourcc = input.fourcc;
+        let out_fmt = Output::set_format(&dev, &out_fmt)?;
+        if out_fmt.fourcc != input.fourcc {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} does not accept {} input", path, input.fourcc),
+            ));
+        }
+        let mut cap_fmt = Capture::format(&dev)?;
+        cap_fmt.width = input.width;
+        cap_fmt.height = input.height;
+        cap_fmt.fourcc = fourcc;
+        let format = Capture::set_format(&dev, &cap_fmt)?;
+        let output = allocate(&dev, path, Type::VideoOutput, buffers.0)?;
+        let capture = allocate(&dev, path, Type::VideoCapture, buffers.1)?;
+        Ok(M2m {
+            fresh: (0..output.count()).rev().collect(),
+            output,
+            capture,
+            started: false,
+            format,
+        })
+    }
+
+    // The handle streaming through the converter, which its controls apply to
+    pub fn handle(&self) -> Arc<Handle> {
+        self.capture.handle()
+    }
+
+    // Format of the converted frames
+    pub fn format(&self) -> &Format {
+        &self.format
+    }
+
+    // Convert one frame. The result stays valid until the next call.
+    pub fn convert(&mut self, frame: &[u8]) -> io::Result<&[u8]> {
+        // An earlier input has been consumed once its result came back,
+        // though encoders may hold on to it as a reference
+        let index = match self.fresh.pop() {
+            Some(index) => index,
+            None => self.output.dequeue_wait(libc::POLLOUT)?,
+        };
+        let len = self.output.buffer_mut(index).len();
+        if frame.len() > len {
+            // Still free for the next frame
+            self.fresh.push(index);
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "frame of {} bytes does not fit the {len} byte converter buffer",
+                    frame.len()
+                ),
+            ));
+        }
+        self.output.buffer_mut(index)[..frame.len()].copy_from_slice(frame);
+        self.output.queue_filled(index, frame.len() as u32)?;
+        if !self.started {
+            self.output.stream_on()?;
+            self.started = true;
+        }
+        let (buf, meta) = self.capture.next_frame()?;
+        Ok(&buf[..(meta.bytesused as usize).min(buf.len())])
+    }
+}
@@ -1,71 +1,241 @@
-use std::fs::File;
-use std::io::ErrorKind;
-use std::io::Write;
-use std::os::unix::io::AsRawFd;
+use nix::sys::signal::Signal;
+use std::borrow::Cow;
+use std::io::{self, ErrorKind};
 use std::process::exit;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use v4l::buffer::Type;
-use v4l::io::mmap::Stream;
-use v4l::io::traits::CaptureStream;
+use std::time::{Duration, Instant};
+use v4l::buffer::{Flags, Metadata, Type};
+use v4l::format::FieldOrder;
+use v4l::timestamp::Timestamp;
+use v4l::video::capture::Parameters;
 use v4l::video::Capture;
-use v4l::Device;
-use v4l::FourCC;
+use v4l::{Device, Format, FourCC, Fraction};
+use v4l2capture_rs::stream::{self, MmapStream, UserPtr};
+use v4l2capture_rs::Stats;
+mod adaptive;
+mod bandwidth;
+mod base64;
+mod benchmark;
+mod circular;
+mod clock;
+mod companion;
+mod config;
+mod container;
+mod control;
+mod controls;
+mod crc;
+mod deadline;
+mod debayer;
+mod decouple;
+mod dedup;
+mod device;
+mod discover;
+mod events;
+mod fields;
+mod filler;
+mod framelog;
+mod gate;
+#[cfg(feature = "gst")]
+mod gst;
+mod gstcaps;
+mod h264;
+mod heartbeat;
+mod histogram;
+#[cfg(feature = "png")]
+mod jpeg;
+mod logfile;
+mod loopback;
+mod m2m;
+mod manifest;
+mod mjpeg;
+mod mmapfile;
+mod motion;
+mod mqueue;
+mod numa;
+mod options;
+mod output;
+mod overlay;
 mod pipe;
+mod pixfmt;
+#[cfg(feature = "png")]
+mod png;
+#[cfg(feature = "png")]
+mod pngseq;
+mod power;
+mod preview;
+mod probe;
+mod pts;
+mod reorder;
+#[cfg(feature = "request-api")]
+mod request;
+mod sched;
+mod selftest;
+mod signals;
+mod snapshot;
+mod source;
+mod splitframes;
+mod startup;
+mod subscribe;
+mod summary;
+mod syncstart;
+mod verify;
 
-fn get_four_bytes(s: &String) -> Option<&[u8; 4]> {
-    let bytes = s.as_bytes();
-    bytes.get(..4).and_then(|slice| {
-        if slice.len() == 4 {
-            let array_ref: &[u8; 4] = slice.try_into().ok()?;
-            Some(array_ref)
-        } else {
-            None
-        }
-    })
-}
+use adaptive::{AdaptiveRate, Change};
+use bandwidth::Bandwidth;
+use circular::Circular;
+use companion::Companion;
+use container::{ContainerWriter, Discard};
+use control::{Command, ControlSocket, Reply};
+use controls::KeyframeForcer;
+use deadline::{Deadline, HoldLimit};
+use debayer::{Debayer, Demosaic};
+use decouple::Decoupled;
+use dedup::Dedup;
+use events::Events;
+use fields::{Bob, Deinterlace, FieldAssembler};
+use filler::Filler;
+use framelog::FrameLog;
+use gate::Gate;
+use h264::HeaderRepeater;
+use heartbeat::Heartbeat;
+use histogram::{FlagHistogram, SizeHistogram};
+use loopback::Loopback;
+use m2m::M2m;
+use motion::Motion;
+use mqueue::MessageQueue;
+use options::{Mode, Options};
+use output::{Framing, Output};
+use overlay::Overlay;
+use pixfmt::Convert;
+use power::{Power, Standby};
+use preview::Preview;
+use pts::{Pts, PtsBase, PtsFile, ZeroBase};
+use reorder::Reorder;
+use snapshot::Snapshot;
+use source::{FileSource, OnCorrupt, Source};
+use splitframes::SplitFrames;
+use startup::Startup;
+use syncstart::SyncStart;
+
+// What --max-open-time leaves for the teardown after stopping the capture
+const RELEASE_GRACE: Duration = Duration::from_secs(1);
+
+// How often --power suspend looks at the gate while the camera is in standby
+const POWER_POLL: Duration = Duration::from_millis(500);
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
-
-    if args.len() < 3 {
-        eprintln!(
-            "Usage: {} /dev/videoX outfile [width height framerate pixelformat max_frames]",
-            args[0]
-        );
+    let mut opts = options::parse_args(&args);
+    if let Some(path) = &opts.log_file {
+        if let Err(e) = logfile::start(path, opts.log_rotate_size) {
+            eprintln!("Failed to open log file {path}: {e}");
+            exit(1);
+        }
+    }
+    if let (true, Some(lib)) = (opts.raw_ioctl, device::libv4l_preload()) {
+        eprintln!("--raw-ioctl: {lib} is preloaded and would emulate formats, unset LD_PRELOAD");
         exit(1);
     }
-    let devname = &args[1];
-    let out_file = &args[2];
-    let mut width: u32 = 640;
-    let mut height: u32 = 480;
-    let mut framerate: u32 = 30;
-    let mut pixelformat = b"MJPG";
-    let mut max_frames: usize = 0;
-    if args.len() >= 4 {
-        width = args[3].parse().expect("failed to parse width");
+    if opts.list_devices {
+        discover::list_devices(opts.json);
+        return;
+    }
+    if let Some(path) = &opts.circular_dump {
+        if let Err(e) = circular::dump(path, &mut io::stdout().lock()) {
+            if e.kind() != ErrorKind::BrokenPipe {
+                eprintln!("{path}: {e}");
+                exit(1);
+            }
+        }
+        return;
+    }
+    if opts.self_test {
+        exit(if selftest::run(&opts.devname) { 0 } else { 1 });
+    }
+    if let Some(selector) = &opts.selector {
+        opts.devname = discover::find_device(selector, opts.first_match).unwrap_or_else(|e| {
+            eprintln!("{e}");
+            exit(1);
+        });
+        eprintln!("Using {}", opts.devname);
     }
-    if args.len() >= 5 {
-        height = args[4].parse().expect("failed to parse height");
+    if opts.list_controls {
+        if let Err(e) = controls::list_controls(&opts.devname, opts.json) {
+            eprintln!("{e}");
+            exit(1);
+        }
+        return;
+    }
+    if opts.format_table {
+        if let Err(e) = discover::format_table(&opts.devname, opts.json) {
+            eprintln!("{e}");
+            exit(1);
+        }
+        return;
     }
-    if args.len() >= 6 {
-        framerate = args[5].parse().expect("failed to parse framerate");
+    if opts.env_report {
+        if let Err(e) = discover::env_report(&opts.devname, opts.json) {
+            eprintln!("{e}");
+            exit(1);
+        }
+        return;
     }
-    if args.len() >= 7 {
-        pixelformat = get_four_bytes(&args[6]).expect("failed to parse pixelformat");
+    if opts.list_all {
+        if let Err(e) = discover::list_all(&opts.devname, opts.json) {
+            eprintln!("{e}");
+            exit(1);
+        }
+        return;
     }
-    if args.len() >= 8 {
-        max_frames = args[7].parse().expect("failed to parse maxframes");
+    if opts.benchmark_formats {
+        if let Err(e) = benchmark::run(&opts.devname, opts.json) {
+            eprintln!("{e}");
+            exit(1);
+        }
+        return;
     }
-    let mut writer =
-        File::create(out_file).unwrap_or_else(|_| panic!("failed to open :{}", out_file));
-    let mut output_to_pipe = false;
-    if pipe::is_pipe(writer.as_raw_fd()) {
-        output_to_pipe = true;
-        match pipe::set_pipe_max_size(writer.as_raw_fd()) {
-            Ok(_) => {}
-            Err(e) => eprintln!("set_pipe_max_size:{e} (ignored)"),
+    // A video output node, e.g. of v4l2loopback, gets the frames queued as
+    // buffers and passes them on as a camera
+    // Only files are cut at keyframes
+    let mut to_output = false;
+    let writer: io::Result<Box<dyn ContainerWriter>> = if opts.null_output {
+        Ok(Box::new(Discard))
+    } else if let Some(png_sequence) = png_sequence(&opts) {
+        png_sequence
+    } else if let Some(dir) = &opts.split_frames {
+        SplitFrames::open(dir).map(|s| Box::new(s) as _)
+    } else if let Some(path) = &opts.circular_file {
+        let Some(size) = opts.circular_size else {
+            eprintln!("--circular-file needs --circular-size");
+            exit(1);
+        };
+        Circular::open(path, size).map(|c| Box::new(c) as _)
+    } else if let Some(name) = opts.out_file.strip_prefix("mq://") {
+        MessageQueue::open(name).map(|q| Box::new(q) as _)
+    } else if let Some(gst) = gst_pipeline(&opts) {
+        gst
+    } else if loopback::is_output_device(&opts.out_file) {
+        Loopback::open(&opts.out_file, opts.loopback_timestamp).map(|l| Box::new(l) as _)
+    } else {
+        if opts.loopback_timestamp.is_some() {
+            eprintln!(
+                "--loopback-timestamp ignored, {} is no output device",
+                opts.out_file
+            );
         }
+        to_output = true;
+        Output::open(&opts).map(|o| Box::new(o) as _)
+    };
+    let mut writer = writer.unwrap_or_else(|e| {
+        eprintln!("Failed to open output {}: {e}", opts.out_file);
+        exit(1);
+    });
+    let mut backpressure = None;
+    if opts.decouple {
+        let decoupled = Decoupled::new(writer, opts.decouple_depth);
+        backpressure = Some(decoupled.dropped());
+        writer = Box::new(decoupled);
     }
     let running = Arc::new(AtomicBool::new(true));
     let r = running.clone();
@@ -74,58 +244,2304 @@ fn main() {
         r.store(false, Ordering::SeqCst);
     })
     .expect("Error setting Ctrl-C handler");
-    let dev = Device::with_path(devname).expect("Failed to open device");
-
-    let mut fmt = dev.format().expect("Failed to read format");
-    fmt.width = width;
-    fmt.height = height;
-    fmt.fourcc = FourCC::new(pixelformat);
-    let fmt = dev.set_format(&fmt).expect("Failed to write format");
-    let mut params = dev.params().expect("Failed to read params");
-    params.interval = v4l::fraction::Fraction {
-        numerator: 1,
-        denominator: framerate,
+    signals::watch(Signal::SIGUSR2).expect("Error setting SIGUSR2 handler");
+    if opts.ring_files.is_some() || opts.start_paused {
+        signals::watch(Signal::SIGUSR1).expect("Error setting SIGUSR1 handler");
+    }
+    // Before anything is allocated for the capture
+    if let Some(node) = opts.numa_node {
+        if let Err(e) = numa::bind_thread(node) {
+            eprintln!("--numa-node: {e}");
+            exit(1);
+        }
+        if !opts.userptr && opts.source_file.is_none() {
+            eprintln!("--numa-node: the driver places its buffers itself, --userptr puts them on the node");
+        }
+    }
+    if opts.pad_to.is_some() && opts.framing != Framing::Raw {
+        eprintln!("--pad-to: the records have a header of their own, not with --framing");
+        exit(1);
+    }
+    if opts.buffer_cache.is_some() && opts.userptr {
+        eprintln!("--buffer-cache: only for the driver's mmap buffers, not --userptr ones");
+    }
+    let mut standby = wake_camera(&opts);
+    let mut startup = Startup::new(opts.measure_startup);
+    let (mut source, mut fmt, mut params) = match &opts.source_file {
+        Some(path) => open_file(&opts, path),
+        None => open_device(&opts, &mut startup),
     };
-    let params = dev.set_params(&params).expect("Failed to set params");
-
-    // The actual format chosen by the device driver may differ from what we
-    // requested! Print it out to get an idea of what is actually used now.
-    eprintln!("Format in use:\n{}", fmt);
-    eprintln!("Params in use:\n{}", params);
-
-    let mut stream =
-        Stream::with_buffers(&dev, Type::VideoCapture, 4).expect("Failed to create buffer stream");
+    // The format chosen from the list is the only one from now on, for
+    // reopen_device as for any later negotiate_format
+    if opts.pixelformats.len() > 1 && opts.source_file.is_none() {
+        opts.pixelformat = fmt.fourcc.repr;
+        opts.pixelformats = vec![fmt.fourcc.repr];
+    }
+    // From the open on, teardown included
+    let opened = Instant::now();
+    let hold_limit = opts
+        .max_open_time
+        .filter(|_| source.stream().is_some())
+        .map(|limit| HoldLimit::start(limit, running.clone()));
+    let companions_stop = Arc::new(AtomicBool::new(false));
+    let sync = opts.sync_start.then(|| {
+        let devices = 1 + opts.companions.len() + opts.meta_capture.iter().count();
+        Arc::new(SyncStart::new(devices, opts.sync_start_at))
+    });
+    let meta = opts
+        .meta_capture
+        .iter()
+        .map(|(device, output)| (device, output, true));
+    let companions: Vec<Companion> =
+        opts.companions
+            .iter()
+            .map(|(device, output)| (device, output, false))
+            .chain(meta)
+            .map(|(device, output, meta)| {
+                let stop = companions_stop.clone();
+                let sync = sync.clone();
+                let offset = opts.sequence_offset;
+                Companion::spawn(device, output, opts.buffers, stop, meta, sync, offset)
+                    .unwrap_or_else(|e| {
+                        eprintln!("Failed to start companion {device}: {e}");
+                        exit(1);
+                    })
+            })
+            .collect();
+    // Companion threads keep the normal policy
+    if let Some(priority) = opts.rt_priority {
+        if let Err(e) = sched::set_fifo(priority) {
+            eprintln!(
+                "Failed to set SCHED_FIFO priority {priority}: {}",
+                sched::error_hint(&e, priority)
+            );
+            exit(1);
+        }
+    }
+    if let Some(sync) = &sync {
+        let stream = match &mut source {
+            Source::Device(stream) => Some(stream),
+            Source::File(_) => None,
+        };
+        if let Err(e) = sync.start(&opts.devname, stream) {
+            eprintln!(
+                "VIDIOC_STREAMON failed: {e} ({})",
+                device::stream_error_hint(&e)
+            );
+            exit(1);
+        }
+        startup.mark("streamon");
+        sync.report();
+    }
+    // Only driver buffers can be gifted to a pipe, file data is reused
+    let zero_copy = matches!(source, Source::Device(_));
+    let mut events = Events::new(opts.events_fd);
+    events.emit(
+        "format_negotiated",
+        &[
+            ("width", &fmt.width),
+            ("height", &fmt.height),
+            ("fourcc", &fmt.fourcc),
+            ("sizeimage", &fmt.size),
+        ],
+    );
+    let source_name = opts.source_file.as_deref().unwrap_or(&opts.devname);
+    events.emit("stream_started", &[("source", &source_name)]);
+    let bandwidth = opts
+        .usb_bandwidth
+        .then(|| Bandwidth::new(source_name, &fmt, frame_interval(&params, opts.framerate)));
 
-    let mut frame_count: usize = 0;
+    let is_jpeg = pixfmt::is_jpeg(&fmt.fourcc.repr);
+    if opts.fix_mjpeg_dht && !is_jpeg {
+        eprintln!("--fix-mjpeg-dht ignored for {}", fmt.fourcc);
+    }
+    if opts.trim_mjpeg && !is_jpeg {
+        eprintln!("--trim-mjpeg ignored for {}", fmt.fourcc);
+    }
+    let mut state = FormatState::new(&opts, &fmt);
+    if opts.print_caps {
+        match gstcaps::caps(&state.out_fmt, &params) {
+            Some((caps, _)) => println!("{caps}"),
+            None => {
+                eprintln!("No GStreamer caps for {}", state.out_fmt.fourcc);
+                exit(1);
+            }
+        }
+        return;
+    }
+    if let Err(e) = writer.begin(&state.out_fmt, &params) {
+        eprintln!("Failed to start output: {e}");
+        exit(1);
+    }
+    watch_consumer(&opts, &mut source, writer.as_ref());
+    // The converter may be the encoder
+    let out_fourcc = state
+        .m2m
+        .as_ref()
+        .map_or(fmt.fourcc, |m2m| m2m.format().fourcc);
+    let segment_on_keyframe = opts.segment_on_keyframe
+        && match (to_output, pixfmt::is_compressed(&out_fourcc.repr)) {
+            (false, _) => {
+                eprintln!("--segment-on-keyframe ignored, not writing to a file");
+                false
+            }
+            (true, false) => {
+                eprintln!("--segment-on-keyframe ignored for raw {}", out_fourcc);
+                false
+            }
+            (true, true) => true,
+        };
+    let mut repeat_headers = opts.repeat_headers.and_then(|interval| {
+        if &out_fourcc.repr != b"H264" {
+            eprintln!("--repeat-headers ignored for {}", out_fourcc);
+            return None;
+        }
+        Some(HeaderRepeater::new(interval))
+    });
+    // Every segment decodable on its own, the first SPS/PPS before each
+    if repeat_headers.is_none() && segment_on_keyframe && &out_fourcc.repr == b"H264" {
+        repeat_headers = Some(HeaderRepeater::new(Duration::ZERO));
+    }
+    // Frames of the segment so far, those held by --reorder-depth included
+    let mut segment_frames = 0;
+    let once_per_key = opts.once_per_key && &out_fourcc.repr == b"H264";
+    if opts.once_per_key && !once_per_key {
+        eprintln!("--once-per-key ignored for {}", out_fourcc);
+    }
+    let mut keyframes = keyframe_forcer(&opts, &source, &state);
+    let mut reorder = opts.reorder_depth.map(Reorder::new);
+    let mut zero_base = opts.zero_base_pts.then(ZeroBase::default);
+    let mut dedup = opts.dedup.then(|| Dedup::new(opts.dedup_sample));
+    let mut filler = opts.fill_on_stall.map(|mode| {
+        let mut filler = Filler::new(mode, frame_interval(&params, opts.framerate));
+        filler.set_format(&state.out_fmt, state.padded_planes.is_some());
+        filler
+    });
+    // The frame log and pts file need timestamps, of the camera unless told
+    // otherwise
+    let mut pts = opts
+        .pts_base
+        .or((opts.frame_log.is_some() || opts.pts_file.is_some()).then_some(PtsBase::Camera))
+        .map(|base| Pts::new(base, opts.framerate));
+    let out_is_jpeg = pixfmt::is_jpeg(&out_fourcc.repr);
+    if opts.jpeg_quality && !out_is_jpeg {
+        eprintln!("--jpeg-quality ignored for {}", out_fourcc);
+    }
+    let jpeg_quality = opts.jpeg_quality && out_is_jpeg;
+    let mut frame_log = opts.frame_log.as_deref().map(|path| {
+        FrameLog::create(path, opts.sequence_offset as u64, jpeg_quality).unwrap_or_else(|e| {
+            eprintln!("Failed to create {path}: {e}");
+            exit(1);
+        })
+    });
+    let mut pts_file = opts.pts_file.as_deref().map(|path| {
+        PtsFile::create(path, opts.pts_unit).unwrap_or_else(|e| {
+            eprintln!("Failed to create {path}: {e}");
+            exit(1);
+        })
+    });
+    let out_is_h264 = &out_fourcc.repr == b"H264";
+    let mut preview = opts.preview.as_deref().map(|path| {
+        let mut preview = Preview::open(path, opts.preview_scale).unwrap_or_else(|e| {
+            eprintln!("Failed to open preview {path}: {e}");
+            exit(1);
+        });
+        preview.set_format(&state.out_fmt);
+        preview
+    });
+    let mut snapshot = Snapshot::new(
+        &opts.snapshot_dir,
+        &fmt.fourcc.repr,
+        is_jpeg,
+        opts.snapshot_format,
+    );
+    if opts.atomic_output {
+        snapshot.set_atomic(opts.delete_incomplete);
+    }
+    let mut snapshot_pending = false;
+    // Control socket clients waiting for the path of the snapshot
+    let mut snapshot_replies: Vec<Reply> = Vec::new();
+    let control = opts.control_socket.as_deref().map(|path| {
+        ControlSocket::bind(path).unwrap_or_else(|e| {
+            eprintln!("--control-socket {path}: {e}");
+            exit(1);
+        })
+    });
+    // A stream restart asked for by the control socket
+    let mut reconfigure = false;
+    // The frame rate for the restart when --adaptive-rate asked for it
+    let mut restart_rate = None;
+    // Writing resumed by the control socket rather than SIGUSR1
+    let mut resume = false;
+    let mut heartbeat = opts.heartbeat_file.as_deref().map(Heartbeat::new);
+    let mut next_stats = opts
+        .stats_interval
+        .map(|interval| Instant::now() + interval);
+    let mut next_format_check = match (&source, opts.recheck_format_interval) {
+        (Source::Device(_), Some(interval)) => Some(Instant::now() + interval),
+        (Source::File(_), Some(_)) => {
+            eprintln!("--recheck-format-interval ignored for --source-file");
+            None
+        }
+        (_, None) => None,
+    };
+    let mut adaptive = match (&source, &backpressure) {
+        _ if !opts.adaptive_rate => None,
+        (Source::File(_), _) => {
+            eprintln!("--adaptive-rate ignored for --source-file");
+            None
+        }
+        (_, None) => {
+            eprintln!("--adaptive-rate ignored without --decouple");
+            None
+        }
+        (Source::Device(_), Some(dropped)) => {
+            AdaptiveRate::new(&opts.devname, &fmt, &params, dropped.clone())
+                .map_err(|e| eprintln!("warning: --adaptive-rate ignored: {e}"))
+                .ok()
+        }
+    };
+    if !opts.subscribe_events.is_empty() && opts.source_file.is_some() {
+        eprintln!("--subscribe-event ignored for --source-file");
+    }
+    let mut histogram = opts.size_histogram.map(SizeHistogram::new);
+    let mut flag_histogram = opts.flag_histogram.then(FlagHistogram::default);
+    // Frames through the overlay, for --frame-counter-overlay
+    let mut overlay_frames = 0;
+    let mut stats = Stats::default();
+    let mut paused = opts.start_paused;
+    let mut gate = opts.gate_file.as_deref().map(Gate::new);
+    let mut events_pending = false;
+    // Set when the capture ends on an error rather than as asked
+    let mut failed = false;
+    // Frame errors in a row, for --max-consecutive-errors
+    let mut consecutive_errors = 0;
+    // --device-reset only tries again once a frame came through
+    let mut reset_armed = opts.device_reset;
+    let mut too_many_errors = false;
+    // Why the loop ended unless it was an error or the running flag
+    let mut stop_reason = None;
+    let started = Instant::now();
+    // The capture stops early enough for the teardown to fit in the hold limit
+    let release_at = hold_limit.as_ref().and(opts.max_open_time).map(|limit| {
+        let grace = RELEASE_GRACE.min(limit / 2);
+        (limit - grace).saturating_sub(opened.elapsed())
+    });
+    let deadline = match (opts.duration, release_at) {
+        (Some(duration), Some(release)) => Some(duration.min(release)),
+        (duration, release) => duration.or(release),
+    };
+    let deadline = deadline.map(|limit| {
+        let deadline = Deadline::start(limit, running.clone());
+        deadline.set_stream(source.stream());
+        deadline
+    });
     while running.load(Ordering::SeqCst) {
-        let (buf, meta) = match stream.next() {
+        if opts.max_frames > 0 && stats.frames_captured >= opts.max_frames {
+            stop_reason = Some("max-frames");
+            break;
+        }
+        while let Some((command, reply)) = control.as_ref().and_then(ControlSocket::take) {
+            match command {
+                Command::Start if paused && !resume => {
+                    resume = true;
+                    reply.ok("writing from the next frame");
+                }
+                Command::Start => reply.ok("already writing"),
+                Command::Stop if paused && !resume => reply.ok("already stopped"),
+                Command::Stop => {
+                    (paused, resume) = (true, false);
+                    eprintln!("Writing stopped by the control socket");
+                    events.emit("writing_stopped", &[]);
+                    reply.ok("stopped");
+                }
+                Command::Snapshot => {
+                    snapshot_pending = true;
+                    snapshot_replies.push(reply);
+                }
+                Command::SetCtrl(name, value) => reply.result(
+                    device::open(&opts.devname)
+                        .map_err(|e| e.to_string())
+                        .and_then(|dev| controls::set_controls(&dev, &[(name, value)]))
+                        .map(|_| String::new()),
+                ),
+                Command::Reconfigure if source.stream().is_none() => {
+                    reply.error("not capturing from a device")
+                }
+                Command::Reconfigure => {
+                    reconfigure = true;
+                    reply.ok("restarting the stream");
+                }
+                Command::Stats => reply.ok(&control::stats_line(&stats)),
+                Command::Quit => {
+                    running.store(false, Ordering::SeqCst);
+                    stop_reason = Some("control-socket");
+                    reply.ok("quitting");
+                }
+            }
+        }
+        if !running.load(Ordering::SeqCst) {
+            break;
+        }
+        // Keep the ring files as they are for later inspection
+        if opts.ring_files.is_some() && !paused && signals::take(Signal::SIGUSR1) {
+            eprintln!("SIGUSR1 received, stopping to preserve the ring files");
+            stop_reason = Some("signal");
+            break;
+        }
+        if events_pending {
+            events_pending = false;
+            let Source::Device(stream) = &mut source else {
+                unreachable!()
+            };
+            let taken = match device::take_events(stream.handle().fd()) {
+                Ok(taken) => taken,
+                Err(e) => {
+                    eprintln!("VIDIOC_DQEVENT failed: {e}");
+                    failed = true;
+                    break;
+                }
+            };
+            for ev in &taken {
+                if opts.subscribe_events.iter().any(|kind| kind.matches(ev)) {
+                    eprintln!("{}", subscribe::describe(ev));
+                }
+            }
+            if !taken.iter().any(device::is_resolution_change) {
+                continue;
+            }
+            eprintln!("Source resolution changed, restarting the stream");
+            reconfigure = true;
+        }
+        if reconfigure {
+            reconfigure = false;
+            let Source::Device(stream) = &mut source else {
+                unreachable!()
+            };
+            stream.release();
+            let rate = restart_rate.take();
+            let previous = fmt;
+            (source, fmt, params) = match reopen_device(&opts, Reopen::Signal(rate)) {
+                Ok(reopened) => reopened,
+                Err(e) => {
+                    eprintln!("Failed to reopen {}: {e}", opts.devname);
+                    failed = true;
+                    break;
+                }
+            };
+            if let Some(d) = &deadline {
+                d.set_stream(source.stream());
+            }
+            if let (Some(a), Some(_)) = (adaptive.as_mut(), rate) {
+                if a.restarted(&params) {
+                    rate_changed(&opts, &params, filler.as_mut(), &mut events);
+                }
+                // Only the rate changed, the output goes on as it was
+                if device::format_drift(&previous, &fmt).is_empty() {
+                    keyframes = keyframe_forcer(&opts, &source, &state);
+                    watch_consumer(&opts, &mut source, writer.as_ref());
+                    continue;
+                }
+            }
+            events.emit(
+                "source_changed",
+                &[
+                    ("width", &fmt.width),
+                    ("height", &fmt.height),
+                    ("sizeimage", &fmt.size),
+                ],
+            );
+            if let Some(asm) = &state.fields {
+                stats.unpaired_fields += asm.unpaired;
+            }
+            state = FormatState::new(&opts, &fmt);
+            keyframes = keyframe_forcer(&opts, &source, &state);
+            if let Some(a) = adaptive.as_mut().filter(|_| rate.is_none()) {
+                a.reset(&fmt, &params);
+            }
+            if let Some(f) = filler.as_mut() {
+                f.set_format(&state.out_fmt, state.padded_planes.is_some());
+            }
+            if let Some(p) = preview.as_mut() {
+                p.set_format(&state.out_fmt);
+            }
+            // A new file for the new geometry, the frames held of the old one
+            // still in the file before
+            let result = flush_reorder(
+                reorder.as_mut(),
+                writer.as_mut(),
+                &mut stats,
+                &mut frame_log,
+                &mut pts_file,
+                jpeg_quality,
+            )
+            .and_then(|_| writer.finalize())
+            .and_then(|_| writer.split())
+            .and_then(|_| writer.begin(&state.out_fmt, &params));
+            if let Err(e) = result {
+                eprintln!("error: {e:?}");
+                failed = true;
+                break;
+            }
+            watch_consumer(&opts, &mut source, writer.as_ref());
+        }
+        if let (Some(next), Some(interval)) =
+            (next_format_check.as_mut(), opts.recheck_format_interval)
+        {
+            if Instant::now() >= *next {
+                *next = Instant::now() + interval;
+                if let Some(stream) = source.stream() {
+                    match device::capture_format(stream.handle().fd()) {
+                        Ok(current) => {
+                            let drift = device::format_drift(&fmt, &current);
+                            if !drift.is_empty() {
+                                eprintln!(
+                                    "warning: the driver format changed: {}",
+                                    drift.join(", ")
+                                );
+                                events.emit(
+                                    "format_mismatch",
+                                    &[
+                                        ("width", &current.width),
+                                        ("height", &current.height),
+                                        ("fourcc", &current.fourcc),
+                                        ("sizeimage", &current.size),
+                                    ],
+                                );
+                                if opts.strict_format {
+                                    eprintln!("--strict-format: stopping");
+                                    failed = true;
+                                    break;
+                                }
+                            }
+                        }
+                        Err(e) => eprintln!("warning: VIDIOC_G_FMT failed: {e}"),
+                    }
+                }
+            }
+        }
+        match adaptive.as_mut().and_then(AdaptiveRate::check) {
+            Some(Change::Applied(new_params)) => {
+                params = new_params;
+                rate_changed(&opts, &params, filler.as_mut(), &mut events);
+                // Stalls are waited for at the new rate
+                if filler.is_some() && opts.select_timeout.is_none() {
+                    if let Source::Device(stream) = &mut source {
+                        let interval = frame_interval(&params, opts.framerate);
+                        stream.set_poll_timeout(Some(interval * 3 / 2));
+                    }
+                }
+            }
+            Some(Change::Restart(rate)) => {
+                restart_rate = Some(rate);
+                reconfigure = true;
+                continue;
+            }
+            None => {}
+        }
+        // Crude CPU limit: leave the next frames waiting in the driver for a while
+        if let Some(pause) = opts.throttle_sleep {
+            if stats.frames_captured > 0 {
+                std::thread::sleep(pause);
+            }
+        }
+        let (buf, meta) = match source.next_frame() {
             Ok(t) => t,
             Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(ref e) if stream::is_event_pending(e) => {
+                events_pending = true;
+                continue;
+            }
+            // Nothing arrived in time; go round to check the running flag
+            Err(ref e) if e.kind() == ErrorKind::TimedOut => {
+                if opts.verbose {
+                    eprintln!(
+                        "No frame within {:?}",
+                        opts.select_timeout.unwrap_or_default()
+                    );
+                }
+                // A camera in standby may send nothing, the gate is not
+                // waited for until the next frame
+                let gate_open = gate.as_ref().is_some_and(Gate::peek);
+                if let Some(s) = standby.as_mut().filter(|s| s.suspended() && gate_open) {
+                    eprintln!("Waking the camera, the gate is open");
+                    if let Err(e) = s.wake() {
+                        eprintln!("warning: --power: failed to wake the camera: {e}");
+                    }
+                }
+                let fill = filler.as_mut().filter(|_| !paused).and_then(|f| f.next());
+                if let Some((frame, meta)) = fill {
+                    if let Err(e) = writer.write_frame(frame, &meta, false) {
+                        if e.kind() != ErrorKind::BrokenPipe {
+                            eprintln!("error: {e:?}");
+                        }
+                        failed = true;
+                        break;
+                    }
+                    stats.record_written(frame.len());
+                    stats.filled += 1;
+                }
+                continue;
+            }
+            // Only from watching the pipe, writes stop at the write error
+            Err(ref e) if e.kind() == ErrorKind::BrokenPipe => {
+                eprintln!("The consumer closed the pipe");
+                stop_reason = Some("consumer-closed");
+                break;
+            }
+            // Switched off by the timer, unless it was an error before that
+            Err(_) if deadline.as_ref().is_some_and(Deadline::expired) => break,
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => {
+                eprintln!("End of source file");
+                if let Source::File(file) = &source {
+                    if file.skipped() > 0 {
+                        eprintln!("Skipped {} damaged records", file.skipped());
+                    }
+                }
+                stop_reason = Some("end-of-file");
+                break;
+            }
             Err(e) => {
+                if e.raw_os_error() == Some(libc::ENODEV) {
+                    events.emit("device_disconnected", &[("device", &opts.devname)]);
+                } else if let (Some(max), false) =
+                    (opts.max_consecutive_errors, opts.no_requeue_on_error)
+                {
+                    // Give a hiccup a chance to pass, the device is still there
+                    consecutive_errors += 1;
+                    if consecutive_errors < max {
+                        eprintln!("Frame error {consecutive_errors} of {max} in a row: {e}");
+                        continue;
+                    }
+                    too_many_errors = true;
+                }
                 println!("raw OS error: {e:?}");
+                if reset_armed
+                    && !opts.no_requeue_on_error
+                    && reset_device(&opts, &mut source, &fmt, &params, &mut events)
+                {
+                    reset_armed = false;
+                    too_many_errors = false;
+                    consecutive_errors = 0;
+                    stats.restart();
+                    if let Some(d) = &deadline {
+                        d.set_stream(source.stream());
+                    }
+                    keyframes = keyframe_forcer(&opts, &source, &state);
+                    watch_consumer(&opts, &mut source, writer.as_ref());
+                    continue;
+                }
+                if let (true, Some(max)) = (too_many_errors, opts.max_consecutive_errors) {
+                    eprintln!("{max} frame errors in a row, giving up");
+                }
+                if opts.no_requeue_on_error {
+                    source.dump_state();
+                }
+                failed = true;
                 break;
             }
         };
-        eprintln!(
-            "Buffer size: {}, seq: {}, timestamp: {}",
-            buf.len(),
-            meta.sequence,
-            meta.timestamp
+        // Numbered on from an earlier run, for everything downstream
+        let renumbered;
+        let meta = match opts.sequence_offset {
+            0 => meta,
+            offset => {
+                renumbered = Metadata {
+                    sequence: meta.sequence.wrapping_add(offset),
+                    ..*meta
+                };
+                &renumbered
+            }
+        };
+        // The stream runs to warm up, but frames are thrown away until SIGUSR1
+        if paused {
+            let by = match (resume, signals::take(Signal::SIGUSR1)) {
+                (true, _) => "Started by the control socket",
+                (false, true) => "SIGUSR1 received",
+                (false, false) => continue,
+            };
+            (paused, resume) = (false, false);
+            let t = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default();
+            eprintln!(
+                "{by}, writing from seq {} at {:.6} [s] (unix time)",
+                meta.sequence,
+                t.as_secs_f64()
+            );
+            events.emit("writing_started", &[("seq", &meta.sequence)]);
+        }
+        // Frames thrown away above do not count as the first one
+        let rebased;
+        let meta = match zero_base.as_mut() {
+            Some(z) => {
+                rebased = Metadata {
+                    timestamp: z.rebase(meta.timestamp),
+                    ..*meta
+                };
+                &rebased
+            }
+            None => meta,
+        };
+        // The driver numbers every frame, so a jump in the sequence means it
+        // had no buffer to fill
+        let lost = stats.record_frame(meta);
+        if lost > 0 {
+            events.emit(
+                "frame_dropped",
+                &[
+                    ("reason", &"no_buffer"),
+                    ("count", &lost),
+                    ("seq", &meta.sequence),
+                ],
+            );
+        }
+        if let Some(h) = flag_histogram.as_mut() {
+            h.add(meta.flags);
+        }
+        // The driver could fill the buffer but flags its data as corrupt;
+        // such data stays out of the output unless asked for
+        if meta.flags.contains(Flags::ERROR) {
+            consecutive_errors += 1;
+            if opts.mode == Some(Mode::FailFast) && !opts.keep_error_frames {
+                eprintln!(
+                    "frame seq {} flagged {}, giving up (--mode fail-fast)",
+                    meta.sequence, meta.flags
+                );
+                failed = true;
+                break;
+            }
+            if let Some(max) = opts
+                .max_consecutive_errors
+                .filter(|&max| consecutive_errors >= max)
+            {
+                if reset_armed && reset_device(&opts, &mut source, &fmt, &params, &mut events) {
+                    reset_armed = false;
+                    consecutive_errors = 0;
+                    stats.restart();
+                    if let Some(d) = &deadline {
+                        d.set_stream(source.stream());
+                    }
+                    keyframes = keyframe_forcer(&opts, &source, &state);
+                    watch_consumer(&opts, &mut source, writer.as_ref());
+                    continue;
+                }
+                eprintln!("{max} frame errors in a row, giving up");
+                too_many_errors = true;
+                failed = true;
+                break;
+            }
+            if !opts.keep_error_frames {
+                match opts.max_consecutive_errors {
+                    Some(max) => eprintln!(
+                        "Frame error {consecutive_errors} of {max} in a row: seq {} flagged {}, \
+                         skipped",
+                        meta.sequence, meta.flags
+                    ),
+                    None => eprintln!(
+                        "warning: frame seq {} flagged {}, skipped",
+                        meta.sequence, meta.flags
+                    ),
+                }
+                stats.error_frames += 1;
+                events.emit(
+                    "frame_dropped",
+                    &[("reason", &"error_flag"), ("seq", &meta.sequence)],
+                );
+                continue;
+            }
+        } else {
+            consecutive_errors = 0;
+            reset_armed = opts.device_reset;
+        }
+        if let Some(clock) = opts.clock {
+            let arrival = clock.now();
+            eprintln!(
+                "Buffer size: {}, seq: {}, timestamp: {} ({}), arrival: {:.6} [s] ({})",
+                buf.len(),
+                meta.sequence,
+                meta.timestamp,
+                clock::driver_clock(meta.flags),
+                arrival.as_secs_f64(),
+                clock.name()
+            );
+        } else {
+            eprintln!(
+                "Buffer size: {}, seq: {}, timestamp: {}",
+                buf.len(),
+                meta.sequence,
+                meta.timestamp
+            );
+        }
+        if opts.verbose {
+            eprintln!("field: {}", fields::name(meta.field));
+        }
+        if opts.probe_frame {
+            probe::report(buf, meta, &fmt, is_jpeg);
+            stop_reason = Some("probe-frame");
+            break;
+        }
+        if opts.measure_startup {
+            // Empty and corrupted buffers do not count
+            if meta.bytesused == 0 || meta.flags.contains(Flags::ERROR) {
+                continue;
+            }
+            startup.mark("first frame");
+            startup.report();
+            stop_reason = Some("measure-startup");
+            break;
+        }
+
+        if let Some(h) = histogram.as_mut() {
+            h.add(meta.bytesused as u64);
+        }
+
+        if let Some(hb) = heartbeat.as_mut() {
+            if let Err(e) = hb.beat(stats.frames_captured) {
+                eprintln!("heartbeat: {e}");
+            }
+        }
+
+        if let (Some(next), Some(interval)) = (next_stats.as_mut(), opts.stats_interval) {
+            if Instant::now() >= *next {
+                *next += interval;
+                eprintln!(
+                    "Stats: {} frames captured, {} written, {} dropped",
+                    stats.frames_captured, stats.frames_written, stats.frames_dropped
+                );
+                stats.report_intervals();
+            }
+        }
+
+        if let Some(max) = state.max_frame_size {
+            if meta.bytesused as usize > max {
+                eprintln!(
+                    "error: frame seq {} has {} bytes, more than the {} byte limit",
+                    meta.sequence, meta.bytesused, max
+                );
+                events.emit(
+                    "frame_dropped",
+                    &[
+                        ("reason", &"oversize"),
+                        ("count", &1),
+                        ("seq", &meta.sequence),
+                    ],
+                );
+                if opts.no_requeue_on_error {
+                    source.dump_state();
+                    failed = true;
+                    break;
+                }
+                if opts.abort_on_oversize {
+                    failed = true;
+                    break;
+                }
+                stats.oversized += 1;
+                continue;
+            }
+        }
+
+        if let Some(g) = gate.as_mut() {
+            let (open, changed) = g.poll();
+            if changed {
+                let (event, state) = match open {
+                    true => ("gate_opened", "open"),
+                    false => ("gate_closed", "closed"),
+                };
+                eprintln!("Gate {state} at seq {}", meta.sequence);
+                events.emit(event, &[("seq", &meta.sequence)]);
+                if let Some(s) = standby.as_mut().filter(|s| s.suspended() == open) {
+                    let result = match open {
+                        true => s.wake(),
+                        false => s.suspend(),
+                    };
+                    if let Err(e) = result {
+                        eprintln!("warning: --power: failed to set {}: {e}", s.name());
+                    }
+                }
+            }
+            if !open {
+                stats.gated += 1;
+                continue;
+            }
+        }
+
+        let mut mapped = zero_copy;
+        // Bytes holding data. Driver buffers are mapped whole and are larger
+        // than a compressed frame, the rest is left over from earlier frames.
+        let mut used = (meta.bytesused as usize).min(buf.len());
+        if opts.verbose {
+            eprintln!("bytesused: {} of {} mapped", meta.bytesused, buf.len());
+        }
+        let field = FieldOrder::try_from(meta.field);
+        let buf = match state.fields.as_mut() {
+            Some(asm) if matches!(field, Ok(FieldOrder::Top | FieldOrder::Bottom)) => {
+                let bottom = matches!(field, Ok(FieldOrder::Bottom));
+                match asm.push(&buf[..used], bottom, meta.sequence) {
+                    Some(frame) => {
+                        mapped = false;
+                        used = frame.len();
+                        frame
+                    }
+                    None => continue,
+                }
+            }
+            _ => buf,
+        };
+        let buf = &buf[..used];
+        let buf = match state.bob.as_mut() {
+            Some(bob) => {
+                let field = match field {
+                    Ok(FieldOrder::Top) => Some(false),
+                    Ok(FieldOrder::Bottom) => Some(true),
+                    _ => None,
+                };
+                match bob.apply(buf, field) {
+                    Some(frame) => {
+                        mapped = false;
+                        frame
+                    }
+                    None => {
+                        eprintln!(
+                            "warning: frame seq {} too short to deinterlace",
+                            meta.sequence
+                        );
+                        buf
+                    }
+                }
+            }
+            None => buf,
+        };
+        // What the crop sees
+        let crop_fourcc = match state.debayer {
+            Some(_) => debayer::FOURCC,
+            None => &fmt.fourcc.repr,
+        };
+        let buf = match state.debayer.as_mut() {
+            Some(debayer) => match debayer.apply(buf) {
+                Some(frame) => {
+                    mapped = false;
+                    frame
+                }
+                None => {
+                    eprintln!(
+                        "warning: frame seq {} too short to debayer, skipped",
+                        meta.sequence
+                    );
+                    continue;
+                }
+            },
+            None => buf,
+        };
+        let cropped;
+        let buf = match &state.crop {
+            Some((planes, rect)) => match pixfmt::crop(buf, crop_fourcc, planes, rect) {
+                Some(frame) => {
+                    cropped = frame;
+                    mapped = false;
+                    &cropped[..]
+                }
+                None => {
+                    eprintln!(
+                        "warning: frame seq {} too short to crop, skipped",
+                        meta.sequence
+                    );
+                    continue;
+                }
+            },
+            None => buf,
+        };
+        let swapped;
+        let buf = if let Some(unit) = state.swap {
+            match pixfmt::swap_pairs(buf, unit) {
+                Some(frame) => {
+                    swapped = frame;
+                    mapped = false;
+                    &swapped[..]
+                }
+                None => {
+                    eprintln!(
+                        "warning: frame seq {} of {} bytes is not whole samples, skipped",
+                        meta.sequence,
+                        buf.len()
+                    );
+                    continue;
+                }
+            }
+        } else {
+            buf
+        };
+        let buf = match state.overlay.as_mut() {
+            Some(overlay) => {
+                mapped = false;
+                let mut text = Vec::new();
+                if opts.timestamp_overlay {
+                    text.push(overlay::time_text(std::time::SystemTime::now()));
+                }
+                if opts.frame_counter_overlay {
+                    let seq = opts.overlay_sequence.then_some(meta.sequence);
+                    text.push(overlay::counter_text(overlay_frames, seq));
+                    overlay_frames += 1;
+                }
+                overlay.apply(buf, &text.join(" "))
+            }
+            None => buf,
+        };
+        let plane;
+        let buf = match &state.plane {
+            Some((planes, index)) => {
+                let p = &planes[*index];
+                // The planes the frame has, short frames lack the last ones
+                let range = pixfmt::plane_range(planes, *index);
+                if buf.len() < range.end {
+                    let count = pixfmt::planes_in(planes, buf.len());
+                    eprintln!(
+                        "warning: frame seq {} of {} bytes has {count} of {} planes, no plane \
+                         {index}, skipped",
+                        meta.sequence,
+                        buf.len(),
+                        planes.len()
+                    );
+                    continue;
+                }
+                let data = &buf[range];
+                if p.stride == p.row_bytes {
+                    data
+                } else {
+                    plane = pixfmt::strip_padding(data, std::slice::from_ref(p));
+                    mapped = false;
+                    &plane[..]
+                }
+            }
+            None => buf,
+        };
+
+        // SIGUSR2 saves the next frame as a still without disturbing the recording
+        if signals::take(Signal::SIGUSR2) {
+            snapshot_pending = true;
+        }
+        if snapshot_pending && meta.bytesused > 0 {
+            snapshot_pending = false;
+            match snapshot.save(buf, meta.sequence) {
+                Ok(path) => {
+                    eprintln!("Saved snapshot {}", path.display());
+                    for reply in snapshot_replies.drain(..) {
+                        reply.ok(&path.display().to_string());
+                    }
+                }
+                Err(e) => {
+                    eprintln!("snapshot: {e}");
+                    for reply in snapshot_replies.drain(..) {
+                        reply.error(&e.to_string());
+                    }
+                }
+            }
+        }
+
+        if dedup.as_mut().is_some_and(|d| d.is_duplicate(buf)) {
+            stats.duplicates += 1;
+        } else {
+            let frame_pts = pts.as_mut().map(|pts| pts.next(meta.timestamp));
+            // --pts-base stamps the frame for the writers too
+            let stamped;
+            let meta = match (frame_pts, opts.pts_base) {
+                (Some(t), Some(_)) => {
+                    eprintln!("pts: {:.6} [s]", t.as_secs_f64());
+                    stamped = Metadata {
+                        timestamp: Timestamp::from(t),
+                        ..*meta
+                    };
+                    &stamped
+                }
+                _ => meta,
+            };
+            let mut frame = Cow::Borrowed(buf);
+            // Still the driver buffer, only shorter
+            if opts.trim_mjpeg && is_jpeg {
+                match mjpeg::frame_end(buf) {
+                    Some(end) => frame = Cow::Borrowed(&buf[..end]),
+                    None => eprintln!(
+                        "warning: no EOI in frame seq {}, writing it whole",
+                        meta.sequence
+                    ),
+                }
+            }
+            if let Some(planes) = &state.padded_planes {
+                frame = Cow::Owned(pixfmt::strip_padding(&frame, planes));
+            }
+            if opts.fix_mjpeg_dht && is_jpeg {
+                if let Some(segs) = mjpeg::segments(&frame) {
+                    if !mjpeg::has_dht(&segs) {
+                        frame = Cow::Owned(mjpeg::insert_default_dht(&frame, &segs));
+                    }
+                }
+            }
+            if let Some(k) = keyframes.as_mut() {
+                if let Err(e) = k.frame() {
+                    eprintln!("warning: forcing a keyframe failed: {e}");
+                }
+            }
+            if let Some(m2m) = state.m2m.as_mut() {
+                match m2m.convert(&frame) {
+                    Ok(converted) => frame = Cow::Borrowed(converted),
+                    Err(e) => {
+                        eprintln!("converter: {e}");
+                        failed = true;
+                        break;
+                    }
+                }
+            }
+            if let Some(word) = state.byteswap {
+                match pixfmt::swap_words(&frame, word) {
+                    Some(swapped) => frame = Cow::Owned(swapped),
+                    None => {
+                        eprintln!(
+                            "warning: frame seq {} of {} bytes is not whole {}-bit words, skipped",
+                            meta.sequence,
+                            frame.len(),
+                            word * 8
+                        );
+                        continue;
+                    }
+                }
+            }
+            if let Some(repeat) = repeat_headers.as_mut() {
+                if let Some(with_headers) = repeat.process(&frame) {
+                    frame = Cow::Owned(with_headers);
+                }
+            }
+            // Parsing every H.264 frame only when someone asks
+            let keyframe = (once_per_key || segment_on_keyframe || frame_log.is_some())
+                && (meta.flags.contains(Flags::KEYFRAME)
+                    || out_is_jpeg
+                    || (out_is_h264 && h264::is_keyframe(&frame)));
+            if once_per_key {
+                if !keyframe {
+                    stats.between_keys += 1;
+                    continue;
+                }
+                stats.key_samples += 1;
+            }
+            if let Some(m) = state.motion.as_mut() {
+                let transition = m.update(buf);
+                let score = format!("{:.1}", m.score());
+                match transition {
+                    Some(true) => {
+                        events.emit(
+                            "motion_started",
+                            &[("seq", &meta.sequence), ("score", &score)],
+                        );
+                        let result = m.take_held().try_for_each(|(held, held_meta)| {
+                            writer.write_frame(&held, &held_meta, false)?;
+                            stats.record_written(held.len());
+                            Ok::<_, io::Error>(())
+                        });
+                        if let Err(e) = result {
+                            eprintln!("error: {e:?}");
+                            failed = true;
+                            break;
+                        }
+                    }
+                    Some(false) => events.emit(
+                        "motion_stopped",
+                        &[("seq", &meta.sequence), ("score", &score)],
+                    ),
+                    None => {}
+                }
+                if !m.recording() {
+                    m.hold(&frame, meta);
+                    continue;
+                }
+            }
+            if segment_on_keyframe && keyframe && segment_frames > 0 {
+                let result = flush_reorder(
+                    reorder.as_mut(),
+                    writer.as_mut(),
+                    &mut stats,
+                    &mut frame_log,
+                    &mut pts_file,
+                    jpeg_quality,
+                )
+                .and_then(|_| writer.finalize())
+                .and_then(|_| writer.split())
+                .and_then(|_| writer.begin(&state.out_fmt, &params));
+                if let Err(e) = result {
+                    eprintln!("error: {e:?}");
+                    failed = true;
+                    break;
+                }
+                segment_frames = 0;
+            }
+            segment_frames += 1;
+            let mapped = mapped && matches!(frame, Cow::Borrowed(_));
+            let tag = FrameTag {
+                pts: frame_pts,
+                keyframe,
+            };
+            // What is written now, with --reorder-depth an older frame held
+            let (frame, meta, tag) = match reorder.as_mut() {
+                Some(r) => match r.push(&frame, meta, tag) {
+                    Some((held, held_meta, held_tag)) => (Cow::Owned(held), held_meta, held_tag),
+                    None => continue,
+                },
+                None => (frame, *meta, tag),
+            };
+            let meta = &meta;
+            let result = writer
+                .write_frame(&frame, meta, mapped)
+                .map(|()| stats.record_written(frame.len()));
+            if let Err(e) = result {
+                if e.kind() != ErrorKind::BrokenPipe {
+                    eprintln!("error: {e:?}");
+                }
+                if opts.no_requeue_on_error {
+                    source.dump_state();
+                }
+                failed = true;
+                break;
+            }
+            if let Some(f) = filler.as_mut() {
+                f.keep(&frame, meta);
+            }
+            if let Some(p) = preview.as_mut() {
+                // Scaled from the buffer as captured, unless that is not
+                // the frame written
+                let data = if p.scales() && reorder.is_none() {
+                    buf
+                } else {
+                    &frame
+                };
+                if let Err(e) = p.write(data) {
+                    if e.kind() != ErrorKind::BrokenPipe {
+                        eprintln!("preview: {e}");
+                    }
+                    eprintln!("Preview closed");
+                    preview = None;
+                }
+            }
+            log_written(&mut frame_log, &mut pts_file, jpeg_quality, &frame, tag);
+        }
+    }
+    if let Some(mut d) = deadline {
+        d.finish();
+        let released = release_at.is_some_and(|at| opts.duration.is_none_or(|d| at < d));
+        if d.expired() && !failed && released {
+            eprintln!(
+                "Device held for most of the --max-open-time of {:?}, releasing it",
+                opts.max_open_time.unwrap_or_default()
+            );
+            stop_reason = stop_reason.or(Some("max-open-time"));
+        } else if d.expired() && !failed {
+            eprintln!(
+                "Duration of {:?} reached",
+                opts.duration.unwrap_or_default()
+            );
+            stop_reason = stop_reason.or(Some("duration"));
+        }
+    }
+    if let Source::Device(stream) = &mut source {
+        if let Err(e) = stream.stop() {
+            eprintln!(
+                "VIDIOC_STREAMOFF failed: {e} ({})",
+                device::stream_error_hint(&e)
+            );
+        }
+    }
+    if let Some(s) = standby.as_mut() {
+        eprintln!("Putting the camera into standby");
+        if let Err(e) = s.suspend() {
+            eprintln!("warning: --power: failed to suspend the camera: {e}");
+        }
+    }
+    // The output is finished with the device closed already
+    let mut held_too_long = false;
+    if let Some(limit) = hold_limit {
+        drop(source);
+        if limit.expired() {
+            held_too_long = true;
+            stop_reason = Some("max-open-time");
+        }
+        drop(limit);
+    }
+    companions_stop.store(true, Ordering::SeqCst);
+    if !failed {
+        let result = flush_reorder(
+            reorder.as_mut(),
+            writer.as_mut(),
+            &mut stats,
+            &mut frame_log,
+            &mut pts_file,
+            jpeg_quality,
         );
+        if let Err(e) = result {
+            eprintln!("error: {e:?}");
+            failed = true;
+        }
+    }
+    // Every way out of the loop ends here, so containers are always complete
+    if let Err(e) = writer.finalize() {
+        eprintln!("error: {e:?}");
+        failed = true;
+    }
+    if let Err(e) = writer.complete(!failed) {
+        eprintln!("Failed to complete the output: {e}");
+    }
+    // Counted as written when they were queued
+    if let Some(dropped) = &backpressure {
+        stats.backpressure = dropped.frames.load(Ordering::Relaxed);
+        stats.frames_written -= stats.backpressure;
+        stats.bytes_written -= dropped.bytes.load(Ordering::Relaxed);
+    }
+    let mut verify_failed = false;
+    if opts.verify_output && !failed {
+        let expected = verify::Expected {
+            frames: stats.frames_written,
+            bytes: stats.bytes_written,
+            framing: opts.framing,
+            jpeg: out_is_jpeg,
+            pad_to: opts.pad_to,
+        };
+        match writer
+            .written_path()
+            .map(|path| verify::check(path, &expected))
+        {
+            Some(Ok(summary)) => eprintln!("Output verified: {summary}"),
+            Some(Err(e)) => {
+                eprintln!("Output verification failed: {e}");
+                verify_failed = true;
+            }
+            None => eprintln!("--verify-output: the output is not a single file, not checked"),
+        }
+    }
+    if let Some(asm) = &state.fields {
+        stats.unpaired_fields += asm.unpaired;
+    }
+    if let Some(r) = &reorder {
+        stats.late += r.late;
+    }
+    if let Some(log) = frame_log.as_mut() {
+        if let Err(e) = log.finish() {
+            eprintln!("frame log: {e}");
+        }
+    }
+    if let Some(file) = pts_file.as_mut() {
+        if let Err(e) = file.finish() {
+            eprintln!("pts file: {e}");
+        }
+    }
+    if !companions.is_empty() {
+        eprintln!("{}:", source_name);
+    }
+    stats.report();
+    if let Some(b) = &bandwidth {
+        b.report(&stats);
+    }
+    for companion in companions {
+        companion.finish();
+    }
+    events.emit(
+        "shutdown",
+        &[
+            ("frames_captured", &stats.frames_captured),
+            ("frames_written", &stats.frames_written),
+            ("bytes_written", &stats.bytes_written),
+        ],
+    );
+    if let Some(h) = &histogram {
+        h.report();
+    }
+    if let Some(h) = &flag_histogram {
+        h.report();
+    }
+    if let Some(path) = &opts.summary_json {
+        let reason = if failed {
+            "error"
+        } else {
+            stop_reason.unwrap_or("signal")
+        };
+        let duration = started.elapsed();
+        if let Err(e) = summary::write(path, &fmt, &params, &stats, duration, reason) {
+            eprintln!("Failed to write {path}: {e}");
+        }
+    }
+    let fps_failed = opts
+        .assert_fps
+        .is_some_and(|target| !fps_within(&stats, target, opts.assert_tolerance));
+    if too_many_errors || verify_failed || fps_failed || held_too_long {
+        exit(1);
+    }
+}
+
+// --assert-fps: the frame rate of the driver timestamps against the target
+fn fps_within(stats: &Stats, target: f64, tolerance: f64) -> bool {
+    let Some(mean) = stats.intervals.mean().filter(|mean| !mean.is_zero()) else {
+        eprintln!("--assert-fps: no frame intervals measured, failing");
+        return false;
+    };
+    let fps = 1.0 / mean.as_secs_f64();
+    let deviation = 100.0 * (fps - target) / target;
+    let within = deviation.abs() <= tolerance;
+    eprintln!(
+        "--assert-fps: measured {fps:.3} fps, {deviation:+.2}% off {target} fps, {} \
+         the tolerance of {tolerance}%",
+        if within { "within" } else { "outside" }
+    );
+    within
+}
 
-        if let Err(e) = if output_to_pipe {
-            pipe::vmsplice_single_buffer(buf, writer.as_raw_fd())
+// Frame processing that depends on the negotiated format, set up again when
+// the source changes
+struct FormatState {
+    max_frame_size: Option<usize>,
+    padded_planes: Option<Vec<pixfmt::Plane>>,
+    fields: Option<FieldAssembler>,
+    m2m: Option<M2m>,
+    motion: Option<Motion>,
+    bob: Option<Bob>,
+    debayer: Option<Debayer>,
+    // Layout of the full frame and the part to keep
+    crop: Option<(Vec<pixfmt::Plane>, pixfmt::Rect)>,
+    // Bytes of the units to swap the byte pairs of: macropixels for --convert
+    // yuyv-uyvy, samples for --y16-endianness big
+    swap: Option<usize>,
+    overlay: Option<Overlay>,
+    // Bytes of the words to reverse, --byteswap
+    byteswap: Option<usize>,
+    // Layout of the frame and the plane to keep, --use-plane
+    plane: Option<(Vec<pixfmt::Plane>, usize)>,
+    // Format of the frames after cropping and conversion
+    out_fmt: Format,
+}
+
+impl FormatState {
+    fn new(opts: &Options, fmt: &Format) -> FormatState {
+        // A frame should never exceed the negotiated sizeimage; allow some slack
+        // for drivers that round it
+        let max_frame_size = opts.max_frame_size.or(match fmt.size as usize {
+            0 => None,
+            size => Some(size + size / 8),
+        });
+        let debayer = if opts.debayer == Demosaic::None {
+            None
         } else {
-            writer.write_all(buf)
-        } {
-            if e.kind() != ErrorKind::BrokenPipe {
-                eprintln!("error: {e:?}");
+            let debayer = Debayer::new(fmt);
+            if debayer.is_none() {
+                eprintln!("--debayer ignored for {}, not 8-bit Bayer", fmt.fourcc);
+            }
+            debayer
+        };
+        // The frame the processing below starts from
+        let rgb = debayer.as_ref().map(|d| d.format(fmt));
+        let src = rgb.as_ref().unwrap_or(fmt);
+        let crop = opts.software_crop.and_then(|rect| software_crop(src, rect));
+        let mut out = match &crop {
+            Some((_, rect)) => {
+                let fourcc = &src.fourcc.repr;
+                Format {
+                    width: rect.width,
+                    height: rect.height,
+                    stride: pixfmt::packed_stride(fourcc, rect.width).unwrap_or_default(),
+                    size: pixfmt::frame_size(fourcc, rect.width, rect.height).unwrap_or(0) as u32,
+                    ..*src
+                }
+            }
+            None => *src,
+        };
+        let swapped = match opts.convert {
+            Some(Convert::YuyvUyvy) => {
+                let swapped = pixfmt::swapped_422(&out.fourcc.repr);
+                if swapped.is_none() {
+                    eprintln!("--convert yuyv-uyvy ignored for {}", out.fourcc);
+                }
+                swapped
+            }
+            None => None,
+        };
+        if let Some(fourcc) = swapped {
+            out.fourcc = FourCC::new(&fourcc);
+        }
+        let y16_swap = opts.y16_big_endian && {
+            let y16 = &out.fourcc.repr == b"Y16 ";
+            if !y16 {
+                eprintln!("--y16-endianness ignored for {}", out.fourcc);
+            }
+            y16
+        };
+        let overlay_option = match (opts.timestamp_overlay, opts.frame_counter_overlay) {
+            (true, _) => "--timestamp-overlay",
+            (false, true) => "--frame-counter-overlay",
+            (false, false) => "",
+        };
+        let overlay = if overlay_option.is_empty() {
+            None
+        } else if pixfmt::is_compressed(&out.fourcc.repr) {
+            eprintln!("{overlay_option} ignored for compressed {}", out.fourcc);
+            None
+        } else {
+            let overlay = Overlay::new(&out, opts.overlay_corner);
+            if overlay.is_none() {
+                eprintln!("{overlay_option} ignored for {}", out.fourcc);
+            }
+            overlay
+        };
+        let plane = opts.use_plane.and_then(|index| {
+            let fourcc = &out.fourcc.repr;
+            if pixfmt::is_compressed(fourcc) {
+                eprintln!("--use-plane ignored for compressed {}", out.fourcc);
+                return None;
+            }
+            let stride = match out.stride {
+                0 => pixfmt::packed_stride(fourcc, out.width).unwrap_or_default(),
+                stride => stride,
+            };
+            let Some(planes) = pixfmt::planes(fourcc, out.width, out.height, stride) else {
+                eprintln!("--use-plane: unknown layout of {}", out.fourcc);
+                return None;
+            };
+            if index >= planes.len() {
+                eprintln!(
+                    "--use-plane {index}: {} has {} planes",
+                    out.fourcc,
+                    planes.len()
+                );
+                exit(1);
             }
+            // Written as a format of its own, which interleaved chroma has not
+            let Some(plane_fourcc) = pixfmt::plane_fourcc(fourcc, index) else {
+                eprintln!(
+                    "--use-plane {index}: no pixel format for plane {index} of {}",
+                    out.fourcc
+                );
+                exit(1);
+            };
+            Some((planes, index, plane_fourcc))
+        });
+        let plane = plane.map(|(planes, index, fourcc)| {
+            let p = &planes[index];
+            out.fourcc = FourCC::new(&fourcc);
+            out.width = p.row_bytes as u32;
+            out.height = p.rows as u32;
+            out.stride = p.row_bytes as u32;
+            out.size = (p.row_bytes * p.rows) as u32;
+            (planes, index)
+        });
+        // Only worth copying when the driver pads the rows
+        let padded_planes = if !opts.strip_padding {
+            None
+        } else if pixfmt::is_compressed(&out.fourcc.repr) {
+            eprintln!("--strip-padding ignored for compressed {}", out.fourcc);
+            None
+        } else {
+            let planes = pixfmt::planes(&out.fourcc.repr, out.width, out.height, out.stride);
+            if planes.is_none() {
+                eprintln!("--strip-padding: unknown layout of {}", out.fourcc);
+            }
+            planes.filter(|planes| planes.iter().any(|p| p.stride > p.row_bytes))
+        };
+        // Each buffer holds half the rows of the negotiated frame height
+        let fields = if !opts.assemble_fields && opts.deinterlace != Deinterlace::Weave {
+            None
+        } else if pixfmt::is_compressed(&fmt.fourcc.repr) {
+            eprintln!("--assemble-fields ignored for compressed {}", fmt.fourcc);
+            None
+        } else {
+            if !matches!(fmt.field_order, FieldOrder::Alternate) {
+                eprintln!(
+                    "warning: device field order is {}, not alternate",
+                    fmt.field_order
+                );
+            }
+            match pixfmt::planes(&fmt.fourcc.repr, fmt.width, fmt.height / 2, fmt.stride) {
+                Some(planes) => Some(FieldAssembler::new(planes)),
+                None => {
+                    eprintln!("--assemble-fields: unknown layout of {}", fmt.fourcc);
+                    None
+                }
+            }
+        };
+        let m2m = opts.m2m_device.as_ref().map(|path| {
+            let fourcc = FourCC::new(&opts.m2m_out_fourcc.unwrap_or(fmt.fourcc.repr));
+            let buffers = (opts.m2m_out_buffers, opts.m2m_cap_buffers);
+            let m2m = M2m::open(path, &out, fourcc, buffers).unwrap_or_else(|e| {
+                eprintln!("Failed to set up converter {path}: {e}");
+                exit(1);
+            });
+            eprintln!("Converter output format:\n{}", m2m.format());
+            m2m
+        });
+        // What is written, after an encoder
+        let byteswap = opts.byteswap.filter(|_| {
+            let fourcc = m2m.as_ref().map_or(out.fourcc, |m2m| m2m.format().fourcc);
+            let raw = !pixfmt::is_compressed(&fourcc.repr);
+            if !raw {
+                eprintln!("--byteswap ignored for compressed {fourcc}");
+            }
+            raw
+        });
+        let motion = opts.motion_threshold.and_then(|threshold| {
+            let motion = Motion::new(threshold, &out, opts.motion_pre, opts.motion_post);
+            if motion.is_none() {
+                eprintln!("--motion-threshold ignored for {}", fmt.fourcc);
+            }
+            motion
+        });
+        let bob = if opts.deinterlace != Deinterlace::Bob {
+            None
+        } else if pixfmt::is_compressed(&fmt.fourcc.repr) {
+            eprintln!("--deinterlace ignored for compressed {}", fmt.fourcc);
+            None
+        } else {
+            // Replayed files have no bytesperline
+            let stride = match fmt.stride {
+                0 => pixfmt::packed_stride(&fmt.fourcc.repr, fmt.width).unwrap_or_default(),
+                stride => stride,
+            };
+            let planes = |height| pixfmt::planes(&fmt.fourcc.repr, fmt.width, height, stride);
+            match (planes(fmt.height), planes(fmt.height / 2)) {
+                (Some(frame), Some(field)) => Some(Bob::new(frame, field)),
+                _ => {
+                    eprintln!("--deinterlace: unknown layout of {}", fmt.fourcc);
+                    None
+                }
+            }
+        };
+        FormatState {
+            max_frame_size,
+            padded_planes,
+            fields,
+            m2m,
+            motion,
+            bob,
+            debayer,
+            crop,
+            swap: match (swapped, y16_swap) {
+                (Some(_), _) => Some(4),
+                (None, true) => Some(2),
+                (None, false) => None,
+            },
+            overlay,
+            byteswap,
+            plane,
+            out_fmt: out,
+        }
+    }
+}
+
+// --exit-on-consumer-close: wake up from waiting for a frame when the reader
+// of the output pipe goes away
+fn watch_consumer(opts: &Options, source: &mut Source, writer: &dyn ContainerWriter) {
+    if !opts.exit_on_consumer_close {
+        return;
+    }
+    let Source::Device(stream) = source else {
+        return;
+    };
+    let fd = writer.pipe_fd();
+    if fd.is_none() {
+        eprintln!("--exit-on-consumer-close ignored, the output is not a pipe");
+    }
+    stream.set_hangup_fd(fd);
+}
+
+// --power: the standby control, kept with suspend for putting the camera
+// into standby when it is not needed
+fn wake_camera(opts: &Options) -> Option<Standby> {
+    let power = opts.power?;
+    if opts.source_file.is_some() {
+        eprintln!("--power ignored for --source-file");
+        return None;
+    }
+    let mut standby = match Standby::find(&opts.devname) {
+        Ok(Some(standby)) => standby,
+        Ok(None) => {
+            eprintln!(
+                "warning: --power ignored, {} has no standby control",
+                opts.devname
+            );
+            return None;
+        }
+        Err(e) => {
+            eprintln!("warning: --power ignored: {e}");
+            return None;
+        }
+    };
+    let state = if standby.suspended() {
+        "in standby"
+    } else {
+        "on"
+    };
+    eprintln!("Camera {state}, power by {:?}", standby.name());
+    if standby.suspended() {
+        if let Err(e) = standby.wake() {
+            eprintln!("warning: --power: failed to wake the camera: {e}");
+        }
+    }
+    (power == Power::Suspend).then_some(standby)
+}
+
+// --png-sequence, with the png feature
+#[cfg(feature = "png")]
+fn png_sequence(opts: &Options) -> Option<io::Result<Box<dyn ContainerWriter>>> {
+    let dir = opts.png_sequence.as_deref()?;
+    Some(pngseq::PngSequence::open(dir).map(|p| Box::new(p) as _))
+}
+
+#[cfg(not(feature = "png"))]
+fn png_sequence(_opts: &Options) -> Option<io::Result<Box<dyn ContainerWriter>>> {
+    None
+}
+
+// --gst-pipeline, with the gst feature
+#[cfg(feature = "gst")]
+fn gst_pipeline(opts: &Options) -> Option<io::Result<Box<dyn ContainerWriter>>> {
+    let description = opts.gst_pipeline.as_deref()?;
+    Some(Ok(Box::new(gst::Gst::new(description))))
+}
+
+#[cfg(not(feature = "gst"))]
+fn gst_pipeline(_opts: &Options) -> Option<io::Result<Box<dyn ContainerWriter>>> {
+    None
+}
+
+// The capture device, close-on-exec unless --no-cloexec
+fn open_capture(opts: &Options) -> io::Result<Device> {
+    let dev = device::open(&opts.devname)?;
+    if opts.no_cloexec {
+        device::set_cloexec(dev.handle().fd(), false)?;
+    }
+    Ok(dev)
+}
+
+// --keyframe-interval on the converter if there is one, else on the camera
+fn keyframe_forcer(opts: &Options, source: &Source, state: &FormatState) -> Option<KeyframeForcer> {
+    let interval = opts.keyframe_interval?;
+    let handle = match (&state.m2m, source) {
+        (Some(m2m), _) => m2m.handle(),
+        (None, Source::Device(stream)) => stream.handle(),
+        (None, Source::File(_)) => {
+            eprintln!("--keyframe-interval ignored for --source-file");
+            return None;
+        }
+    };
+    KeyframeForcer::new(handle, interval)
+        .map_err(|e| eprintln!("warning: --keyframe-interval ignored: {e}"))
+        .ok()
+}
+
+// Check a --software-crop rectangle against the frame and subsampling
+fn software_crop(fmt: &Format, rect: pixfmt::Rect) -> Option<(Vec<pixfmt::Plane>, pixfmt::Rect)> {
+    let fourcc = &fmt.fourcc.repr;
+    if pixfmt::is_compressed(fourcc) {
+        eprintln!("--software-crop ignored for compressed {}", fmt.fourcc);
+        return None;
+    }
+    let spec = format!("{},{},{},{}", rect.x, rect.y, rect.width, rect.height);
+    if rect.x.checked_add(rect.width).is_none_or(|r| r > fmt.width)
+        || rect
+            .y
+            .checked_add(rect.height)
+            .is_none_or(|b| b > fmt.height)
+    {
+        eprintln!(
+            "error: --software-crop {spec} is outside the {}x{} frame",
+            fmt.width, fmt.height
+        );
+        exit(1);
+    }
+    if pixfmt::crop_aligned(fourcc, &rect) == Some(false) {
+        eprintln!(
+            "error: --software-crop {spec} splits the chroma samples of {}; use even values",
+            fmt.fourcc
+        );
+        exit(1);
+    }
+    // Replayed files have no bytesperline
+    let stride = match fmt.stride {
+        0 => pixfmt::packed_stride(fourcc, fmt.width).unwrap_or_default(),
+        stride => stride,
+    };
+    let planes = pixfmt::planes(fourcc, fmt.width, fmt.height, stride)
+        .filter(|_| pixfmt::crop_aligned(fourcc, &rect).is_some());
+    if planes.is_none() {
+        eprintln!("--software-crop: unknown layout of {}", fmt.fourcc);
+    }
+    Some((planes?, rect))
+}
+
+// Configure the capture device and map its buffers
+fn open_device(opts: &Options, startup: &mut Startup) -> (Source, Format, Parameters) {
+    let dev = open_capture(opts).expect("Failed to open device");
+    startup.mark("open");
+    if !opts.require_caps.is_empty() {
+        if let Err(e) = device::require_caps(&dev, opts.require_caps) {
+            eprintln!("{}: {e}", opts.devname);
+            exit(1);
+        }
+    }
+    if let Some(prio) = opts.priority {
+        if let Err(e) = device::set_priority(&dev, prio) {
+            if e.raw_os_error() == Some(libc::EBUSY) {
+                eprintln!("Failed to set priority: another client holds record priority");
+            } else {
+                eprintln!("Failed to set priority: {e}");
+            }
+            exit(1);
+        }
+    }
+    if let Some(path) = &opts.load_edid {
+        let edid = std::fs::read(path).unwrap_or_else(|e| {
+            eprintln!("Failed to read {path}: {e}");
+            exit(1);
+        });
+        if let Err(e) = device::set_edid(&dev, &edid) {
+            eprintln!("Failed to load EDID: {e}");
+            exit(1);
+        }
+        eprintln!("Loaded {} byte EDID from {path}", edid.len());
+    }
+    if let Some(path) = &opts.dump_edid {
+        match device::get_edid(&dev).and_then(|edid| std::fs::write(path, &edid).map(|_| edid)) {
+            Ok(edid) => eprintln!("Saved {} byte EDID to {path}", edid.len()),
+            Err(e) => eprintln!("Failed to dump EDID: {e}"),
+        }
+    }
+
+    let (mut width, mut height) = (opts.width, opts.height);
+    if opts.query_dv_timings || opts.set_dv_timings {
+        match device::query_dv_timings(&dev) {
+            Ok(timings) => {
+                eprintln!("Detected DV timings: {}", timings);
+                if opts.set_dv_timings {
+                    if let Err(e) = device::set_dv_timings(&dev, &timings) {
+                        eprintln!("Failed to set DV timings: {}", device::dv_timings_error(&e));
+                        exit(1);
+                    }
+                    // The format has to follow the timings of the source
+                    (width, height) = (timings.width(), timings.height());
+                }
+            }
+            Err(e) => {
+                eprintln!(
+                    "Failed to query DV timings: {}",
+                    device::dv_timings_error(&e)
+                );
+                if opts.set_dv_timings {
+                    exit(1);
+                }
+            }
+        }
+    }
+
+    let (fmt, params) = if opts.no_set_format {
+        // Passively stream whatever another client has configured
+        let fmt = dev.format().expect("Failed to read format");
+        let params = dev.params().expect("Failed to read params");
+        (fmt, params)
+    } else {
+        negotiate_format(&dev, opts, width, height)
+    };
+    startup.mark("format set");
+    if !opts.set_ctrls.is_empty() {
+        if let Err(e) = controls::set_controls(&dev, &opts.set_ctrls) {
+            eprintln!("{}: {e}", opts.devname);
+            exit(1);
+        }
+    }
+
+    // The actual format chosen by the device driver may differ from what we
+    // requested! Print it out to get an idea of what is actually used now.
+    eprintln!("Format in use:\n{}", fmt);
+    eprintln!("Params in use:\n{}", params);
+
+    if let Some(clock) = opts.clock {
+        match dev.query_caps() {
+            Ok(caps) => clock::request_driver_clock(&caps.driver, clock),
+            Err(e) => eprintln!("query_caps: {e}"),
+        }
+    }
+
+    // Under --sync-start streaming starts with the other devices
+    let stream = start_stream(&dev, opts, !opts.sync_start);
+    if !opts.sync_start {
+        startup.mark("streamon");
+    }
+    (Source::Device(stream), fmt, params)
+}
+
+// Map the buffers and, if `start`, start streaming
+fn start_stream(dev: &Device, opts: &Options, start: bool) -> MmapStream {
+    let user_ptr = opts.userptr.then(|| UserPtr {
+        // The driver tells how large a frame can get
+        size: dev
+            .format()
+            .map(|fmt| fmt.size as usize)
+            .unwrap_or_else(|e| {
+                eprintln!("Failed to read format: {e}");
+                exit(1);
+            }),
+        align: opts.userptr_align.unwrap_or_else(pipe::page_size),
+        hugepages: opts.userptr_hugepages,
+        numa_node: opts.numa_node,
+    });
+    let mut stream = MmapStream::allocate_cached(
+        dev,
+        Type::VideoCapture,
+        opts.buffers,
+        user_ptr,
+        opts.buffer_cache,
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("Failed to create buffer stream: {e}");
+        exit(1);
+    });
+    if opts.dump_buffers {
+        // vmsplice can only gift whole pages
+        let page = pipe::page_size();
+        for index in 0..stream.count() {
+            let buf = stream.buffer(index);
+            eprintln!(
+                "Buffer {}: mapped {:p}+{} ({}, {})",
+                index,
+                buf.as_ptr(),
+                buf.len(),
+                if pipe::is_page_aligned(buf) {
+                    "page aligned"
+                } else {
+                    "NOT page aligned"
+                },
+                if buf.len().is_multiple_of(page) {
+                    "whole pages"
+                } else {
+                    "partial page"
+                },
+            );
+        }
+    }
+    // Filling in for a stalled camera needs a wakeup once a frame is overdue,
+    // which is taken as half an interval late
+    let stall_timeout = opts.fill_on_stall.map(|_| {
+        let params = dev.params().ok();
+        let interval = params.map_or(frame_interval_of(opts.framerate), |p| {
+            frame_interval(&p, opts.framerate)
+        });
+        interval * 3 / 2
+    });
+    // Waking a camera in standby when the gate opens needs a look at it
+    // while no frames come
+    let power_timeout =
+        (opts.power == Some(Power::Suspend) && opts.gate_file.is_some()).then_some(POWER_POLL);
+    stream.set_poll_timeout(opts.select_timeout.or(stall_timeout).or(power_timeout));
+    if let Err(e) = stream.set_nonblocking(opts.nonblocking) {
+        eprintln!("Failed to set the device blocking mode: {e}");
+        exit(1);
+    }
+    if let Some(depth) = opts.queue_depth {
+        stream.set_queue_depth(depth);
+        eprintln!(
+            "Queue depth {} of {} buffers",
+            stream.queue_depth(),
+            stream.count()
+        );
+    }
+    #[cfg(feature = "request-api")]
+    if !opts.request_schedule.is_empty() {
+        let media = opts
+            .media_device
+            .clone()
+            .or_else(|| request::media_device(&opts.devname))
+            .unwrap_or_else(|| {
+                eprintln!(
+                    "--request-api: no media device found for {}, use --media-device",
+                    opts.devname
+                );
+                exit(1);
+            });
+        match request::Requests::new(dev, &media, &opts.request_schedule) {
+            Ok(requests) => stream.set_queue_hook(Box::new(requests)),
+            Err(e) => {
+                eprintln!("--request-api: {e}");
+                exit(1);
+            }
+        }
+    }
+    // Resolution changes of the input show up as events
+    match device::subscribe_source_change(stream.handle().fd()) {
+        Ok(()) => stream.watch_events(),
+        Err(e) if opts.verbose => eprintln!("No source change events: {e}"),
+        Err(_) => {}
+    }
+    if subscribe::subscribe(dev, &opts.subscribe_events) {
+        stream.watch_events();
+    }
+    if start {
+        if let Err(e) = stream.start() {
+            eprintln!(
+                "VIDIOC_STREAMON failed: {e} ({})",
+                device::stream_error_hint(&e)
+            );
+            exit(1);
+        }
+    }
+    stream
+}
+
+// Kept with a frame held by --reorder-depth, for the logs once it is written
+#[derive(Clone, Copy)]
+struct FrameTag {
+    pts: Option<Duration>,
+    keyframe: bool,
+}
+
+// The --frame-log and --pts-file lines of a frame written
+fn log_written(
+    frame_log: &mut Option<FrameLog>,
+    pts_file: &mut Option<PtsFile>,
+    jpeg_quality: bool,
+    frame: &[u8],
+    tag: FrameTag,
+) {
+    let quality = jpeg_quality
+        .then(|| mjpeg::segments(frame).and_then(|segs| mjpeg::quality(frame, &segs)))
+        .flatten();
+    if jpeg_quality && frame_log.is_none() {
+        match quality {
+            Some(quality) => eprintln!("JPEG quality: {quality}"),
+            None => eprintln!("JPEG quality: unknown, no luma quantization table"),
+        }
+    }
+    if let Some(log) = frame_log.as_mut() {
+        let pts = tag.pts.unwrap_or_default();
+        if let Err(e) = log.log(pts, frame.len(), tag.keyframe, quality) {
+            eprintln!("frame log: {e}");
+            *frame_log = None;
+        }
+    }
+    if let Some(file) = pts_file.as_mut() {
+        if let Err(e) = file.log(tag.pts.unwrap_or_default()) {
+            eprintln!("pts file: {e}");
+            *pts_file = None;
+        }
+    }
+}
+
+// Write out the frames --reorder-depth holds, before the file they belong in
+// is finished
+fn flush_reorder(
+    reorder: Option<&mut Reorder<FrameTag>>,
+    writer: &mut dyn ContainerWriter,
+    stats: &mut Stats,
+    frame_log: &mut Option<FrameLog>,
+    pts_file: &mut Option<PtsFile>,
+    jpeg_quality: bool,
+) -> io::Result<()> {
+    let Some(r) = reorder else {
+        return Ok(());
+    };
+    r.drain().try_for_each(|(held, held_meta, tag)| {
+        writer.write_frame(&held, &held_meta, false)?;
+        stats.record_written(held.len());
+        log_written(frame_log, pts_file, jpeg_quality, &held, tag);
+        Ok(())
+    })
+}
+
+// The frame rate --adaptive-rate changed to
+fn rate_changed(
+    opts: &Options,
+    params: &Parameters,
+    filler: Option<&mut Filler>,
+    events: &mut Events,
+) {
+    let interval = frame_interval(params, opts.framerate);
+    events.emit("rate_changed", &[("fps", &(1.0 / interval.as_secs_f64()))]);
+    if let Some(f) = filler {
+        f.set_interval(interval);
+    }
+}
+
+// How reopen_device sets the device up again
+enum Reopen<'a> {
+    // After its input signal changed: the size comes from the new DV timings
+    // or, failing that, from the driver itself. With the frame rate to ask
+    // for, when that is what changed.
+    Signal(Option<Fraction>),
+    // With the format and frame rate it had
+    As(&'a Format, &'a Parameters),
+}
+
+// Open the device again after its stream was released and start streaming
+fn reopen_device(opts: &Options, how: Reopen) -> io::Result<(Source, Format, Parameters)> {
+    let dev = open_capture(opts)?;
+    if let Some(prio) = opts.priority {
+        if let Err(e) = device::set_priority(&dev, prio) {
+            eprintln!("Failed to set priority: {e}");
+        }
+    }
+    let mut fmt = dev.format()?;
+    match how {
+        Reopen::Signal(new_rate) => {
+            if !opts.no_set_format {
+                match device::query_dv_timings(&dev) {
+                    Ok(timings) => {
+                        eprintln!("Detected DV timings: {}", timings);
+                        if let Err(e) = device::set_dv_timings(&dev, &timings) {
+                            eprintln!("Failed to set DV timings: {}", device::dv_timings_error(&e));
+                        }
+                        (fmt.width, fmt.height) = (timings.width(), timings.height());
+                    }
+                    Err(e) if e.raw_os_error() == Some(libc::ENOTTY) => {}
+                    Err(e) => eprintln!(
+                        "Failed to query DV timings: {}",
+                        device::dv_timings_error(&e)
+                    ),
+                }
+                fmt.fourcc = FourCC::new(&opts.pixelformat);
+                fmt = dev.set_format(&fmt)?;
+            }
+            if let Some(new_rate) = new_rate {
+                if let Err(e) = dev.set_params(&Parameters::new(new_rate)) {
+                    eprintln!("Failed to set params: {e}");
+                }
+            }
+        }
+        Reopen::As(previous, params) if !opts.no_set_format => {
+            fmt = dev.set_format(previous)?;
+            dev.set_params(params)?;
+        }
+        Reopen::As(..) => {}
+    }
+    let params = dev.params()?;
+    eprintln!("Format in use:\n{}", fmt);
+    let stream = start_stream(&dev, opts, true);
+    Ok((Source::Device(stream), fmt, params))
+}
+
+// --device-reset: dump the driver state to the kernel log, then open the
+// device again and stream with the format it had. False if it did not come
+// back.
+fn reset_device(
+    opts: &Options,
+    source: &mut Source,
+    fmt: &Format,
+    params: &Parameters,
+    events: &mut Events,
+) -> bool {
+    let Source::Device(stream) = source else {
+        return false;
+    };
+    eprintln!("Resetting {}", opts.devname);
+    if let Err(e) = device::log_status(stream.handle().fd()) {
+        eprintln!("VIDIOC_LOG_STATUS failed: {e}");
+    }
+    stream.release();
+    // A device that fell off the bus takes a few seconds to come back
+    let mut reopened = reopen_device(opts, Reopen::As(fmt, params));
+    for _ in 0..10 {
+        if reopened.is_ok() {
             break;
         }
-        frame_count += 1;
-        if max_frames > 0 && frame_count >= max_frames {
+        std::thread::sleep(Duration::from_millis(500));
+        reopened = reopen_device(opts, Reopen::As(fmt, params));
+    }
+    match reopened {
+        Ok((reopened, _, _)) => *source = reopened,
+        Err(e) => {
+            eprintln!("Failed to reopen {}: {e}", opts.devname);
+            return false;
+        }
+    }
+    events.emit("device_reset", &[("device", &opts.devname)]);
+    eprintln!("{} reopened", opts.devname);
+    true
+}
+
+// Set the requested pixel format, size and frame rate
+fn negotiate_format(dev: &Device, opts: &Options, width: u32, height: u32) -> (Format, Parameters) {
+    let mut fmt = dev.format().expect("Failed to read format");
+    // What the device was set to, for other clients, put back if we fail
+    let saved = (fmt, dev.params().ok());
+    let fail = |what: &str, e: io::Error| -> ! {
+        eprintln!("{what}: {e}");
+        rollback(dev, &saved);
+        exit(1);
+    };
+    let candidates = match opts.pixelformats.len() {
+        0 | 1 => vec![opts.pixelformat],
+        _ => opts.pixelformats.clone(),
+    };
+    let ladder = if opts.resolution_ladder.is_empty() || opts.set_dv_timings {
+        vec![(width, height)]
+    } else {
+        opts.resolution_ladder.clone()
+    };
+    // Take the first pixel format the driver accepts unchanged, and of that
+    // the first resolution, or whatever it made of the last ones
+    let mut negotiated = None;
+    for &fourcc in &candidates {
+        fmt.fourcc = FourCC::new(&fourcc);
+        for (i, &(width, height)) in ladder.iter().enumerate() {
+            fmt.width = width;
+            fmt.height = height;
+            let got = retry_busy(opts, "Writing the format", || dev.set_format(&fmt))
+                .unwrap_or_else(|e| fail("Failed to write format", e));
+            if ladder.len() > 1 {
+                if got.width == width && got.height == height {
+                    negotiated = Some(got);
+                    break;
+                }
+                eprintln!(
+                    "{}x{} not supported (driver chose {}x{})",
+                    width, height, got.width, got.height
+                );
+                if i == ladder.len() - 1 {
+                    eprintln!("warning: no exact match in the resolution ladder");
+                }
+            }
+            negotiated = Some(got);
+        }
+        if candidates.len() == 1 {
             break;
         }
+        let got = negotiated.unwrap().fourcc;
+        if got == fmt.fourcc {
+            eprintln!("Pixel format {got} chosen");
+            break;
+        }
+        eprintln!("{} not supported (driver chose {got})", fmt.fourcc);
+        if fourcc == candidates[candidates.len() - 1] {
+            eprintln!("warning: none of the pixel formats accepted, using {got}");
+        }
+    }
+    let fmt = negotiated.unwrap();
+    let mut params = dev
+        .params()
+        .unwrap_or_else(|e| fail("Failed to read params", e));
+    params.interval = v4l::fraction::Fraction {
+        numerator: 1,
+        denominator: opts.framerate,
+    };
+    let params = retry_busy(opts, "Setting params", || dev.set_params(&params))
+        .unwrap_or_else(|e| fail("Failed to set params", e));
+    if opts.strict_format && !check_strict(dev, opts, width, height, &fmt, &params) {
+        rollback(dev, &saved);
+        exit(1);
+    }
+    (fmt, params)
+}
+
+// A driver may still be busy for a moment after another client released
+// the device: try again --format-retries times, waiting longer each time
+fn retry_busy<T>(
+    opts: &Options,
+    what: &str,
+    mut f: impl FnMut() -> io::Result<T>,
+) -> io::Result<T> {
+    let mut delay = opts.format_retry_delay;
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Err(e) if e.raw_os_error() == Some(libc::EBUSY) && attempt < opts.format_retries => {
+                attempt += 1;
+                eprintln!(
+                    "{what}: {e}, retry {attempt} of {} in {delay:?}",
+                    opts.format_retries
+                );
+                std::thread::sleep(delay);
+                delay *= 2;
+            }
+            result => return result,
+        }
     }
 }
+
+// Restore the format and frame rate found at startup
+fn rollback(dev: &Device, (fmt, params): &(Format, Option<Parameters>)) {
+    if let Err(e) = dev.set_format(fmt) {
+        eprintln!("warning: failed to restore the format: {e}");
+    }
+    if let Some(params) = params {
+        if let Err(e) = dev.set_params(params) {
+            eprintln!("warning: failed to restore the frame rate: {e}");
+        }
+    }
+    eprintln!("Restored the previous device configuration");
+}
+
+// Time per frame the driver settled on
+fn frame_interval(params: &Parameters, framerate: u32) -> Duration {
+    let interval = params.interval;
+    if interval.numerator == 0 || interval.denominator == 0 {
+        return frame_interval_of(framerate);
+    }
+    Duration::from_secs_f64(interval.numerator as f64 / interval.denominator as f64)
+}
+
+fn frame_interval_of(framerate: u32) -> Duration {
+    Duration::from_secs_f64(1.0 / framerate.max(1) as f64)
+}
+
+// For --strict-format: false unless the driver took the mode as requested,
+// pointing to the nearest one it has
+fn check_strict(
+    dev: &Device,
+    opts: &Options,
+    width: u32,
+    height: u32,
+    fmt: &Format,
+    params: &Parameters,
+) -> bool {
+    let fourcc = FourCC::new(&opts.pixelformat);
+    let interval = params.interval;
+    let fps = interval.denominator as f64 / interval.numerator.max(1) as f64;
+    let mut mismatches = Vec::new();
+    if fmt.width != width || fmt.height != height {
+        mismatches.push(format!(
+            "size {}x{} instead of {}x{}",
+            fmt.width, fmt.height, width, height
+        ));
+    }
+    if fmt.fourcc != fourcc && !opts.pixelformats.contains(&fmt.fourcc.repr) {
+        mismatches.push(format!("format {} instead of {}", fmt.fourcc, fourcc));
+    }
+    if fps.round() as u32 != opts.framerate {
+        mismatches.push(format!("{:.3} fps instead of {}", fps, opts.framerate));
+    }
+    if mismatches.is_empty() {
+        return true;
+    }
+    eprintln!(
+        "error: the driver changed the mode: {}",
+        mismatches.join(", ")
+    );
+    match discover::nearest_mode(dev, fourcc, width, height, opts.framerate) {
+        Some(mode) => eprintln!("Nearest supported mode: {mode}"),
+        None => eprintln!("The device does not list its modes"),
+    }
+    false
+}
+
+// Replay a raw capture file with the geometry given on the command line
+fn open_file(opts: &Options, path: &str) -> (Source, Format, Parameters) {
+    let mut fmt = Format::new(opts.width, opts.height, FourCC::new(&opts.pixelformat));
+    fmt.size = pixfmt::frame_size(&opts.pixelformat, opts.width, opts.height).unwrap_or(0) as u32;
+    let mut file = FileSource::open(
+        path,
+        &opts.pixelformat,
+        opts.width,
+        opts.height,
+        opts.framerate,
+        opts.source_framing,
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("Failed to open {path}: {e}");
+        exit(1);
+    });
+    file.set_repeat_last(opts.repeat_last_on_eof);
+    file.set_on_corrupt(opts.source_corrupt.unwrap_or(OnCorrupt::Abort));
+    eprintln!("Replaying {path} as:\n{}", fmt);
+    (
+        Source::File(file),
+        fmt,
+        Parameters::with_fps(opts.framerate),
+    )
+}
@@ -0,0 +1,60 @@
+// DIR/manifest.json next to a directory of frame files (--split-frames,
+// --png-sequence): every file with the sequence number, timestamp and size of
+// its frame, so that readers need not scan the file names. The closing
+// brackets are rewritten after each entry, the file is valid JSON after
+// every frame and a crash loses the frame being written at most.
+//
+//     {"frames": [
+//     {"file": "frame_00000000.jpg", "seq": 0, "timestamp": 12.345678, "bytes": 93408}
+//     ]}
+
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::time::Duration;
+use v4l::buffer::Metadata;
+
+const HEAD: &[u8] = b"{\"frames\": [";
+const TAIL: &[u8] = b"\n]}\n";
+
+pub struct Manifest {
+    file: File,
+    // Where the tail starts, the next entry goes
+    end: u64,
+    entries: usize,
+}
+
+impl Manifest {
+    pub fn create(dir: &Path) -> io::Result<Manifest> {
+        let file = File::create(dir.join("manifest.json"))?;
+        file.write_all_at(HEAD, 0)?;
+        file.write_all_at(TAIL, HEAD.len() as u64)?;
+        Ok(Manifest {
+            file,
+            end: HEAD.len() as u64,
+            entries: 0,
+        })
+    }
+
+    // `name` relative to the directory, as written
+    pub fn add(&mut self, name: &str, meta: &Metadata, bytes: usize) -> io::Result<()> {
+        let entry = format!(
+            "{}\n{{\"file\": \"{name}\", \"seq\": {}, \"timestamp\": {:.6}, \"bytes\": {bytes}}}",
+            if self.entries > 0 { "," } else { "" },
+            meta.sequence,
+            Duration::from(meta.timestamp).as_secs_f64()
+        );
+        let mut data = entry.into_bytes();
+        let end = self.end + data.len() as u64;
+        data.extend_from_slice(TAIL);
+        self.file.write_all_at(&data, self.end)?;
+        self.end = end;
+        self.entries += 1;
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+}
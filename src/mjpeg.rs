@@ -0,0 +1,215 @@
+// Minimal JPEG marker parsing for MJPEG frames
+
+pub const SOI: u8 = 0xd8;
+pub const EOI: u8 = 0xd9;
+pub const SOS: u8 = 0xda;
+pub const DHT: u8 = 0xc4;
+pub const DQT: u8 = 0xdb;
+
+// One marker segment: marker byte, offset of the 0xFF and total length
+// including the marker itself
+pub struct Segment {
+    pub marker: u8,
+    pub offset: usize,
+    pub len: usize,
+}
+
+// Start Of Frame markers (C0-CF except DHT, JPG and DAC)
+pub fn is_sof(marker: u8) -> bool {
+    (0xc0..=0xcf).contains(&marker) && !matches!(marker, 0xc4 | 0xc8 | 0xcc)
+}
+
+// Markers that are not followed by a length field
+fn is_standalone(marker: u8) -> bool {
+    matches!(marker, 0x01 | 0xd0..=0xd9)
+}
+
+// List the header segments of a JPEG frame, up to and including SOS.
+// Returns None if the buffer does not start with SOI or a segment is truncated.
+pub fn segments(buf: &[u8]) -> Option<Vec<Segment>> {
+    if buf.len() < 2 || buf[0] != 0xff || buf[1] != SOI {
+        return None;
+    }
+    let mut segs = vec![Segment {
+        marker: SOI,
+        offset: 0,
+        len: 2,
+    }];
+    let mut pos = 2;
+    loop {
+        if *buf.get(pos)? != 0xff {
+            return None;
+        }
+        // Skip fill bytes
+        while *buf.get(pos + 1)? == 0xff {
+            pos += 1;
+        }
+        let marker = buf[pos + 1];
+        let len = if is_standalone(marker) {
+            2
+        } else {
+            let l = u16::from_be_bytes([*buf.get(pos + 2)?, *buf.get(pos + 3)?]) as usize;
+            if l < 2 || pos + 2 + l > buf.len() {
+                return None;
+            }
+            2 + l
+        };
+        segs.push(Segment {
+            marker,
+            offset: pos,
+            len,
+        });
+        if marker == SOS || marker == EOI {
+            return Some(segs);
+        }
+        pos += len;
+    }
+}
+
+// Length of the frame up to and including its EOI marker, searched for after
+// the headers so that embedded thumbnails do not end it early. Anything
+// after that is padding. None if there is no EOI.
+pub fn frame_end(buf: &[u8]) -> Option<usize> {
+    let start = match segments(buf) {
+        Some(segs) => segs.last().map_or(2, |seg| seg.offset + seg.len),
+        None => 2,
+    };
+    buf.get(start..)?
+        .windows(2)
+        .position(|w| w == [0xff, EOI])
+        .map(|p| start + p + 2)
+}
+
+pub fn has_dht(segs: &[Segment]) -> bool {
+    segs.iter().any(|s| s.marker == DHT)
+}
+
+// Huffman tables from ITU T.81 Annex K.3, which MJPEG streams without a DHT
+// segment implicitly use
+const DC_LUMA_BITS: [u8; 16] = [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0];
+const DC_CHROMA_BITS: [u8; 16] = [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0];
+const DC_VALUES: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+const AC_LUMA_BITS: [u8; 16] = [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7d];
+#[rustfmt::skip]
+const AC_LUMA_VALUES: [u8; 162] = [
+    0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07,
+    0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xa1, 0x08, 0x23, 0x42, 0xb1, 0xc1, 0x15, 0x52, 0xd1, 0xf0,
+    0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0a, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x25, 0x26, 0x27, 0x28,
+    0x29, 0x2a, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49,
+    0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69,
+    0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89,
+    0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7,
+    0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3, 0xc4, 0xc5,
+    0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda, 0xe1, 0xe2,
+    0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8,
+    0xf9, 0xfa,
+];
+const AC_CHROMA_BITS: [u8; 16] = [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77];
+#[rustfmt::skip]
+const AC_CHROMA_VALUES: [u8; 162] = [
+    0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61, 0x71,
+    0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xa1, 0xb1, 0xc1, 0x09, 0x23, 0x33, 0x52, 0xf0,
+    0x15, 0x62, 0x72, 0xd1, 0x0a, 0x16, 0x24, 0x34, 0xe1, 0x25, 0xf1, 0x17, 0x18, 0x19, 0x1a, 0x26,
+    0x27, 0x28, 0x29, 0x2a, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48,
+    0x49, 0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68,
+    0x69, 0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87,
+    0x88, 0x89, 0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5,
+    0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3,
+    0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda,
+    0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8,
+    0xf9, 0xfa,
+];
+
+// DHT segment holding the four standard tables
+fn default_dht() -> Vec<u8> {
+    let tables: [(u8, &[u8], &[u8]); 4] = [
+        (0x00, &DC_LUMA_BITS, &DC_VALUES),
+        (0x01, &DC_CHROMA_BITS, &DC_VALUES),
+        (0x10, &AC_LUMA_BITS, &AC_LUMA_VALUES),
+        (0x11, &AC_CHROMA_BITS, &AC_CHROMA_VALUES),
+    ];
+    let mut body = Vec::new();
+    for (class_id, bits, values) in tables {
+        body.push(class_id);
+        body.extend_from_slice(bits);
+        body.extend_from_slice(values);
+    }
+    let mut seg = vec![0xff, DHT];
+    seg.extend_from_slice(&((body.len() + 2) as u16).to_be_bytes());
+    seg.extend_from_slice(&body);
+    seg
+}
+
+// Copy the frame with the standard Huffman tables inserted after the SOF
+// segment (or before SOS if there is no SOF)
+pub fn insert_default_dht(buf: &[u8], segs: &[Segment]) -> Vec<u8> {
+    let pos = segs
+        .iter()
+        .find(|s| is_sof(s.marker))
+        .map(|s| s.offset + s.len)
+        .or_else(|| segs.iter().find(|s| s.marker == SOS).map(|s| s.offset))
+        .unwrap_or(2);
+    let dht = default_dht();
+    let mut out = Vec::with_capacity(buf.len() + dht.len());
+    out.extend_from_slice(&buf[..pos]);
+    out.extend_from_slice(&dht);
+    out.extend_from_slice(&buf[pos..]);
+    out
+}
+
+// Luminance quantization table of ITU T.81 Annex K.1, which the usual
+// encoders scale by the quality factor
+#[rustfmt::skip]
+const STD_LUMA_QUANT: [u16; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61,
+    12, 12, 14, 19, 26, 58, 60, 55,
+    14, 13, 16, 24, 40, 57, 69, 56,
+    14, 17, 22, 29, 51, 87, 80, 62,
+    18, 22, 37, 56, 68, 109, 103, 77,
+    24, 35, 55, 64, 81, 104, 113, 92,
+    49, 64, 78, 87, 103, 121, 120, 101,
+    72, 92, 95, 98, 112, 100, 103, 99,
+];
+
+// Table 0 (luma) of the DQT segments, in whatever order it is stored
+fn luma_quant(buf: &[u8], segs: &[Segment]) -> Option<[u16; 64]> {
+    for seg in segs.iter().filter(|s| s.marker == DQT) {
+        let mut body = buf.get(seg.offset + 4..seg.offset + seg.len)?;
+        while let Some((&pq_tq, rest)) = body.split_first() {
+            let wide = pq_tq >> 4 != 0;
+            let size = if wide { 128 } else { 64 };
+            let values = rest.get(..size)?;
+            body = &rest[size..];
+            if pq_tq & 0x0f != 0 {
+                continue;
+            }
+            let mut table = [0; 64];
+            for (k, q) in table.iter_mut().enumerate() {
+                *q = if wide {
+                    u16::from_be_bytes([values[2 * k], values[2 * k + 1]])
+                } else {
+                    values[k] as u16
+                };
+            }
+            return Some(table);
+        }
+    }
+    None
+}
+
+// Quality factor (1-100) the luma table was made with, estimated the way
+// libjpeg scales the standard table: the mean scale over all coefficients,
+// where quality q < 50 scales by 5000 / q and higher ones by 200 - 2q. None
+// without a luma table.
+pub fn quality(buf: &[u8], segs: &[Segment]) -> Option<u32> {
+    let table = luma_quant(buf, segs)?;
+    let sum: u32 = table.iter().map(|&q| q as u32).sum();
+    let std: u32 = STD_LUMA_QUANT.iter().map(|&q| q as u32).sum();
+    let scale = sum as f64 * 100.0 / std as f64;
+    let quality = if scale <= 100.0 {
+        (200.0 - scale) / 2.0
+    } else {
+        5000.0 / scale
+    };
+    Some(quality.round().clamp(1.0, 100.0) as u32)
+}
@@ -0,0 +1,112 @@
+// --mmap-output: a regular file written by copying the frames into a shared
+// mapping of it, which grows a chunk at a time
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+
+const CHUNK: usize = 64 << 20;
+
+pub struct MmapFile {
+    // A handle of its own on the output file, readable as a shared mapping
+    // needs it to be
+    file: File,
+    // Null while nothing is mapped
+    map: *mut u8,
+    mapped: usize,
+    // Bytes written
+    len: usize,
+}
+
+// The mapping is only touched through &mut self
+unsafe impl Send for MmapFile {}
+
+impl MmapFile {
+    pub fn new(file: &File) -> io::Result<MmapFile> {
+        Ok(MmapFile {
+            file: OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(format!("/proc/self/fd/{}", file.as_raw_fd()))?,
+            map: ptr::null_mut(),
+            mapped: 0,
+            len: 0,
+        })
+    }
+
+    // Extend the file and the mapping to whole chunks holding `need` bytes.
+    // The blocks are allocated up front where the file system can, so that a
+    // full disk is an error here instead of a SIGBUS on the copy.
+    fn grow(&mut self, need: usize) -> io::Result<()> {
+        let size = need.div_ceil(CHUNK) * CHUNK;
+        let fd = self.file.as_raw_fd();
+        let err = unsafe { libc::fallocate(fd, 0, 0, size as libc::off_t) };
+        if err != 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() != Some(libc::EOPNOTSUPP) {
+                return Err(e);
+            }
+            self.file.set_len(size as u64)?;
+        }
+        let map = unsafe {
+            if self.map.is_null() {
+                libc::mmap(
+                    ptr::null_mut(),
+                    size,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_SHARED,
+                    fd,
+                    0,
+                )
+            } else {
+                // Start the writeback of what is there so far
+                libc::msync(self.map as *mut _, self.mapped, libc::MS_ASYNC);
+                libc::mremap(self.map as *mut _, self.mapped, size, libc::MREMAP_MAYMOVE)
+            }
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        self.map = map as *mut u8;
+        self.mapped = size;
+        Ok(())
+    }
+
+    pub fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        if self.len + buf.len() > self.mapped {
+            self.grow(self.len + buf.len())?;
+        }
+        unsafe { ptr::copy_nonoverlapping(buf.as_ptr(), self.map.add(self.len), buf.len()) };
+        self.len += buf.len();
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        if !self.map.is_null()
+            && unsafe { libc::msync(self.map as *mut _, self.mapped, libc::MS_SYNC) } != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        self.file.sync_data()
+    }
+
+    // Drop the mapping and cut the file to the data written. Writing again
+    // maps it anew.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if !self.map.is_null() {
+            unsafe { libc::munmap(self.map as *mut _, self.mapped) };
+            self.map = ptr::null_mut();
+            self.mapped = 0;
+        }
+        self.file.set_len(self.len as u64)
+    }
+}
+
+impl Drop for MmapFile {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            eprintln!("--mmap-output: failed to truncate the output: {e}");
+        }
+    }
+}
@@ -0,0 +1,142 @@
+// Frame difference trigger for --motion-threshold: frames are only written
+// while the picture changes, with some frames before and after
+
+use std::collections::VecDeque;
+use v4l::buffer::Metadata;
+use v4l::Format;
+
+// Pixels between samples, across and down
+const GRID: usize = 8;
+
+pub struct Motion {
+    threshold: f64,
+    // Byte of the first luma sample, bytes between pixels, between rows
+    offset: usize,
+    pixel: usize,
+    stride: usize,
+    width: usize,
+    height: usize,
+    last: Vec<u8>,
+    score: f64,
+    // Frames from before the motion, written when it starts
+    held: VecDeque<(Vec<u8>, Metadata)>,
+    pre: usize,
+    post: usize,
+    // Still frames to go before recording stops
+    remaining: usize,
+    recording: bool,
+}
+
+impl Motion {
+    // None for formats without a plain luma (or green) sample per pixel
+    pub fn new(threshold: f64, fmt: &Format, pre: usize, post: usize) -> Option<Motion> {
+        let (offset, pixel) = match &fmt.fourcc.repr {
+            b"YUYV" | b"YVYU" => (0, 2),
+            // The high byte of little endian Y16
+            b"UYVY" | b"VYUY" | b"Y16 " => (1, 2),
+            b"GREY" | b"NV12" | b"NV21" | b"NV16" | b"NV61" | b"YU12" | b"YV12" | b"422P" => (0, 1),
+            b"RGB3" | b"BGR3" => (1, 3),
+            b"RGB4" | b"BGR4" | b"XR24" | b"AR24" | b"XB24" | b"AB24" => (1, 4),
+            _ => return None,
+        };
+        Some(Motion {
+            threshold,
+            offset,
+            pixel,
+            // Replayed files have no bytesperline
+            stride: match fmt.stride {
+                0 => fmt.width as usize * pixel,
+                stride => stride as usize,
+            },
+            width: fmt.width as usize,
+            height: fmt.height as usize,
+            last: Vec::new(),
+            score: 0.0,
+            held: VecDeque::with_capacity(pre),
+            pre,
+            post,
+            remaining: 0,
+            recording: false,
+        })
+    }
+
+    // Mean absolute luma difference to the previous frame over the sample
+    // grid, 0 to 255
+    fn difference(&mut self, buf: &[u8]) -> f64 {
+        let mut samples = Vec::with_capacity(self.last.len());
+        for y in (0..self.height).step_by(GRID) {
+            for x in (0..self.width).step_by(GRID) {
+                match buf.get(y * self.stride + x * self.pixel + self.offset) {
+                    Some(&v) => samples.push(v),
+                    None => break,
+                }
+            }
+        }
+        let score = if samples.len() == self.last.len() && !samples.is_empty() {
+            let sum: u64 = samples
+                .iter()
+                .zip(&self.last)
+                .map(|(&a, &b)| a.abs_diff(b) as u64)
+                .sum();
+            sum as f64 / samples.len() as f64
+        } else {
+            0.0
+        };
+        self.last = samples;
+        score
+    }
+
+    // Look at the next frame. Some(true) when recording starts with it,
+    // Some(false) when it stops before it.
+    pub fn update(&mut self, buf: &[u8]) -> Option<bool> {
+        self.score = self.difference(buf);
+        let moving = self.score > self.threshold;
+        if moving {
+            self.remaining = self.post;
+        }
+        match (self.recording, moving) {
+            (false, true) => {
+                self.recording = true;
+                Some(true)
+            }
+            (true, false) if self.remaining == 0 => {
+                self.recording = false;
+                Some(false)
+            }
+            (true, false) => {
+                self.remaining -= 1;
+                None
+            }
+            _ => None,
+        }
+    }
+
+    pub fn recording(&self) -> bool {
+        self.recording
+    }
+
+    pub fn score(&self) -> f64 {
+        self.score
+    }
+
+    // Keep a frame that is not written, in case motion follows
+    pub fn hold(&mut self, frame: &[u8], meta: &Metadata) {
+        if self.pre == 0 {
+            return;
+        }
+        let mut slot = if self.held.len() == self.pre {
+            self.held.pop_front().unwrap_or_default()
+        } else {
+            Default::default()
+        };
+        slot.0.clear();
+        slot.0.extend_from_slice(frame);
+        slot.1 = *meta;
+        self.held.push_back(slot);
+    }
+
+    // The held frames, oldest first, to be written when recording starts
+    pub fn take_held(&mut self) -> impl Iterator<Item = (Vec<u8>, Metadata)> + '_ {
+        self.held.drain(..)
+    }
+}
@@ -0,0 +1,160 @@
+// mq://NAME as outfile: every frame a message on the POSIX message queue
+// /NAME, created unless there. A message is 16 bytes of kind, sequence number
+// (u32 LE) and timestamp in microseconds (u64 LE), then for kind "FRAM" the
+// frame. Frames too large for the messages of the queue go to a shared
+// memory object instead, kind "SHMR" followed by the frame length (u64 LE)
+// and the name of the object, e.g. /NAME.3. There is one more of those than
+// the queue holds messages, reused in turn: a reader copies a frame out
+// before it takes the next message. They are removed when the capture ends.
+//
+// A full queue does not hold up the capture, the frame is dropped.
+
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::mqueue::{mq_getattr, mq_open, mq_send, MQ_OFlag, MqdT};
+use nix::sys::mman::{shm_open, shm_unlink};
+use nix::sys::stat::Mode;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::FromRawFd;
+use std::time::Duration;
+use v4l::buffer::Metadata;
+use v4l::video::capture::Parameters;
+use v4l::Format;
+
+use crate::container::ContainerWriter;
+
+const HEADER_LEN: usize = 16;
+
+pub struct MessageQueue {
+    name: String,
+    mq: MqdT,
+    msgsize: usize,
+    // Shared memory for large frames, opened when first needed
+    slots: Vec<Option<File>>,
+    next_slot: usize,
+    dropped: u64,
+}
+
+impl MessageQueue {
+    // `name` without the leading slash
+    pub fn open(name: &str) -> io::Result<MessageQueue> {
+        let name = format!("/{name}");
+        let cname = CString::new(name.clone())
+            .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "NUL in the queue name"))?;
+        let flags = MQ_OFlag::O_WRONLY | MQ_OFlag::O_CREAT | MQ_OFlag::O_NONBLOCK;
+        let mq = mq_open(&cname, flags, Mode::from_bits_truncate(0o600), None)?;
+        let attr = mq_getattr(&mq)?;
+        eprintln!(
+            "Queue {name}: {} messages of {} bytes",
+            attr.maxmsg(),
+            attr.msgsize()
+        );
+        Ok(MessageQueue {
+            name,
+            mq,
+            msgsize: attr.msgsize() as usize,
+            slots: (0..=attr.maxmsg()).map(|_| None).collect(),
+            next_slot: 0,
+            dropped: 0,
+        })
+    }
+
+    fn slot_name(&self, slot: usize) -> String {
+        format!("{}.{slot}", self.name)
+    }
+
+    // Into the next shared memory object, returning its name. The object is
+    // only taken for good by advance() once its message is sent.
+    fn put_shared(&mut self, buf: &[u8]) -> io::Result<String> {
+        let slot = self.next_slot;
+        let name = self.slot_name(slot);
+        if self.slots[slot].is_none() {
+            let flags = OFlag::O_RDWR | OFlag::O_CREAT;
+            let fd = shm_open(name.as_str(), flags, Mode::from_bits_truncate(0o600))?;
+            self.slots[slot] = Some(unsafe { File::from_raw_fd(fd) });
+        }
+        let file = self.slots[slot].as_ref().unwrap();
+        if file.metadata()?.len() < buf.len() as u64 {
+            file.set_len(buf.len() as u64)?;
+        }
+        file.write_all_at(buf, 0)?;
+        Ok(name)
+    }
+
+    fn advance(&mut self) {
+        self.next_slot = (self.next_slot + 1) % self.slots.len();
+    }
+
+    fn drop_frame(&mut self, meta: &Metadata) {
+        eprintln!(
+            "warning: queue {} full, frame seq {} dropped",
+            self.name, meta.sequence
+        );
+        self.dropped += 1;
+    }
+}
+
+impl ContainerWriter for MessageQueue {
+    fn begin(&mut self, _format: &Format, _params: &Parameters) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_frame(&mut self, buf: &[u8], meta: &Metadata, _mapped: bool) -> io::Result<()> {
+        let inline = HEADER_LEN + buf.len() <= self.msgsize;
+        let mut message = Vec::with_capacity(if inline { HEADER_LEN + buf.len() } else { 64 });
+        message.extend_from_slice(if inline { b"FRAM" } else { b"SHMR" });
+        message.extend_from_slice(&meta.sequence.to_le_bytes());
+        let micros = Duration::from(meta.timestamp).as_micros() as u64;
+        message.extend_from_slice(&micros.to_le_bytes());
+        if inline {
+            message.extend_from_slice(buf);
+        } else {
+            // The objects of the messages waiting are all in use
+            let attr = mq_getattr(&self.mq)?;
+            if attr.curmsgs() >= attr.maxmsg() {
+                self.drop_frame(meta);
+                return Ok(());
+            }
+            let name = self.put_shared(buf)?;
+            message.extend_from_slice(&(buf.len() as u64).to_le_bytes());
+            message.extend_from_slice(name.as_bytes());
+        }
+        match mq_send(&self.mq, &message, 0) {
+            Ok(()) => {
+                if !inline {
+                    self.advance();
+                }
+                Ok(())
+            }
+            Err(Errno::EAGAIN) => {
+                self.drop_frame(meta);
+                Ok(())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        if self.dropped > 0 {
+            eprintln!(
+                "Dropped {} frames on the full queue {}",
+                self.dropped, self.name
+            );
+            self.dropped = 0;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for MessageQueue {
+    fn drop(&mut self) {
+        for slot in 0..self.slots.len() {
+            if self.slots[slot].take().is_some() {
+                _ = shm_unlink(self.slot_name(slot).as_str());
+            }
+        }
+    }
+}
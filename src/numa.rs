@@ -0,0 +1,70 @@
+// --numa-node: keep the capture thread and the memory it allocates on one
+// node of a multi-socket machine
+
+use std::fs;
+use std::io;
+
+const NODES: &str = "/sys/devices/system/node";
+
+// "0-3,8-11" as in the cpulist and online files of sysfs
+fn parse_list(s: &str) -> Vec<usize> {
+    let mut out = Vec::new();
+    for range in s.trim().split(',').filter(|r| !r.is_empty()) {
+        let (first, last) = range.split_once('-').unwrap_or((range, range));
+        if let (Ok(first), Ok(last)) = (first.parse::<usize>(), last.parse()) {
+            out.extend(first..=last);
+        }
+    }
+    out
+}
+
+// Pin the calling thread to the CPUs of `node` and bind the memory it
+// allocates from now on to the node. Threads started afterwards inherit both.
+pub fn bind_thread(node: u32) -> Result<(), String> {
+    let list = match fs::read_to_string(format!("{NODES}/node{node}/cpulist")) {
+        Ok(list) => list,
+        Err(_) => {
+            let online = fs::read_to_string(format!("{NODES}/online")).unwrap_or_default();
+            return Err(format!(
+                "no NUMA node {node} (online: {})",
+                match online.trim() {
+                    "" => "none",
+                    nodes => nodes,
+                }
+            ));
+        }
+    };
+    let cpus = parse_list(&list);
+    if cpus.is_empty() {
+        return Err(format!("NUMA node {node} has no CPUs"));
+    }
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for &cpu in &cpus {
+        unsafe { libc::CPU_SET(cpu, &mut set) };
+    }
+    if unsafe { libc::sched_setaffinity(0, std::mem::size_of_val(&set), &set) } == -1 {
+        return Err(format!(
+            "failed to pin to the CPUs of node {node}: {}",
+            io::Error::last_os_error()
+        ));
+    }
+    let bits = libc::c_ulong::BITS as usize;
+    let mut mask = vec![0 as libc::c_ulong; node as usize / bits + 1];
+    mask[node as usize / bits] |= 1 << (node as usize % bits);
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_set_mempolicy,
+            libc::MPOL_BIND,
+            mask.as_ptr(),
+            mask.len() * bits + 1,
+        )
+    };
+    if result == -1 {
+        return Err(format!(
+            "failed to bind memory to node {node}: {}",
+            io::Error::last_os_error()
+        ));
+    }
+    eprintln!("Running on NUMA node {node}, CPUs {}", list.trim());
+    Ok(())
+}
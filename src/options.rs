@@ -0,0 +1,1282 @@
+use nix::unistd::Group;
+use std::process::exit;
+use std::time::Duration;
+use v4l::capability::Flags;
+use v4l2capture_rs::parse::{self, ParseError};
+use v4l2capture_rs::stream::BufferCache;
+
+use crate::circular;
+use crate::clock::Clock;
+use crate::config::{self, Value};
+use crate::debayer::Demosaic;
+use crate::device::{self, Priority};
+use crate::discover::Selector;
+use crate::fields::Deinterlace;
+use crate::filler::FillMode;
+use crate::output::{CopyMode, Framing, PAD_HEADER_LEN};
+use crate::overlay::Corner;
+use crate::pipe;
+use crate::pixfmt::{Convert, Rect};
+use crate::power::Power;
+use crate::pts::{PtsBase, PtsUnit};
+use crate::snapshot::SnapshotFormat;
+use crate::source::OnCorrupt;
+use crate::subscribe::EventKind;
+
+const DEFAULT_BUFFER_CAPACITY: usize = 1 << 20;
+const DEFAULT_HISTOGRAM_BUCKET: u64 = 16384;
+// Frame errors in a row --mode resilient rides out
+const RESILIENT_MAX_ERRORS: u32 = 10;
+
+// --mode: how frame errors are taken, for the options about each kind that
+// are not given
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    // The first frame error ends the capture
+    FailFast,
+    // Frames in error are skipped, the device is reset and files are read on
+    // past damage, up to --max-consecutive-errors
+    Resilient,
+}
+
+impl Mode {
+    pub fn parse(s: &str) -> Option<Mode> {
+        match s {
+            "fail-fast" => Some(Mode::FailFast),
+            "resilient" => Some(Mode::Resilient),
+            _ => None,
+        }
+    }
+}
+
+// Command line settings
+pub struct Options {
+    pub devname: String,
+    pub out_file: String,
+    pub width: u32,
+    pub height: u32,
+    pub framerate: u32,
+    pub pixelformat: [u8; 4],
+    // A list of pixel formats to try in order, pixelformat its first until
+    // the device has chosen
+    pub pixelformats: Vec<[u8; 4]>,
+    pub max_frames: usize,
+    pub rotate_daily: bool,
+    pub rotate_size: Option<u64>,
+    pub log_file: Option<String>,
+    pub log_rotate_size: Option<u64>,
+    pub dedup: bool,
+    pub dedup_sample: usize,
+    pub buffer_capacity: Option<usize>,
+    pub mmap_output: bool,
+    pub verbose: bool,
+    pub fix_mjpeg_dht: bool,
+    pub clock: Option<Clock>,
+    pub max_frame_size: Option<usize>,
+    pub abort_on_oversize: bool,
+    pub source_file: Option<String>,
+    pub pts_base: Option<PtsBase>,
+    pub loopback_timestamp: Option<PtsBase>,
+    pub priority: Option<Priority>,
+    pub heartbeat_file: Option<String>,
+    pub stats_interval: Option<Duration>,
+    pub copy_mode: CopyMode,
+    pub load_edid: Option<String>,
+    pub dump_edid: Option<String>,
+    pub query_dv_timings: bool,
+    pub set_dv_timings: bool,
+    pub ring_files: Option<u32>,
+    pub ring_frames: u64,
+    pub snapshot_dir: String,
+    pub file_mode: Option<u32>,
+    pub file_group: Option<u32>,
+    pub resolution_ladder: Vec<(u32, u32)>,
+    pub size_histogram: Option<u64>,
+    pub flag_histogram: bool,
+    pub buffers: u32,
+    pub queue_depth: Option<usize>,
+    pub events_fd: Option<i32>,
+    pub selector: Option<Selector>,
+    pub first_match: bool,
+    pub no_requeue_on_error: bool,
+    pub strip_padding: bool,
+    pub m2m_device: Option<String>,
+    pub m2m_out_fourcc: Option<[u8; 4]>,
+    pub m2m_out_buffers: u32,
+    pub m2m_cap_buffers: u32,
+    pub timestamp_filename: bool,
+    pub snapshot_format: SnapshotFormat,
+    pub no_set_format: bool,
+    pub raw_ioctl: bool,
+    pub assemble_fields: bool,
+    pub deinterlace: Deinterlace,
+    pub debayer: Demosaic,
+    pub throttle_sleep: Option<Duration>,
+    pub dump_buffers: bool,
+    pub fsync_interval: Option<Duration>,
+    pub select_timeout: Option<Duration>,
+    pub trim_mjpeg: bool,
+    pub start_paused: bool,
+    pub repeat_headers: Option<Duration>,
+    pub keyframe_interval: Option<u64>,
+    pub probe_frame: bool,
+    pub print_caps: bool,
+    pub measure_startup: bool,
+    pub list_devices: bool,
+    pub format_table: bool,
+    pub env_report: bool,
+    pub list_all: bool,
+    pub benchmark_formats: bool,
+    pub self_test: bool,
+    pub list_controls: bool,
+    pub set_ctrls: Vec<(String, String)>,
+    pub subscribe_events: Vec<EventKind>,
+    pub strict_format: bool,
+    pub format_retries: u32,
+    pub format_retry_delay: Duration,
+    pub fill_on_stall: Option<FillMode>,
+    pub tees: Vec<String>,
+    pub rt_priority: Option<i32>,
+    pub summary_json: Option<String>,
+    pub verify_output: bool,
+    pub assert_fps: Option<f64>,
+    pub assert_tolerance: f64,
+    pub null_output: bool,
+    pub usb_bandwidth: bool,
+    pub exit_on_consumer_close: bool,
+    pub sequence_offset: u32,
+    pub software_crop: Option<Rect>,
+    pub use_plane: Option<usize>,
+    pub convert: Option<Convert>,
+    pub y16_big_endian: bool,
+    // Bytes of the words --byteswap reverses
+    pub byteswap: Option<usize>,
+    pub reorder_depth: Option<usize>,
+    pub decouple: bool,
+    pub decouple_depth: usize,
+    pub adaptive_rate: bool,
+    pub duration: Option<Duration>,
+    pub max_open_time: Option<Duration>,
+    pub recheck_format_interval: Option<Duration>,
+    pub jpeg_quality: bool,
+    pub timestamp_overlay: bool,
+    pub frame_counter_overlay: bool,
+    pub overlay_sequence: bool,
+    pub zero_base_pts: bool,
+    pub device_reset: bool,
+    pub overlay_corner: Corner,
+    #[cfg(feature = "gst")]
+    pub gst_pipeline: Option<String>,
+    #[cfg(feature = "png")]
+    pub png_sequence: Option<String>,
+    pub split_frames: Option<String>,
+    pub circular_file: Option<String>,
+    pub circular_size: Option<u64>,
+    pub circular_dump: Option<String>,
+    // Controls of consecutive frames, repeated
+    #[cfg(feature = "request-api")]
+    pub request_schedule: Vec<Vec<(String, String)>>,
+    #[cfg(feature = "request-api")]
+    pub media_device: Option<String>,
+    pub motion_threshold: Option<f64>,
+    pub gate_file: Option<String>,
+    pub power: Option<Power>,
+    pub control_socket: Option<String>,
+    pub motion_pre: usize,
+    pub motion_post: usize,
+    pub max_consecutive_errors: Option<u32>,
+    pub mode: Option<Mode>,
+    // Other nodes to record alongside, with their output files
+    pub companions: Vec<(String, String)>,
+    pub sync_start: bool,
+    // Unix time
+    pub sync_start_at: Option<Duration>,
+    pub meta_capture: Option<(String, String)>,
+    pub json: bool,
+    pub preview: Option<String>,
+    pub preview_scale: usize,
+    pub frames_per_file: Option<u64>,
+    pub segment_on_keyframe: bool,
+    pub frame_log: Option<String>,
+    pub pts_file: Option<String>,
+    pub pts_unit: PtsUnit,
+    pub require_caps: Flags,
+    pub framing: Framing,
+    pub keep_error_frames: bool,
+    pub once_per_key: bool,
+    pub pad_to: Option<usize>,
+    pub source_framing: Framing,
+    pub source_corrupt: Option<OnCorrupt>,
+    pub repeat_last_on_eof: bool,
+    pub nonblocking: bool,
+    pub no_cloexec: bool,
+    pub userptr: bool,
+    pub userptr_align: Option<usize>,
+    pub userptr_hugepages: bool,
+    pub buffer_cache: Option<BufferCache>,
+    pub numa_node: Option<u32>,
+    pub atomic_output: bool,
+    pub delete_incomplete: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            devname: String::new(),
+            out_file: String::new(),
+            width: 640,
+            height: 480,
+            framerate: 30,
+            pixelformat: *b"MJPG",
+            pixelformats: Vec::new(),
+            max_frames: 0,
+            rotate_daily: false,
+            rotate_size: None,
+            log_file: None,
+            log_rotate_size: None,
+            dedup: false,
+            dedup_sample: 4096,
+            buffer_capacity: None,
+            mmap_output: false,
+            verbose: false,
+            fix_mjpeg_dht: false,
+            clock: None,
+            max_frame_size: None,
+            abort_on_oversize: false,
+            source_file: None,
+            pts_base: None,
+            loopback_timestamp: None,
+            priority: None,
+            heartbeat_file: None,
+            stats_interval: None,
+            copy_mode: CopyMode::Auto,
+            load_edid: None,
+            dump_edid: None,
+            query_dv_timings: false,
+            set_dv_timings: false,
+            ring_files: None,
+            ring_frames: 300,
+            snapshot_dir: ".".to_string(),
+            file_mode: None,
+            file_group: None,
+            resolution_ladder: Vec::new(),
+            size_histogram: None,
+            flag_histogram: false,
+            buffers: 4,
+            queue_depth: None,
+            events_fd: None,
+            selector: None,
+            first_match: false,
+            no_requeue_on_error: false,
+            strip_padding: false,
+            m2m_device: None,
+            m2m_out_fourcc: None,
+            m2m_out_buffers: 2,
+            m2m_cap_buffers: 2,
+            timestamp_filename: false,
+            snapshot_format: SnapshotFormat::Raw,
+            no_set_format: false,
+            raw_ioctl: false,
+            assemble_fields: false,
+            deinterlace: Deinterlace::None,
+            debayer: Demosaic::None,
+            throttle_sleep: None,
+            dump_buffers: false,
+            fsync_interval: None,
+            select_timeout: None,
+            trim_mjpeg: false,
+            start_paused: false,
+            repeat_headers: None,
+            keyframe_interval: None,
+            probe_frame: false,
+            print_caps: false,
+            measure_startup: false,
+            list_devices: false,
+            format_table: false,
+            env_report: false,
+            list_all: false,
+            benchmark_formats: false,
+            self_test: false,
+            list_controls: false,
+            set_ctrls: Vec::new(),
+            subscribe_events: Vec::new(),
+            strict_format: false,
+            format_retries: 3,
+            format_retry_delay: Duration::from_millis(100),
+            fill_on_stall: None,
+            tees: Vec::new(),
+            rt_priority: None,
+            summary_json: None,
+            verify_output: false,
+            assert_fps: None,
+            assert_tolerance: 5.0,
+            null_output: false,
+            usb_bandwidth: false,
+            exit_on_consumer_close: false,
+            sequence_offset: 0,
+            software_crop: None,
+            use_plane: None,
+            convert: None,
+            y16_big_endian: false,
+            byteswap: None,
+            reorder_depth: None,
+            decouple: false,
+            decouple_depth: 8,
+            adaptive_rate: false,
+            duration: None,
+            max_open_time: None,
+            recheck_format_interval: None,
+            jpeg_quality: false,
+            timestamp_overlay: false,
+            frame_counter_overlay: false,
+            overlay_sequence: false,
+            zero_base_pts: false,
+            device_reset: false,
+            overlay_corner: Corner::TopLeft,
+            #[cfg(feature = "gst")]
+            gst_pipeline: None,
+            #[cfg(feature = "png")]
+            png_sequence: None,
+            split_frames: None,
+            circular_file: None,
+            circular_size: None,
+            circular_dump: None,
+            #[cfg(feature = "request-api")]
+            request_schedule: Vec::new(),
+            #[cfg(feature = "request-api")]
+            media_device: None,
+            motion_threshold: None,
+            gate_file: None,
+            power: None,
+            control_socket: None,
+            motion_pre: 15,
+            motion_post: 30,
+            max_consecutive_errors: None,
+            mode: None,
+            companions: Vec::new(),
+            sync_start: false,
+            sync_start_at: None,
+            meta_capture: None,
+            json: false,
+            preview: None,
+            preview_scale: 1,
+            frames_per_file: None,
+            segment_on_keyframe: false,
+            frame_log: None,
+            pts_file: None,
+            pts_unit: PtsUnit::Microseconds,
+            require_caps: Flags::empty(),
+            framing: Framing::Raw,
+            keep_error_frames: false,
+            once_per_key: false,
+            pad_to: None,
+            source_framing: Framing::Raw,
+            source_corrupt: None,
+            repeat_last_on_eof: false,
+            nonblocking: false,
+            no_cloexec: false,
+            userptr: false,
+            userptr_align: None,
+            userptr_hugepages: false,
+            buffer_cache: None,
+            numa_node: None,
+            atomic_output: false,
+            delete_incomplete: false,
+        }
+    }
+}
+
+// A control setting, NAME=VALUE
+fn parse_setting(arg: &str, s: &str) -> Result<(String, String), ParseError> {
+    let (name, value) = s
+        .split_once('=')
+        .ok_or_else(|| ParseError::new(arg, s, "expected NAME=VALUE"))?;
+    Ok((name.trim().to_string(), value.trim().to_string()))
+}
+
+// --request-api: control settings per frame, the frames separated by ';'
+// and the settings of a frame by ','
+#[cfg(feature = "request-api")]
+fn parse_schedule(arg: &str, s: &str) -> Result<Vec<Vec<(String, String)>>, ParseError> {
+    s.split(';')
+        .map(|frame| {
+            frame
+                .split(',')
+                .map(|setting| parse_setting(arg, setting))
+                .collect()
+        })
+        .collect()
+}
+
+// Group id from a numeric id or a group name
+fn parse_group(s: &str) -> Result<u32, ParseError> {
+    if let Ok(gid) = s.parse() {
+        return Ok(gid);
+    }
+    match Group::from_name(s) {
+        Ok(Some(group)) => Ok(group.gid.as_raw()),
+        _ => Err(ParseError::new("group", s, "no such group")),
+    }
+}
+
+fn usage(prog: &str) -> ! {
+    eprintln!(
+        "Usage: {} /dev/videoX outfile [width height framerate pixelformat max_frames] [options]",
+        prog
+    );
+    eprintln!(
+        "       {} --by-name NAME|--by-serial SERIAL|--source-file PATH outfile [...]",
+        prog
+    );
+    eprintln!("       {} --list-devices [--json]", prog);
+    eprintln!("       {} --list-controls [--json] [device]", prog);
+    eprintln!("       {} --format-table [--json] [device]", prog);
+    eprintln!("       {} --env-report [--json] [device]", prog);
+    eprintln!("       {} --list-all [--json] [device]", prog);
+    eprintln!("       {} --benchmark-formats [--json] [device]", prog);
+    eprintln!("       {} --self-test [device]", prog);
+    eprintln!("       {} --circular-dump PATH > frames", prog);
+    eprintln!("Options:");
+    eprintln!("  --config PATH         read settings from a TOML file, e.g. buffers = 8;");
+    eprintln!("                        command line options override them");
+    eprintln!("  --pixelformat FOURCC[,FOURCC...]  as the positional pixelformat, which also");
+    eprintln!("                        takes a list: the first the device accepts exactly");
+    eprintln!("  --resolution-ladder WxH,WxH,...  use the first resolution the device supports");
+    eprintln!("  --no-set-format       stream with the format and frame rate the device already");
+    eprintln!("                        has, leaving another client's settings untouched");
+    eprintln!("  --raw-ioctl           refuse to run with libv4l preloaded (LD_PRELOAD), so");
+    eprintln!("                        that formats and frames are the hardware's own");
+    eprintln!("  --buffers COUNT       capture buffers to allocate (default 4)");
+    eprintln!("  --queue-depth COUNT   buffers kept queued in the driver (default: all);");
+    eprintln!("                        fewer means lower latency but earlier frame drops");
+    eprintln!("  --timestamp-filename  expand %Y %m %d %H %M %S (start time), %D (device),");
+    eprintln!("                        %F (fourcc) and %% in outfile, again for each new file");
+    eprintln!("  --rotate-daily        start a new file (outfile_YYYYMMDD) at local midnight");
+    eprintln!("  --rotate-size BYTES   start a new file when the current one reaches BYTES");
+    eprintln!("  --log-file PATH       append what is printed on stderr to PATH as well");
+    eprintln!("  --log-rotate-size BYTES  move a log of BYTES to PATH.1 (and on to PATH.3)");
+    eprintln!("                        and start a new one");
+    eprintln!("  --frames-per-file N   start a new numbered file (outfile_NNNN) every N frames");
+    eprintln!("  --segment-on-keyframe  start a new numbered file at every keyframe of");
+    eprintln!("                        compressed frames, named by the time instead with");
+    eprintln!("                        --timestamp-filename; H.264 ones get SPS/PPS in front");
+    eprintln!(
+        "  --ring-files COUNT    write segments to COUNT files in turn, SIGUSR1 freezes them"
+    );
+    eprintln!("  --start-paused        stream but discard frames until SIGUSR1, then write;");
+    eprintln!("                        with --ring-files the next SIGUSR1 stops");
+    eprintln!("  --ring-frames FRAMES  frames per ring file (default 300)");
+    eprintln!("  --control-socket PATH  take commands on a unix socket, a line each, between");
+    eprintln!("                        frames: start, stop, snapshot, set-ctrl NAME=VALUE,");
+    eprintln!("                        reconfigure, stats, quit; answered by an ok/error line");
+    eprintln!("  --snapshot-dir DIR    where SIGUSR2 saves a still of the next frame (default .)");
+    eprintln!("  --snapshot-format jpeg|png  png decodes MJPEG stills (needs the png feature)");
+    eprintln!("  --select-timeout MS   wake up after MS without a frame to check for shutdown");
+    eprintln!("  --throttle-sleep MS   sleep MS after each frame to save CPU; this also lowers");
+    eprintln!("                        the frame rate, as frames arriving meanwhile are dropped");
+    eprintln!("  --dedup               skip frames identical to the previous one");
+    eprintln!("  --dedup-sample BYTES  bytes hashed per frame for --dedup (0: all, default 4096)");
+    eprintln!("  --file-mode OCTAL     permissions of created output files, e.g. 0640");
+    eprintln!("  --file-group GROUP    group (name or id) of created output files");
+    eprintln!("  --atomic-output       write files and snapshots as NAME.tmp, renamed to NAME");
+    eprintln!("                        once complete; a failed capture leaves the .tmp");
+    eprintln!("  --delete-incomplete   with --atomic-output, delete the .tmp of a failed capture");
+    eprintln!("  --fsync-interval SECONDS  flush output files to disk at least this often");
+    eprintln!("  --buffered-output     buffer writes to regular files (1 MiB)");
+    eprintln!("  --buffer-capacity BYTES  capacity for --buffered-output");
+    eprintln!("  --mmap-output         write regular output files through a memory mapping");
+    eprintln!(
+        "  --fix-mjpeg-dht       insert standard Huffman tables into MJPEG frames lacking them"
+    );
+    eprintln!("  --trim-mjpeg          drop the padding after the EOI marker of MJPEG frames");
+    eprintln!("  --repeat-headers SECONDS  put the H.264 SPS/PPS in front of a keyframe at");
+    eprintln!("                        least this often, for consumers joining late");
+    eprintln!("  --keyframe-interval FRAMES  make the H.264 encoder (the camera, or the");
+    eprintln!("                        --m2m-device) send a keyframe every FRAMES frames");
+    eprintln!("  --m2m-device PATH     send every frame through the mem2mem device at PATH,");
+    eprintln!("                        e.g. a hardware scaler or encoder, and write its results");
+    eprintln!("  --m2m-out-fourcc FOURCC  format the --m2m-device converts to, e.g. RGB3");
+    eprintln!("                        (default: the captured one)");
+    eprintln!("  --m2m-out-buffers N   buffers on the input queue of the --m2m-device (default 2)");
+    eprintln!("  --m2m-cap-buffers N   buffers on its result queue (default 2); both have to");
+    eprintln!("                        be at least the minimum the driver reports");
+    eprintln!("  --clock monotonic|realtime  clock for arrival timestamps in the frame log");
+    eprintln!("  --max-frame-size BYTES  drop frames larger than BYTES (default: sizeimage + 1/8)");
+    eprintln!("  --abort-on-oversize   stop instead of dropping oversized frames");
+    eprintln!("  --by-name NAME        capture from the camera with this card name");
+    eprintln!("  --by-serial SERIAL    capture from the camera with this USB serial or bus info");
+    eprintln!("  --companion DEVICE:OUTPUT  also record another node of the same sensor, e.g.");
+    eprintln!("                        its encoder output, in the format it is set to");
+    eprintln!("  --meta-capture DEVICE[:OUTPUT]  record the metadata node DEVICE alongside, to");
+    eprintln!("                        OUTPUT (default outfile.meta) as sequence, length, data");
+    eprintln!("  --sync-start          start streaming on the device and the companions");
+    eprintln!("                        together, reporting how far apart they started");
+    eprintln!("  --sync-start-at UNIXTIME  with --sync-start, start at this wall clock time");
+    eprintln!("  --motion-threshold T  write only while the mean luma change per sampled pixel");
+    eprintln!("                        (0-255) from one frame to the next is above T");
+    eprintln!("  --gate-file PATH      write frames only while PATH holds 1, true, on, yes or");
+    eprintln!("                        high, e.g. a GPIO value in sysfs; read for every frame");
+    eprintln!("  --power on|suspend    wake a camera with a standby control before the capture;");
+    eprintln!("                        with suspend put it into standby while the gate is");
+    eprintln!("                        closed and after the capture");
+    eprintln!("  --motion-pre FRAMES   frames kept from before the motion (default 15)");
+    eprintln!("  --motion-post FRAMES  frames written after it stopped (default 30)");
+    eprintln!("  --gst-pipeline DESC   feed the frames to the GStreamer pipeline DESC instead");
+    eprintln!("                        of outfile (needs the gst feature and gst-launch-1.0)");
+    eprintln!("  --png-sequence DIR    decode MJPEG frames into DIR/frame_00000000.png and on");
+    eprintln!("                        instead of outfile, skipping undecodable ones (png");
+    eprintln!("                        feature); DIR/manifest.json lists the files");
+    eprintln!("  --split-frames DIR    save every frame as it is in DIR/frame_00000000.EXT and");
+    eprintln!("                        on instead of outfile, listed in DIR/manifest.json");
+    eprintln!("  --circular-file PATH  write frames into PATH of --circular-size BYTES as a");
+    eprintln!("                        ring instead of outfile, overwriting the oldest, or");
+    eprintln!("                        carry on the ring there; --circular-dump reads it out");
+    eprintln!("  --request-api SCHEDULE  per-frame controls through media requests, frames");
+    eprintln!("                        separated by ';', e.g. \"exposure_time_absolute=100;");
+    eprintln!("                        exposure_time_absolute=400\", repeated (request-api");
+    eprintln!("                        feature)");
+    eprintln!("  --media-device PATH   media node for --request-api (default: from sysfs)");
+    eprintln!("  --software-crop X,Y,W,H  keep only this part of raw frames, cut out in");
+    eprintln!("                        software for devices without cropping");
+    eprintln!("  --use-plane N         write only plane N of planar frames, e.g. 0 for the");
+    eprintln!("                        luma of NV12 as GREY, without row padding");
+    eprintln!("  --convert yuyv-uyvy   swap luma and chroma bytes of packed 4:2:2 frames,");
+    eprintln!("                        YUYV <-> UYVY and YVYU <-> VYUY");
+    eprintln!("  --timestamp-overlay   burn the local capture time into the luma of raw YUV");
+    eprintln!("                        and GREY frames");
+    eprintln!("  --frame-counter-overlay  burn the number of the frame, counted from 0, into");
+    eprintln!("                        them likewise, after the time with both");
+    eprintln!("  --overlay-sequence    draw the driver sequence number after the frame");
+    eprintln!("                        number, as NUMBER:SEQUENCE");
+    eprintln!("  --overlay-corner top-left|top-right|bottom-left|bottom-right  where the");
+    eprintln!("                        overlay goes (default top-left)");
+    eprintln!("  --y16-endianness little|big  byte order of Y16 samples from the camera;");
+    eprintln!("                        big endian ones are written little endian as Y16 is");
+    eprintln!("  --strip-padding       drop the padding the driver adds after the rows of raw");
+    eprintln!("                        frames (bytesperline beyond the pixels), so that they");
+    eprintln!("                        are written tightly packed");
+    eprintln!("  --byteswap none|16|32  reverse the bytes of every 16 or 32 bit word of raw");
+    eprintln!("                        frames before writing, for consumers of the other");
+    eprintln!("                        byte order");
+    eprintln!("  --reorder-depth N     hold up to N frames back and write them in timestamp");
+    eprintln!("                        order; frames older than ones written are dropped");
+    eprintln!("  --decouple            write in a thread of its own from a queue of frame");
+    eprintln!("                        copies, dropping the oldest when the writer falls behind");
+    eprintln!("  --decouple-depth N    frames the --decouple queue holds (default 8)");
+    eprintln!("  --adaptive-rate       with --decouple, ask the camera for a lower frame rate");
+    eprintln!("                        while frames keep being dropped from the full queue,");
+    eprintln!("                        and for a higher one again once the writer keeps up");
+    eprintln!("  --verify-output       read the output back after a clean exit and check its");
+    eprintln!("                        size and frame count, exit 1 if they are off");
+    eprintln!("  --assert-fps TARGET   at exit, exit 1 unless the frame rate measured from the");
+    eprintln!("                        driver timestamps is within the tolerance of TARGET");
+    eprintln!("  --assert-tolerance PCT  allowed deviation from --assert-fps in percent");
+    eprintln!("                        (default 5)");
+    eprintln!("  --null-output         process and count the frames but write them nowhere;");
+    eprintln!("                        outfile is not opened, for benchmarks and liveness checks");
+    eprintln!("  --usb-bandwidth       estimate the byte rate of the mode against the USB bus");
+    eprintln!("                        speed, and at the end against what came through");
+    eprintln!("  --exit-on-consumer-close  stop as soon as the reader of the output pipe exits,");
+    eprintln!("                        not only at the next write");
+    eprintln!("  --sequence-offset N   add N to the frame sequence numbers everywhere they are");
+    eprintln!("                        shown or written, to continue the numbering of a run");
+    eprintln!("  --summary-json PATH   write a JSON report of the run to PATH at the end");
+    eprintln!("  --rt-priority N       capture with SCHED_FIFO at priority N (1-99)");
+    eprintln!("  --tee PATH            write the frames to PATH as well (may be repeated);");
+    eprintln!("                        pipes share the data through tee(2) instead of copies");
+    eprintln!("  --fill-on-stall repeat|black  when a frame is half an interval late, write");
+    eprintln!("                        the last one again or a black frame in its place");
+    eprintln!("  --strict-format       fail if the driver changes size, format or frame rate,");
+    eprintln!("                        naming the nearest mode it supports");
+    eprintln!("  --format-retries N    retry setting the format and frame rate N times while");
+    eprintln!("                        the device is busy (EBUSY), e.g. just released by");
+    eprintln!("                        another process (default 3)");
+    eprintln!("  --format-retry-delay MS  wait before the first retry, doubled for each one");
+    eprintln!("                        after it (default 100)");
+    eprintln!("  --list-devices        list the capture devices and exit, with --json as JSON");
+    eprintln!("                        with their formats, frame sizes and frame rates");
+    eprintln!("  --list-controls       list the controls of the device and exit, likewise");
+    eprintln!("  --format-table        list every format and size of the device with the");
+    eprintln!("                        frame rates it reaches there and exit, likewise");
+    eprintln!("  --env-report          print the kernel, driver and USB firmware versions for");
+    eprintln!("                        bug reports and exit, likewise");
+    eprintln!("  --list-all            all of the above plus the inputs and TV standards of");
+    eprintln!("                        the device, noting what it does not support");
+    eprintln!("  --self-test           capture from vivid (or the device) and run the frames");
+    eprintln!("                        through the converters and writers, PASS/FAIL each");
+    eprintln!("  --set-ctrl NAME=VALUE set a control (name as listed, or id) before capturing;");
+    eprintln!("                        all of them are applied together or not at all");
+    eprintln!("  --subscribe-event NAME  log the V4L2 events NAME with their payload:");
+    eprintln!("                        vsync, eos, ctrl (of every control), frame-sync,");
+    eprintln!("                        source-change, motion-det or a driver's event number;");
+    eprintln!("                        may be repeated");
+    eprintln!("  --first-match         take the first camera if several match");
+    eprintln!("  --source-file PATH    replay a raw capture file instead of the device, using");
+    eprintln!("                        width/height/pixelformat for its geometry");
+    eprintln!("  --source-framing raw|pts|pts-crc  pts: the source file was written with");
+    eprintln!("                        --framing pts, replay it with the original frame spacing");
+    eprintln!("  --source-corrupt abort|skip  on a truncated record or a CRC mismatch in the");
+    eprintln!("                        source file, stop with an error (default) or go on");
+    eprintln!("  --repeat-last-on-eof  at the end of the source file, go on with its last");
+    eprintln!("                        frame at the frame rate until max_frames or a signal");
+    eprintln!("  --framing raw|pts|pts-crc|base64  pts: prefix each frame with its timestamp");
+    eprintln!("                        and length, pts-crc: and the CRC-32 of the frame;");
+    eprintln!("                        base64: a \"SEQ BASE64\" text line per frame, only as a");
+    eprintln!("                        debug transport for text-only channels");
+    eprintln!("  --keep-error-frames   write buffers the driver flags ERROR too, which are");
+    eprintln!("                        skipped and counted by default");
+    eprintln!("  --once-per-key        H.264 only: write just the keyframe starting each");
+    eprintln!("                        keyframe interval, a timelapse at the GOP cadence");
+    eprintln!("  --pad-to BYTES        write fixed size records: the frame length (u32 LE),");
+    eprintln!("                        the frame and zeros up to BYTES; longer frames fail");
+    eprintln!("  --frame-log PATH      write frame,index,pts,size,keyframe lines for each");
+    eprintln!("                        written frame, to compare with ffprobe");
+    eprintln!("  --pts-file PATH       write the timestamp of each written frame on a line,");
+    eprintln!("                        for muxing the raw output elsewhere");
+    eprintln!("  --pts-unit ns|us|ms|90k  unit of the --pts-file timestamps (default us)");
+    eprintln!("  --jpeg-quality        estimate the quality factor of every MJPEG frame from");
+    eprintln!("                        its luma table, as a --frame-log column or printed");
+    eprintln!("  --zero-base-pts       count the buffer timestamps from the first frame written,");
+    eprintln!("                        in --framing pts records as everywhere else");
+    eprintln!("  --pts-base camera|wallclock|sequence  timestamp of written frames, given");
+    eprintln!("                        to the writers in place of the buffer timestamp");
+    eprintln!("  --loopback-timestamp camera|wallclock|sequence  timestamps the readers of an");
+    eprintln!("                        output device get: of the frame, of the time of day");
+    eprintln!("                        it was queued (CLOCK_REALTIME) or at the nominal rate");
+    eprintln!("                        (default: the driver's)");
+    eprintln!("  --require-caps LIST   fail unless the device has these capabilities, e.g.");
+    eprintln!("                        capture,streaming (also output, m2m, meta-capture, ...)");
+    eprintln!("  --nonblocking         keep the device fd O_NONBLOCK, DQBUF retries after poll()");
+    eprintln!("                        on EAGAIN (default: blocking DQBUF)");
+    eprintln!("  --no-cloexec          leave the device and output fds open across exec, for");
+    eprintln!("                        children meant to inherit them");
+    eprintln!("  --userptr             capture into buffers allocated here (USERPTR I/O)");
+    eprintln!("  --userptr-align BYTES  align the user buffers to BYTES, a power of two");
+    eprintln!("                        multiple of the page size (implies --userptr)");
+    eprintln!("  --userptr-hugepages   allocate the user buffers from huge pages, which must be");
+    eprintln!("                        reserved in /proc/sys/vm/nr_hugepages (implies --userptr)");
+    eprintln!("  --buffer-cache coherent|cached  ask for coherent or CPU cached mmap buffers,");
+    eprintln!("                        where the driver takes cache hints (default: its own)");
+    eprintln!("  --numa-node N         run on the CPUs of NUMA node N and allocate its memory,");
+    eprintln!("                        the --userptr buffers included, there");
+    eprintln!("  --priority background|interactive|record  access priority on the device");
+    eprintln!("  --heartbeat-file PATH  write the frame count to PATH every second");
+    eprintln!("  --duration SECONDS    stop after SECONDS, switching the stream off to get out");
+    eprintln!("                        of waiting for a frame if the camera hangs");
+    eprintln!("  --max-open-time SECONDS  close the device within SECONDS of opening it,");
+    eprintln!("                        stopping the capture a second early, exiting if stuck");
+    eprintln!("  --recheck-format-interval SECONDS  read the format back this often and warn");
+    eprintln!("                        if the driver changed it (stop with --strict-format)");
+    eprintln!("  --stats-interval SECONDS  print frame counts and interval jitter this often");
+    eprintln!("  --copy-mode auto|always|never  write() or vmsplice() frames into a pipe");
+    eprintln!("  --load-edid PATH      set the EDID of an HDMI receiver from a binary file");
+    eprintln!("  --dump-edid PATH      save the current EDID to a file");
+    eprintln!("  --query-dv-timings    print the timings detected on an HDMI input");
+    eprintln!("  --set-dv-timings      apply the detected timings and capture at their size");
+    eprintln!("  --size-histogram      print a histogram of frame sizes at exit");
+    eprintln!("  --histogram-bucket BYTES  bucket width for --size-histogram (default 16384)");
+    eprintln!("  --flag-histogram      print how many frames had each buffer flag (KEYFRAME,");
+    eprintln!("                        PFRAME, BFRAME, ERROR, ...) at exit");
+    eprintln!("  --events-fd FD        write session events as key=value lines to FD");
+    eprintln!("  --print-caps          print the negotiated format as GStreamer caps, e.g.");
+    eprintln!("                        video/x-raw,format=YUY2,width=640,height=480,");
+    eprintln!("                        framerate=30/1, and exit");
+    eprintln!("  --probe-frame         describe the first frame in detail and exit,");
+    eprintln!("                        without touching outfile");
+    eprintln!("  --preview PATH        also write every frame to PATH, e.g. a preview's FIFO");
+    eprintln!("  --preview-scale DIVISOR  shrink raw frames for --preview by DIVISOR, the");
+    eprintln!("                        recording keeps the full size");
+    eprintln!("  --measure-startup     print the time from device open to the first valid");
+    eprintln!("                        frame, step by step, and exit");
+    eprintln!("  --dump-buffers        (debug) print the address, length and page alignment");
+    eprintln!("                        of each mapped buffer");
+    eprintln!("  --max-consecutive-errors N  skip frames that fail or are flagged corrupt, and");
+    eprintln!("                        only give up (exit status 1) at N errors in a row");
+    eprintln!("  --device-reset        instead of giving up on frame errors, log the driver");
+    eprintln!("                        state (VIDIOC_LOG_STATUS), reopen the device and go on;");
+    eprintln!("                        once more only after a good frame");
+    eprintln!("  --mode fail-fast|resilient  default for the options on frame errors:");
+    eprintln!("                        fail-fast ends the capture at the first frame error,");
+    eprintln!("                        flagged corrupt or oversized too; resilient skips");
+    eprintln!("                        frames in error, {RESILIENT_MAX_ERRORS} in a row at most, resets the");
+    eprintln!("                        device and reads files on past damaged records");
+    eprintln!("  --no-requeue-on-error  (debug) stop at the first frame error and print the");
+    eprintln!("                        buffer state instead of carrying on");
+    eprintln!("  --assemble-fields     weave alternate top/bottom field buffers into frames");
+    eprintln!("  --deinterlace none|bob|weave  bob: keep the top field of raw frames (or each");
+    eprintln!("                        field buffer) and interpolate the other rows;");
+    eprintln!("                        weave: as --assemble-fields (default none)");
+    eprintln!("  --debayer none|bilinear  demosaic 8-bit Bayer frames (BA81, GBRG, GRBG, RGGB)");
+    eprintln!("                        to RGB3 (default none)");
+    eprintln!("  --verbose             print extra diagnostics, e.g. the field of each buffer");
+    exit(1);
+}
+
+// Parse the command line, exiting with a message if it is malformed
+pub fn parse_args(args: &[String]) -> Options {
+    try_parse_args(args).unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        exit(1);
+    })
+}
+
+// Split arguments into positional ones and `--name [value]` options
+fn try_parse_args(args: &[String]) -> Result<Options, ParseError> {
+    let prog = args.first().map(String::as_str).unwrap_or("v4l2capture-rs");
+    let mut positional: Vec<&String> = Vec::new();
+    let mut opts = Options::default();
+
+    if let Some(i) = args.iter().position(|a| a == "--config") {
+        let path = args
+            .get(i + 1)
+            .ok_or_else(|| ParseError::new("--config", "", "missing value"))?;
+        apply_config(&mut opts, path)?;
+    }
+
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        if !arg.starts_with("--") {
+            positional.push(arg);
+            continue;
+        }
+        let mut value = |name: &str| -> Result<&String, ParseError> {
+            iter.next()
+                .ok_or_else(|| ParseError::new(name, "", "missing value"))
+        };
+        if arg == "--config" {
+            value(arg)?;
+        } else if !apply_option(&mut opts, arg, value)? {
+            eprintln!("Unknown option: {}", arg);
+            usage(prog);
+        }
+    }
+    apply_mode(&mut opts);
+
+    // Nothing to capture
+    if opts.list_devices || opts.circular_dump.is_some() {
+        return Ok(opts);
+    }
+    // Only the device is needed
+    if opts.self_test {
+        opts.devname = positional
+            .first()
+            .map_or(String::new(), |dev| dev.to_string());
+        return Ok(opts);
+    }
+    if opts.list_controls
+        || opts.format_table
+        || opts.env_report
+        || opts.list_all
+        || opts.benchmark_formats
+    {
+        opts.devname = positional
+            .first()
+            .map_or("/dev/video0".to_string(), |dev| dev.to_string());
+        return Ok(opts);
+    }
+    // The device is not named on the command line when it is looked up or
+    // replaced by a file
+    let no_device = String::new();
+    if opts.selector.is_some() || opts.source_file.is_some() {
+        positional.insert(0, &no_device);
+    }
+    if positional.len() < 2 {
+        usage(prog);
+    }
+    opts.devname = positional[0].clone();
+    opts.out_file = positional[1].clone();
+    if let Some((_, output)) = opts.meta_capture.as_mut().filter(|(_, o)| o.is_empty()) {
+        *output = format!("{}.meta", opts.out_file);
+    }
+    // Nothing gets written, leave the file alone
+    if opts.probe_frame || opts.measure_startup || opts.print_caps {
+        opts.out_file = "/dev/null".to_string();
+    }
+    if positional.len() >= 3 {
+        opts.width = parse::nonzero("width", positional[2])?;
+    }
+    if positional.len() >= 4 {
+        opts.height = parse::nonzero("height", positional[3])?;
+    }
+    if positional.len() >= 5 {
+        opts.framerate = parse::nonzero("framerate", positional[4])?;
+    }
+    if positional.len() >= 6 {
+        set_pixelformats(&mut opts, parse::fourcc_list(positional[5])?);
+    }
+    if positional.len() >= 7 {
+        opts.max_frames = parse::number("max_frames", positional[6])?;
+    }
+    Ok(opts)
+}
+
+// The defaults of --mode, under the options given
+fn apply_mode(opts: &mut Options) {
+    match opts.mode {
+        Some(Mode::FailFast) => opts.abort_on_oversize = true,
+        Some(Mode::Resilient) => {
+            opts.max_consecutive_errors
+                .get_or_insert(RESILIENT_MAX_ERRORS);
+            opts.device_reset = true;
+            opts.source_corrupt.get_or_insert(OnCorrupt::Skip);
+        }
+        None => {}
+    }
+}
+
+fn set_pixelformats(opts: &mut Options, list: Vec<[u8; 4]>) {
+    opts.pixelformat = list[0];
+    opts.pixelformats = list;
+}
+
+// Settings from a config file, applied before the command line so that the
+// command line wins. Keys are option names without the dashes, plus the
+// positional width, height, framerate, pixelformat and max_frames.
+fn apply_config(opts: &mut Options, path: &str) -> Result<(), ParseError> {
+    for entry in config::load(path)? {
+        let key = entry.key.replace('_', "-");
+        let text = match &entry.value {
+            Value::Str(s) => s.clone(),
+            Value::Int(n) => n.to_string(),
+            Value::Bool(b) => b.to_string(),
+        };
+        let in_file =
+            |e: ParseError| ParseError::new("config", path, format!("line {}: {e}", entry.line));
+        // [controls] holds what --set-ctrl sets, one control a line
+        match entry.table.as_deref() {
+            Some("controls") => {
+                opts.set_ctrls.push((entry.key.clone(), text));
+                continue;
+            }
+            Some(table) => {
+                return Err(in_file(ParseError::new(
+                    "table",
+                    table,
+                    "unknown, only [controls] is supported",
+                )))
+            }
+            None => {}
+        }
+        match key.as_str() {
+            "width" => opts.width = parse::nonzero("width", &text).map_err(in_file)?,
+            "height" => opts.height = parse::nonzero("height", &text).map_err(in_file)?,
+            "framerate" => opts.framerate = parse::nonzero("framerate", &text).map_err(in_file)?,
+            "pixelformat" | "fourcc" => {
+                set_pixelformats(opts, parse::fourcc_list(&text).map_err(in_file)?)
+            }
+            "max-frames" => {
+                opts.max_frames = parse::number("max_frames", &text).map_err(in_file)?
+            }
+            _ => {
+                let arg = format!("--{key}");
+                let mut taken = false;
+                let value = |name: &str| {
+                    taken = true;
+                    match entry.value {
+                        Value::Bool(_) => {
+                            Err(ParseError::new(name, "", "needs a value, not a boolean"))
+                        }
+                        _ => Ok(&text),
+                    }
+                };
+                // A false flag is the default, only check that it exists
+                let known = match entry.value {
+                    Value::Bool(false) => apply_option(&mut Options::default(), &arg, value),
+                    _ => apply_option(opts, &arg, value),
+                }
+                .map_err(in_file)?;
+                if !known || key == "config" {
+                    eprintln!(
+                        "warning: {path} line {}: unknown setting {:?}",
+                        entry.line, entry.key
+                    );
+                } else if !taken && !matches!(entry.value, Value::Bool(_)) {
+                    return Err(in_file(ParseError::new(
+                        &arg,
+                        "",
+                        "is a flag, set it to true or false",
+                    )));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Set one `--name` option, taking its value from `value` if it has one.
+// Returns false for unknown options.
+fn apply_option<'a>(
+    opts: &mut Options,
+    arg: &str,
+    mut value: impl FnMut(&str) -> Result<&'a String, ParseError>,
+) -> Result<bool, ParseError> {
+    match arg {
+        "--pixelformat" => set_pixelformats(opts, parse::fourcc_list(value(arg)?)?),
+        "--resolution-ladder" => {
+            opts.resolution_ladder = value(arg)?
+                .split(',')
+                .map(parse::resolution)
+                .collect::<Result<_, _>>()?
+        }
+        "--require-caps" => {
+            for name in value(arg)?.split(',') {
+                opts.require_caps |= device::capability(name).ok_or_else(|| {
+                    ParseError::new(arg, name, "unknown capability, try capture,streaming")
+                })?;
+            }
+        }
+        "--nonblocking" => opts.nonblocking = true,
+        "--select-timeout" => {
+            opts.select_timeout = Some(Duration::from_millis(parse::nonzero(arg, value(arg)?)?))
+        }
+        "--no-cloexec" => opts.no_cloexec = true,
+        "--userptr" => opts.userptr = true,
+        "--userptr-align" => {
+            let align: usize = parse::nonzero(arg, value(arg)?)?;
+            let page = pipe::page_size();
+            if !align.is_power_of_two() || !align.is_multiple_of(page) {
+                return Err(ParseError::new(
+                    arg,
+                    &align.to_string(),
+                    format!("not a power of two multiple of the page size ({page})"),
+                ));
+            }
+            opts.userptr = true;
+            opts.userptr_align = Some(align);
+        }
+        "--numa-node" => opts.numa_node = Some(parse::number(arg, value(arg)?)?),
+        "--buffer-cache" => {
+            opts.buffer_cache = Some(parse::keyword(arg, value(arg)?, BufferCache::parse)?)
+        }
+        "--userptr-hugepages" => {
+            opts.userptr = true;
+            opts.userptr_hugepages = true;
+        }
+        "--buffers" => opts.buffers = parse::number(arg, value(arg)?)?,
+        "--queue-depth" => opts.queue_depth = Some(parse::number(arg, value(arg)?)?),
+        "--atomic-output" => opts.atomic_output = true,
+        "--delete-incomplete" => opts.delete_incomplete = true,
+        "--timestamp-filename" => opts.timestamp_filename = true,
+        "--rotate-daily" => opts.rotate_daily = true,
+        "--rotate-size" => opts.rotate_size = Some(parse::number(arg, value(arg)?)?),
+        "--log-file" => opts.log_file = Some(value(arg)?.clone()),
+        "--log-rotate-size" => opts.log_rotate_size = Some(parse::nonzero(arg, value(arg)?)?),
+        "--frames-per-file" => opts.frames_per_file = Some(parse::nonzero(arg, value(arg)?)?),
+        "--segment-on-keyframe" => opts.segment_on_keyframe = true,
+        "--ring-files" => {
+            let files: u32 = parse::number(arg, value(arg)?)?;
+            opts.ring_files = Some(files.max(1))
+        }
+        "--start-paused" => opts.start_paused = true,
+        "--ring-frames" => {
+            let frames: u64 = parse::number(arg, value(arg)?)?;
+            opts.ring_frames = frames.max(1)
+        }
+        "--snapshot-format" => {
+            opts.snapshot_format = parse::keyword(arg, value(arg)?, SnapshotFormat::parse)?
+        }
+        "--snapshot-dir" => opts.snapshot_dir = value(arg)?.clone(),
+        "--dedup" => opts.dedup = true,
+        "--dedup-sample" => opts.dedup_sample = parse::number(arg, value(arg)?)?,
+        "--file-mode" => opts.file_mode = Some(parse::octal(arg, value(arg)?)?),
+        "--file-group" => opts.file_group = Some(parse_group(value(arg)?)?),
+        "--buffered-output" => {
+            opts.buffer_capacity.get_or_insert(DEFAULT_BUFFER_CAPACITY);
+        }
+        "--buffer-capacity" => opts.buffer_capacity = Some(parse::number(arg, value(arg)?)?),
+        "--mmap-output" => opts.mmap_output = true,
+        "--size-histogram" => {
+            opts.size_histogram.get_or_insert(DEFAULT_HISTOGRAM_BUCKET);
+        }
+        "--flag-histogram" => opts.flag_histogram = true,
+        "--histogram-bucket" => opts.size_histogram = Some(parse::number(arg, value(arg)?)?),
+        "--events-fd" => opts.events_fd = Some(parse::number(arg, value(arg)?)?),
+        "--fsync-interval" => {
+            opts.fsync_interval = Some(Duration::from_secs(parse::nonzero(arg, value(arg)?)?))
+        }
+        "--probe-frame" => opts.probe_frame = true,
+        "--print-caps" => opts.print_caps = true,
+        "--measure-startup" => opts.measure_startup = true,
+        "--list-devices" => opts.list_devices = true,
+        "--list-controls" => opts.list_controls = true,
+        "--format-table" => opts.format_table = true,
+        "--env-report" => opts.env_report = true,
+        "--list-all" => opts.list_all = true,
+        "--benchmark-formats" => opts.benchmark_formats = true,
+        "--self-test" => opts.self_test = true,
+        "--set-ctrl" => opts.set_ctrls.push(parse_setting(arg, value(arg)?)?),
+        "--subscribe-event" => {
+            let kind = parse::keyword(arg, value(arg)?, EventKind::parse)?;
+            opts.subscribe_events.push(kind);
+        }
+        "--strict-format" => opts.strict_format = true,
+        "--format-retries" => opts.format_retries = parse::number(arg, value(arg)?)?,
+        "--format-retry-delay" => {
+            opts.format_retry_delay = Duration::from_millis(parse::nonzero(arg, value(arg)?)?)
+        }
+        "--tee" => opts.tees.push(value(arg)?.clone()),
+        "--summary-json" => opts.summary_json = Some(value(arg)?.clone()),
+        "--verify-output" => opts.verify_output = true,
+        "--assert-fps" => {
+            let v = value(arg)?;
+            let fps: f64 = parse::number(arg, v)?;
+            if !(fps > 0.0 && fps.is_finite()) {
+                return Err(ParseError::new(arg, v, "must be a positive frame rate"));
+            }
+            opts.assert_fps = Some(fps);
+        }
+        "--assert-tolerance" => {
+            let v = value(arg)?;
+            opts.assert_tolerance = parse::number(arg, v)?;
+            if opts.assert_tolerance.is_nan() || opts.assert_tolerance < 0.0 {
+                return Err(ParseError::new(arg, v, "must not be negative"));
+            }
+        }
+        "--null-output" => opts.null_output = true,
+        "--usb-bandwidth" => opts.usb_bandwidth = true,
+        "--exit-on-consumer-close" => opts.exit_on_consumer_close = true,
+        "--sequence-offset" => opts.sequence_offset = parse::number(arg, value(arg)?)?,
+        "--use-plane" => opts.use_plane = Some(parse::number(arg, value(arg)?)?),
+        "--software-crop" => {
+            let s = value(arg)?;
+            let v: Vec<&str> = s.split(',').collect();
+            let [x, y, width, height] = v[..] else {
+                return Err(ParseError::new(arg, s, "expected X,Y,W,H"));
+            };
+            opts.software_crop = Some(Rect {
+                x: parse::number(arg, x)?,
+                y: parse::number(arg, y)?,
+                width: parse::nonzero(arg, width)?,
+                height: parse::nonzero(arg, height)?,
+            });
+        }
+        "--reorder-depth" => opts.reorder_depth = Some(parse::nonzero(arg, value(arg)?)?),
+        "--decouple" => opts.decouple = true,
+        "--decouple-depth" => opts.decouple_depth = parse::nonzero(arg, value(arg)?)?,
+        "--adaptive-rate" => opts.adaptive_rate = true,
+        "--y16-endianness" => {
+            let endianness = |s: &str| match s {
+                "little" => Some(false),
+                "big" => Some(true),
+                _ => None,
+            };
+            opts.y16_big_endian = parse::keyword(arg, value(arg)?, endianness)?
+        }
+        "--byteswap" => {
+            let word = |s: &str| match s {
+                "none" => Some(None),
+                "16" => Some(Some(2)),
+                "32" => Some(Some(4)),
+                _ => None,
+            };
+            opts.byteswap = parse::keyword(arg, value(arg)?, word)?
+        }
+        "--timestamp-overlay" => opts.timestamp_overlay = true,
+        "--frame-counter-overlay" => opts.frame_counter_overlay = true,
+        "--overlay-sequence" => opts.overlay_sequence = true,
+        "--overlay-corner" => {
+            opts.overlay_corner = parse::keyword(arg, value(arg)?, Corner::parse)?
+        }
+        "--convert" => opts.convert = Some(parse::keyword(arg, value(arg)?, Convert::parse)?),
+        "--rt-priority" => {
+            let s = value(arg)?;
+            let priority = parse::number(arg, s)?;
+            if !(1..=99).contains(&priority) {
+                return Err(ParseError::new(arg, s, "must be 1 to 99"));
+            }
+            opts.rt_priority = Some(priority);
+        }
+        #[cfg(feature = "gst")]
+        "--gst-pipeline" => opts.gst_pipeline = Some(value(arg)?.clone()),
+        #[cfg(feature = "png")]
+        "--png-sequence" => opts.png_sequence = Some(value(arg)?.clone()),
+        "--split-frames" => opts.split_frames = Some(value(arg)?.clone()),
+        "--circular-file" => opts.circular_file = Some(value(arg)?.clone()),
+        "--circular-size" => {
+            let size: u64 = parse::nonzero(arg, value(arg)?)?;
+            if size <= circular::HEADER_LEN + circular::RECORD_LEN {
+                return Err(ParseError::new(
+                    arg,
+                    &size.to_string(),
+                    "no room for a frame",
+                ));
+            }
+            opts.circular_size = Some(size);
+        }
+        "--circular-dump" => opts.circular_dump = Some(value(arg)?.clone()),
+        #[cfg(feature = "request-api")]
+        "--request-api" => opts.request_schedule = parse_schedule(arg, value(arg)?)?,
+        #[cfg(feature = "request-api")]
+        "--media-device" => opts.media_device = Some(value(arg)?.clone()),
+        "--motion-threshold" => opts.motion_threshold = Some(parse::number(arg, value(arg)?)?),
+        "--gate-file" => opts.gate_file = Some(value(arg)?.clone()),
+        "--power" => opts.power = Some(parse::keyword(arg, value(arg)?, Power::parse)?),
+        "--control-socket" => opts.control_socket = Some(value(arg)?.clone()),
+        "--motion-pre" => opts.motion_pre = parse::number(arg, value(arg)?)?,
+        "--motion-post" => opts.motion_post = parse::number(arg, value(arg)?)?,
+        "--fill-on-stall" => {
+            opts.fill_on_stall = Some(parse::keyword(arg, value(arg)?, FillMode::parse)?)
+        }
+        "--max-consecutive-errors" => {
+            opts.max_consecutive_errors = Some(parse::nonzero(arg, value(arg)?)?)
+        }
+        "--device-reset" => opts.device_reset = true,
+        "--mode" => opts.mode = Some(parse::keyword(arg, value(arg)?, Mode::parse)?),
+        "--sync-start" => opts.sync_start = true,
+        "--sync-start-at" => {
+            let s = value(arg)?;
+            let at = Duration::try_from_secs_f64(parse::number(arg, s)?)
+                .map_err(|e| ParseError::new(arg, s, e))?;
+            opts.sync_start_at = Some(at);
+            opts.sync_start = true;
+        }
+        "--companion" => {
+            let spec = value(arg)?;
+            let (device, output) = spec
+                .split_once(':')
+                .filter(|(device, output)| !device.is_empty() && !output.is_empty())
+                .ok_or_else(|| ParseError::new(arg, spec, "expected DEVICE:OUTPUT"))?;
+            opts.companions
+                .push((device.to_string(), output.to_string()));
+        }
+        "--meta-capture" => {
+            let spec = value(arg)?;
+            // The output is named after outfile unless given
+            let (device, output) = spec.split_once(':').unwrap_or((spec, ""));
+            if device.is_empty() {
+                return Err(ParseError::new(arg, spec, "expected DEVICE[:OUTPUT]"));
+            }
+            opts.meta_capture = Some((device.to_string(), output.to_string()));
+        }
+        "--json" => opts.json = true,
+        "--preview" => opts.preview = Some(value(arg)?.clone()),
+        "--preview-scale" => opts.preview_scale = parse::nonzero(arg, value(arg)?)?,
+        "--dump-buffers" => opts.dump_buffers = true,
+        "--no-requeue-on-error" => opts.no_requeue_on_error = true,
+        "--verbose" => opts.verbose = true,
+        "--no-set-format" => opts.no_set_format = true,
+        "--raw-ioctl" => opts.raw_ioctl = true,
+        "--throttle-sleep" => {
+            opts.throttle_sleep = Some(Duration::from_millis(parse::nonzero(arg, value(arg)?)?))
+        }
+        "--assemble-fields" => opts.assemble_fields = true,
+        "--deinterlace" => opts.deinterlace = parse::keyword(arg, value(arg)?, Deinterlace::parse)?,
+        "--debayer" => opts.debayer = parse::keyword(arg, value(arg)?, Demosaic::parse)?,
+        "--strip-padding" => opts.strip_padding = true,
+        "--m2m-device" => opts.m2m_device = Some(value(arg)?.clone()),
+        "--m2m-out-fourcc" => opts.m2m_out_fourcc = Some(parse::fourcc(value(arg)?)?),
+        "--m2m-out-buffers" => opts.m2m_out_buffers = parse::nonzero(arg, value(arg)?)?,
+        "--m2m-cap-buffers" => opts.m2m_cap_buffers = parse::nonzero(arg, value(arg)?)?,
+        "--fix-mjpeg-dht" => opts.fix_mjpeg_dht = true,
+        "--trim-mjpeg" => opts.trim_mjpeg = true,
+        "--repeat-headers" => {
+            opts.repeat_headers = Some(Duration::from_secs(parse::nonzero(arg, value(arg)?)?))
+        }
+        "--keyframe-interval" => opts.keyframe_interval = Some(parse::nonzero(arg, value(arg)?)?),
+        "--clock" => opts.clock = Some(parse::keyword(arg, value(arg)?, Clock::parse)?),
+        "--max-frame-size" => opts.max_frame_size = Some(parse::number(arg, value(arg)?)?),
+        "--abort-on-oversize" => opts.abort_on_oversize = true,
+        "--by-name" => opts.selector = Some(Selector::Name(value(arg)?.clone())),
+        "--by-serial" => opts.selector = Some(Selector::Serial(value(arg)?.clone())),
+        "--first-match" => opts.first_match = true,
+        "--source-file" => opts.source_file = Some(value(arg)?.clone()),
+        "--load-edid" => opts.load_edid = Some(value(arg)?.clone()),
+        "--dump-edid" => opts.dump_edid = Some(value(arg)?.clone()),
+        "--query-dv-timings" => opts.query_dv_timings = true,
+        "--set-dv-timings" => opts.set_dv_timings = true,
+        "--framing" => opts.framing = parse::keyword(arg, value(arg)?, Framing::parse)?,
+        "--keep-error-frames" => opts.keep_error_frames = true,
+        "--once-per-key" => opts.once_per_key = true,
+        "--pad-to" => {
+            let size: usize = parse::nonzero(arg, value(arg)?)?;
+            if size <= PAD_HEADER_LEN {
+                return Err(ParseError::new(
+                    arg,
+                    &size.to_string(),
+                    "no room for a frame",
+                ));
+            }
+            opts.pad_to = Some(size);
+        }
+        "--source-corrupt" => {
+            opts.source_corrupt = Some(parse::keyword(arg, value(arg)?, OnCorrupt::parse)?)
+        }
+        "--source-framing" => {
+            let v = value(arg)?;
+            opts.source_framing = match parse::keyword(arg, v, Framing::parse)? {
+                Framing::Base64 => {
+                    return Err(ParseError::new(arg, v, "base64 lines cannot be replayed"))
+                }
+                framing => framing,
+            }
+        }
+        "--repeat-last-on-eof" => opts.repeat_last_on_eof = true,
+        "--copy-mode" => opts.copy_mode = parse::keyword(arg, value(arg)?, CopyMode::parse)?,
+        "--duration" => {
+            opts.duration = Some(Duration::from_secs(parse::nonzero(arg, value(arg)?)?))
+        }
+        "--max-open-time" => {
+            opts.max_open_time = Some(Duration::from_secs(parse::nonzero(arg, value(arg)?)?))
+        }
+        "--recheck-format-interval" => {
+            opts.recheck_format_interval =
+                Some(Duration::from_secs(parse::nonzero(arg, value(arg)?)?))
+        }
+        "--stats-interval" => {
+            opts.stats_interval = Some(Duration::from_secs(parse::nonzero(arg, value(arg)?)?))
+        }
+        "--heartbeat-file" => opts.heartbeat_file = Some(value(arg)?.clone()),
+        "--priority" => opts.priority = Some(parse::keyword(arg, value(arg)?, Priority::parse)?),
+        "--frame-log" => opts.frame_log = Some(value(arg)?.clone()),
+        "--pts-file" => opts.pts_file = Some(value(arg)?.clone()),
+        "--pts-unit" => opts.pts_unit = parse::keyword(arg, value(arg)?, PtsUnit::parse)?,
+        "--jpeg-quality" => opts.jpeg_quality = true,
+        "--zero-base-pts" => opts.zero_base_pts = true,
+        "--pts-base" => opts.pts_base = Some(parse::keyword(arg, value(arg)?, PtsBase::parse)?),
+        "--loopback-timestamp" => {
+            opts.loopback_timestamp = Some(parse::keyword(arg, value(arg)?, PtsBase::parse)?)
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with(args: &[&str]) -> Result<Options, ParseError> {
+        let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+        let mut opts = Options::default();
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            let value = |name: &str| {
+                iter.next()
+                    .ok_or_else(|| ParseError::new(name, "", "missing value"))
+            };
+            assert!(apply_option(&mut opts, arg, value)?, "{arg} unknown");
+        }
+        Ok(opts)
+    }
+
+    #[test]
+    fn select_timeout() {
+        let opts = with(&["--select-timeout", "250"]).unwrap();
+        assert_eq!(opts.select_timeout, Some(Duration::from_millis(250)));
+        assert!(with(&["--select-timeout", "0"]).is_err());
+        assert!(with(&["--select-timeout"]).is_err());
+    }
+
+    #[test]
+    fn trim_mjpeg() {
+        assert!(!Options::default().trim_mjpeg);
+        assert!(with(&["--trim-mjpeg"]).unwrap().trim_mjpeg);
+    }
+
+    #[test]
+    fn repeat_headers() {
+        let opts = with(&["--repeat-headers", "2"]).unwrap();
+        assert_eq!(opts.repeat_headers, Some(Duration::from_secs(2)));
+        assert!(with(&["--repeat-headers", "x"]).is_err());
+    }
+}
@@ -0,0 +1,822 @@
+use nix::unistd::{access, fchown, AccessFlags, Gid};
+use std::fs::{File, OpenOptions, Permissions};
+use std::io::{self, BufWriter, Write};
+use std::mem;
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use v4l::buffer::Metadata;
+use v4l::video::capture::Parameters;
+use v4l::Format;
+
+use crate::base64;
+use crate::container::ContainerWriter;
+use crate::crc;
+use crate::device;
+use crate::mmapfile::MmapFile;
+use crate::options::Options;
+use crate::pipe;
+use crate::pixfmt;
+
+// When to close the current output file and start a new one
+#[derive(Default)]
+pub struct Rotation {
+    pub daily: bool,
+    pub max_size: Option<u64>,
+    // Frames per file
+    pub max_frames: Option<u64>,
+    pub ring: Option<Ring>,
+    // Files started by split() at keyframes, --segment-on-keyframe
+    pub keyframes: bool,
+}
+
+// Fixed number of files reused in turn, each holding the same number of frames
+#[derive(Clone, Copy)]
+pub struct Ring {
+    pub files: u32,
+    pub frames: u64,
+}
+
+impl Rotation {
+    fn enabled(&self) -> bool {
+        self.daily
+            || self.max_size.is_some()
+            || self.max_frames.is_some()
+            || self.ring.is_some()
+            || self.keyframes
+    }
+}
+
+// How frames reach a pipe consumer
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum CopyMode {
+    // vmsplice giftable buffers, copy the rest
+    Auto,
+    // Always write(), e.g. for consumers that hold on to pipe pages too long
+    Always,
+    // vmsplice every driver buffer, without gifting the unaligned ones
+    Never,
+}
+
+impl CopyMode {
+    pub fn parse(s: &str) -> Option<CopyMode> {
+        match s {
+            "auto" => Some(CopyMode::Auto),
+            "always" => Some(CopyMode::Always),
+            "never" => Some(CopyMode::Never),
+            _ => None,
+        }
+    }
+}
+
+// How frames are laid out in the output
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    // Frames back to back
+    Raw,
+    // Every frame preceded by a record header, see pts_header
+    Pts,
+    // The same with a CRC of the frame data in the header, see pts_crc_header
+    PtsCrc,
+    // A text line per frame, see base64_line; a debug transport for channels
+    // that only take text, a third larger than the frames
+    Base64,
+}
+
+impl Framing {
+    pub fn parse(s: &str) -> Option<Framing> {
+        match s {
+            "raw" => Some(Framing::Raw),
+            "pts" => Some(Framing::Pts),
+            "pts-crc" => Some(Framing::PtsCrc),
+            "base64" => Some(Framing::Base64),
+            _ => None,
+        }
+    }
+
+    // Bytes in front of every frame; base64 lines are not the frame bytes
+    // at all
+    pub fn header_len(self) -> usize {
+        match self {
+            Framing::Raw | Framing::Base64 => 0,
+            Framing::Pts => PTS_HEADER_LEN,
+            Framing::PtsCrc => PTS_CRC_HEADER_LEN,
+        }
+    }
+}
+
+pub const PTS_HEADER_LEN: usize = 12;
+pub const PTS_CRC_HEADER_LEN: usize = 16;
+
+// Record header of --framing pts: the buffer timestamp in nanoseconds as a
+// little endian u64, then the frame length in bytes as a little endian u32
+pub fn pts_header(meta: &Metadata, len: usize) -> [u8; PTS_HEADER_LEN] {
+    let ns = Duration::from(meta.timestamp).as_nanos() as u64;
+    let mut header = [0; PTS_HEADER_LEN];
+    header[..8].copy_from_slice(&ns.to_le_bytes());
+    header[8..].copy_from_slice(&(len as u32).to_le_bytes());
+    header
+}
+
+// Record header of --framing pts-crc: the pts header followed by the
+// CRC-32 of the frame data, also a little endian u32
+pub fn pts_crc_header(meta: &Metadata, buf: &[u8]) -> [u8; PTS_CRC_HEADER_LEN] {
+    let mut header = [0; PTS_CRC_HEADER_LEN];
+    header[..PTS_HEADER_LEN].copy_from_slice(&pts_header(meta, buf.len()));
+    header[PTS_HEADER_LEN..].copy_from_slice(&crc::crc32(buf).to_le_bytes());
+    header
+}
+
+// Line of --framing base64: the sequence number in decimal, a space, the
+// frame in base64 and a newline
+pub fn base64_line(meta: &Metadata, buf: &[u8], line: &mut Vec<u8>) {
+    line.clear();
+    line.extend_from_slice(format!("{} ", meta.sequence).as_bytes());
+    base64::encode(buf, line);
+    line.push(b'\n');
+}
+
+pub const PAD_HEADER_LEN: usize = 4;
+
+// Record of --pad-to: the frame length as a little endian u32, the frame,
+// then zeros up to `size` bytes in all
+pub fn padded_record(buf: &[u8], size: usize, record: &mut Vec<u8>) -> io::Result<()> {
+    if PAD_HEADER_LEN + buf.len() > size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "frame of {} bytes does not fit in --pad-to {size} byte records",
+                buf.len()
+            ),
+        ));
+    }
+    record.clear();
+    record.extend_from_slice(&(buf.len() as u32).to_le_bytes());
+    record.extend_from_slice(buf);
+    record.resize(size, 0);
+    Ok(())
+}
+
+// Mode and group given to created output files
+#[derive(Clone, Copy, Default)]
+pub struct FilePerms {
+    pub mode: Option<u32>,
+    pub group: Option<u32>,
+    // --no-cloexec: left open for children
+    pub inherit: bool,
+}
+
+// Fail with a clear message, before the device is set up, when the output
+// cannot be opened or created. New files (rotated ones too) go into the
+// directory of `path`.
+fn check_writable(path: &str) -> io::Result<()> {
+    let p = Path::new(path);
+    if p.exists() {
+        return access(p, AccessFlags::W_OK).map_err(|e| {
+            io::Error::new(
+                io::Error::from(e).kind(),
+                format!("{path} is not writable: {e}"),
+            )
+        });
+    }
+    let dir = match p.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if !dir.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("directory {} does not exist", dir.display()),
+        ));
+    }
+    access(dir, AccessFlags::W_OK | AccessFlags::X_OK).map_err(|e| {
+        io::Error::new(
+            io::Error::from(e).kind(),
+            format!("cannot create files in {}: {e}", dir.display()),
+        )
+    })
+}
+
+// Create or truncate an output file. Pipes and devices are left as they are.
+fn create_file(path: &str, perms: &FilePerms) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    if let Some(mode) = perms.mode {
+        options.mode(mode);
+    }
+    let file = options.open(path)?;
+    if perms.inherit {
+        device::set_cloexec(file.as_raw_fd(), false)?;
+    }
+    if file.metadata()?.is_file() {
+        // The creation mode is reduced by the umask, so set it again
+        if let Some(mode) = perms.mode {
+            file.set_permissions(Permissions::from_mode(mode))?;
+        }
+        if let Some(gid) = perms.group {
+            fchown(file.as_raw_fd(), None, Some(Gid::from_raw(gid)))?;
+        }
+    }
+    Ok(file)
+}
+
+// Where a file is written under --atomic-output until it is complete
+pub fn tmp_name(path: &str) -> String {
+    format!("{path}.tmp")
+}
+
+// Move a finished temporary file to its name, or when the capture failed
+// leave it for inspection or delete it
+pub fn complete_tmp(path: &str, clean: bool, delete_incomplete: bool) -> io::Result<()> {
+    let tmp = tmp_name(path);
+    if clean {
+        std::fs::rename(&tmp, path)
+    } else if delete_incomplete {
+        std::fs::remove_file(&tmp)
+    } else {
+        eprintln!("Incomplete output left in {tmp}");
+        Ok(())
+    }
+}
+
+// File that counts the write calls reaching the kernel. With --mmap-output
+// regular files are written through a mapping instead.
+struct CountingFile {
+    file: File,
+    syscalls: u64,
+    map: Option<MmapFile>,
+}
+
+impl CountingFile {
+    fn new(file: File, mmap: bool) -> io::Result<CountingFile> {
+        let map = match mmap && file.metadata()?.is_file() {
+            true => Some(MmapFile::new(&file)?),
+            false => None,
+        };
+        Ok(CountingFile {
+            file,
+            syscalls: 0,
+            map,
+        })
+    }
+
+    fn sync_data(&mut self) -> io::Result<()> {
+        match self.map.as_mut() {
+            Some(map) => map.sync(),
+            None => self.file.sync_data(),
+        }
+    }
+}
+
+impl Write for CountingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(map) = self.map.as_mut() {
+            map.write_all(buf)?;
+            return Ok(buf.len());
+        }
+        self.syscalls += 1;
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+// Destination of captured frames, either a pipe or a (possibly rotated) file
+pub struct Output {
+    path: String,
+    // Output path before token expansion, expanded again for every new file
+    template: String,
+    tokens: Option<Tokens>,
+    // With capacity 0 every frame is written straight through
+    file: BufWriter<CountingFile>,
+    capacity: usize,
+    mmap: bool,
+    syscalls: u64,
+    verbose: bool,
+    to_pipe: bool,
+    // --tee sinks: pipes, the output among them if it is one, get the data
+    // through `tee_pipe` when there are several, other sinks a copy
+    pipe_sinks: Vec<RawFd>,
+    tee_pipe: Option<pipe::TeePipe>,
+    // With whether each is a pipe
+    tees: Vec<(File, bool)>,
+    copy_mode: CopyMode,
+    framing: Framing,
+    // --pad-to: bytes per record
+    pad_to: Option<usize>,
+    // The frame being written made over, as a base64 line or padded record
+    scratch: Vec<u8>,
+    rotation: Rotation,
+    perms: FilePerms,
+    // Regular files only, syncing a device or pipe is pointless
+    fsync_interval: Option<Duration>,
+    last_sync: Instant,
+    // Final name of the file being written as <name>.tmp, with --atomic-output
+    pending: Option<String>,
+    atomic: bool,
+    delete_incomplete: bool,
+    written: u64,
+    frames: u64,
+    segment: u32,
+    day: String,
+    next_midnight: libc::time_t,
+}
+
+// Local calendar time for the given unix time
+pub fn local_tm(t: libc::time_t) -> libc::tm {
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&t, &mut tm) };
+    tm
+}
+
+pub fn now() -> libc::time_t {
+    unsafe { libc::time(std::ptr::null_mut()) }
+}
+
+fn date_stamp(tm: &libc::tm) -> String {
+    format!(
+        "{:04}{:02}{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday
+    )
+}
+
+// Unix time of the next local midnight. mktime() normalizes the day overflow
+// and, with tm_isdst = -1, picks the right UTC offset across DST transitions.
+fn next_local_midnight(t: libc::time_t) -> libc::time_t {
+    let mut tm = local_tm(t);
+    tm.tm_mday += 1;
+    tm.tm_hour = 0;
+    tm.tm_min = 0;
+    tm.tm_sec = 0;
+    tm.tm_isdst = -1;
+    unsafe { libc::mktime(&mut tm) }
+}
+
+// Insert `_suffix` between the file stem and its extension
+fn with_suffix(path: &str, suffix: &str) -> String {
+    let p = Path::new(path);
+    let stem = p.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+    let name = match p.extension().and_then(|s| s.to_str()) {
+        Some(ext) => format!("{}_{}.{}", stem, suffix, ext),
+        None => format!("{}_{}", stem, suffix),
+    };
+    p.with_file_name(name).to_string_lossy().into_owned()
+}
+
+// Values for the `%` tokens of --timestamp-filename
+struct Tokens {
+    device: String,
+    fourcc: String,
+}
+
+impl Tokens {
+    fn new(opts: &Options) -> Tokens {
+        let source = opts.source_file.as_deref().unwrap_or(&opts.devname);
+        let name = Path::new(source)
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or("");
+        // Keep device names usable as a file name component
+        let device = name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        Tokens {
+            device,
+            fourcc: String::from_utf8_lossy(&opts.pixelformat)
+                .trim()
+                .to_string(),
+        }
+    }
+
+    // Replace %Y %m %d %H %M %S (local time t), %D (device), %F (fourcc) and %%
+    fn expand(&self, path: &str, t: libc::time_t) -> String {
+        let tm = local_tm(t);
+        let mut out = String::new();
+        let mut chars = path.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                out.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('Y') => out += &format!("{:04}", tm.tm_year + 1900),
+                Some('m') => out += &format!("{:02}", tm.tm_mon + 1),
+                Some('d') => out += &format!("{:02}", tm.tm_mday),
+                Some('H') => out += &format!("{:02}", tm.tm_hour),
+                Some('M') => out += &format!("{:02}", tm.tm_min),
+                Some('S') => out += &format!("{:02}", tm.tm_sec),
+                Some('D') => out += &self.device,
+                Some('F') => out += &self.fourcc,
+                Some('%') => out.push('%'),
+                Some(other) => {
+                    out.push('%');
+                    out.push(other);
+                }
+                None => out.push('%'),
+            }
+        }
+        out
+    }
+}
+
+// Name of a rotated file: outfile[_YYYYMMDD][_NNNN].ext
+fn segment_name(path: &str, rotation: &Rotation, day: &str, segment: u32) -> String {
+    let mut suffix = Vec::new();
+    if rotation.daily {
+        suffix.push(day.to_string());
+    }
+    if rotation.max_size.is_some()
+        || rotation.max_frames.is_some()
+        || rotation.ring.is_some()
+        || rotation.keyframes
+        || segment > 0
+    {
+        suffix.push(format!("{:04}", segment));
+    }
+    with_suffix(path, &suffix.join("_"))
+}
+
+impl Output {
+    pub fn open(opts: &Options) -> io::Result<Output> {
+        let tokens = opts.timestamp_filename.then(|| Tokens::new(opts));
+        let t = now();
+        let expanded = match &tokens {
+            Some(tokens) => tokens.expand(&opts.out_file, t),
+            None => opts.out_file.clone(),
+        };
+        let path = expanded.as_str();
+        let mut rotation = Rotation {
+            daily: opts.rotate_daily,
+            max_size: opts.rotate_size,
+            max_frames: opts.frames_per_file,
+            ring: opts.ring_files.map(|files| Ring {
+                files,
+                frames: opts.ring_frames,
+            }),
+            // Numbered from the first file when there will be keyframes;
+            // the format the driver settles on is not known yet
+            keyframes: opts.segment_on_keyframe
+                && pixfmt::is_compressed(&opts.m2m_out_fourcc.unwrap_or(opts.pixelformat)),
+        };
+        if rotation.ring.is_some()
+            && (rotation.daily || rotation.max_size.is_some() || rotation.max_frames.is_some())
+        {
+            eprintln!(
+                "Ring files in use, --rotate-daily, --rotate-size and --frames-per-file ignored"
+            );
+            rotation.daily = false;
+            rotation.max_size = None;
+            rotation.max_frames = None;
+        }
+        let is_fifo = std::fs::metadata(path)
+            .map(|m| m.file_type().is_fifo())
+            .unwrap_or(false);
+        if is_fifo && rotation.enabled() {
+            eprintln!("Output is a pipe, file rotation disabled");
+            rotation = Rotation::default();
+        }
+        let day = date_stamp(&local_tm(t));
+        // Rotated files always carry their date/segment suffix
+        let first = if rotation.enabled() {
+            segment_name(path, &rotation, &day, 0)
+        } else {
+            path.to_string()
+        };
+        let perms = FilePerms {
+            mode: opts.file_mode,
+            group: opts.file_group,
+            inherit: opts.no_cloexec,
+        };
+        check_writable(&first)?;
+        // Pipes and devices have no name to rename to
+        let atomic = opts.atomic_output
+            && std::fs::metadata(&first)
+                .map(|m| m.is_file())
+                .unwrap_or(true);
+        let file = if atomic {
+            create_file(&tmp_name(&first), &perms)?
+        } else {
+            create_file(&first, &perms)?
+        };
+        let to_pipe = pipe::is_pipe(file.as_raw_fd());
+        let is_file = file.metadata()?.is_file();
+        let mmap = opts.mmap_output && is_file;
+        if opts.mmap_output && !is_file {
+            eprintln!("--mmap-output: {path} is not a regular file, writing it normally");
+        }
+        // Pipe consumers are latency sensitive, never buffer for them. The
+        // mapping is a buffer already.
+        let capacity = match opts.buffer_capacity {
+            Some(capacity) if !to_pipe && !mmap => capacity,
+            _ => 0,
+        };
+        let mut pipe_sinks = Vec::new();
+        if to_pipe {
+            pipe_sinks.push(file.as_raw_fd());
+        }
+        let mut tees = Vec::new();
+        for path in &opts.tees {
+            let tee = create_file(path, &perms)
+                .map_err(|e| io::Error::new(e.kind(), format!("--tee {path}: {e}")))?;
+            let is_pipe = pipe::is_pipe(tee.as_raw_fd());
+            if is_pipe {
+                pipe_sinks.push(tee.as_raw_fd());
+            }
+            tees.push((tee, is_pipe));
+        }
+        let tee_pipe = if pipe_sinks.len() > 1 {
+            Some(pipe::TeePipe::new()?)
+        } else {
+            None
+        };
+        let output = Output {
+            path: path.to_string(),
+            template: opts.out_file.clone(),
+            tokens,
+            file: BufWriter::with_capacity(capacity, CountingFile::new(file, mmap)?),
+            capacity,
+            mmap,
+            syscalls: 0,
+            verbose: opts.verbose,
+            to_pipe,
+            pipe_sinks,
+            tee_pipe,
+            tees,
+            copy_mode: opts.copy_mode,
+            framing: opts.framing,
+            pad_to: opts.pad_to,
+            scratch: Vec::new(),
+            rotation,
+            perms,
+            fsync_interval: opts.fsync_interval.filter(|_| is_file),
+            last_sync: Instant::now(),
+            pending: atomic.then_some(first),
+            atomic,
+            delete_incomplete: opts.delete_incomplete,
+            written: 0,
+            frames: 0,
+            segment: 0,
+            day,
+            next_midnight: next_local_midnight(t),
+        };
+        for &fd in &output.pipe_sinks {
+            match pipe::set_pipe_max_size(fd) {
+                Ok(_) => {}
+                Err(e) => eprintln!("set_pipe_max_size:{e} (ignored)"),
+            }
+        }
+        Ok(output)
+    }
+
+    // Decide at a frame boundary whether the next frame starts a new file
+    fn rotate_if_needed(&mut self, next_len: usize) -> io::Result<()> {
+        let mut rotate = false;
+        if self.rotation.daily {
+            let t = now();
+            if t >= self.next_midnight {
+                self.day = date_stamp(&local_tm(t));
+                self.next_midnight = next_local_midnight(t);
+                self.segment = 0;
+                rotate = true;
+            }
+        }
+        if let Some(max_size) = self.rotation.max_size {
+            if !rotate && self.written > 0 && self.written + next_len as u64 > max_size {
+                self.segment += 1;
+                rotate = true;
+            }
+        }
+        if let Some(max_frames) = self.rotation.max_frames {
+            if !rotate && self.frames >= max_frames {
+                self.segment += 1;
+                rotate = true;
+            }
+        }
+        if let Some(ring) = self.rotation.ring {
+            if self.frames >= ring.frames {
+                self.segment = (self.segment + 1) % ring.files;
+                rotate = true;
+            }
+        }
+        if rotate {
+            self.open_next()?;
+        }
+        Ok(())
+    }
+
+    // Close the current file and create the one for self.segment
+    fn open_next(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.fsync_interval.is_some() {
+            self.sync()?;
+        }
+        self.syscalls += self.file.get_ref().syscalls;
+        // The previous file is finished
+        self.complete(true)?;
+        // Ring files must keep their names to be reused
+        if let (Some(tokens), None) = (&self.tokens, self.rotation.ring) {
+            self.path = tokens.expand(&self.template, now());
+        }
+        let path = segment_name(&self.path, &self.rotation, &self.day, self.segment);
+        eprintln!("Rotating output to {}", path);
+        let file = if self.atomic {
+            let file = create_file(&tmp_name(&path), &self.perms)?;
+            self.pending = Some(path);
+            file
+        } else {
+            create_file(&path, &self.perms)?
+        };
+        self.file = BufWriter::with_capacity(self.capacity, CountingFile::new(file, self.mmap)?);
+        self.written = 0;
+        self.frames = 0;
+        Ok(())
+    }
+
+    // Push written data to the disk so a power loss cannot take it
+    fn sync(&mut self) -> io::Result<()> {
+        let start = Instant::now();
+        self.file.flush()?;
+        self.file.get_mut().sync_data()?;
+        self.last_sync = Instant::now();
+        if self.verbose {
+            eprintln!(
+                "fdatasync took {:.3} [ms]",
+                (self.last_sync - start).as_secs_f64() * 1000.0
+            );
+        }
+        Ok(())
+    }
+
+    // Write one frame. `mapped` tells whether it lives in a driver buffer
+    // that stays untouched until it is requeued; only such buffers may be
+    // vmspliced, heap data must be copied as it is freed afterwards.
+    // `header` goes in front of the frame, in the same file
+    fn write_buffer(&mut self, header: &[u8], buf: &[u8], mapped: bool) -> io::Result<()> {
+        self.write_tees(header, buf, mapped)?;
+        if self.to_pipe {
+            return Ok(());
+        }
+        if self.rotation.enabled() {
+            self.rotate_if_needed(header.len() + buf.len())?;
+        }
+        self.file.write_all(header)?;
+        self.file.write_all(buf)?;
+        self.written += (header.len() + buf.len()) as u64;
+        self.frames += 1;
+        if let Some(interval) = self.fsync_interval {
+            if self.last_sync.elapsed() >= interval {
+                self.sync()?;
+            }
+        }
+        Ok(())
+    }
+
+    // Write to the pipe sinks and the other --tee sinks
+    fn write_tees(&mut self, header: &[u8], buf: &[u8], mapped: bool) -> io::Result<()> {
+        for (tee, is_pipe) in &self.tees {
+            if !is_pipe {
+                pipe::write_all(header, tee.as_raw_fd())?;
+                pipe::write_all(buf, tee.as_raw_fd())?;
+            }
+        }
+        let Some(tee_pipe) = self
+            .tee_pipe
+            .as_ref()
+            .filter(|_| self.copy_mode != CopyMode::Always)
+        else {
+            for &fd in &self.pipe_sinks {
+                write_pipe(fd, header, buf, mapped, self.copy_mode)?;
+            }
+            return Ok(());
+        };
+        if !header.is_empty() {
+            pipe::tee_to_pipes(header, None, tee_pipe, &self.pipe_sinks)?;
+        }
+        // As in write_pipe, only page aligned driver buffers are gifted
+        let flags = match self.copy_mode {
+            _ if !mapped => None,
+            _ if pipe::is_page_aligned(buf) => Some(pipe::SpliceFFlags::SPLICE_F_GIFT),
+            CopyMode::Never => Some(pipe::SpliceFFlags::empty()),
+            _ => None,
+        };
+        pipe::tee_to_pipes(buf, flags, tee_pipe, &self.pipe_sinks)
+    }
+}
+
+// Write a frame to a single pipe
+fn write_pipe(
+    fd: RawFd,
+    header: &[u8],
+    buf: &[u8],
+    mapped: bool,
+    copy_mode: CopyMode,
+) -> io::Result<()> {
+    if !header.is_empty() {
+        pipe::write_all(header, fd)?;
+    }
+    match copy_mode {
+        CopyMode::Never if mapped && !pipe::is_page_aligned(buf) => {
+            pipe::vmsplice_all(buf, fd, pipe::SpliceFFlags::empty())
+        }
+        CopyMode::Auto | CopyMode::Never if mapped => pipe::vmsplice_single_buffer(buf, fd),
+        _ => pipe::write_all(buf, fd),
+    }
+}
+
+// Raw frames, back to back or with a record header each
+impl ContainerWriter for Output {
+    fn begin(&mut self, _format: &Format, _params: &Parameters) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_frame(&mut self, buf: &[u8], meta: &Metadata, mapped: bool) -> io::Result<()> {
+        if let Some(size) = self.pad_to {
+            let mut record = mem::take(&mut self.scratch);
+            let result = padded_record(buf, size, &mut record)
+                .and_then(|_| self.write_buffer(&[], &record, false));
+            self.scratch = record;
+            return result;
+        }
+        match self.framing {
+            Framing::Raw => self.write_buffer(&[], buf, mapped),
+            Framing::Pts => self.write_buffer(&pts_header(meta, buf.len()), buf, mapped),
+            Framing::PtsCrc => self.write_buffer(&pts_crc_header(meta, buf), buf, mapped),
+            Framing::Base64 => {
+                let mut line = mem::take(&mut self.scratch);
+                base64_line(meta, buf, &mut line);
+                let result = self.write_buffer(&[], &line, false);
+                self.scratch = line;
+                result
+            }
+        }
+    }
+
+    // Flush buffered data at the end of the capture
+    fn finalize(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.fsync_interval.is_some() {
+            self.sync()?;
+        }
+        if let Some(map) = self.file.get_mut().map.as_mut() {
+            map.finish()?;
+        }
+        if self.verbose && !self.to_pipe {
+            eprintln!(
+                "Output: {} write syscalls (buffer capacity {} bytes)",
+                self.syscalls + self.file.get_ref().syscalls,
+                self.capacity
+            );
+        }
+        Ok(())
+    }
+
+    // Give the file written under --atomic-output its name, after finalize().
+    // `clean` is false when the capture failed.
+    fn complete(&mut self, clean: bool) -> io::Result<()> {
+        match self.pending.take() {
+            Some(path) => complete_tmp(&path, clean, self.delete_incomplete),
+            None => Ok(()),
+        }
+    }
+
+    // Not for pipes and devices, or when the frames are spread over files
+    fn written_path(&self) -> Option<&str> {
+        let is_file = self
+            .file
+            .get_ref()
+            .file
+            .metadata()
+            .is_ok_and(|m| m.is_file());
+        let single = !self.rotation.enabled() && self.segment == 0 && self.tokens.is_none();
+        (is_file && single).then_some(self.path.as_str())
+    }
+
+    fn pipe_fd(&self) -> Option<RawFd> {
+        self.to_pipe.then(|| self.file.get_ref().file.as_raw_fd())
+    }
+
+    // Continue in a new numbered file, e.g. because the frame geometry
+    // changed. Pipes and devices just carry on.
+    fn split(&mut self) -> io::Result<()> {
+        if self.to_pipe || !self.file.get_ref().file.metadata()?.is_file() {
+            return Ok(());
+        }
+        self.segment = match self.rotation.ring {
+            Some(ring) => (self.segment + 1) % ring.files,
+            None => self.segment + 1,
+        };
+        self.open_next()
+    }
+}
@@ -0,0 +1,164 @@
+// --timestamp-overlay, --frame-counter-overlay: the capture time and the
+// frame number burnt into the luma of raw frames, light digits on a dark
+// box, in a small built-in font
+
+use std::time::{SystemTime, UNIX_EPOCH};
+use v4l::Format;
+
+use crate::output::local_tm;
+use crate::pixfmt;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl Corner {
+    pub fn parse(s: &str) -> Option<Corner> {
+        match s {
+            "top-left" => Some(Corner::TopLeft),
+            "top-right" => Some(Corner::TopRight),
+            "bottom-left" => Some(Corner::BottomLeft),
+            "bottom-right" => Some(Corner::BottomRight),
+            _ => None,
+        }
+    }
+}
+
+// 5x7 glyphs, one byte per row with the leftmost pixel in bit 4
+fn glyph(c: char) -> [u8; 7] {
+    match c {
+        '0' => [0x0e, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0e],
+        '1' => [0x04, 0x0c, 0x04, 0x04, 0x04, 0x04, 0x0e],
+        '2' => [0x0e, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1f],
+        '3' => [0x1f, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0e],
+        '4' => [0x02, 0x06, 0x0a, 0x12, 0x1f, 0x02, 0x02],
+        '5' => [0x1f, 0x10, 0x1e, 0x01, 0x01, 0x11, 0x0e],
+        '6' => [0x06, 0x08, 0x10, 0x1e, 0x11, 0x11, 0x0e],
+        '7' => [0x1f, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0e, 0x11, 0x11, 0x0e, 0x11, 0x11, 0x0e],
+        '9' => [0x0e, 0x11, 0x11, 0x0f, 0x01, 0x02, 0x0c],
+        '-' => [0x00, 0x00, 0x00, 0x1f, 0x00, 0x00, 0x00],
+        ':' => [0x00, 0x0c, 0x0c, 0x00, 0x0c, 0x0c, 0x00],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x0c],
+        _ => [0; 7],
+    }
+}
+
+// A glyph with a font pixel of space around it
+const CELL_WIDTH: usize = 6;
+const CELL_HEIGHT: usize = 9;
+// Limited range luma
+const INK: u8 = 235;
+const BACKGROUND: u8 = 16;
+
+// Local time with milliseconds, "2024-05-01 12:34:56.789"
+pub fn time_text(t: SystemTime) -> String {
+    let since = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let tm = local_tm(since.as_secs() as libc::time_t);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:03}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec,
+        since.subsec_millis()
+    )
+}
+
+// The frame number, with --overlay-sequence "12:345" for the driver's
+pub fn counter_text(index: u64, sequence: Option<u32>) -> String {
+    match sequence {
+        Some(seq) => format!("{index}:{seq}"),
+        None => index.to_string(),
+    }
+}
+
+pub struct Overlay {
+    corner: Corner,
+    // Where the luma of pixel x in a row is: offset + x * step
+    offset: usize,
+    step: usize,
+    stride: usize,
+    width: usize,
+    height: usize,
+    // Frame pixels per font pixel
+    scale: usize,
+    out: Vec<u8>,
+}
+
+impl Overlay {
+    // None for formats without a luma byte per pixel
+    pub fn new(fmt: &Format, corner: Corner) -> Option<Overlay> {
+        let (offset, step) = match &fmt.fourcc.repr {
+            b"YUYV" | b"YVYU" => (0, 2),
+            b"UYVY" | b"VYUY" => (1, 2),
+            b"GREY" | b"NV12" | b"NV21" | b"NV16" | b"NV61" | b"YU12" | b"YV12" | b"422P" => (0, 1),
+            _ => return None,
+        };
+        // Replayed files have no bytesperline
+        let stride = match fmt.stride {
+            0 => pixfmt::packed_stride(&fmt.fourcc.repr, fmt.width)?,
+            stride => stride,
+        };
+        Some(Overlay {
+            corner,
+            offset,
+            step,
+            stride: stride as usize,
+            width: fmt.width as usize,
+            height: fmt.height as usize,
+            // The box a 27th of the frame height, at least 9 rows
+            scale: (fmt.height as usize / 240).max(1),
+            out: Vec::new(),
+        })
+    }
+
+    // The frame with the text drawn in. Text that does not fit is cut off.
+    pub fn apply(&mut self, buf: &[u8], text: &str) -> &[u8] {
+        self.out.clear();
+        self.out.extend_from_slice(buf);
+        let s = self.scale;
+        let box_width = (text.chars().count() * CELL_WIDTH + 1) * s;
+        let box_height = CELL_HEIGHT * s;
+        let margin = 4 * s;
+        let left = match self.corner {
+            Corner::TopLeft | Corner::BottomLeft => margin,
+            Corner::TopRight | Corner::BottomRight => self.width.saturating_sub(box_width + margin),
+        };
+        let top = match self.corner {
+            Corner::TopLeft | Corner::TopRight => margin,
+            Corner::BottomLeft | Corner::BottomRight => {
+                self.height.saturating_sub(box_height + margin)
+            }
+        };
+        let glyphs: Vec<[u8; 7]> = text.chars().map(glyph).collect();
+        for y in 0..box_height.min(self.height.saturating_sub(top)) {
+            // Font row, with the blank row above the glyphs as -1
+            let row = (y / s) as isize - 1;
+            for x in 0..box_width.min(self.width.saturating_sub(left)) {
+                let col = x / s;
+                let (cell, bit) = (
+                    col.saturating_sub(1) / CELL_WIDTH,
+                    col.saturating_sub(1) % CELL_WIDTH,
+                );
+                let lit = col > 0
+                    && bit < 5
+                    && (0..7).contains(&row)
+                    && glyphs
+                        .get(cell)
+                        .is_some_and(|g| g[row as usize] & (0x10 >> bit) != 0);
+                let at = (top + y) * self.stride + self.offset + (left + x) * self.step;
+                if let Some(luma) = self.out.get_mut(at) {
+                    *luma = if lit { INK } else { BACKGROUND };
+                }
+            }
+        }
+        &self.out
+    }
+}
@@ -0,0 +1,94 @@
+// Parsing of command line values. Every function returns an error instead
+// of panicking, whatever the input.
+
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseError {
+    what: String,
+    value: String,
+    reason: String,
+}
+
+impl ParseError {
+    pub fn new(what: &str, value: &str, reason: impl fmt::Display) -> ParseError {
+        ParseError {
+            what: what.to_string(),
+            value: value.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.value.is_empty() {
+            write!(f, "{}: {}", self.what, self.reason)
+        } else {
+            write!(f, "invalid {} {:?}: {}", self.what, self.value, self.reason)
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+// Pixel format code of one to four printable ASCII characters, padded with
+// spaces as in "Y16 "
+pub fn fourcc(s: &str) -> Result<[u8; 4], ParseError> {
+    let bytes = s.as_bytes();
+    if bytes.is_empty() || bytes.len() > 4 {
+        return Err(ParseError::new("fourcc", s, "must be 1 to 4 characters"));
+    }
+    if !bytes.iter().all(|b| b.is_ascii_graphic() || *b == b' ') {
+        return Err(ParseError::new("fourcc", s, "must be printable ASCII"));
+    }
+    let mut code = [b' '; 4];
+    code[..bytes.len()].copy_from_slice(bytes);
+    Ok(code)
+}
+
+// Pixel formats in order of preference, "MJPG,YUYV"
+pub fn fourcc_list(s: &str) -> Result<Vec<[u8; 4]>, ParseError> {
+    s.split(',').map(fourcc).collect()
+}
+
+pub fn number<T>(what: &str, s: &str) -> Result<T, ParseError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    s.trim().parse().map_err(|e| ParseError::new(what, s, e))
+}
+
+// Like number(), but zero is rejected too
+pub fn nonzero<T>(what: &str, s: &str) -> Result<T, ParseError>
+where
+    T: FromStr + Default + PartialEq,
+    T::Err: fmt::Display,
+{
+    let n = number(what, s)?;
+    if n == T::default() {
+        return Err(ParseError::new(what, s, "must not be zero"));
+    }
+    Ok(n)
+}
+
+pub fn octal(what: &str, s: &str) -> Result<u32, ParseError> {
+    u32::from_str_radix(s.trim(), 8).map_err(|e| ParseError::new(what, s, e))
+}
+
+// "1280x720" -> (1280, 720)
+pub fn resolution(s: &str) -> Result<(u32, u32), ParseError> {
+    let (w, h) = s
+        .trim()
+        .split_once('x')
+        .ok_or_else(|| ParseError::new("resolution", s, "expected WIDTHxHEIGHT"))?;
+    Ok((nonzero("resolution", w)?, nonzero("resolution", h)?))
+}
+
+// One of a fixed set of words, recognized by `parse`
+pub fn keyword<T>(what: &str, s: &str, parse: fn(&str) -> Option<T>) -> Result<T, ParseError> {
+    parse(s).ok_or_else(|| ParseError::new(what, s, "unknown value"))
+}
@@ -1,8 +1,12 @@
 use nix::errno::Errno;
-use nix::fcntl::{fcntl, FcntlArg};
-use nix::fcntl::{vmsplice, SpliceFFlags};
+pub use nix::fcntl::SpliceFFlags;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::fcntl::{splice, tee, vmsplice};
+use nix::unistd::{sysconf, write, SysconfVar};
 use std::io::{self, IoSlice};
-use std::os::unix::io::RawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
 
 // Check if the given file descriptor is a pipe
 pub fn is_pipe(fd: RawFd) -> bool {
@@ -24,32 +28,170 @@ pub fn get_pipe_max_size() -> Result<usize, io::Error> {
     Ok(max_size)
 }
 
+// Upper bound when the maximum cannot be read, the default pipe-max-size
+const FALLBACK_PIPE_MAX_SIZE: libc::c_int = 1 << 20;
+
 // Set the size of the given pipe file descriptor to the maximum size
 #[cfg(target_os = "linux")]
 pub fn set_pipe_max_size(fd: RawFd) -> Result<(), io::Error> {
-    let max_size: libc::c_int = get_pipe_max_size()? as _;
+    let current_size = fcntl(fd, FcntlArg::F_GETPIPE_SZ)?;
+    let max_size: libc::c_int = match get_pipe_max_size() {
+        Ok(max_size) => max_size as _,
+        // /proc may be hidden in a container
+        Err(_) => return grow_pipe(fd, current_size),
+    };
 
     // If the current size is less than the maximum size, set the pipe size to the maximum size
-    let current_size = fcntl(fd, FcntlArg::F_GETPIPE_SZ)?;
     if current_size < max_size {
         _ = fcntl(fd, FcntlArg::F_SETPIPE_SZ(max_size))?;
     }
     Ok(())
 }
 
+// Without a known maximum: double the size until the kernel refuses, with
+// EPERM past the limit of unprivileged users, or the fallback bound is hit
 #[cfg(target_os = "linux")]
-pub fn vmsplice_single_buffer(mut buf: &[u8], fd: RawFd) -> Result<(), io::Error> {
-    if buf.is_empty() {
-        return Ok(());
-    };
-    loop {
+fn grow_pipe(fd: RawFd, mut size: libc::c_int) -> Result<(), io::Error> {
+    while size < FALLBACK_PIPE_MAX_SIZE {
+        match fcntl(fd, FcntlArg::F_SETPIPE_SZ(size * 2)) {
+            Ok(granted) if granted > size => size = granted,
+            Ok(_) | Err(Errno::EPERM | Errno::EINVAL | Errno::EBUSY) => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+// System page size, as required for SPLICE_F_GIFT
+pub fn page_size() -> usize {
+    static PAGE_SIZE: OnceLock<usize> = OnceLock::new();
+    *PAGE_SIZE.get_or_init(|| match sysconf(SysconfVar::PAGE_SIZE) {
+        Ok(Some(n)) if n > 0 => n as usize,
+        _ => 4096,
+    })
+}
+
+pub fn is_page_aligned(buf: &[u8]) -> bool {
+    (buf.as_ptr() as usize).is_multiple_of(page_size())
+}
+
+// Plain copying write of the whole buffer
+pub fn write_all(mut buf: &[u8], fd: RawFd) -> Result<(), io::Error> {
+    while !buf.is_empty() {
+        match write(fd, buf) {
+            Ok(n) => buf = &buf[n..],
+            Err(Errno::EINTR) => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+pub fn vmsplice_single_buffer(buf: &[u8], fd: RawFd) -> Result<(), io::Error> {
+    // Gifting pages that are not page aligned can corrupt the data seen by
+    // the reader, so copy such buffers instead.
+    if !is_page_aligned(buf) {
+        static WARNED: AtomicBool = AtomicBool::new(false);
+        if !WARNED.swap(true, Ordering::Relaxed) {
+            eprintln!(
+                "Buffer at {:p} is not aligned to the {} byte page size, falling back to write",
+                buf.as_ptr(),
+                page_size()
+            );
+        }
+        return write_all(buf, fd);
+    }
+    vmsplice_all(buf, fd, SpliceFFlags::SPLICE_F_GIFT)
+}
+
+// Map the whole buffer into the pipe
+#[cfg(target_os = "linux")]
+pub fn vmsplice_all(mut buf: &[u8], fd: RawFd, flags: SpliceFFlags) -> Result<(), io::Error> {
+    while !buf.is_empty() {
         let iov = IoSlice::new(buf);
-        match vmsplice(fd, &[iov], SpliceFFlags::SPLICE_F_GIFT) {
-            Ok(n) if n == iov.len() => return Ok(()),
+        match vmsplice(fd, &[iov], flags) {
             Ok(n) if n != 0 => buf = &buf[n..],
             Ok(_) => unreachable!(),
-            Err(err) if err == Errno::EINTR => {}
+            Err(Errno::EINTR) => {}
             Err(err) => return Err(err.into()),
         }
     }
+    Ok(())
+}
+
+// Pipe of our own that the data for several pipe sinks passes through, see
+// tee_to_pipes
+#[cfg(target_os = "linux")]
+pub struct TeePipe {
+    read: OwnedFd,
+    write: OwnedFd,
+    // Bytes put in at a time, a page short of the pipe size so that data not
+    // starting on a page boundary fits too
+    chunk: usize,
+}
+
+#[cfg(target_os = "linux")]
+impl TeePipe {
+    pub fn new() -> Result<TeePipe, io::Error> {
+        let (read, write) = nix::unistd::pipe2(OFlag::O_CLOEXEC)?;
+        let (read, write) = unsafe { (OwnedFd::from_raw_fd(read), OwnedFd::from_raw_fd(write)) };
+        if let Err(e) = set_pipe_max_size(write.as_raw_fd()) {
+            eprintln!("set_pipe_max_size:{e} (ignored)");
+        }
+        let size = fcntl(write.as_raw_fd(), FcntlArg::F_GETPIPE_SZ)? as usize;
+        Ok(TeePipe {
+            read,
+            write,
+            chunk: size.saturating_sub(page_size()).max(page_size()),
+        })
+    }
+}
+
+// Send the buffer to all `sinks`, which must be pipes, without copying it for
+// each: it is vmspliced into `pipe` with `flags` (written, with None), tee(2)d
+// from there into all sinks but the last and spliced into that one. A sink
+// that takes only part of a tee gets the rest written, as tee cannot go on
+// where it stopped.
+#[cfg(target_os = "linux")]
+pub fn tee_to_pipes(
+    buf: &[u8],
+    flags: Option<SpliceFFlags>,
+    pipe: &TeePipe,
+    sinks: &[RawFd],
+) -> Result<(), io::Error> {
+    let Some((&last, others)) = sinks.split_last() else {
+        return Ok(());
+    };
+    let (read, write) = (pipe.read.as_raw_fd(), pipe.write.as_raw_fd());
+    for chunk in buf.chunks(pipe.chunk) {
+        match flags {
+            Some(flags) => vmsplice_all(chunk, write, flags)?,
+            None => write_all(chunk, write)?,
+        }
+        for &sink in others {
+            let teed = loop {
+                match tee(read, sink, chunk.len(), SpliceFFlags::empty()) {
+                    Err(Errno::EINTR) => {}
+                    result => break result?,
+                }
+            };
+            write_all(&chunk[teed..], sink)?;
+        }
+        let mut left = chunk.len();
+        while left > 0 {
+            match splice(read, None, last, None, left, SpliceFFlags::SPLICE_F_MOVE) {
+                Ok(n) if n != 0 => left -= n,
+                Ok(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "splice to the last sink moved nothing",
+                    ))
+                }
+                Err(Errno::EINTR) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+    Ok(())
 }
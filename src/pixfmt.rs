@@ -0,0 +1,290 @@
+// Sizing information for raw pixel formats
+
+use std::ops::Range;
+
+// Bits per pixel, averaged over the planes for planar formats
+pub fn bits_per_pixel(fourcc: &[u8; 4]) -> Option<u32> {
+    match fourcc {
+        b"YUYV" | b"YVYU" | b"UYVY" | b"VYUY" | b"NV16" | b"NV61" | b"422P" | b"RGBP" | b"RGBO" => {
+            Some(16)
+        }
+        b"NV12" | b"NV21" | b"YU12" | b"YV12" => Some(12),
+        b"GREY" | b"BA81" | b"GBRG" | b"GRBG" | b"RGGB" => Some(8),
+        b"Y16 " => Some(16),
+        b"RGB3" | b"BGR3" => Some(24),
+        b"RGB4" | b"BGR4" | b"XR24" | b"AR24" | b"XB24" | b"AB24" => Some(32),
+        _ => None,
+    }
+}
+
+// Formats whose frames vary in size and have no pixel layout
+pub fn is_compressed(fourcc: &[u8; 4]) -> bool {
+    matches!(
+        fourcc,
+        b"MJPG" | b"JPEG" | b"H264" | b"HEVC" | b"H265" | b"VP80" | b"VP90" | b"MPG4" | b"MPG2"
+    )
+}
+
+pub fn is_jpeg(fourcc: &[u8; 4]) -> bool {
+    matches!(fourcc, b"MJPG" | b"JPEG")
+}
+
+// Size of one tightly packed frame
+pub fn frame_size(fourcc: &[u8; 4], width: u32, height: u32) -> Option<usize> {
+    let bpp = bits_per_pixel(fourcc)? as usize;
+    Some(width as usize * height as usize * bpp / 8)
+}
+
+// One plane of a frame: `rows` rows of `row_bytes` pixel data, `stride` apart
+pub struct Plane {
+    pub stride: usize,
+    pub row_bytes: usize,
+    pub rows: usize,
+}
+
+// bytesperline of unpadded rows (of the first plane)
+pub fn packed_stride(fourcc: &[u8; 4], width: u32) -> Option<u32> {
+    match fourcc {
+        b"NV12" | b"NV21" | b"NV16" | b"NV61" | b"YU12" | b"YV12" | b"422P" => Some(width),
+        _ => Some(width * bits_per_pixel(fourcc)? / 8),
+    }
+}
+
+// Plane layout for a single buffer frame with the given bytesperline
+pub fn planes(fourcc: &[u8; 4], width: u32, height: u32, stride: u32) -> Option<Vec<Plane>> {
+    let (w, h, s) = (width as usize, height as usize, stride as usize);
+    let plane = |stride, row_bytes, rows| Plane {
+        stride,
+        row_bytes,
+        rows,
+    };
+    let layout = match fourcc {
+        b"NV12" | b"NV21" => vec![plane(s, w, h), plane(s, w, h / 2)],
+        b"NV16" | b"NV61" => vec![plane(s, w, h), plane(s, w, h)],
+        b"YU12" | b"YV12" => vec![
+            plane(s, w, h),
+            plane(s / 2, w / 2, h / 2),
+            plane(s / 2, w / 2, h / 2),
+        ],
+        b"422P" => vec![
+            plane(s, w, h),
+            plane(s / 2, w / 2, h),
+            plane(s / 2, w / 2, h),
+        ],
+        _ => vec![plane(s, w * bits_per_pixel(fourcc)? as usize / 8, h)],
+    };
+    Some(layout)
+}
+
+// Bytes taken as one sample when scaling plane `index`: a pixel, a CbCr pair
+// in the semi-planar chroma plane, or the two pixels sharing their chroma in
+// packed 4:2:2
+fn sample_bytes(fourcc: &[u8; 4], index: usize) -> Option<usize> {
+    match fourcc {
+        b"YUYV" | b"YVYU" | b"UYVY" | b"VYUY" => Some(4),
+        b"NV12" | b"NV21" | b"NV16" | b"NV61" => Some(if index == 0 { 1 } else { 2 }),
+        b"YU12" | b"YV12" | b"422P" => Some(1),
+        _ => bits_per_pixel(fourcc)
+            .filter(|bits| bits % 8 == 0)
+            .map(|bits| bits as usize / 8),
+    }
+}
+
+// Where plane `index` is in a frame of the given layout
+pub fn plane_range(planes: &[Plane], index: usize) -> Range<usize> {
+    let start = planes[..index].iter().map(|p| p.stride * p.rows).sum();
+    start..start + planes[index].stride * planes[index].rows
+}
+
+// How many of the planes a buffer of `len` bytes holds in full
+pub fn planes_in(planes: &[Plane], len: usize) -> usize {
+    (0..planes.len())
+        .take_while(|&index| plane_range(planes, index).end <= len)
+        .count()
+}
+
+// Format of plane `index` on its own: GREY for the luma and the separate
+// chroma planes of planar YUV, None where there is no fourcc for it, as for
+// the interleaved chroma of NV12
+pub fn plane_fourcc(fourcc: &[u8; 4], index: usize) -> Option<[u8; 4]> {
+    match fourcc {
+        b"NV12" | b"NV21" | b"NV16" | b"NV61" if index == 0 => Some(*b"GREY"),
+        b"YU12" | b"YV12" | b"422P" => Some(*b"GREY"),
+        _ => None,
+    }
+}
+
+// Nearest neighbour downscale by an integer divisor, every plane alike, into
+// a tightly packed frame. None for formats without a known sample layout.
+pub fn downscale(
+    buf: &[u8],
+    fourcc: &[u8; 4],
+    planes: &[Plane],
+    divisor: usize,
+) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut offset = 0;
+    for (index, p) in planes.iter().enumerate() {
+        let unit = sample_bytes(fourcc, index)?;
+        let samples = p.row_bytes / unit / divisor;
+        for row in 0..p.rows / divisor {
+            let start = offset + row * divisor * p.stride;
+            for n in 0..samples {
+                let src = start + n * divisor * unit;
+                out.extend_from_slice(buf.get(src..src + unit)?);
+            }
+        }
+        offset += p.stride * p.rows;
+    }
+    Some(out)
+}
+
+// Copy out the pixel data without the padding at the end of each row
+pub fn strip_padding(buf: &[u8], planes: &[Plane]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(planes.iter().map(|p| p.row_bytes * p.rows).sum());
+    let mut offset = 0;
+    for p in planes {
+        for row in 0..p.rows {
+            let start = offset + row * p.stride;
+            match buf.get(start..start + p.row_bytes) {
+                Some(data) => out.extend_from_slice(data),
+                None => return out,
+            }
+        }
+        offset += p.stride * p.rows;
+    }
+    out
+}
+
+// A black frame in the given layout, padding included. Luma is 16 and chroma
+// 128, as in limited range video; RGB is all zero.
+pub fn black_frame(fourcc: &[u8; 4], planes: &[Plane]) -> Option<Vec<u8>> {
+    let pattern: &[u8] = match fourcc {
+        b"YUYV" | b"YVYU" => &[16, 128],
+        b"UYVY" | b"VYUY" => &[128, 16],
+        b"NV12" | b"NV21" | b"NV16" | b"NV61" | b"YU12" | b"YV12" | b"422P" => &[16],
+        _ if is_compressed(fourcc) => return None,
+        _ => &[0],
+    };
+    let mut out = Vec::with_capacity(planes.iter().map(|p| p.stride * p.rows).sum());
+    for (index, p) in planes.iter().enumerate() {
+        // Only packed formats mix luma and chroma in a plane
+        let pattern = if index > 0 { &[128][..] } else { pattern };
+        for _ in 0..p.rows {
+            out.extend(pattern.iter().cycle().take(p.row_bytes));
+            out.resize(out.len() + p.stride.saturating_sub(p.row_bytes), 0);
+        }
+    }
+    Some(out)
+}
+
+// --convert
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Convert {
+    // Luma and chroma bytes of packed 4:2:2 swapped, YUYV <-> UYVY
+    YuyvUyvy,
+}
+
+impl Convert {
+    pub fn parse(s: &str) -> Option<Convert> {
+        match s {
+            "yuyv-uyvy" => Some(Convert::YuyvUyvy),
+            _ => None,
+        }
+    }
+}
+
+// The packed 4:2:2 format with the luma and chroma bytes the other way round
+pub fn swapped_422(fourcc: &[u8; 4]) -> Option<[u8; 4]> {
+    match fourcc {
+        b"YUYV" => Some(*b"UYVY"),
+        b"UYVY" => Some(*b"YUYV"),
+        b"YVYU" => Some(*b"VYUY"),
+        b"VYUY" => Some(*b"YVYU"),
+        _ => None,
+    }
+}
+
+// Swap the bytes of every pair, Y0 U Y1 V becoming U Y0 V Y1 or a big
+// endian sample little endian. None unless the buffer holds whole units of
+// `unit` bytes, macropixels or samples.
+pub fn swap_pairs(buf: &[u8], unit: usize) -> Option<Vec<u8>> {
+    if !buf.len().is_multiple_of(unit) {
+        return None;
+    }
+    Some(
+        buf.chunks_exact(2)
+            .flat_map(|pair| [pair[1], pair[0]])
+            .collect(),
+    )
+}
+
+// Reverse the bytes of every word of `word` bytes, for --byteswap. None
+// unless the buffer holds whole words.
+pub fn swap_words(buf: &[u8], word: usize) -> Option<Vec<u8>> {
+    if !buf.len().is_multiple_of(word) {
+        return None;
+    }
+    Some(
+        buf.chunks_exact(word)
+            .flat_map(|w| w.iter().rev().copied())
+            .collect(),
+    )
+}
+
+// Part of the frame, for --software-crop
+#[derive(Clone, Copy)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+// For every plane: the pixels across and down that share a sample, and the
+// bytes of a sample. In packed 4:2:2 two pixels make one sample, as they
+// share their chroma.
+fn subsampling(fourcc: &[u8; 4]) -> Option<Vec<(u32, u32, usize)>> {
+    Some(match fourcc {
+        b"YUYV" | b"YVYU" | b"UYVY" | b"VYUY" => vec![(2, 1, 4)],
+        b"NV12" | b"NV21" => vec![(1, 1, 1), (2, 2, 2)],
+        b"NV16" | b"NV61" => vec![(1, 1, 1), (2, 1, 2)],
+        b"YU12" | b"YV12" => vec![(1, 1, 1), (2, 2, 1), (2, 2, 1)],
+        b"GREY" => vec![(1, 1, 1)],
+        b"Y16 " => vec![(1, 1, 2)],
+        b"422P" => vec![(1, 1, 1), (2, 1, 1), (2, 1, 1)],
+        _ => {
+            let bits = bits_per_pixel(fourcc).filter(|bits| bits % 8 == 0)?;
+            vec![(1, 1, bits as usize / 8)]
+        }
+    })
+}
+
+// Whether the rectangle starts and ends on whole samples of every plane
+pub fn crop_aligned(fourcc: &[u8; 4], rect: &Rect) -> Option<bool> {
+    let aligned = subsampling(fourcc)?.iter().all(|&(across, down, _)| {
+        [rect.x, rect.width]
+            .iter()
+            .all(|v| v.is_multiple_of(across))
+            && [rect.y, rect.height].iter().all(|v| v.is_multiple_of(down))
+    });
+    Some(aligned)
+}
+
+// Copy the rectangle out of a frame laid out as `planes`, tightly packed.
+// None for unknown layouts and frames too short for theirs.
+pub fn crop(buf: &[u8], fourcc: &[u8; 4], planes: &[Plane], rect: &Rect) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut offset = 0;
+    for (p, (across, down, unit)) in planes.iter().zip(subsampling(fourcc)?) {
+        let start = (rect.x / across) as usize * unit;
+        let len = (rect.width / across) as usize * unit;
+        let first = (rect.y / down) as usize;
+        for row in first..first + (rect.height / down) as usize {
+            let at = offset + row * p.stride + start;
+            out.extend_from_slice(buf.get(at..at + len)?);
+        }
+        offset += p.stride * p.rows;
+    }
+    Some(out)
+}
@@ -0,0 +1,80 @@
+// --png-sequence: every MJPEG frame decoded and saved as DIR/frame_%08d.png,
+// for annotation and vision tools that take image sequences, listed in
+// DIR/manifest.json
+
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::PathBuf;
+use v4l::buffer::Metadata;
+use v4l::video::capture::Parameters;
+use v4l::Format;
+
+use crate::container::ContainerWriter;
+use crate::jpeg;
+use crate::manifest::Manifest;
+use crate::pixfmt;
+use crate::png;
+
+pub struct PngSequence {
+    dir: PathBuf,
+    // Number of the next file, on across format changes
+    next: u64,
+    // Frames that did not decode, skipped without a file
+    failed: usize,
+    manifest: Manifest,
+}
+
+impl PngSequence {
+    pub fn open(dir: &str) -> io::Result<PngSequence> {
+        fs::create_dir_all(dir)?;
+        let dir = PathBuf::from(dir);
+        Ok(PngSequence {
+            manifest: Manifest::create(&dir)?,
+            dir,
+            next: 0,
+            failed: 0,
+        })
+    }
+}
+
+impl ContainerWriter for PngSequence {
+    fn begin(&mut self, format: &Format, _params: &Parameters) -> io::Result<()> {
+        if !pixfmt::is_jpeg(&format.fourcc.repr) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("--png-sequence needs MJPEG frames, not {}", format.fourcc),
+            ));
+        }
+        Ok(())
+    }
+
+    fn write_frame(&mut self, buf: &[u8], meta: &Metadata, _mapped: bool) -> io::Result<()> {
+        let image = match jpeg::decode(buf) {
+            Ok(image) => image,
+            Err(e) => {
+                eprintln!(
+                    "warning: frame seq {} not decoded, skipped: {e}",
+                    meta.sequence
+                );
+                self.failed += 1;
+                return Ok(());
+            }
+        };
+        let name = format!("frame_{:08}.png", self.next);
+        let png = png::encode_rgb(image.width, image.height, &image.rgb);
+        fs::write(self.dir.join(&name), &png)?;
+        self.manifest.add(&name, meta, png.len())?;
+        self.next += 1;
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        if self.failed > 0 {
+            eprintln!(
+                "PNG sequence: {} frames written, {} skipped as undecodable",
+                self.next, self.failed
+            );
+        }
+        self.manifest.finish()
+    }
+}
@@ -0,0 +1,135 @@
+// --power: cameras with a control to put them into standby, found by its
+// name as there is no standard one. The camera is woken before the capture,
+// and with suspend sent to standby again while the gate is closed and once
+// the capture is over.
+
+use std::io;
+use v4l::control::{Control, Description, Type, Value};
+use v4l::Device;
+
+use crate::controls::short_name;
+use crate::device;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Power {
+    On,
+    Suspend,
+}
+
+impl Power {
+    pub fn parse(s: &str) -> Option<Power> {
+        match s {
+            "on" => Some(Power::On),
+            "suspend" => Some(Power::Suspend),
+            _ => None,
+        }
+    }
+}
+
+// Controls that are set for standby, and ones that are set while powered
+const STANDBY_NAMES: &[&str] = &[
+    "standby",
+    "standby_mode",
+    "sleep",
+    "sleep_mode",
+    "suspend",
+    "power_save",
+    "power_saving",
+    "low_power",
+    "low_power_mode",
+];
+const POWER_NAMES: &[&str] = &["power", "power_on", "camera_power", "sensor_power"];
+
+pub struct Standby {
+    dev: Device,
+    desc: Description,
+    // Value that means standby
+    standby: bool,
+    suspended: bool,
+}
+
+// Only on and off, a boolean or an integer of 0 and 1
+fn two_state(desc: &Description) -> bool {
+    match desc.typ {
+        Type::Boolean => true,
+        Type::Integer => desc.minimum == 0 && desc.maximum == 1,
+        _ => false,
+    }
+}
+
+impl Standby {
+    // The standby control of the device, if it has one
+    pub fn find(path: &str) -> Result<Option<Standby>, String> {
+        let dev = device::open(path).map_err(|e| format!("{path}: {e}"))?;
+        let controls = dev
+            .query_controls()
+            .map_err(|e| format!("failed to query controls: {e}"))?;
+        let found = controls.into_iter().find_map(|desc| {
+            let name = short_name(&desc.name);
+            let standby = if STANDBY_NAMES.contains(&name.as_str()) {
+                true
+            } else if POWER_NAMES.contains(&name.as_str()) {
+                false
+            } else {
+                return None;
+            };
+            two_state(&desc).then_some((desc, standby))
+        });
+        let Some((desc, standby)) = found else {
+            return Ok(None);
+        };
+        let mut s = Standby {
+            dev,
+            desc,
+            standby,
+            suspended: false,
+        };
+        s.suspended = s.read()? == s.standby;
+        Ok(Some(s))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.desc.name
+    }
+
+    pub fn suspended(&self) -> bool {
+        self.suspended
+    }
+
+    fn read(&self) -> Result<bool, String> {
+        match self.dev.control(self.desc.id) {
+            Ok(Control {
+                value: Value::Boolean(on),
+                ..
+            }) => Ok(on),
+            Ok(Control {
+                value: Value::Integer(v),
+                ..
+            }) => Ok(v != 0),
+            Ok(_) => Err(format!("{}: unexpected value type", self.desc.name)),
+            Err(e) => Err(format!("failed to read {}: {e}", self.desc.name)),
+        }
+    }
+
+    fn set(&mut self, suspended: bool) -> io::Result<()> {
+        let on = suspended == self.standby;
+        let value = match self.desc.typ {
+            Type::Boolean => Value::Boolean(on),
+            _ => Value::Integer(on as i64),
+        };
+        self.dev.set_control(Control {
+            id: self.desc.id,
+            value,
+        })?;
+        self.suspended = suspended;
+        Ok(())
+    }
+
+    pub fn wake(&mut self) -> io::Result<()> {
+        self.set(false)
+    }
+
+    pub fn suspend(&mut self) -> io::Result<()> {
+        self.set(true)
+    }
+}
@@ -0,0 +1,69 @@
+use std::fs::File;
+use std::io::{self, Write};
+use v4l::Format;
+
+use crate::pixfmt::{self, Plane};
+
+// Second sink getting the captured frames, for a preview consumer. Raw frames
+// can be made smaller there with --preview-scale; the recording keeps the full
+// resolution.
+pub struct Preview {
+    file: File,
+    divisor: usize,
+    fourcc: [u8; 4],
+    // Layout of the captured frames when they are scaled down
+    planes: Option<Vec<Plane>>,
+}
+
+impl Preview {
+    pub fn open(path: &str, divisor: usize) -> io::Result<Preview> {
+        Ok(Preview {
+            file: File::create(path)?,
+            divisor,
+            fourcc: [0; 4],
+            planes: None,
+        })
+    }
+
+    // Set up the scaling for the format being captured
+    pub fn set_format(&mut self, fmt: &Format) {
+        self.fourcc = fmt.fourcc.repr;
+        self.planes = None;
+        if self.divisor <= 1 {
+            return;
+        }
+        // Chroma is subsampled by up to 2, keep it in step with the luma
+        let step = self.divisor as u32 * 2;
+        if pixfmt::is_compressed(&self.fourcc) {
+            eprintln!("--preview-scale needs raw frames, not {}", fmt.fourcc);
+        } else if !fmt.width.is_multiple_of(step) || !fmt.height.is_multiple_of(step) {
+            eprintln!(
+                "--preview-scale: {}x{} is not a multiple of {}, preview not scaled",
+                fmt.width, fmt.height, step
+            );
+        } else {
+            self.planes = pixfmt::planes(&self.fourcc, fmt.width, fmt.height, fmt.stride);
+            match self.planes {
+                Some(_) => eprintln!(
+                    "Preview: {}x{}",
+                    fmt.width / self.divisor as u32,
+                    fmt.height / self.divisor as u32
+                ),
+                None => eprintln!("--preview-scale: unknown layout of {}", fmt.fourcc),
+            }
+        }
+    }
+
+    // Whether frames are scaled, in which case write() wants them as captured
+    pub fn scales(&self) -> bool {
+        self.planes.is_some()
+    }
+
+    pub fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        let scaled = self
+            .planes
+            .as_ref()
+            .and_then(|planes| pixfmt::downscale(buf, &self.fourcc, planes, self.divisor));
+        self.file.write_all(scaled.as_deref().unwrap_or(buf))
+    }
+}
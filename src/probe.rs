@@ -0,0 +1,62 @@
+// One-shot description of a captured frame for --probe-frame
+
+use v4l::buffer::Metadata;
+use v4l::Format;
+
+use crate::{fields, mjpeg};
+
+const DUMP_BYTES: usize = 16;
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// `buf` is the whole mapped buffer, the frame is its first bytesused bytes
+pub fn report(buf: &[u8], meta: &Metadata, fmt: &Format, is_jpeg: bool) {
+    let used = (meta.bytesused as usize).min(buf.len());
+    let data = &buf[..used];
+    eprintln!("Frame probe:");
+    eprintln!("  sequence  : {}", meta.sequence);
+    eprintln!("  timestamp : {}", meta.timestamp);
+    eprintln!("  flags     : {}", meta.flags);
+    eprintln!("  field     : {}", fields::name(meta.field));
+    eprintln!("  bytesused : {}", meta.bytesused);
+    eprintln!("  buffer    : {} bytes", buf.len());
+    eprintln!("  sizeimage : {} bytes", fmt.size);
+    if fmt.size > 0 && meta.bytesused != fmt.size {
+        eprintln!(
+            "  frame is {:.1}% of sizeimage",
+            meta.bytesused as f64 * 100.0 / fmt.size as f64
+        );
+    }
+    eprintln!("  first     : {}", hex(&data[..used.min(DUMP_BYTES)]));
+    eprintln!(
+        "  last      : {}",
+        hex(&data[used.saturating_sub(DUMP_BYTES)..])
+    );
+    if !is_jpeg {
+        return;
+    }
+    match mjpeg::segments(data) {
+        Some(segs) => {
+            for seg in &segs {
+                eprintln!(
+                    "  marker    : ff{:02x} at {} ({} bytes)",
+                    seg.marker, seg.offset, seg.len
+                );
+            }
+            if !mjpeg::has_dht(&segs) {
+                eprintln!("  no DHT, the decoder must use the standard tables");
+            }
+        }
+        None => eprintln!("  JPEG headers are missing or truncated"),
+    }
+    match mjpeg::frame_end(data) {
+        Some(end) => eprintln!("  EOI ends at {} ({} bytes after it)", end, used - end),
+        None => eprintln!("  no EOI marker"),
+    }
+}
@@ -0,0 +1,134 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::time::Duration;
+use v4l::timestamp::Timestamp;
+
+use crate::clock::Clock;
+
+// Source of the presentation timestamps of written frames
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PtsBase {
+    // Driver buffer timestamps
+    Camera,
+    // Monotonic time the frame was dequeued
+    Wallclock,
+    // Written frame count at the nominal frame rate, so dropped or skipped
+    // frames leave no gaps
+    Sequence,
+}
+
+impl PtsBase {
+    pub fn parse(s: &str) -> Option<PtsBase> {
+        match s {
+            "camera" => Some(PtsBase::Camera),
+            "wallclock" => Some(PtsBase::Wallclock),
+            "sequence" => Some(PtsBase::Sequence),
+            _ => None,
+        }
+    }
+}
+
+// Assigns timestamps to written frames, starting from zero at the first one
+pub struct Pts {
+    base: PtsBase,
+    interval: Duration,
+    first: Option<Duration>,
+    frames: u32,
+}
+
+fn to_duration(ts: Timestamp) -> Duration {
+    Duration::from_secs(ts.sec.max(0) as u64) + Duration::from_micros(ts.usec.max(0) as u64)
+}
+
+impl Pts {
+    pub fn new(base: PtsBase, framerate: u32) -> Pts {
+        Pts {
+            base,
+            interval: Duration::from_secs(1) / framerate.max(1),
+            first: None,
+            frames: 0,
+        }
+    }
+
+    // Timestamp of the next written frame
+    pub fn next(&mut self, camera: Timestamp) -> Duration {
+        let t = match self.base {
+            PtsBase::Camera => to_duration(camera),
+            PtsBase::Wallclock => Clock::Monotonic.now(),
+            PtsBase::Sequence => self.interval * self.frames,
+        };
+        self.frames += 1;
+        let first = *self.first.get_or_insert(t);
+        t.saturating_sub(first)
+    }
+}
+
+// --zero-base-pts: buffer timestamps counted from the first frame, for the
+// pts framing and everything else reading them
+#[derive(Default)]
+pub struct ZeroBase {
+    first: Option<Duration>,
+}
+
+impl ZeroBase {
+    pub fn rebase(&mut self, ts: Timestamp) -> Timestamp {
+        let t = to_duration(ts);
+        let first = *self.first.get_or_insert(t);
+        Timestamp::from(t.saturating_sub(first))
+    }
+}
+
+// Unit of the timestamps in a --pts-file
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PtsUnit {
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    // The 90 kHz clock of MPEG-TS and RTP
+    Ticks90k,
+}
+
+impl PtsUnit {
+    pub fn parse(s: &str) -> Option<PtsUnit> {
+        match s {
+            "ns" => Some(PtsUnit::Nanoseconds),
+            "us" => Some(PtsUnit::Microseconds),
+            "ms" => Some(PtsUnit::Milliseconds),
+            "90k" => Some(PtsUnit::Ticks90k),
+            _ => None,
+        }
+    }
+
+    fn convert(self, t: Duration) -> u128 {
+        match self {
+            PtsUnit::Nanoseconds => t.as_nanos(),
+            PtsUnit::Microseconds => t.as_micros(),
+            PtsUnit::Milliseconds => t.as_millis(),
+            PtsUnit::Ticks90k => t.as_nanos() * 90 / 1_000_000,
+        }
+    }
+}
+
+// --pts-file: the timestamp of every written frame on a line of its own,
+// for muxing the raw stream elsewhere
+pub struct PtsFile {
+    out: BufWriter<File>,
+    unit: PtsUnit,
+}
+
+impl PtsFile {
+    pub fn create(path: &str, unit: PtsUnit) -> io::Result<PtsFile> {
+        Ok(PtsFile {
+            out: BufWriter::new(File::create(path)?),
+            unit,
+        })
+    }
+
+    pub fn log(&mut self, pts: Duration) -> io::Result<()> {
+        writeln!(self.out, "{}", self.unit.convert(pts))
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
@@ -0,0 +1,65 @@
+// --reorder-depth: hold back a few frames and let them out by timestamp, so
+// that the writers never see the timestamps going back. Each frame carries
+// a `T` of the caller's along, for what is done with it once it is let out.
+
+use v4l::buffer::Metadata;
+
+fn key(meta: &Metadata) -> (libc::time_t, libc::time_t) {
+    (meta.timestamp.sec, meta.timestamp.usec)
+}
+
+pub struct Reorder<T> {
+    depth: usize,
+    // Sorted by timestamp, oldest first
+    held: Vec<(Vec<u8>, Metadata, T)>,
+    // Timestamp of the last frame let out
+    last: Option<(libc::time_t, libc::time_t)>,
+    // Frames older than one already let out, dropped
+    pub late: usize,
+}
+
+impl<T> Reorder<T> {
+    pub fn new(depth: usize) -> Self {
+        Reorder {
+            depth,
+            held: Vec::with_capacity(depth + 1),
+            last: None,
+            late: 0,
+        }
+    }
+
+    // Take a frame, returning the oldest one once more than `depth` are held
+    pub fn push(
+        &mut self,
+        frame: &[u8],
+        meta: &Metadata,
+        tag: T,
+    ) -> Option<(Vec<u8>, Metadata, T)> {
+        let t = key(meta);
+        if self.last.is_some_and(|last| t < last) {
+            eprintln!(
+                "warning: frame seq {} is older than the frames written, dropped",
+                meta.sequence
+            );
+            self.late += 1;
+            return None;
+        }
+        // After the frames with the same timestamp, which keeps their order
+        let at = self.held.partition_point(|(_, held, _)| key(held) <= t);
+        self.held.insert(at, (frame.to_vec(), *meta, tag));
+        if self.held.len() <= self.depth {
+            return None;
+        }
+        let (frame, meta, tag) = self.held.remove(0);
+        self.last = Some(key(&meta));
+        Some((frame, meta, tag))
+    }
+
+    // The frames still held, on shutdown and before the output is cut
+    pub fn drain(&mut self) -> impl Iterator<Item = (Vec<u8>, Metadata, T)> {
+        if let Some((_, meta, _)) = self.held.last() {
+            self.last = Some(key(meta));
+        }
+        std::mem::take(&mut self.held).into_iter()
+    }
+}
@@ -0,0 +1,132 @@
+// --request-api: per-frame controls through the V4L2 request API. Every
+// capture buffer is queued in a media request that carries the controls of
+// the next schedule entry, so that they apply to exactly the frame the
+// buffer receives, e.g. for exposure bracketing.
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::raw::c_void;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
+use std::sync::Arc;
+use v4l::device::Handle;
+use v4l::Device;
+use v4l2capture_rs::stream::QueueHook;
+
+use crate::controls::{self, Settings};
+
+// _IOR('|', 0x05, int), _IO('|', 0x80) and _IO('|', 0x81) of linux/media.h
+const MEDIA_IOC_REQUEST_ALLOC: libc::c_ulong = 0x8004_7c05;
+const MEDIA_REQUEST_IOC_QUEUE: libc::c_ulong = 0x7c80;
+const MEDIA_REQUEST_IOC_REINIT: libc::c_ulong = 0x7c81;
+
+// The media controller node of a video node, found in sysfs next to it
+pub fn media_device(video: &str) -> Option<String> {
+    let name = Path::new(video).file_name()?;
+    let dev = fs::canonicalize(
+        Path::new("/sys/class/video4linux")
+            .join(name)
+            .join("device"),
+    )
+    .ok()?;
+    // Platform drivers register it on the device, UVC on the USB interface
+    let media = [Some(dev.as_path()), dev.parent()]
+        .into_iter()
+        .flatten()
+        .flat_map(|dir| fs::read_dir(dir).into_iter().flatten().flatten())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .find(|name| name.starts_with("media"))
+        .map(|name| format!("/dev/{name}"));
+    media
+}
+
+pub struct Requests {
+    media: File,
+    video: Arc<Handle>,
+    schedule: Vec<Settings>,
+    next: usize,
+    // Request of every buffer, allocated when it is first queued
+    requests: Vec<Option<File>>,
+}
+
+impl Requests {
+    // Check the schedule against the controls of `dev`
+    pub fn new(
+        dev: &Device,
+        media: &str,
+        schedule: &[Vec<(String, String)>],
+    ) -> Result<Requests, String> {
+        let controls = dev
+            .query_controls()
+            .map_err(|e| format!("failed to query controls: {e}"))?;
+        let schedule = schedule
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| {
+                controls::resolve(&controls, entry).map_err(|e| format!("entry {}: {e}", i + 1))
+            })
+            .collect::<Result<_, _>>()?;
+        let media = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(media)
+            .map_err(|e| format!("{media}: {e}"))?;
+        Ok(Requests {
+            media,
+            video: dev.handle(),
+            schedule,
+            next: 0,
+            requests: Vec::new(),
+        })
+    }
+
+    fn alloc(&self) -> io::Result<File> {
+        let mut fd: libc::c_int = -1;
+        if unsafe { libc::ioctl(self.media.as_raw_fd(), MEDIA_IOC_REQUEST_ALLOC, &mut fd) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+}
+
+fn request_ioctl(request: &File, op: libc::c_ulong) -> io::Result<()> {
+    if unsafe { libc::ioctl(request.as_raw_fd(), op, std::ptr::null_mut::<c_void>()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl QueueHook for Requests {
+    // A buffer is queued again once it has been dequeued, and its request
+    // with it completed, so the request can be reused
+    fn before_queue(&mut self, index: usize) -> io::Result<Option<RawFd>> {
+        if index >= self.requests.len() {
+            self.requests.resize_with(index + 1, || None);
+        }
+        let fd = match &self.requests[index] {
+            Some(request) => {
+                request_ioctl(request, MEDIA_REQUEST_IOC_REINIT)?;
+                request.as_raw_fd()
+            }
+            None => {
+                let request = self.alloc()?;
+                let fd = request.as_raw_fd();
+                self.requests[index] = Some(request);
+                fd
+            }
+        };
+        let entry = self.next;
+        self.next = (self.next + 1) % self.schedule.len();
+        self.schedule[entry]
+            .apply(self.video.fd(), Some(fd))
+            .map_err(|e| io::Error::other(format!("request of buffer {index}: {e}")))?;
+        Ok(Some(fd))
+    }
+
+    fn after_queue(&mut self, index: usize) -> io::Result<()> {
+        match &self.requests[index] {
+            Some(request) => request_ioctl(request, MEDIA_REQUEST_IOC_QUEUE),
+            None => Ok(()),
+        }
+    }
+}
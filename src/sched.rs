@@ -0,0 +1,34 @@
+// Real-time scheduling of the capture thread, for --rt-priority
+
+use std::io;
+
+// Priorities from here on run ahead of most kernel threads
+const HIGH_PRIORITY: i32 = 90;
+
+// Switch the calling thread to SCHED_FIFO at `priority` (1-99)
+pub fn set_fifo(priority: i32) -> io::Result<()> {
+    if priority >= HIGH_PRIORITY {
+        eprintln!(
+            "warning: SCHED_FIFO priority {priority} can starve kernel threads; a busy loop \
+             at this priority may lock up the system"
+        );
+    }
+    let param = libc::sched_param {
+        sched_priority: priority,
+    };
+    if unsafe { libc::sched_setscheduler(0, libc::SCHED_FIFO, &param) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// What to do about a failed set_fifo()
+pub fn error_hint(e: &io::Error, priority: i32) -> String {
+    match e.raw_os_error() {
+        Some(libc::EPERM) => format!(
+            "not permitted; needs root, CAP_SYS_NICE or an RLIMIT_RTPRIO of at least {priority} \
+             (rtprio in /etc/security/limits.conf)"
+        ),
+        _ => e.to_string(),
+    }
+}
@@ -0,0 +1,286 @@
+// --self-test: capture a few frames from the vivid test driver and put them
+// through the converters and writers, for checking a build or a kernel
+
+use std::time::Duration;
+use v4l::buffer::{Metadata, Type};
+use v4l::video::capture::Parameters;
+use v4l::video::Capture;
+use v4l::{Device, Format, FourCC};
+use v4l2capture_rs::stream::MmapStream;
+
+use crate::container::ContainerWriter;
+use crate::discover;
+use crate::fields::Bob;
+use crate::loopback::Loopback;
+use crate::motion::Motion;
+use crate::options::Options;
+use crate::output::{Framing, Output};
+use crate::pixfmt::{self, Rect};
+use crate::verify;
+
+const WIDTH: u32 = 640;
+const HEIGHT: u32 = 480;
+const FRAMES: usize = 10;
+
+// Outcome of every component, printed as it is known
+struct Report {
+    failures: usize,
+}
+
+impl Report {
+    fn result(&mut self, component: &str, result: Result<String, String>) {
+        match result {
+            Ok(detail) => println!("PASS  {component:<14} {detail}"),
+            Err(reason) => {
+                println!("FAIL  {component:<14} {reason}");
+                self.failures += 1;
+            }
+        }
+    }
+
+    fn skip(&self, component: &str, reason: &str) {
+        println!("SKIP  {component:<14} {reason}");
+    }
+}
+
+// Set the known format and check that the driver kept it
+fn configure(dev: &Device) -> Result<Format, String> {
+    let wanted = Format::new(WIDTH, HEIGHT, FourCC::new(b"YUYV"));
+    let fmt = Capture::set_format(dev, &wanted).map_err(|e| format!("VIDIOC_S_FMT: {e}"))?;
+    if fmt.width != WIDTH || fmt.height != HEIGHT || fmt.fourcc != wanted.fourcc {
+        return Err(format!(
+            "got {}x{} {}, asked for {WIDTH}x{HEIGHT} YUYV",
+            fmt.width, fmt.height, fmt.fourcc
+        ));
+    }
+    Ok(fmt)
+}
+
+// Frames with their metadata, non-empty and in sequence
+fn capture(dev: &Device) -> Result<Vec<(Vec<u8>, Metadata)>, String> {
+    let mut stream = MmapStream::with_buffers(dev, Type::VideoCapture, 4)
+        .map_err(|e| format!("buffer allocation: {e}"))?;
+    stream.set_poll_timeout(Some(Duration::from_secs(2)));
+    stream
+        .start()
+        .map_err(|e| format!("VIDIOC_STREAMON: {e}"))?;
+    let mut frames: Vec<(Vec<u8>, Metadata)> = Vec::with_capacity(FRAMES);
+    for n in 0..FRAMES {
+        let (buf, meta) = stream.next_frame().map_err(|e| format!("frame {n}: {e}"))?;
+        if meta.bytesused == 0 {
+            return Err(format!("frame {n} (seq {}) is empty", meta.sequence));
+        }
+        if let Some((_, last)) = frames.last() {
+            if meta.sequence <= last.sequence {
+                return Err(format!(
+                    "frame {n}: sequence {} after {}",
+                    meta.sequence, last.sequence
+                ));
+            }
+        }
+        frames.push((buf[..meta.bytesused as usize].to_vec(), *meta));
+    }
+    stream.release();
+    Ok(frames)
+}
+
+fn check_len(what: &str, len: usize, expected: usize) -> Result<(), String> {
+    if len != expected {
+        return Err(format!("{what}: {len} bytes, expected {expected}"));
+    }
+    Ok(())
+}
+
+fn converters(report: &mut Report, fmt: &Format, frames: &[(Vec<u8>, Metadata)]) {
+    let fourcc = &fmt.fourcc.repr;
+    let frame_planes = pixfmt::planes(fourcc, fmt.width, fmt.height, fmt.stride);
+    let field_planes = pixfmt::planes(fourcc, fmt.width, fmt.height / 2, fmt.stride);
+    let (Some(frame_planes), Some(field_planes)) = (frame_planes, field_planes) else {
+        report.result(
+            "converters",
+            Err(format!("no plane layout for {}", fmt.fourcc)),
+        );
+        return;
+    };
+    let packed = pixfmt::frame_size(fourcc, fmt.width, fmt.height).unwrap_or(0);
+
+    let strip = frames.iter().try_for_each(|(buf, _)| {
+        check_len(
+            "frame",
+            pixfmt::strip_padding(buf, &frame_planes).len(),
+            packed,
+        )
+    });
+    report.result(
+        "strip-padding",
+        strip.map(|()| format!("{packed} bytes a frame")),
+    );
+
+    let rect = Rect {
+        x: fmt.width / 4,
+        y: fmt.height / 4,
+        width: fmt.width / 2,
+        height: fmt.height / 2,
+    };
+    let cropped = pixfmt::frame_size(fourcc, rect.width, rect.height).unwrap_or(0);
+    let crop = frames.iter().try_for_each(|(buf, _)| {
+        let out = pixfmt::crop(buf, fourcc, &frame_planes, &rect).ok_or("frame too short")?;
+        check_len("cropped frame", out.len(), cropped)
+    });
+    report.result(
+        "crop",
+        crop.map(|()| format!("{}x{} of {cropped} bytes", rect.width, rect.height)),
+    );
+
+    let frame_size: usize = frame_planes.iter().map(|p| p.stride * p.rows).sum();
+    let mut bob = Bob::new(frame_planes, field_planes);
+    let deinterlace = frames.iter().try_for_each(|(buf, _)| {
+        let out = bob.apply(buf, None).ok_or("frame too short")?;
+        check_len("deinterlaced frame", out.len(), frame_size)
+    });
+    report.result(
+        "bob",
+        deinterlace.map(|()| format!("{frame_size} bytes a frame")),
+    );
+
+    let swap = frames.iter().try_for_each(|(buf, _)| {
+        let out = pixfmt::swap_pairs(buf, 4).ok_or("not whole macropixels")?;
+        let back = pixfmt::swap_pairs(&out, 4).ok_or("not whole macropixels")?;
+        if back != *buf {
+            return Err("swapping twice changed the frame".to_string());
+        }
+        Ok(())
+    });
+    report.result("yuyv-uyvy", swap.map(|()| "round trip".to_string()));
+
+    let motion = match Motion::new(0.0, fmt, 0, 0) {
+        Some(mut motion) => {
+            frames.iter().for_each(|(buf, _)| {
+                motion.update(buf);
+            });
+            Ok(format!("last score {:.2}", motion.score()))
+        }
+        None => Err(format!("no luma layout for {}", fmt.fourcc)),
+    };
+    report.result("motion", motion);
+}
+
+// Write the frames and, if it makes a file, read it back
+fn write_all(
+    writer: &mut dyn ContainerWriter,
+    fmt: &Format,
+    frames: &[(Vec<u8>, Metadata)],
+    verify: Option<verify::Expected>,
+) -> Result<String, String> {
+    writer
+        .begin(fmt, &Parameters::with_fps(30))
+        .map_err(|e| format!("begin: {e}"))?;
+    for (n, (buf, meta)) in frames.iter().enumerate() {
+        writer
+            .write_frame(buf, meta, false)
+            .map_err(|e| format!("frame {n}: {e}"))?;
+    }
+    writer.finalize().map_err(|e| format!("finalize: {e}"))?;
+    writer
+        .complete(true)
+        .map_err(|e| format!("complete: {e}"))?;
+    match (verify, writer.written_path()) {
+        (Some(expected), Some(path)) => verify::check(path, &expected),
+        _ => Ok(format!("{} frames", frames.len())),
+    }
+}
+
+fn writers(report: &mut Report, fmt: &Format, frames: &[(Vec<u8>, Metadata)]) {
+    let bytes = frames.iter().map(|(buf, _)| buf.len() as u64).sum();
+    for (name, tag, framing) in [
+        ("output raw", "raw", Framing::Raw),
+        ("output pts", "pts", Framing::Pts),
+    ] {
+        let path =
+            std::env::temp_dir().join(format!("v4l2capture-selftest-{}.{tag}", std::process::id()));
+        let opts = Options {
+            out_file: path.to_string_lossy().into_owned(),
+            framing,
+            ..Options::default()
+        };
+        let expected = verify::Expected {
+            frames: frames.len(),
+            bytes,
+            framing,
+            jpeg: false,
+            pad_to: None,
+        };
+        let result = Output::open(&opts)
+            .map_err(|e| format!("open {}: {e}", opts.out_file))
+            .and_then(|mut out| write_all(&mut out, fmt, frames, Some(expected)));
+        let _ = std::fs::remove_file(&opts.out_file);
+        report.result(name, result);
+    }
+    // vivid has output nodes too, taking the formats its capture side gives
+    match discover::find_driver("vivid", true) {
+        Some(path) => {
+            let result = Loopback::open(&path, None)
+                .map_err(|e| format!("open {path}: {e}"))
+                .and_then(|mut out| write_all(&mut out, fmt, frames, None))
+                .map(|detail| format!("{detail} to {path}"));
+            report.result("loopback", result);
+        }
+        None => report.skip("loopback", "no vivid output node"),
+    }
+}
+
+// Run all checks on `device`, or the first vivid capture node if it is
+// empty. False if any of them failed.
+pub fn run(device: &str) -> bool {
+    let mut report = Report { failures: 0 };
+    let path = match device {
+        "" => discover::find_driver("vivid", false),
+        device => Some(device.to_string()),
+    };
+    let Some(path) = path else {
+        report.result(
+            "device",
+            Err("no vivid capture node, try modprobe vivid".to_string()),
+        );
+        return false;
+    };
+    let dev = match Device::with_path(&path) {
+        Ok(dev) => dev,
+        Err(e) => {
+            report.result("device", Err(format!("{path}: {e}")));
+            return false;
+        }
+    };
+    report.result("device", Ok(path.clone()));
+    let fmt = configure(&dev);
+    let detail = fmt
+        .as_ref()
+        .map(|fmt| format!("{}x{} {}", fmt.width, fmt.height, fmt.fourcc));
+    report.result("format", detail.map_err(Clone::clone));
+    let Ok(fmt) = fmt else {
+        return false;
+    };
+    let frames = capture(&dev);
+    let detail = frames.as_ref().map(|frames| {
+        let (first, last) = (&frames[0].1, &frames[frames.len() - 1].1);
+        format!(
+            "{} frames, seq {}..{}",
+            frames.len(),
+            first.sequence,
+            last.sequence
+        )
+    });
+    report.result("capture", detail.map_err(Clone::clone));
+    let Ok(frames) = frames else {
+        return false;
+    };
+    drop(dev);
+    converters(&mut report, &fmt, &frames);
+    writers(&mut report, &fmt, &frames);
+    if report.failures > 0 {
+        println!("{} component(s) failed", report.failures);
+    } else {
+        println!("All components passed");
+    }
+    report.failures == 0
+}
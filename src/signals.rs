@@ -0,0 +1,21 @@
+use nix::sys::signal::{signal, SigHandler, Signal};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Signals received but not yet handled by the capture loop
+static PENDING: [AtomicBool; 32] = [const { AtomicBool::new(false) }; 32];
+
+extern "C" fn on_signal(sig: libc::c_int) {
+    if let Some(flag) = PENDING.get(sig as usize) {
+        flag.store(true, Ordering::SeqCst);
+    }
+}
+
+// Record deliveries of `sig` instead of taking the default action
+pub fn watch(sig: Signal) -> nix::Result<()> {
+    unsafe { signal(sig, SigHandler::Handler(on_signal)) }.map(|_| ())
+}
+
+// Whether `sig` arrived since the last call
+pub fn take(sig: Signal) -> bool {
+    PENDING[sig as usize].swap(false, Ordering::SeqCst)
+}
@@ -0,0 +1,115 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "png")]
+use crate::jpeg;
+use crate::mjpeg;
+use crate::output::{self, local_tm, now};
+#[cfg(feature = "png")]
+use crate::png;
+
+// File format of the stills
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotFormat {
+    // The frame as captured
+    Raw,
+    // MJPEG frames decoded and stored as PNG
+    #[cfg(feature = "png")]
+    Png,
+}
+
+impl SnapshotFormat {
+    pub fn parse(s: &str) -> Option<SnapshotFormat> {
+        match s {
+            "jpeg" | "raw" => Some(SnapshotFormat::Raw),
+            #[cfg(feature = "png")]
+            "png" => Some(SnapshotFormat::Png),
+            _ => None,
+        }
+    }
+}
+
+// Still images saved on request next to the main recording
+pub struct Snapshot {
+    dir: PathBuf,
+    is_jpeg: bool,
+    #[cfg(feature = "png")]
+    format: SnapshotFormat,
+    ext: String,
+    // --atomic-output, and whether to delete failed files
+    atomic: Option<bool>,
+}
+
+impl Snapshot {
+    pub fn new(dir: &str, fourcc: &[u8; 4], is_jpeg: bool, mut format: SnapshotFormat) -> Snapshot {
+        if format != SnapshotFormat::Raw && !is_jpeg {
+            eprintln!("PNG snapshots need MJPEG frames, saving raw frames instead");
+            format = SnapshotFormat::Raw;
+        }
+        let ext = if format != SnapshotFormat::Raw {
+            "png".to_string()
+        } else if is_jpeg {
+            "jpg".to_string()
+        } else {
+            String::from_utf8_lossy(fourcc).trim().to_lowercase()
+        };
+        Snapshot {
+            dir: Path::new(dir).to_path_buf(),
+            is_jpeg,
+            #[cfg(feature = "png")]
+            format,
+            ext,
+            atomic: None,
+        }
+    }
+
+    // Write each still as <name>.tmp first and rename it when complete
+    pub fn set_atomic(&mut self, delete_incomplete: bool) {
+        self.atomic = Some(delete_incomplete);
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let Some(delete_incomplete) = self.atomic else {
+            return fs::write(path, data);
+        };
+        let path = path.to_string_lossy();
+        let result = fs::write(output::tmp_name(&path), data);
+        output::complete_tmp(&path, result.is_ok(), delete_incomplete)?;
+        result
+    }
+
+    // Save one frame as snapshot_YYYYMMDD_HHMMSS_SEQ.ext and return its path
+    pub fn save(&self, buf: &[u8], sequence: u32) -> io::Result<PathBuf> {
+        let tm = local_tm(now());
+        let name = format!(
+            "snapshot_{:04}{:02}{:02}_{:02}{:02}{:02}_{}.{}",
+            tm.tm_year + 1900,
+            tm.tm_mon + 1,
+            tm.tm_mday,
+            tm.tm_hour,
+            tm.tm_min,
+            tm.tm_sec,
+            sequence,
+            self.ext
+        );
+        let path = self.dir.join(name);
+        #[cfg(feature = "png")]
+        if self.format == SnapshotFormat::Png {
+            let image = jpeg::decode(buf)?;
+            self.write(
+                &path,
+                &png::encode_rgb(image.width, image.height, &image.rgb),
+            )?;
+            return Ok(path);
+        }
+        // Image viewers do not know the implicit MJPEG Huffman tables
+        match mjpeg::segments(buf) {
+            Some(segs) if self.is_jpeg && !mjpeg::has_dht(&segs) => {
+                self.write(&path, &mjpeg::insert_default_dht(buf, &segs))?
+            }
+            _ => self.write(&path, buf)?,
+        }
+        Ok(path)
+    }
+}
@@ -0,0 +1,399 @@
+use std::fs::File;
+use std::io::{self, ErrorKind, Read};
+use std::time::{Duration, Instant};
+use v4l::buffer::{Flags, Metadata};
+
+use crate::crc;
+use crate::mjpeg;
+use crate::output::{self, PTS_HEADER_LEN};
+use crate::pixfmt;
+use v4l2capture_rs::stream::MmapStream;
+
+const READ_CHUNK: usize = 1 << 20;
+
+// Where frames come from: a live device or a previously captured file
+pub enum Source {
+    Device(MmapStream),
+    File(FileSource),
+}
+
+impl Source {
+    // Next frame; a file source reports its end with UnexpectedEof
+    pub fn next_frame(&mut self) -> io::Result<(&[u8], &Metadata)> {
+        match self {
+            Source::Device(stream) => stream.next_frame(),
+            Source::File(file) => file.next_frame(),
+        }
+    }
+
+    // The driver stream, None for a file
+    pub fn stream(&self) -> Option<&MmapStream> {
+        match self {
+            Source::Device(stream) => Some(stream),
+            Source::File(_) => None,
+        }
+    }
+
+    // Print the state of the buffers, for --no-requeue-on-error
+    pub fn dump_state(&self) {
+        let Source::Device(stream) = self else {
+            return;
+        };
+        if let Some(index) = stream.last_index() {
+            let meta = stream.metadata(index);
+            eprintln!(
+                "Held buffer {}: bytesused {}, seq {}, timestamp {}, flags {}, field {}",
+                index, meta.bytesused, meta.sequence, meta.timestamp, meta.flags, meta.field
+            );
+        }
+        for index in 0..stream.count() {
+            match stream.query_flags(index) {
+                Ok(flags) => eprintln!("Buffer {}: {}", index, flags),
+                Err(e) => eprintln!("Buffer {}: QUERYBUF failed: {}", index, e),
+            }
+        }
+    }
+}
+
+enum Framing {
+    // Raw frames of a fixed size
+    Fixed(usize),
+    // JPEG frames delimited by SOI/EOI, skipping any padding in between
+    Jpeg,
+    // Records written with --framing pts, which carry their own length and
+    // timestamp, and with --framing pts-crc a CRC of the data
+    Pts { crc: bool },
+}
+
+// What to do about a damaged record of a --framing pts file
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OnCorrupt {
+    // Stop the replay with an error
+    Abort,
+    // Warn and go on with the next record, or end at a truncated one
+    Skip,
+}
+
+impl OnCorrupt {
+    pub fn parse(s: &str) -> Option<OnCorrupt> {
+        match s {
+            "abort" => Some(OnCorrupt::Abort),
+            "skip" => Some(OnCorrupt::Skip),
+            _ => None,
+        }
+    }
+}
+
+// Longest record accepted from a --framing pts file, anything longer means
+// the file is not one
+const MAX_RECORD: usize = 1 << 30;
+
+// Reads frames back from a capture file. Timestamps of raw files are
+// synthesized from the frame rate since they do not carry any; --framing pts
+// files are replayed with the spacing they were captured with.
+pub struct FileSource {
+    file: File,
+    framing: Framing,
+    data: Vec<u8>,
+    start: usize,
+    frame: (usize, usize),
+    meta: Metadata,
+    interval: Duration,
+    frames: u32,
+    eof: bool,
+    // Timestamp of the last record read from a --framing pts file
+    pts: Duration,
+    // When the first record was replayed, and its timestamp
+    replay_start: Option<(Instant, Duration)>,
+    // --repeat-last-on-eof, and when the next repetition is due once the end
+    // is reached
+    repeat_last: bool,
+    next_repeat: Option<Instant>,
+    on_corrupt: OnCorrupt,
+    // Bytes of the file dropped from `data`, for the offsets in messages
+    consumed: u64,
+    // Records read, damaged ones included
+    records: usize,
+    skipped: usize,
+}
+
+impl FileSource {
+    pub fn open(
+        path: &str,
+        fourcc: &[u8; 4],
+        width: u32,
+        height: u32,
+        framerate: u32,
+        records: output::Framing,
+    ) -> io::Result<FileSource> {
+        let name = String::from_utf8_lossy(fourcc);
+        let framing = if records != output::Framing::Raw {
+            Framing::Pts {
+                crc: records == output::Framing::PtsCrc,
+            }
+        } else if pixfmt::is_jpeg(fourcc) {
+            Framing::Jpeg
+        } else if pixfmt::is_compressed(fourcc) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("cannot find frame boundaries in a {name} stream"),
+            ));
+        } else if let Some(size) = pixfmt::frame_size(fourcc, width, height) {
+            Framing::Fixed(size)
+        } else {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("unknown size of a {name} frame"),
+            ));
+        };
+        Ok(FileSource {
+            file: File::open(path)?,
+            framing,
+            data: Vec::new(),
+            start: 0,
+            frame: (0, 0),
+            meta: Metadata::default(),
+            interval: Duration::from_secs(1) / framerate.max(1),
+            frames: 0,
+            eof: false,
+            pts: Duration::ZERO,
+            replay_start: None,
+            repeat_last: false,
+            next_repeat: None,
+            on_corrupt: OnCorrupt::Abort,
+            consumed: 0,
+            records: 0,
+            skipped: 0,
+        })
+    }
+
+    pub fn set_on_corrupt(&mut self, on_corrupt: OnCorrupt) {
+        self.on_corrupt = on_corrupt;
+    }
+
+    // Go on with the last frame at the frame rate after the end of the file
+    pub fn set_repeat_last(&mut self, repeat: bool) {
+        self.repeat_last = repeat;
+    }
+
+    // Read more data, dropping what has already been consumed.
+    // Returns false at end of file.
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.eof {
+            return Ok(false);
+        }
+        // The last frame stays, to be repeated if this was the end
+        let keep = self.frame.0.min(self.start);
+        self.data.drain(..keep);
+        self.consumed += keep as u64;
+        self.start -= keep;
+        self.frame.0 -= keep;
+        let len = self.data.len();
+        self.data.resize(len + READ_CHUNK, 0);
+        let n = loop {
+            match self.file.read(&mut self.data[len..]) {
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                result => break result?,
+            }
+        };
+        self.data.truncate(len + n);
+        self.eof = n == 0;
+        Ok(!self.eof)
+    }
+
+    // Make at least `len` bytes from the start available
+    fn fill_to(&mut self, len: usize) -> io::Result<()> {
+        while self.data.len() - self.start < len {
+            if !self.fill()? {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+        }
+        Ok(())
+    }
+
+    fn find(&self, from: usize, pattern: &[u8]) -> Option<usize> {
+        self.data[from..]
+            .windows(pattern.len())
+            .position(|w| w == pattern)
+            .map(|p| from + p)
+    }
+
+    // Locate the next frame in the buffered data as (offset, length)
+    fn next_range(&mut self) -> io::Result<(usize, usize)> {
+        match self.framing {
+            Framing::Fixed(size) => {
+                self.fill_to(size)?;
+                Ok((self.start, size))
+            }
+            Framing::Pts { crc } => loop {
+                let header_len = if crc {
+                    output::PTS_CRC_HEADER_LEN
+                } else {
+                    PTS_HEADER_LEN
+                };
+                let offset = self.consumed + self.start as u64;
+                let record = self.records;
+                self.fill_record(header_len, record, offset)?;
+                let header = &self.data[self.start..self.start + header_len];
+                let ns = u64::from_le_bytes(header[..8].try_into().unwrap());
+                let len = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+                let stored = crc.then(|| u32::from_le_bytes(header[12..16].try_into().unwrap()));
+                // No way to find the next record after a bad length
+                if len > MAX_RECORD {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "record {record} at offset {offset}: length of {len} bytes, \
+                             not a --framing pts file?"
+                        ),
+                    ));
+                }
+                self.fill_record(header_len + len, record, offset)?;
+                self.records += 1;
+                let data = self.start + header_len;
+                if let Some(stored) = stored {
+                    let computed = crc::crc32(&self.data[data..data + len]);
+                    if computed != stored {
+                        self.corrupt(format!(
+                            "record {record} at offset {offset}: CRC mismatch, \
+                             {stored:08x} stored, {computed:08x} computed"
+                        ))?;
+                        self.start = data + len;
+                        continue;
+                    }
+                }
+                self.pts = Duration::from_nanos(ns);
+                break Ok((data, len));
+            },
+            Framing::Jpeg => {
+                let soi = loop {
+                    if let Some(p) = self.find(self.start, &[0xff, mjpeg::SOI, 0xff]) {
+                        break p;
+                    }
+                    // Keep the last bytes, a marker may straddle the chunk boundary
+                    self.start = self.data.len().saturating_sub(2).max(self.start);
+                    if !self.fill()? {
+                        return Err(ErrorKind::UnexpectedEof.into());
+                    }
+                };
+                self.start = soi;
+                let mut from = 2;
+                loop {
+                    if let Some(p) = self.find(self.start + from, &[0xff, mjpeg::EOI]) {
+                        return Ok((self.start, p + 2 - self.start));
+                    }
+                    from = (self.data.len() - self.start).saturating_sub(1).max(2);
+                    if !self.fill()? {
+                        return Err(ErrorKind::UnexpectedEof.into());
+                    }
+                }
+            }
+        }
+    }
+
+    // fill_to() for a record: an end of file inside it is a truncated file
+    fn fill_record(&mut self, len: usize, record: usize, offset: u64) -> io::Result<()> {
+        match self.fill_to(len) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof && self.data.len() > self.start => {
+                let have = self.data.len() - self.start;
+                self.corrupt(format!(
+                    "record {record} at offset {offset}: truncated, \
+                     {have} of {len} bytes"
+                ))?;
+                // Nothing after it to go on with
+                self.start = self.data.len();
+                Err(e)
+            }
+            result => result,
+        }
+    }
+
+    // Damaged record: an error to stop at, or a warning with --source-corrupt skip
+    fn corrupt(&mut self, problem: String) -> io::Result<()> {
+        if self.on_corrupt == OnCorrupt::Abort {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("{problem}; --source-corrupt skip to go on"),
+            ));
+        }
+        eprintln!("warning: {problem}, skipped");
+        self.skipped += 1;
+        Ok(())
+    }
+
+    // Damaged records passed over with --source-corrupt skip
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    pub fn next_frame(&mut self) -> io::Result<(&[u8], &Metadata)> {
+        let ts = if self.next_repeat.is_some() {
+            self.repeat()
+        } else {
+            match self.next_range() {
+                Ok((offset, len)) => {
+                    self.start = offset + len;
+                    self.frame = (offset, len);
+                    match self.framing {
+                        Framing::Pts { .. } => {
+                            self.pace();
+                            self.pts
+                        }
+                        _ => self.interval * self.frames,
+                    }
+                }
+                Err(e)
+                    if e.kind() == ErrorKind::UnexpectedEof
+                        && self.repeat_last
+                        && self.frames > 0 =>
+                {
+                    eprintln!("End of source file, repeating the last frame");
+                    self.next_repeat = Some(Instant::now() + self.interval);
+                    self.repeat()
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        let (offset, len) = self.frame;
+        let sequence = self.frames;
+        self.frames += 1;
+        self.meta = Metadata {
+            bytesused: len as u32,
+            flags: Flags::TIMESTAMP_COPY,
+            field: 0,
+            timestamp: ts.into(),
+            sequence,
+        };
+        Ok((&self.data[offset..offset + len], &self.meta))
+    }
+
+    // Wait for the next repetition of the last frame, returning its timestamp
+    fn repeat(&mut self) -> Duration {
+        let due = self.next_repeat.unwrap_or_else(Instant::now);
+        let now = Instant::now();
+        if due > now {
+            std::thread::sleep(due - now);
+        }
+        self.next_repeat = Some(due + self.interval);
+        match self.framing {
+            Framing::Pts { .. } => {
+                self.pts += self.interval;
+                self.pts
+            }
+            _ => self.interval * self.frames,
+        }
+    }
+
+    // Hold the record back until as much time has passed since the first one
+    // as between their timestamps
+    fn pace(&mut self) {
+        let (start, first) = *self.replay_start.get_or_insert((Instant::now(), self.pts));
+        if let Some(offset) = self.pts.checked_sub(first) {
+            let due = start + offset;
+            let now = Instant::now();
+            if due > now {
+                std::thread::sleep(due - now);
+            }
+        }
+    }
+}
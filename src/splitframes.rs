@@ -0,0 +1,60 @@
+// --split-frames: every frame as it came in saved as DIR/frame_%08d.EXT,
+// with DIR/manifest.json listing them
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use v4l::buffer::Metadata;
+use v4l::video::capture::Parameters;
+use v4l::Format;
+
+use crate::container::ContainerWriter;
+use crate::manifest::Manifest;
+use crate::pixfmt;
+
+pub struct SplitFrames {
+    dir: PathBuf,
+    // File extension after the pixel format, jpg for MJPEG
+    ext: String,
+    // Number of the next file, on across format changes
+    next: u64,
+    manifest: Manifest,
+}
+
+impl SplitFrames {
+    pub fn open(dir: &str) -> io::Result<SplitFrames> {
+        fs::create_dir_all(dir)?;
+        let dir = PathBuf::from(dir);
+        Ok(SplitFrames {
+            manifest: Manifest::create(&dir)?,
+            dir,
+            ext: String::new(),
+            next: 0,
+        })
+    }
+}
+
+impl ContainerWriter for SplitFrames {
+    fn begin(&mut self, format: &Format, _params: &Parameters) -> io::Result<()> {
+        self.ext = if pixfmt::is_jpeg(&format.fourcc.repr) {
+            "jpg".to_string()
+        } else {
+            String::from_utf8_lossy(&format.fourcc.repr)
+                .trim()
+                .to_lowercase()
+        };
+        Ok(())
+    }
+
+    fn write_frame(&mut self, buf: &[u8], meta: &Metadata, _mapped: bool) -> io::Result<()> {
+        let name = format!("frame_{:08}.{}", self.next, self.ext);
+        fs::write(self.dir.join(&name), buf)?;
+        self.manifest.add(&name, meta, buf.len())?;
+        self.next += 1;
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        self.manifest.finish()
+    }
+}
@@ -0,0 +1,39 @@
+use std::time::Instant;
+
+// Time from opening the device to the first frame, for --measure-startup.
+// Does nothing unless enabled.
+pub struct Startup {
+    start: Option<Instant>,
+    marks: Vec<(&'static str, Instant)>,
+}
+
+impl Startup {
+    pub fn new(enabled: bool) -> Startup {
+        Startup {
+            start: enabled.then(Instant::now),
+            marks: Vec::new(),
+        }
+    }
+
+    // Note that the step `what` is done
+    pub fn mark(&mut self, what: &'static str) {
+        if self.start.is_some() {
+            self.marks.push((what, Instant::now()));
+        }
+    }
+
+    // Every step with the time it took and the total
+    pub fn report(&self) {
+        let Some(mut last) = self.start else {
+            return;
+        };
+        let ms = |from: Instant, to: Instant| (to - from).as_secs_f64() * 1000.0;
+        for &(what, t) in &self.marks {
+            eprintln!("Startup: {what:<12} +{:8.3} [ms]", ms(last, t));
+            last = t;
+        }
+        if let Some(start) = self.start {
+            eprintln!("Startup: {:<12}  {:8.3} [ms]", "total", ms(start, last));
+        }
+    }
+}
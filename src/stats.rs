@@ -0,0 +1,179 @@
+use std::time::{Duration, Instant};
+use v4l::buffer::Metadata;
+
+// Distribution of the time between consecutive frames
+#[derive(Default, Clone, Copy)]
+pub struct Intervals {
+    pub min: Option<Duration>,
+    pub max: Option<Duration>,
+    pub total: Duration,
+    pub count: u32,
+    // Sum of the squared intervals in seconds², for the standard deviation
+    sum_sq: f64,
+}
+
+impl Intervals {
+    pub fn add(&mut self, interval: Duration) {
+        self.min = Some(self.min.map_or(interval, |min| min.min(interval)));
+        self.max = Some(self.max.map_or(interval, |max| max.max(interval)));
+        self.total += interval;
+        self.count += 1;
+        self.sum_sq += interval.as_secs_f64().powi(2);
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        (self.count > 0).then(|| self.total / self.count)
+    }
+
+    pub fn std_dev(&self) -> Option<Duration> {
+        let mean = self.mean()?.as_secs_f64();
+        let variance = self.sum_sq / self.count as f64 - mean * mean;
+        Some(Duration::from_secs_f64(variance.max(0.0).sqrt()))
+    }
+
+    // "min .., mean .., max .., stddev .. [ms]", once there are intervals
+    pub fn summary(&self) -> Option<String> {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        Some(format!(
+            "min {:.3}, mean {:.3}, max {:.3}, stddev {:.3} [ms]",
+            ms(self.min?),
+            ms(self.mean()?),
+            ms(self.max?),
+            ms(self.std_dev()?)
+        ))
+    }
+}
+
+// Counters accumulated over a capture run
+#[derive(Default)]
+pub struct Stats {
+    pub frames_captured: usize,
+    pub frames_written: usize,
+    // Frames the driver had no free buffer for, from sequence number gaps
+    pub frames_dropped: usize,
+    pub bytes_written: u64,
+    // Bytes the driver filled in, of all captured frames
+    pub bytes_captured: u64,
+    pub duplicates: usize,
+    pub oversized: usize,
+    pub unpaired_fields: usize,
+    // Frames too late for --reorder-depth to put in order
+    pub late: usize,
+    // Frames --decouple dropped because the writer fell behind
+    pub backpressure: usize,
+    // Frames captured while --gate-file was closed
+    pub gated: usize,
+    // Frames put in for intervals without one, see --fill-on-stall
+    pub filled: usize,
+    // Buffers the driver flagged ERROR, not written without --keep-error-frames
+    pub error_frames: usize,
+    // Keyframes written and the frames between them left out, --once-per-key
+    pub key_samples: usize,
+    pub between_keys: usize,
+    // Driver timestamp differences between consecutive frames
+    pub intervals: Intervals,
+    // Time between the dequeues, which shows frames arriving in bursts
+    pub arrival: Intervals,
+    last_sequence: Option<u32>,
+    last_timestamp: Option<Duration>,
+    last_arrival: Option<Instant>,
+}
+
+impl Stats {
+    // Account for a dequeued frame, returning how many were lost before it
+    pub fn record_frame(&mut self, meta: &Metadata) -> u32 {
+        self.frames_captured += 1;
+        self.bytes_captured += meta.bytesused as u64;
+        let mut lost = 0;
+        if let Some(last) = self.last_sequence {
+            lost = meta.sequence.wrapping_sub(last).wrapping_sub(1);
+            // Sequence numbers going back mean a restart, not a loss
+            if lost >= u32::MAX / 2 {
+                lost = 0;
+            }
+            self.frames_dropped += lost as usize;
+        }
+        self.last_sequence = Some(meta.sequence);
+
+        let ts = meta.timestamp;
+        let t = Duration::from_secs(ts.sec.max(0) as u64)
+            + Duration::from_micros(ts.usec.max(0) as u64);
+        if let Some(interval) = self.last_timestamp.and_then(|last| t.checked_sub(last)) {
+            self.intervals.add(interval);
+        }
+        self.last_timestamp = Some(t);
+        let now = Instant::now();
+        if let Some(last) = self.last_arrival {
+            self.arrival.add(now - last);
+        }
+        self.last_arrival = Some(now);
+        lost
+    }
+
+    // Forget the previous frame, so that a gap in the stream, e.g. while it
+    // is reconfigured, counts as neither lost frames nor a long interval
+    pub fn restart(&mut self) {
+        self.last_sequence = None;
+        self.last_timestamp = None;
+        self.last_arrival = None;
+    }
+
+    pub fn record_written(&mut self, bytes: usize) {
+        self.frames_written += 1;
+        self.bytes_written += bytes as u64;
+    }
+
+    pub fn report(&self) {
+        eprintln!(
+            "Captured {} frames, wrote {} frames ({} bytes)",
+            self.frames_captured, self.frames_written, self.bytes_written
+        );
+        if self.frames_dropped > 0 {
+            eprintln!("Driver dropped {} frames", self.frames_dropped);
+        }
+        if self.duplicates > 0 {
+            eprintln!("Skipped {} duplicate frames", self.duplicates);
+        }
+        if self.oversized > 0 {
+            eprintln!("Dropped {} oversized frames", self.oversized);
+        }
+        if self.unpaired_fields > 0 {
+            eprintln!("Dropped {} unpaired fields", self.unpaired_fields);
+        }
+        if self.late > 0 {
+            eprintln!("Dropped {} frames too late to reorder", self.late);
+        }
+        if self.backpressure > 0 {
+            eprintln!(
+                "Dropped {} frames for a writer falling behind",
+                self.backpressure
+            );
+        }
+        if self.gated > 0 {
+            eprintln!("Skipped {} frames while the gate was closed", self.gated);
+        }
+        if self.filled > 0 {
+            eprintln!("Filled in {} frames while the camera stalled", self.filled);
+        }
+        if self.error_frames > 0 {
+            eprintln!("Skipped {} frames flagged as corrupt", self.error_frames);
+        }
+        if self.key_samples > 0 || self.between_keys > 0 {
+            eprintln!(
+                "Wrote {} keyframe samples, skipped {} frames between them",
+                self.key_samples, self.between_keys
+            );
+        }
+        self.report_intervals();
+    }
+
+    // The timing part of report(), also printed periodically
+    pub fn report_intervals(&self) {
+        if let Some(summary) = self.intervals.summary() {
+            eprintln!("Frame interval: {summary}");
+        }
+        if let Some(summary) = self.arrival.summary() {
+            eprintln!("Arrival interval: {summary}");
+        }
+    }
+}
@@ -0,0 +1,753 @@
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind};
+use std::os::unix::io::RawFd;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{fmt, mem, ptr, slice};
+use v4l::buffer::{Flags, Metadata, Type};
+use v4l::device::{Device, Handle};
+use v4l::memory::Memory;
+use v4l::timestamp::Timestamp;
+use v4l::v4l2;
+use v4l::v4l_sys::*;
+
+// Called around every VIDIOC_QBUF, e.g. to queue each buffer in a media
+// request of the request API, carrying controls for the frame it receives
+pub trait QueueHook: Send {
+    // The request to queue buffer `index` in, if any
+    fn before_queue(&mut self, index: usize) -> io::Result<Option<RawFd>>;
+    // The buffer is queued; e.g. submit its request
+    fn after_queue(&mut self, index: usize) -> io::Result<()>;
+}
+
+// Fewest buffers we are willing to stream with
+pub const MIN_BUFFERS: u32 = 2;
+
+// Buffers we allocate ourselves and hand to the driver (V4L2_MEMORY_USERPTR)
+// instead of mapping the driver's
+#[derive(Clone, Copy)]
+pub struct UserPtr {
+    // Bytes per buffer, the sizeimage of the format
+    pub size: usize,
+    // Where buffers start, a power of two multiple of the page size
+    pub align: usize,
+    // Back the buffers with huge pages (MAP_HUGETLB), which have to be
+    // reserved in /proc/sys/vm/nr_hugepages
+    pub hugepages: bool,
+    // Place the buffers in the memory of this NUMA node
+    pub numa_node: Option<u32>,
+}
+
+// Not in the bindings: mmap buffers allocated without cache coherency
+// (V4L2_MEMORY_FLAG_NON_COHERENT), for drivers that report the hints
+const MEMORY_FLAG_NON_COHERENT: u8 = 1;
+const BUF_CAP_SUPPORTS_MMAP_CACHE_HINTS: u32 = 1 << 6;
+
+// How mmap buffers are to be cached, where the driver takes the hint
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum BufferCache {
+    // Uncached or snooped memory, as the driver does by default
+    Coherent,
+    // CPU cached memory, invalidated by the kernel at each dequeue before
+    // the frame is read
+    Cached,
+}
+
+impl BufferCache {
+    pub fn parse(s: &str) -> Option<BufferCache> {
+        match s {
+            "coherent" => Some(BufferCache::Coherent),
+            "cached" => Some(BufferCache::Cached),
+            _ => None,
+        }
+    }
+}
+
+// Close-on-exec, or with `false` inherited by the children started
+pub fn set_cloexec(fd: RawFd, cloexec: bool) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let flags = if cloexec {
+        flags | libc::FD_CLOEXEC
+    } else {
+        flags & !libc::FD_CLOEXEC
+    };
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, flags) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Bind the pages of a mapping to a NUMA node, before they are touched
+fn bind_to_node(ptr: *mut libc::c_void, len: usize, node: u32) -> io::Result<()> {
+    let bits = libc::c_ulong::BITS as usize;
+    let mut mask = vec![0 as libc::c_ulong; node as usize / bits + 1];
+    mask[node as usize / bits] |= 1 << (node as usize % bits);
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            ptr,
+            len,
+            libc::MPOL_BIND,
+            mask.as_ptr(),
+            mask.len() * bits + 1,
+            0,
+        )
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// From /proc/meminfo, 2 MiB if it cannot be read
+fn huge_page_size() -> usize {
+    std::fs::read_to_string("/proc/meminfo")
+        .ok()
+        .and_then(|info| {
+            let line = info.lines().find(|l| l.starts_with("Hugepagesize:"))?;
+            let kb: usize = line.split_whitespace().nth(1)?.parse().ok()?;
+            Some(kb * 1024)
+        })
+        .unwrap_or(2 << 20)
+}
+
+// Anonymous memory for one user buffer: the whole mapping and the aligned
+// part of it the driver gets
+fn alloc_user_buffer(user_ptr: &UserPtr) -> io::Result<((*mut u8, usize), *mut u8)> {
+    let page = if user_ptr.hugepages {
+        huge_page_size()
+    } else {
+        unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+    };
+    // mmap() only guarantees page alignment, map more to be able to align
+    let slack = user_ptr.align.saturating_sub(page);
+    let len = (user_ptr.size + slack).div_ceil(page) * page;
+    let mut flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+    // Bound pages are faulted in below instead
+    if user_ptr.numa_node.is_none() {
+        flags |= libc::MAP_POPULATE;
+    }
+    if user_ptr.hugepages {
+        flags |= libc::MAP_HUGETLB;
+    }
+    let ptr = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            flags,
+            -1,
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    if let Some(node) = user_ptr.numa_node {
+        if let Err(e) = bind_to_node(ptr, len, node) {
+            unsafe { libc::munmap(ptr, len) };
+            return Err(e);
+        }
+        for offset in (0..len).step_by(page) {
+            unsafe { (ptr as *mut u8).add(offset).write_volatile(0) };
+        }
+    }
+    let ptr = ptr as *mut u8;
+    let offset = ptr.align_offset(user_ptr.align.max(1));
+    Ok(((ptr, len), unsafe { ptr.add(offset) }))
+}
+
+// Memory mapped capture buffers, or with allocate_with() user allocated ones.
+// Unlike v4l::io::mmap::Stream, buffers can be accessed by index and the
+// dequeue can be done without blocking, which is needed for event loops.
+pub struct MmapStream {
+    handle: Arc<Handle>,
+    buf_type: Type,
+    bufs: Vec<(*mut u8, usize)>,
+    // USERPTR I/O: how buffers are allocated, and the mappings they are in
+    user_ptr: Option<UserPtr>,
+    allocs: Vec<(*mut u8, usize)>,
+    // Requested cache hint, None for what the driver does; and whether the
+    // buffers did come out non-coherent
+    cache: Option<BufferCache>,
+    non_coherent: bool,
+    meta: Vec<Metadata>,
+    // Buffer handed out by the last next_frame() call, requeued on the following one
+    last: Option<usize>,
+    // Number of buffers kept queued in the driver
+    depth: usize,
+    // Dequeued buffers waiting for their turn to be queued again, oldest first
+    idle: VecDeque<usize>,
+    // poll() timeout in milliseconds, -1 to wait forever
+    timeout: i32,
+    // Stop waiting when the device has an event (POLLPRI) pending
+    watch_events: bool,
+    active: bool,
+    hook: Option<Box<dyn QueueHook>>,
+    // Waiting fails with BrokenPipe once this reports POLLERR or POLLHUP
+    hangup_fd: Option<RawFd>,
+}
+
+// The mappings are owned by the stream and only accessed through it
+unsafe impl Send for MmapStream {}
+
+impl MmapStream {
+    pub fn with_buffers(dev: &Device, buf_type: Type, buf_count: u32) -> io::Result<MmapStream> {
+        MmapStream::create(dev, buf_type, buf_count, None, None)
+    }
+
+    fn create(
+        dev: &Device,
+        buf_type: Type,
+        buf_count: u32,
+        user_ptr: Option<UserPtr>,
+        cache: Option<BufferCache>,
+    ) -> io::Result<MmapStream> {
+        let mut stream = MmapStream {
+            handle: dev.handle(),
+            buf_type,
+            bufs: Vec::new(),
+            user_ptr,
+            allocs: Vec::new(),
+            cache,
+            non_coherent: false,
+            meta: Vec::new(),
+            last: None,
+            depth: 0,
+            idle: VecDeque::new(),
+            timeout: -1,
+            watch_events: false,
+            active: false,
+            hook: None,
+            hangup_fd: None,
+        };
+        stream.map_buffers(buf_count)?;
+        stream.depth = stream.bufs.len();
+        Ok(stream)
+    }
+
+    fn memory(&self) -> Memory {
+        match self.user_ptr {
+            Some(_) => Memory::UserPtr,
+            None => Memory::Mmap,
+        }
+    }
+
+    // Request and mmap the driver buffers, or allocate user buffers; the
+    // driver may grant fewer
+    fn map_buffers(&mut self, buf_count: u32) -> io::Result<()> {
+        let mut reqbufs = v4l2_requestbuffers {
+            count: buf_count,
+            type_: self.buf_type as u32,
+            memory: self.memory() as u32,
+            ..unsafe { mem::zeroed() }
+        };
+        if self.user_ptr.is_none() && self.cache == Some(BufferCache::Cached) {
+            reqbufs.flags = MEMORY_FLAG_NON_COHERENT;
+        }
+        unsafe {
+            v4l2::ioctl(
+                self.handle.fd(),
+                v4l2::vidioc::VIDIOC_REQBUFS,
+                &mut reqbufs as *mut _ as *mut std::os::raw::c_void,
+            )?;
+        }
+        // Drivers without the hints clear the flag, older kernels ignore it
+        self.non_coherent = reqbufs.flags & MEMORY_FLAG_NON_COHERENT != 0;
+        if self.cache == Some(BufferCache::Cached) && !self.non_coherent {
+            let why = if reqbufs.capabilities & BUF_CAP_SUPPORTS_MMAP_CACHE_HINTS == 0 {
+                "the driver takes no cache hints"
+            } else {
+                "the driver did not grant them"
+            };
+            eprintln!("warning: cached buffers not allocated, {why}; using its default");
+        }
+        if let Some(user_ptr) = self.user_ptr {
+            for _ in 0..reqbufs.count {
+                let (alloc, ptr) = alloc_user_buffer(&user_ptr)?;
+                self.allocs.push(alloc);
+                self.bufs.push((ptr, user_ptr.size));
+            }
+            self.meta = vec![Metadata::default(); self.bufs.len()];
+            return Ok(());
+        }
+        for index in 0..reqbufs.count {
+            let mut v4l2_buf = v4l2_buffer {
+                index,
+                ..self.buffer_desc()
+            };
+            unsafe {
+                v4l2::ioctl(
+                    self.handle.fd(),
+                    v4l2::vidioc::VIDIOC_QUERYBUF,
+                    &mut v4l2_buf as *mut _ as *mut std::os::raw::c_void,
+                )?;
+                let ptr = v4l2::mmap(
+                    ptr::null_mut(),
+                    v4l2_buf.length as usize,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_SHARED,
+                    self.handle.fd(),
+                    v4l2_buf.m.offset as libc::off_t,
+                )?;
+                self.bufs.push((ptr as *mut u8, v4l2_buf.length as usize));
+            }
+        }
+        self.meta = vec![Metadata::default(); self.bufs.len()];
+        Ok(())
+    }
+
+    // Map a new set of buffers on the same device handle, e.g. after a format
+    // change. Timeout, queue depth and event watching stay as they were;
+    // streaming starts again on the next frame or start().
+    pub fn reallocate(&mut self, buf_count: u32) -> io::Result<()> {
+        self.release();
+        self.map_buffers(buf_count)?;
+        self.depth = self.depth.clamp(1, self.bufs.len().max(1));
+        Ok(())
+    }
+
+    // Allocate buffers, retrying with fewer buffers while the driver reports ENOMEM
+    pub fn allocate(dev: &Device, buf_type: Type, buf_count: u32) -> io::Result<MmapStream> {
+        MmapStream::allocate_with(dev, buf_type, buf_count, None)
+    }
+
+    // As allocate(), with user buffers if `user_ptr` is given
+    pub fn allocate_with(
+        dev: &Device,
+        buf_type: Type,
+        buf_count: u32,
+        user_ptr: Option<UserPtr>,
+    ) -> io::Result<MmapStream> {
+        MmapStream::allocate_cached(dev, buf_type, buf_count, user_ptr, None)
+    }
+
+    // As allocate_with(), asking for mmap buffers cached as `cache` says
+    pub fn allocate_cached(
+        dev: &Device,
+        buf_type: Type,
+        mut buf_count: u32,
+        user_ptr: Option<UserPtr>,
+        cache: Option<BufferCache>,
+    ) -> io::Result<MmapStream> {
+        loop {
+            match MmapStream::create(dev, buf_type, buf_count, user_ptr, cache) {
+                Err(e) if e.raw_os_error() == Some(libc::ENOMEM) => {
+                    if buf_count <= MIN_BUFFERS {
+                        return Err(io::Error::new(
+                            ErrorKind::OutOfMemory,
+                            format!(
+                                "failed to allocate {buf_count} buffers: {e} (try a lower resolution)"
+                            ),
+                        ));
+                    }
+                    eprintln!(
+                        "Allocating {} buffers failed with ENOMEM, retrying with {}",
+                        buf_count,
+                        buf_count - 1
+                    );
+                    buf_count -= 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn buffer_desc(&self) -> v4l2_buffer {
+        v4l2_buffer {
+            type_: self.buf_type as u32,
+            memory: self.memory() as u32,
+            ..unsafe { mem::zeroed() }
+        }
+    }
+
+    pub fn handle(&self) -> Arc<Handle> {
+        self.handle.clone()
+    }
+
+    // Also watch `fd` while waiting for frames and fail with BrokenPipe when
+    // it hangs up, e.g. the write end of a pipe whose reader has exited
+    pub fn set_hangup_fd(&mut self, fd: Option<RawFd>) {
+        self.hangup_fd = fd;
+    }
+
+    pub fn set_queue_hook(&mut self, hook: Box<dyn QueueHook>) {
+        self.hook = Some(hook);
+    }
+
+    // Number of allocated buffers
+    pub fn count(&self) -> usize {
+        self.bufs.len()
+    }
+
+    // Keep only `depth` of the buffers queued in the driver. A shallow queue
+    // bounds the latency, since the driver cannot get ahead of us by more than
+    // `depth` frames, but drops frames sooner when we fall behind. The other
+    // buffers are requeued in turn, so a dequeued buffer is not refilled until
+    // all others have been used, which gives pipe readers of vmspliced frames
+    // more time. Takes effect on the next start().
+    pub fn set_queue_depth(&mut self, depth: usize) {
+        self.depth = depth.clamp(1, self.bufs.len().max(1));
+    }
+
+    pub fn queue_depth(&self) -> usize {
+        self.depth
+    }
+
+    // Let dequeue_wait() return an EventPending error when the device has
+    // subscribed events to dequeue
+    pub fn watch_events(&mut self) {
+        self.watch_events = true;
+    }
+
+    // Give up waiting for a frame after `timeout` with a TimedOut error, so
+    // the caller gets a chance to look around. None waits forever.
+    pub fn set_poll_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout.map_or(-1, |t| t.as_millis().min(i32::MAX as u128) as i32);
+    }
+
+    // The device is opened with O_NONBLOCK. Clearing it makes DQBUF sleep
+    // until a buffer is done; next_frame() polls first either way, while
+    // try_next_frame() needs the flag set. It belongs to the open file and so
+    // applies to every handle of the device.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        let fd = self.handle.fd();
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let flags = if nonblocking {
+            flags | libc::O_NONBLOCK
+        } else {
+            flags & !libc::O_NONBLOCK
+        };
+        if unsafe { libc::fcntl(fd, libc::F_SETFL, flags) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn buffer(&self, index: usize) -> &[u8] {
+        let (ptr, len) = self.bufs[index];
+        unsafe { slice::from_raw_parts(ptr, len) }
+    }
+
+    // Writable view of a buffer, for filling output queues
+    pub fn buffer_mut(&mut self, index: usize) -> &mut [u8] {
+        let (ptr, len) = self.bufs[index];
+        unsafe { slice::from_raw_parts_mut(ptr, len) }
+    }
+
+    pub fn metadata(&self, index: usize) -> &Metadata {
+        &self.meta[index]
+    }
+
+    // Buffer returned by the last next_frame() or try_next_frame() call
+    pub fn last_index(&self) -> Option<usize> {
+        self.last
+    }
+
+    // Current driver flags (queued, done, error...) of a buffer
+    pub fn query_flags(&self, index: usize) -> io::Result<Flags> {
+        let mut v4l2_buf = v4l2_buffer {
+            index: index as u32,
+            ..self.buffer_desc()
+        };
+        unsafe {
+            v4l2::ioctl(
+                self.handle.fd(),
+                v4l2::vidioc::VIDIOC_QUERYBUF,
+                &mut v4l2_buf as *mut _ as *mut std::os::raw::c_void,
+            )?;
+        }
+        Ok(v4l2_buf.flags.into())
+    }
+
+    pub fn queue(&mut self, index: usize) -> io::Result<()> {
+        self.queue_filled(index, 0)
+    }
+
+    // Queue a buffer holding `bytesused` bytes of data, as output queues need
+    pub fn queue_filled(&mut self, index: usize, bytesused: u32) -> io::Result<()> {
+        self.queue_buffer(index, bytesused, None)
+    }
+
+    // As queue_filled, the buffer carrying `timestamp` to the other side,
+    // e.g. the readers of a loopback device
+    pub fn queue_stamped(
+        &mut self,
+        index: usize,
+        bytesused: u32,
+        timestamp: Timestamp,
+    ) -> io::Result<()> {
+        self.queue_buffer(index, bytesused, Some(timestamp))
+    }
+
+    fn queue_buffer(
+        &mut self,
+        index: usize,
+        bytesused: u32,
+        timestamp: Option<Timestamp>,
+    ) -> io::Result<()> {
+        let mut v4l2_buf = v4l2_buffer {
+            index: index as u32,
+            bytesused,
+            ..self.buffer_desc()
+        };
+        if let Some(timestamp) = timestamp {
+            v4l2_buf.timestamp = timestamp.into();
+        }
+        if self.user_ptr.is_some() {
+            let (ptr, len) = self.bufs[index];
+            v4l2_buf.m.userptr = ptr as std::os::raw::c_ulong;
+            v4l2_buf.length = len as u32;
+        }
+        // The CPU does not write into capture buffers, so there is nothing to
+        // clean before the device fills them. Invalidation is left on: it is
+        // what makes the frame read after DQBUF come from memory.
+        if self.non_coherent && matches!(self.buf_type, Type::VideoCapture | Type::MetaCapture) {
+            v4l2_buf.flags |= V4L2_BUF_FLAG_NO_CACHE_CLEAN;
+        }
+        if let Some(hook) = self.hook.as_mut() {
+            if let Some(request_fd) = hook.before_queue(index)? {
+                v4l2_buf.flags |= V4L2_BUF_FLAG_REQUEST_FD;
+                v4l2_buf.__bindgen_anon_1.request_fd = request_fd;
+            }
+        }
+        unsafe {
+            v4l2::ioctl(
+                self.handle.fd(),
+                v4l2::vidioc::VIDIOC_QBUF,
+                &mut v4l2_buf as *mut _ as *mut std::os::raw::c_void,
+            )?;
+        }
+        match self.hook.as_mut() {
+            Some(hook) => hook.after_queue(index),
+            None => Ok(()),
+        }
+    }
+
+    // Take a filled buffer from the driver. While the device is O_NONBLOCK
+    // (see set_nonblocking) this fails with WouldBlock if no frame is ready yet.
+    pub fn dequeue(&mut self) -> io::Result<usize> {
+        let mut v4l2_buf = self.buffer_desc();
+        unsafe {
+            v4l2::ioctl(
+                self.handle.fd(),
+                v4l2::vidioc::VIDIOC_DQBUF,
+                &mut v4l2_buf as *mut _ as *mut std::os::raw::c_void,
+            )?;
+        }
+        let index = v4l2_buf.index as usize;
+        self.meta[index] = Metadata {
+            bytesused: v4l2_buf.bytesused,
+            flags: v4l2_buf.flags.into(),
+            field: v4l2_buf.field,
+            timestamp: v4l2_buf.timestamp.into(),
+            sequence: v4l2_buf.sequence,
+        };
+        Ok(index)
+    }
+
+    // Queue the first `depth` buffers and start streaming
+    pub fn start(&mut self) -> io::Result<()> {
+        let depth = self.depth.min(self.bufs.len());
+        for index in 0..depth {
+            self.queue(index)?;
+        }
+        self.idle = (depth..self.bufs.len()).collect();
+        self.stream_on()
+    }
+
+    // Start streaming without queueing anything, e.g. for output queues
+    pub fn stream_on(&mut self) -> io::Result<()> {
+        let mut typ = self.buf_type as u32;
+        unsafe {
+            v4l2::ioctl(
+                self.handle.fd(),
+                v4l2::vidioc::VIDIOC_STREAMON,
+                &mut typ as *mut _ as *mut std::os::raw::c_void,
+            )?;
+        }
+        self.active = true;
+        Ok(())
+    }
+
+    pub fn is_streaming(&self) -> bool {
+        self.active
+    }
+
+    // Stop streaming; the driver returns all buffers to us
+    pub fn stop(&mut self) -> io::Result<()> {
+        let mut typ = self.buf_type as u32;
+        unsafe {
+            v4l2::ioctl(
+                self.handle.fd(),
+                v4l2::vidioc::VIDIOC_STREAMOFF,
+                &mut typ as *mut _ as *mut std::os::raw::c_void,
+            )?;
+        }
+        self.active = false;
+        self.last = None;
+        self.idle.clear();
+        Ok(())
+    }
+
+    // Start streaming on first use, otherwise give back the previous buffer
+    // and queue the one that has been idle longest in its place
+    fn requeue_last(&mut self) -> io::Result<()> {
+        if !self.active {
+            return self.start();
+        }
+        if let Some(index) = self.last.take() {
+            self.idle.push_back(index);
+            if let Some(next) = self.idle.pop_front() {
+                self.queue(next)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Non-blocking variant of next_frame(): returns the index of the filled buffer
+    // or a WouldBlock error. Calling it again after WouldBlock is fine.
+    pub fn try_next_frame(&mut self) -> io::Result<usize> {
+        self.requeue_last()?;
+        let index = self.dequeue()?;
+        self.last = Some(index);
+        Ok(index)
+    }
+
+    // Wait until the driver hands back a buffer. `events` is POLLIN for
+    // capture and POLLOUT for output queues.
+    pub fn dequeue_wait(&mut self, events: i16) -> io::Result<usize> {
+        let mut fds = [
+            libc::pollfd {
+                fd: self.handle.fd(),
+                events: if self.watch_events {
+                    events | libc::POLLPRI
+                } else {
+                    events
+                },
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: self.hangup_fd.unwrap_or(-1),
+                events: 0,
+                revents: 0,
+            },
+        ];
+        let count = if self.hangup_fd.is_some() { 2 } else { 1 };
+        loop {
+            match unsafe { libc::poll(fds.as_mut_ptr(), count, self.timeout) } {
+                -1 => return Err(io::Error::last_os_error()),
+                0 => return Err(ErrorKind::TimedOut.into()),
+                _ => {}
+            }
+            if fds[1].revents & (libc::POLLERR | libc::POLLHUP) != 0 {
+                return Err(io::Error::new(ErrorKind::BrokenPipe, "the reader hung up"));
+            }
+            // Take a ready frame first, the event stays pending
+            if fds[0].revents == libc::POLLPRI {
+                return Err(io::Error::other(EventPending));
+            }
+            match self.dequeue() {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                result => return result,
+            }
+        }
+    }
+
+    // Wait for the next frame. The returned buffer stays valid until the next
+    // call. It is the whole buffer; only its first `bytesused` bytes are the
+    // frame, which for compressed formats is usually much less.
+    pub fn next_frame(&mut self) -> io::Result<(&[u8], &Metadata)> {
+        self.requeue_last()?;
+        let index = self.dequeue_wait(libc::POLLIN)?;
+        self.last = Some(index);
+        Ok((self.buffer(index), self.metadata(index)))
+    }
+
+    // Like next_frame(), but the buffer is not given back by the next call:
+    // it stays out of the queue until give_back(index)
+    pub fn next_held(&mut self) -> io::Result<usize> {
+        self.requeue_last()?;
+        self.dequeue_wait(libc::POLLIN)
+    }
+
+    // Return a buffer taken with next_held() to the queue, in the same order
+    // as requeue_last(): the one idle longest is queued in its place
+    pub fn give_back(&mut self, index: usize) -> io::Result<()> {
+        if !self.active {
+            return Ok(());
+        }
+        self.idle.push_back(index);
+        match self.idle.pop_front() {
+            Some(next) => self.queue(next),
+            None => Ok(()),
+        }
+    }
+
+    // Stop streaming and hand the buffers back to the driver, e.g. before the
+    // format changes. Nothing is left to capture with afterwards.
+    pub fn release(&mut self) {
+        if self.active {
+            // ENODEV: the device is gone, nothing left to stop
+            _ = self.stop();
+        }
+        if self.user_ptr.is_none() {
+            for &(ptr, len) in &self.bufs {
+                unsafe {
+                    _ = v4l2::munmap(ptr as *mut std::os::raw::c_void, len);
+                }
+            }
+        }
+        self.bufs.clear();
+        self.meta.clear();
+        self.idle.clear();
+        self.last = None;
+        let mut reqbufs = v4l2_requestbuffers {
+            count: 0,
+            type_: self.buf_type as u32,
+            memory: self.memory() as u32,
+            ..unsafe { mem::zeroed() }
+        };
+        unsafe {
+            _ = v4l2::ioctl(
+                self.handle.fd(),
+                v4l2::vidioc::VIDIOC_REQBUFS,
+                &mut reqbufs as *mut _ as *mut std::os::raw::c_void,
+            );
+        }
+        // User memory only once the driver has let go of it
+        for (ptr, len) in self.allocs.drain(..) {
+            unsafe {
+                libc::munmap(ptr as *mut libc::c_void, len);
+            }
+        }
+    }
+}
+
+impl Drop for MmapStream {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+// dequeue_wait() was woken by an event instead of a frame
+#[derive(Debug)]
+pub struct EventPending;
+
+impl fmt::Display for EventPending {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "device event pending")
+    }
+}
+
+impl std::error::Error for EventPending {}
+
+pub fn is_event_pending(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<EventPending>())
+}
@@ -0,0 +1,171 @@
+// --subscribe-event: V4L2 events of the driver besides the source change the
+// capture acts on, each logged with its payload as it is dequeued
+
+use std::io;
+use std::mem;
+use std::os::raw::c_void;
+use std::os::unix::io::RawFd;
+use v4l::control::Type;
+use v4l::v4l2;
+use v4l::v4l_sys::*;
+use v4l::Device;
+
+use crate::device::VIDIOC_SUBSCRIBE_EVENT;
+
+// V4L2_EVENT_CTRL_CH_*, missing from the bindings
+const CTRL_CH_VALUE: u32 = 1 << 0;
+const CTRL_CH_FLAGS: u32 = 1 << 1;
+const CTRL_CH_RANGE: u32 = 1 << 2;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct EventKind(u32);
+
+impl EventKind {
+    // A name as the kernel's V4L2_EVENT_* without the prefix, or the number
+    // of a driver specific event
+    pub fn parse(s: &str) -> Option<EventKind> {
+        let type_ = match s {
+            "vsync" => V4L2_EVENT_VSYNC,
+            "eos" => V4L2_EVENT_EOS,
+            "ctrl" => V4L2_EVENT_CTRL,
+            "frame-sync" => V4L2_EVENT_FRAME_SYNC,
+            "source-change" => V4L2_EVENT_SOURCE_CHANGE,
+            "motion-det" => V4L2_EVENT_MOTION_DET,
+            _ => match s.strip_prefix("0x") {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => s.parse().ok()?,
+            },
+        };
+        // 0 is V4L2_EVENT_ALL, for unsubscribing only
+        (type_ != 0).then_some(EventKind(type_))
+    }
+
+    pub fn matches(&self, ev: &v4l2_event) -> bool {
+        self.0 == ev.type_
+    }
+}
+
+fn name(type_: u32) -> String {
+    match type_ {
+        V4L2_EVENT_VSYNC => "vsync".to_string(),
+        V4L2_EVENT_EOS => "eos".to_string(),
+        V4L2_EVENT_CTRL => "ctrl".to_string(),
+        V4L2_EVENT_FRAME_SYNC => "frame-sync".to_string(),
+        V4L2_EVENT_SOURCE_CHANGE => "source-change".to_string(),
+        V4L2_EVENT_MOTION_DET => "motion-det".to_string(),
+        _ => format!("{type_:#010x}"),
+    }
+}
+
+fn subscribe_one(fd: RawFd, type_: u32, id: u32) -> io::Result<()> {
+    let mut sub: v4l2_event_subscription = unsafe { mem::zeroed() };
+    (sub.type_, sub.id) = (type_, id);
+    unsafe {
+        v4l2::ioctl(
+            fd,
+            VIDIOC_SUBSCRIBE_EVENT,
+            &mut sub as *mut _ as *mut c_void,
+        )
+    }
+}
+
+// Subscribe to the events on the device, control events for every control
+// it has. Tells whether anything was subscribed.
+pub fn subscribe(dev: &Device, kinds: &[EventKind]) -> bool {
+    let fd = dev.handle().fd();
+    let mut any = false;
+    for kind in kinds {
+        let result = if kind.0 == V4L2_EVENT_CTRL {
+            dev.query_controls().and_then(|controls| {
+                controls
+                    .iter()
+                    .filter(|desc| !matches!(desc.typ, Type::CtrlClass))
+                    .try_for_each(|desc| subscribe_one(fd, V4L2_EVENT_CTRL, desc.id))
+            })
+        } else {
+            subscribe_one(fd, kind.0, 0)
+        };
+        match result {
+            Ok(()) => any = true,
+            Err(e) => eprintln!("warning: --subscribe-event {}: {e}", name(kind.0)),
+        }
+    }
+    any
+}
+
+fn u32_at(data: &[u8], at: usize) -> u32 {
+    u32::from_ne_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+fn i32_at(data: &[u8], at: usize) -> i32 {
+    i32::from_ne_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+// The payload as far as the kernel defines it, raw bytes otherwise
+fn payload(ev: &v4l2_event) -> String {
+    let data = unsafe { &ev.u.data };
+    match ev.type_ {
+        V4L2_EVENT_VSYNC => format!("field {}", data[0]),
+        V4L2_EVENT_EOS => String::new(),
+        V4L2_EVENT_CTRL => {
+            let changes = u32_at(data, 0);
+            let value = match u32_at(data, 4) {
+                t if t == Type::Integer64 as u32 => {
+                    i64::from_ne_bytes(data[8..16].try_into().unwrap())
+                }
+                _ => i32_at(data, 8) as i64,
+            };
+            let mut parts = vec![format!("control {:#010x}", ev.id)];
+            if changes & CTRL_CH_VALUE != 0 {
+                parts.push(format!("value {value}"));
+            }
+            if changes & CTRL_CH_FLAGS != 0 {
+                parts.push(format!("flags {:#x}", u32_at(data, 16)));
+            }
+            if changes & CTRL_CH_RANGE != 0 {
+                parts.push(format!(
+                    "range {}..{} step {} default {}",
+                    i32_at(data, 20),
+                    i32_at(data, 24),
+                    i32_at(data, 28),
+                    i32_at(data, 32)
+                ));
+            }
+            parts.join(", ")
+        }
+        V4L2_EVENT_FRAME_SYNC => format!("frame {}", u32_at(data, 0)),
+        V4L2_EVENT_SOURCE_CHANGE => {
+            let changes = u32_at(data, 0);
+            match changes & V4L2_EVENT_SRC_CH_RESOLUTION {
+                0 => format!("changes {changes:#x}"),
+                _ => format!("changes {changes:#x} (resolution)"),
+            }
+        }
+        V4L2_EVENT_MOTION_DET => format!(
+            "flags {:#x}, frame {}, regions {:#x}",
+            u32_at(data, 0),
+            u32_at(data, 4),
+            u32_at(data, 8)
+        ),
+        _ => {
+            let used = data
+                .iter()
+                .rposition(|&b| b != 0)
+                .map_or(0, |last| last + 1);
+            let hex: Vec<String> = data[..used].iter().map(|b| format!("{b:02x}")).collect();
+            format!("data {}", hex.join(" "))
+        }
+    }
+}
+
+// A line for the log
+pub fn describe(ev: &v4l2_event) -> String {
+    let at = ev.timestamp.tv_sec as f64 + ev.timestamp.tv_nsec as f64 / 1e9;
+    let payload = payload(ev);
+    let sep = if payload.is_empty() { "" } else { ": " };
+    format!(
+        "Event {} seq {} at {at:.6} [s]{sep}{payload}",
+        name(ev.type_),
+        ev.sequence
+    )
+}
@@ -0,0 +1,51 @@
+// --summary-json: one JSON object describing how a capture run went
+
+use std::io;
+use std::time::Duration;
+use v4l::video::capture::Parameters;
+use v4l::Format;
+use v4l2capture_rs::Stats;
+
+use crate::discover::{fps, json_str};
+
+// Milliseconds as a JSON number, null when there is nothing to measure
+fn ms(d: Option<Duration>) -> String {
+    d.map_or("null".to_string(), |d| {
+        format!("{}", (d.as_secs_f64() * 1e6).round() / 1000.0)
+    })
+}
+
+// `reason` is what ended the capture: max-frames, end-of-file, signal,
+// error and so on
+pub fn write(
+    path: &str,
+    fmt: &Format,
+    params: &Parameters,
+    stats: &Stats,
+    duration: Duration,
+    reason: &str,
+) -> io::Result<()> {
+    let json = format!(
+        concat!(
+            "{{\"format\":{{\"width\":{},\"height\":{},\"fourcc\":{},\"fps\":{}}},",
+            "\"frames_captured\":{},\"frames_written\":{},\"frames_dropped\":{},",
+            "\"duplicates\":{},\"error_frames\":{},\"bytes_written\":{},\"duration\":{:.3},",
+            "\"interval_mean_ms\":{},\"interval_jitter_ms\":{},\"exit_reason\":{}}}\n"
+        ),
+        fmt.width,
+        fmt.height,
+        json_str(&fmt.fourcc.to_string()),
+        fps(&params.interval),
+        stats.frames_captured,
+        stats.frames_written,
+        stats.frames_dropped,
+        stats.duplicates,
+        stats.error_frames,
+        stats.bytes_written,
+        duration.as_secs_f64(),
+        ms(stats.intervals.mean()),
+        ms(stats.intervals.std_dev()),
+        json_str(reason)
+    );
+    std::fs::write(path, json)
+}
@@ -0,0 +1,97 @@
+// --sync-start: the capture threads of all devices wait for each other and
+// turn streaming on together, at a given wall clock time if there is one
+
+use std::io;
+use std::sync::{Barrier, Mutex};
+use std::time::Duration;
+use v4l2capture_rs::stream::MmapStream;
+
+use crate::clock::Clock;
+
+pub struct SyncStart {
+    barrier: Barrier,
+    // Unix time to start streaming at
+    at: Option<Duration>,
+    // Device and when its VIDIOC_STREAMON was called and returned
+    times: Mutex<Vec<(String, Duration, Duration)>>,
+}
+
+impl SyncStart {
+    pub fn new(devices: usize, at: Option<Duration>) -> SyncStart {
+        SyncStart {
+            barrier: Barrier::new(devices),
+            at,
+            times: Mutex::new(Vec::new()),
+        }
+    }
+
+    // Start streaming once all devices are ready, returning when all have
+    // started or failed to. Without a stream, e.g. for a replayed file, only
+    // wait for the others.
+    pub fn start(&self, device: &str, stream: Option<&mut MmapStream>) -> io::Result<()> {
+        self.barrier.wait();
+        let Some(stream) = stream else {
+            self.barrier.wait();
+            return Ok(());
+        };
+        if let Some(at) = self.at {
+            sleep_until(at);
+        }
+        let before = Clock::Monotonic.now();
+        let result = stream.start();
+        let after = Clock::Monotonic.now();
+        if result.is_ok() {
+            self.times
+                .lock()
+                .unwrap()
+                .push((device.to_string(), before, after));
+        }
+        self.barrier.wait();
+        result
+    }
+
+    // When every device started, on CLOCK_MONOTONIC like the buffer
+    // timestamps, and how far apart
+    pub fn report(&self) {
+        let times = self.times.lock().unwrap();
+        let Some(first) = times.iter().map(|&(_, before, _)| before).min() else {
+            return;
+        };
+        for (device, before, after) in times.iter() {
+            eprintln!(
+                "Streamon {}: {:.6} [s] (+{:.3} [ms], call took {:.3} [ms])",
+                device,
+                before.as_secs_f64(),
+                (*before - first).as_secs_f64() * 1000.0,
+                (*after - *before).as_secs_f64() * 1000.0
+            );
+        }
+        let last = times.iter().map(|&(_, _, after)| after).max().unwrap();
+        eprintln!(
+            "Streamon spread: {:.3} [ms] over {} devices",
+            (last - first).as_secs_f64() * 1000.0,
+            times.len()
+        );
+    }
+}
+
+fn sleep_until(at: Duration) {
+    if at <= Clock::Realtime.now() {
+        eprintln!("warning: --sync-start-at time has passed, starting now");
+        return;
+    }
+    let ts = libc::timespec {
+        tv_sec: at.as_secs() as libc::time_t,
+        tv_nsec: at.subsec_nanos() as libc::c_long,
+    };
+    // Restarted after signals, with the absolute time
+    while unsafe {
+        libc::clock_nanosleep(
+            libc::CLOCK_REALTIME,
+            libc::TIMER_ABSTIME,
+            &ts,
+            std::ptr::null_mut(),
+        )
+    } == libc::EINTR
+    {}
+}
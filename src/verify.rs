@@ -0,0 +1,150 @@
+// --verify-output: read the finished output back and check that it holds
+// what was written
+
+use crate::base64;
+use crate::crc;
+use crate::mjpeg;
+use crate::output::{Framing, PAD_HEADER_LEN, PTS_HEADER_LEN};
+
+// What the capture wrote, to compare the file with
+pub struct Expected {
+    pub frames: usize,
+    pub bytes: u64,
+    pub framing: Framing,
+    pub jpeg: bool,
+    pub pad_to: Option<usize>,
+}
+
+// Frames found in a file of --framing pts or pts-crc records, with the CRCs
+// checked for the latter
+fn count_records(data: &[u8], framing: Framing) -> Result<usize, String> {
+    let header_len = framing.header_len();
+    let mut pos = 0;
+    let mut frames = 0;
+    while pos < data.len() {
+        let header = data
+            .get(pos..pos + header_len)
+            .ok_or(format!("record {frames} at {pos}: truncated header"))?;
+        let len = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+        let frame = data
+            .get(pos + header_len..pos + header_len + len)
+            .ok_or(format!("record {frames}: {len} bytes of data past the end"))?;
+        if framing == Framing::PtsCrc {
+            let stored = u32::from_le_bytes(header[PTS_HEADER_LEN..].try_into().unwrap());
+            if crc::crc32(frame) != stored {
+                return Err(format!("record {frames} at {pos}: CRC mismatch"));
+            }
+        }
+        pos += header_len + len;
+        frames += 1;
+    }
+    Ok(frames)
+}
+
+// Frames found in back to back JPEG images
+fn count_jpeg(data: &[u8]) -> Result<usize, String> {
+    let mut pos = 0;
+    let mut frames = 0;
+    while pos < data.len() {
+        if !data[pos..].starts_with(&[0xff, 0xd8]) {
+            return Err(format!("frame {frames} at {pos}: no SOI marker"));
+        }
+        let end = mjpeg::frame_end(&data[pos..])
+            .ok_or(format!("frame {frames} at {pos}: no EOI marker"))?;
+        pos += end;
+        frames += 1;
+        // Skip untrimmed padding up to the next image
+        match data[pos..].windows(2).position(|w| w == [0xff, 0xd8]) {
+            Some(skip) => pos += skip,
+            None => break,
+        }
+    }
+    Ok(frames)
+}
+
+// Frames and their bytes in a file of --pad-to records of `size` bytes
+fn count_padded(data: &[u8], size: usize) -> Result<(usize, u64), String> {
+    if !data.len().is_multiple_of(size) {
+        return Err(format!(
+            "{} bytes, not a whole number of {size} byte records",
+            data.len()
+        ));
+    }
+    let mut bytes = 0;
+    for (n, record) in data.chunks(size).enumerate() {
+        let len = u32::from_le_bytes(record[..PAD_HEADER_LEN].try_into().unwrap()) as usize;
+        if PAD_HEADER_LEN + len > size {
+            return Err(format!("record {n}: length {len} past the record end"));
+        }
+        bytes += len as u64;
+    }
+    Ok((data.len() / size, bytes))
+}
+
+// Frames and their bytes decoded from the lines of a --framing base64 file
+fn count_lines(data: &[u8]) -> Result<(usize, u64), String> {
+    let mut frames = 0;
+    let mut bytes = 0;
+    for line in data.split_inclusive(|&b| b == b'\n') {
+        let text = line
+            .strip_suffix(b"\n")
+            .ok_or(format!("line {frames}: no newline at the end"))?;
+        let (_, encoded) = std::str::from_utf8(text)
+            .ok()
+            .and_then(|text| text.split_once(' '))
+            .filter(|(seq, _)| seq.parse::<u32>().is_ok())
+            .ok_or(format!("line {frames}: no sequence number"))?;
+        let frame =
+            base64::decode(encoded.as_bytes()).ok_or(format!("line {frames}: not valid base64"))?;
+        bytes += frame.len() as u64;
+        frames += 1;
+    }
+    Ok((frames, bytes))
+}
+
+// A description of the file if it checks out, the problem otherwise
+pub fn check(path: &str, expected: &Expected) -> Result<String, String> {
+    let data = std::fs::read(path).map_err(|e| format!("{path}: {e}"))?;
+    if let Some(size) = expected.pad_to {
+        let (frames, bytes) = count_padded(&data, size).map_err(|e| format!("{path}: {e}"))?;
+        if (frames, bytes) != (expected.frames, expected.bytes) {
+            return Err(format!(
+                "{path}: {frames} frames of {bytes} bytes, expected {} of {}",
+                expected.frames, expected.bytes
+            ));
+        }
+        return Ok(format!("{path}: {frames} records of {size} bytes"));
+    }
+    if expected.framing == Framing::Base64 {
+        let (frames, bytes) = count_lines(&data)?;
+        if (frames, bytes) != (expected.frames, expected.bytes) {
+            return Err(format!(
+                "{path}: {frames} frames of {bytes} bytes, expected {} of {}",
+                expected.frames, expected.bytes
+            ));
+        }
+        return Ok(format!("{path}: {frames} lines, {bytes} bytes decoded"));
+    }
+    let headers = (expected.framing.header_len() * expected.frames) as u64;
+    if data.len() as u64 != expected.bytes + headers {
+        return Err(format!(
+            "{path}: {} bytes, expected {}",
+            data.len(),
+            expected.bytes + headers
+        ));
+    }
+    let frames = match (expected.framing, expected.jpeg) {
+        (Framing::Pts | Framing::PtsCrc, _) => Some(count_records(&data, expected.framing)?),
+        (Framing::Raw, true) => Some(count_jpeg(&data)?),
+        // Raw frames carry no boundaries to count
+        (Framing::Raw, false) | (Framing::Base64, _) => None,
+    };
+    match frames {
+        Some(frames) if frames != expected.frames => Err(format!(
+            "{path}: {frames} frames, expected {}",
+            expected.frames
+        )),
+        Some(frames) => Ok(format!("{path}: {frames} frames, {} bytes", data.len())),
+        None => Ok(format!("{path}: {} bytes", data.len())),
+    }
+}